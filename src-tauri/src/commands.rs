@@ -1,15 +1,40 @@
 use crate::storage;
-use crate::download::manager::DownloadManager;
+use crate::storage::credentials::{self, Credentials};
+use crate::storage::db::SearchQuery;
+use crate::storage::history::HistoryPage;
+use crate::download::manager::{DownloadManager, NetworkOptions, UrlAnalysis};
+use crate::download::queue::QueueEntry;
+use crate::download::dash::DashDownloader;
+use crate::download::hls::HlsDownloader;
+use crate::download::http::{ProxySettings, RequestOptions};
+use crate::download::Downloader;
+use crate::download::media::StreamInfo;
+use crate::download::torrent::{self, magnet::{self, MagnetLink}, TorrentDownloader, TorrentInfo};
 use tauri::{AppHandle, State};
 use tokio::sync::Mutex;
 use std::path::Path;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Serialize)]
 pub struct DownloadResponse {
     pub id: String,
     pub download_type: String,
     pub original_url: Option<String>,
+    /// Folder downloads are saved under the source folder's name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    /// Files queued for a folder download
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<ChildResponse>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChildResponse {
+    pub id: String,
+    pub url: String,
+    pub path: String,
+    pub filename: String,
+    pub download_type: String,
 }
 
 #[tauri::command]
@@ -29,18 +54,93 @@ pub fn file_exists(path: String) -> bool {
 pub async fn download_file(
     state: State<'_, Mutex<DownloadManager>>,
     url: String,
-    save_path: String
+    save_path: String,
+    checksum: Option<String>,
+    files: Option<Vec<usize>>,
+    proxy: Option<ProxySettings>,
+    request: Option<RequestOptions>
+) -> Result<DownloadResponse, String> {
+    // Folders resolve every file over the network; don't hold the manager meanwhile
+    let network = NetworkOptions { proxy, request };
+    let (http, downloaders) = {
+        let manager = state.lock().await;
+        (manager.http(&url, &network)?, manager.downloaders())
+    };
+    let resolved = DownloadManager::resolve(&url, &http, &downloaders).await;
+    let result_json = state.lock().await.download(url, save_path, checksum, files, network, resolved).await?;
+    parse_download_response(&result_json)
+}
+
+/// Type, file name and size of what `url` points at, for prefilling the
+/// save dialog before `download_file`.
+#[tauri::command]
+pub async fn analyze_url(
+    state: State<'_, Mutex<DownloadManager>>,
+    url: String,
+    proxy: Option<ProxySettings>,
+    request: Option<RequestOptions>
+) -> Result<UrlAnalysis, String> {
+    let (http, downloaders) = {
+        let manager = state.lock().await;
+        (manager.http(&url, &NetworkOptions { proxy, request })?, manager.downloaders())
+    };
+    DownloadManager::analyze(&url, &http, &downloaders).await
+}
+
+/// Lists the files of a `.torrent` or magnet link so the user can choose
+/// which to download. Magnet links are resolved from peers first.
+#[tauri::command]
+pub async fn inspect_torrent(
+    state: State<'_, Mutex<DownloadManager>>,
+    url: String
+) -> Result<TorrentInfo, String> {
+    let http = state.lock().await.http(&url, &NetworkOptions::default())?;
+    let meta = if magnet::is_magnet(&url) {
+        let link = MagnetLink::parse(&url).map_err(|e| e.to_string())?;
+        torrent::resolve_magnet(&link, &http).await.map(|(meta, _)| meta)
+    } else {
+        TorrentDownloader::fetch(&url, &http).await
+    };
+    Ok(TorrentInfo::from(&meta.map_err(|e| e.to_string())?))
+}
+
+/// Lists the variants of an HLS playlist or the representations of a DASH
+/// manifest; pass the chosen indices as `files` when starting the download
+/// (one for HLS, a video and an audio one for DASH).
+#[tauri::command]
+pub async fn inspect_stream(
+    state: State<'_, Mutex<DownloadManager>>,
+    url: String
+) -> Result<StreamInfo, String> {
+    let http = state.lock().await.http(&url, &NetworkOptions::default())?;
+    let info = if DashDownloader.detect(&url) {
+        DashDownloader::inspect(&url, &http).await
+    } else {
+        HlsDownloader::inspect(&url, &http).await
+    };
+    info.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn resume_download(
+    state: State<'_, Mutex<DownloadManager>>,
+    id: String
 ) -> Result<DownloadResponse, String> {
     let mut manager = state.lock().await;
-    let result_json = manager.download(url, save_path).await?;
-    
-    let result: serde_json::Value = serde_json::from_str(&result_json)
+    let result_json = manager.resume(id).await?;
+    parse_download_response(&result_json)
+}
+
+fn parse_download_response(result_json: &str) -> Result<DownloadResponse, String> {
+    let result: serde_json::Value = serde_json::from_str(result_json)
         .map_err(|e| e.to_string())?;
     
     Ok(DownloadResponse {
         id: result["id"].as_str().unwrap_or_default().to_string(),
         download_type: result["download_type"].as_str().unwrap_or("http").to_string(),
         original_url: result["original_url"].as_str().map(|s| s.to_string()),
+        filename: result["filename"].as_str().map(|s| s.to_string()),
+        children: serde_json::from_value(result["children"].clone()).unwrap_or_default(),
     })
 }
 
@@ -53,27 +153,155 @@ pub async fn pause_download(
     manager.pause(id)
 }
 
+#[tauri::command]
+pub async fn set_download_speed_limit(
+    state: State<'_, Mutex<DownloadManager>>,
+    id: String,
+    bytes_per_sec: Option<u64>
+) -> Result<(), String> {
+    let mut manager = state.lock().await;
+    manager.set_speed_limit(id, bytes_per_sec);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_download_queue(
+    state: State<'_, Mutex<DownloadManager>>
+) -> Result<Vec<QueueEntry>, String> {
+    Ok(state.lock().await.queue_entries())
+}
+
 #[tauri::command]
 pub fn load_settings(app: AppHandle) -> Result<storage::AppSettings, String> {
     storage::load_settings(&app)
 }
 
 #[tauri::command]
-pub fn save_settings(app: AppHandle, settings: storage::AppSettings) -> Result<(), String> {
-    storage::save_settings(&app, &settings)
+pub async fn save_settings(
+    app: AppHandle,
+    state: State<'_, Mutex<DownloadManager>>,
+    settings: storage::AppSettings
+) -> Result<(), String> {
+    storage::save_settings(&app, &settings)?;
+    let mut manager = state.lock().await;
+    manager.set_max_concurrent(settings.max_concurrent_downloads);
+    manager.set_global_speed_limit(settings.speed_limit);
+    manager.set_retry_policy(settings.retry);
+    manager.set_proxy(settings.proxy);
+    Ok(())
+}
+
+/// JSON files that had to be restored from backup (or reset) since startup.
+#[tauri::command]
+pub fn take_storage_recoveries() -> Vec<storage::RecoveryNotice> {
+    storage::take_recoveries()
+}
+
+#[tauri::command]
+pub async fn load_download_history(
+    state: State<'_, Mutex<DownloadManager>>
+) -> Result<storage::DownloadHistory, String> {
+    let history = state.lock().await.history().clone();
+    tokio::task::spawn_blocking(move || history.snapshot()).await.map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn save_download_history(
+    state: State<'_, Mutex<DownloadManager>>,
+    history: storage::DownloadHistory
+) -> Result<(), String> {
+    state.lock().await.history().replace(history);
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn clear_download_history(
+    state: State<'_, Mutex<DownloadManager>>
+) -> Result<(), String> {
+    state.lock().await.history().clear();
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn get_download(
+    state: State<'_, Mutex<DownloadManager>>,
+    id: String
+) -> Result<Option<storage::DownloadHistoryItem>, String> {
+    Ok(state.lock().await.history().get(&id))
+}
+
+#[tauri::command]
+pub async fn list_downloads(
+    state: State<'_, Mutex<DownloadManager>>,
+    status: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>
+) -> Result<HistoryPage, String> {
+    // Query without holding the manager
+    let history = state.lock().await.history().clone();
+    tokio::task::spawn_blocking(move || {
+        history.list(status.as_deref(), offset.unwrap_or(0), limit.unwrap_or(50))
+    }).await.map_err(|e| e.to_string())?
+}
+
+/// `from`/`to` are RFC 3339 timestamps bounding the creation date.
+#[tauri::command]
+pub async fn search_downloads(
+    state: State<'_, Mutex<DownloadManager>>,
+    query: Option<String>,
+    status: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>
+) -> Result<HistoryPage, String> {
+    let history = state.lock().await.history().clone();
+    tokio::task::spawn_blocking(move || history.search(&SearchQuery {
+        text: query,
+        status,
+        from,
+        to,
+        offset: offset.unwrap_or(0),
+        limit: limit.unwrap_or(50),
+    })).await.map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+pub async fn remove_download(
+    state: State<'_, Mutex<DownloadManager>>,
+    id: String
+) -> Result<(), String> {
+    state.lock().await.remove(&id);
+    Ok(())
+}
+
+/// Stores a login for the server `url` points at, used when the URL itself
+/// has no credentials.
+#[tauri::command]
+pub fn save_credentials(url: String, username: String, password: String) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(&url).map_err(|e| e.to_string())?;
+    let origin = credentials::origin_of(&parsed).ok_or("URL has no host")?;
+    credentials::save(&origin, &Credentials { username, password })
 }
 
 #[tauri::command]
-pub fn load_download_history(app: AppHandle) -> Result<storage::DownloadHistory, String> {
-    storage::load_download_history(&app)
+pub fn delete_credentials(url: String) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(&url).map_err(|e| e.to_string())?;
+    let origin = credentials::origin_of(&parsed).ok_or("URL has no host")?;
+    credentials::delete(&origin)
 }
 
+/// Stores a bearer token sent to every request to the server `url` points at.
 #[tauri::command]
-pub fn save_download_history(app: AppHandle, history: storage::DownloadHistory) -> Result<(), String> {
-    storage::save_download_history(&app, &history)
+pub fn save_bearer_token(url: String, token: String) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(&url).map_err(|e| e.to_string())?;
+    let origin = credentials::origin_of(&parsed).ok_or("URL has no host")?;
+    credentials::save_token(&origin, &token)
 }
 
 #[tauri::command]
-pub fn clear_download_history(app: AppHandle) -> Result<(), String> {
-    storage::clear_download_history(&app)
+pub fn delete_bearer_token(url: String) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(&url).map_err(|e| e.to_string())?;
+    let origin = credentials::origin_of(&parsed).ok_or("URL has no host")?;
+    credentials::delete_token(&origin)
 }
@@ -0,0 +1,377 @@
+use crate::download::http::HttpHelper;
+use crate::download::DownloadError;
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha1,
+    Md5,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().replace('-', "").as_str() {
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "sha1" => Some(HashAlgorithm::Sha1),
+            "md5" => Some(HashAlgorithm::Md5),
+            "blake3" | "b3" => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    /// Bare hex digests are told apart by length; 64 characters is assumed
+    /// to be SHA-256 since BLAKE3 sums are usually labelled.
+    fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            64 => Some(HashAlgorithm::Sha256),
+            40 => Some(HashAlgorithm::Sha1),
+            32 => Some(HashAlgorithm::Md5),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExpectedChecksum {
+    pub algorithm: HashAlgorithm,
+    /// Lowercase hex
+    pub value: String,
+}
+
+impl ExpectedChecksum {
+    /// Accepts `algorithm:hex` (e.g. `sha256:ab12...`) or a bare hex digest.
+    pub fn parse(spec: &str) -> Result<Self, DownloadError> {
+        let spec = spec.trim();
+        let (algorithm, value) = match spec.split_once(':') {
+            Some((name, value)) => {
+                let algorithm = HashAlgorithm::from_name(name)
+                    .ok_or_else(|| DownloadError::Other(format!("Unsupported hash algorithm: {}", name)))?;
+                (algorithm, value.trim())
+            }
+            None => {
+                let algorithm = HashAlgorithm::from_hex_len(spec.len())
+                    .ok_or_else(|| DownloadError::Other(format!("Unrecognized checksum: {}", spec)))?;
+                (algorithm, spec)
+            }
+        };
+
+        Self::new(algorithm, value)
+    }
+
+    fn new(algorithm: HashAlgorithm, value: &str) -> Result<Self, DownloadError> {
+        if value.is_empty() || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(DownloadError::Other(format!("Checksum is not a hex digest: {}", value)));
+        }
+
+        Ok(Self {
+            algorithm,
+            value: value.to_ascii_lowercase(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChecksumResult {
+    pub algorithm: HashAlgorithm,
+    pub value: String,
+    pub verified: bool,
+}
+
+/// Turns what the user passed to `download_file` into an expected digest.
+/// URLs are fetched and read as a `.sha256`-style file or a `SHASUMS` list,
+/// picking the line for `filename`.
+pub async fn resolve(spec: &str, filename: &str, http: &HttpHelper) -> Result<ExpectedChecksum, DownloadError> {
+    let spec = spec.trim();
+    if !(spec.starts_with("http://") || spec.starts_with("https://")) {
+        return ExpectedChecksum::parse(spec);
+    }
+
+    let response = http.send(http.client().get(spec))
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(DownloadError::from_status(&response));
+    }
+    let body = response.text()
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+
+    // Extension of the checksum file names the algorithm (`.sha256`, `.md5`, `SHA1SUMS`, ...)
+    let hint = ["sha256", "sha1", "md5", "b3", "blake3"].iter()
+        .find(|name| spec.to_ascii_lowercase().contains(*name))
+        .and_then(|name| HashAlgorithm::from_name(name));
+
+    parse_sums(&body, filename, hint)
+        .ok_or_else(|| DownloadError::Other(format!("No checksum for {} found at {}", filename, spec)))
+}
+
+/// Understands GNU coreutils output (`<hex>  name` / `<hex> *name`), BSD tag
+/// style (`SHA256 (name) = <hex>`) and single-digest files.
+fn parse_sums(body: &str, filename: &str, hint: Option<HashAlgorithm>) -> Option<ExpectedChecksum> {
+    let lines: Vec<&str> = body.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect();
+
+    let build = |algorithm: Option<HashAlgorithm>, hex: &str| {
+        let algorithm = algorithm.or(hint).or_else(|| HashAlgorithm::from_hex_len(hex.len()))?;
+        ExpectedChecksum::new(algorithm, hex).ok()
+    };
+
+    for line in &lines {
+        if let Some((tag, rest)) = line.split_once(" (") {
+            if let Some((name, hex)) = rest.split_once(") = ") {
+                if name == filename {
+                    return build(HashAlgorithm::from_name(tag), hex.trim());
+                }
+            }
+            continue;
+        }
+
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let hex = parts.next().unwrap_or_default();
+        let name = parts.next().unwrap_or_default().trim().trim_start_matches('*');
+        let name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+        if name == filename {
+            return build(None, hex);
+        }
+    }
+
+    // `.sha256` files often contain only the digest; a line naming another
+    // file is not a match
+    match lines.as_slice() {
+        [only] if !only.contains(char::is_whitespace) => build(None, only),
+        _ => None,
+    }
+}
+
+pub enum StreamHasher {
+    Sha256(Sha256),
+    Sha1(Sha1),
+    Md5(Md5),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl StreamHasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => StreamHasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha1 => StreamHasher::Sha1(Sha1::new()),
+            HashAlgorithm::Md5 => StreamHasher::Md5(Md5::new()),
+            HashAlgorithm::Blake3 => StreamHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            StreamHasher::Sha256(h) => h.update(data),
+            StreamHasher::Sha1(h) => h.update(data),
+            StreamHasher::Md5(h) => h.update(data),
+            StreamHasher::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    /// Feeds the first `len` bytes of `path`, e.g. the part of a `.fdm` file
+    /// written before a resume.
+    pub async fn update_from_file(&mut self, path: &Path, len: u64) -> Result<(), DownloadError> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .map_err(|e| DownloadError::IoError(e.to_string()))?;
+        let mut reader = file.take(len);
+        let mut buf = vec![0u8; 256 * 1024];
+        loop {
+            let n = reader.read(&mut buf)
+                .await
+                .map_err(|e| DownloadError::IoError(e.to_string()))?;
+            if n == 0 {
+                return Ok(());
+            }
+            self.update(&buf[..n]);
+        }
+    }
+
+    pub fn finalize_hex(self) -> String {
+        match self {
+            StreamHasher::Sha256(h) => hex::encode(h.finalize()),
+            StreamHasher::Sha1(h) => hex::encode(h.finalize()),
+            StreamHasher::Md5(h) => hex::encode(h.finalize()),
+            StreamHasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+/// Compares a finished digest against the expected one. A mismatching
+/// `.fdm` file is deleted, since resuming on top of it can't fix it.
+pub async fn verify(expected: &ExpectedChecksum, actual: String, temp_path: &Path) -> Result<ChecksumResult, DownloadError> {
+    if actual != expected.value {
+        let _ = tokio::fs::remove_file(temp_path).await;
+        return Err(DownloadError::ChecksumMismatch {
+            expected: expected.value.clone(),
+            actual,
+        });
+    }
+    Ok(ChecksumResult {
+        algorithm: expected.algorithm,
+        value: actual,
+        verified: true,
+    })
+}
+
+/// Starts an incremental hasher for a download that already has `downloaded`
+/// bytes in `temp_path`.
+pub async fn resume_hasher(
+    expected: Option<&ExpectedChecksum>,
+    temp_path: &Path,
+    downloaded: u64,
+) -> Result<Option<StreamHasher>, DownloadError> {
+    let Some(expected) = expected else {
+        return Ok(None);
+    };
+    let mut hasher = StreamHasher::new(expected.algorithm);
+    if downloaded > 0 {
+        hasher.update_from_file(temp_path, downloaded).await?;
+    }
+    Ok(Some(hasher))
+}
+
+/// Hashes a whole file; used when chunks didn't arrive in order (segmented downloads).
+pub async fn hash_file(path: &Path, algorithm: HashAlgorithm) -> Result<String, DownloadError> {
+    let len = tokio::fs::metadata(path)
+        .await
+        .map_err(|e| DownloadError::IoError(e.to_string()))?
+        .len();
+    let mut hasher = StreamHasher::new(algorithm);
+    hasher.update_from_file(path, len).await?;
+    Ok(hasher.finalize_hex())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    const SHA256_ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    const SHA1_ABC: &str = "a9993e364706816aba3e25717850c26c9cd0d89d";
+    const MD5_ABC: &str = "900150983cd24fb0d6963f7d28e17f72";
+
+    #[test]
+    fn parses_specs() {
+        let upper = SHA256_ABC.to_ascii_uppercase();
+        let cases = [
+            (format!("sha256:{}", SHA256_ABC), HashAlgorithm::Sha256),
+            (format!("  SHA-256: {} ", upper), HashAlgorithm::Sha256),
+            (format!("b3:{}", SHA256_ABC), HashAlgorithm::Blake3),
+            (SHA256_ABC.to_string(), HashAlgorithm::Sha256),
+            (SHA1_ABC.to_string(), HashAlgorithm::Sha1),
+            (MD5_ABC.to_string(), HashAlgorithm::Md5),
+        ];
+        for (spec, algorithm) in cases {
+            let parsed = ExpectedChecksum::parse(&spec).unwrap();
+            assert_eq!(parsed.algorithm, algorithm, "{}", spec);
+            assert_eq!(parsed.value, parsed.value.to_ascii_lowercase());
+        }
+
+        for spec in ["crc32:cbf43926", "abc123", "sha256:", "sha256:xyz", ""] {
+            assert!(ExpectedChecksum::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn parses_sums_files() {
+        // body, algorithm hinted by the URL, expected result
+        type Case<'a> = (&'a str, Option<HashAlgorithm>, Option<(HashAlgorithm, &'a str)>);
+        let cases: &[Case] = &[
+            // GNU text and binary mode, with a directory in front
+            (&format!("{}  other.iso\n{}  file.iso\n", MD5_ABC, SHA256_ABC), None, Some((HashAlgorithm::Sha256, SHA256_ABC))),
+            (&format!("{} *file.iso", SHA1_ABC), None, Some((HashAlgorithm::Sha1, SHA1_ABC))),
+            (&format!("{}  ./dist/file.iso", MD5_ABC), None, Some((HashAlgorithm::Md5, MD5_ABC))),
+            // BSD tags name the algorithm themselves
+            (&format!("SHA256 (other.iso) = {}\nMD5 (file.iso) = {}", SHA256_ABC, MD5_ABC), None, Some((HashAlgorithm::Md5, MD5_ABC))),
+            (&format!("BLAKE3 (file.iso) = {}", SHA256_ABC), None, Some((HashAlgorithm::Blake3, SHA256_ABC))),
+            // A lone digest, where the file name decides a 64-char one is BLAKE3
+            (&format!("# comment\n{}\n", SHA256_ABC), Some(HashAlgorithm::Blake3), Some((HashAlgorithm::Blake3, SHA256_ABC))),
+            (&format!("{}  file.iso", SHA256_ABC), None, Some((HashAlgorithm::Sha256, SHA256_ABC))),
+            (&format!("{}  other.iso\n{}  another.iso", SHA256_ABC, MD5_ABC), None, None),
+            (&format!("{}  other.iso", SHA256_ABC), None, None),
+            (&format!("SHA256 (other.iso) = {}", SHA256_ABC), None, None),
+            ("not a digest  file.iso", None, None),
+            ("", None, None),
+        ];
+        for (body, hint, expected) in cases {
+            let parsed = parse_sums(body, "file.iso", *hint).map(|c| (c.algorithm, c.value));
+            assert_eq!(parsed, expected.map(|(a, v)| (a, v.to_string())), "{:?}", body);
+        }
+    }
+
+    /// Serves `body` with `status` to every request.
+    async fn serve(status: &'static str, body: String) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 4096];
+                    let _ = socket.read(&mut buf).await;
+                    let response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn resolves_specs_and_urls() {
+        let http = HttpHelper::with_options("", &Default::default(), &Default::default()).unwrap();
+
+        let inline = resolve(&format!("md5:{}", MD5_ABC), "file.iso", &http).await.unwrap();
+        assert_eq!((inline.algorithm, inline.value.as_str()), (HashAlgorithm::Md5, MD5_ABC));
+
+        let base = serve("200 OK", format!("{}  file.iso\n", SHA256_ABC)).await;
+        let fetched = resolve(&format!("{}/SHA256SUMS", base), "file.iso", &http).await.unwrap();
+        assert_eq!((fetched.algorithm, fetched.value.as_str()), (HashAlgorithm::Sha256, SHA256_ABC));
+        // The URL's `.b3` beats the digest length
+        let fetched = resolve(&format!("{}/file.iso.b3", base), "file.iso", &http).await.unwrap();
+        assert_eq!(fetched.algorithm, HashAlgorithm::Blake3);
+
+        let missing = resolve(&format!("{}/SHA256SUMS", base), "other.iso", &http).await;
+        assert!(matches!(missing, Err(DownloadError::Other(msg)) if msg.starts_with("No checksum for other.iso")));
+
+        let base = serve("404 Not Found", String::new()).await;
+        let gone = resolve(&format!("{}/SHA256SUMS", base), "file.iso", &http).await;
+        assert!(matches!(gone, Err(DownloadError::HttpStatus { status: 404, .. })));
+    }
+
+    #[tokio::test]
+    async fn hashes_and_verifies_files() {
+        let path = std::env::temp_dir().join(format!("checksum-{}.fdm", uuid::Uuid::new_v4()));
+        tokio::fs::write(&path, b"abc").await.unwrap();
+
+        assert_eq!(hash_file(&path, HashAlgorithm::Sha256).await.unwrap(), SHA256_ABC);
+        assert_eq!(hash_file(&path, HashAlgorithm::Sha1).await.unwrap(), SHA1_ABC);
+        assert_eq!(hash_file(&path, HashAlgorithm::Md5).await.unwrap(), MD5_ABC);
+
+        // Resuming after "ab" and feeding "c" gives the same digest
+        let expected = ExpectedChecksum::parse(SHA256_ABC).unwrap();
+        let mut hasher = resume_hasher(Some(&expected), &path, 2).await.unwrap().unwrap();
+        hasher.update(b"c");
+        let result = verify(&expected, hasher.finalize_hex(), &path).await.unwrap();
+        assert!(result.verified);
+        assert!(path.exists());
+
+        let mismatch = verify(&expected, MD5_ABC.to_string(), &path).await;
+        assert!(matches!(mismatch, Err(DownloadError::ChecksumMismatch { .. })));
+        assert!(!path.exists(), "a mismatching .fdm is deleted");
+    }
+}
@@ -0,0 +1,48 @@
+use reqwest::cookie::Jar;
+use reqwest::Url;
+
+/// Adds a `Cookie` header style string (`a=1; b=2`) for the host of `url`.
+pub fn add_cookie_string(jar: &Jar, cookies: &str, url: &Url) {
+    for pair in cookies.split(';').map(str::trim).filter(|pair| pair.contains('=')) {
+        jar.add_cookie_str(&format!("{}; Path=/", pair), url);
+    }
+}
+
+/// Adds every unexpired cookie of a Netscape `cookies.txt`, the format
+/// browser extensions and curl export. Returns how many were added.
+pub fn add_netscape_file(jar: &Jar, text: &str) -> usize {
+    let now = chrono::Utc::now().timestamp();
+    let mut added = 0;
+    for line in text.lines() {
+        // curl marks HttpOnly cookies with a prefix on an otherwise commented line
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [domain, include_subdomains, path, secure, expires, name, value] = fields[..] else {
+            continue;
+        };
+        // 0 marks a session cookie
+        let expires: i64 = expires.trim().parse().unwrap_or(0);
+        if expires != 0 && expires < now {
+            continue;
+        }
+
+        let host = domain.trim_start_matches('.');
+        let secure = secure.eq_ignore_ascii_case("TRUE");
+        let Ok(url) = Url::parse(&format!("{}://{}{}", if secure { "https" } else { "http" }, host, path)) else {
+            continue;
+        };
+        let mut cookie = format!("{}={}; Path={}", name, value, path);
+        if include_subdomains.eq_ignore_ascii_case("TRUE") {
+            cookie.push_str(&format!("; Domain={}", host));
+        }
+        if secure {
+            cookie.push_str("; Secure");
+        }
+        jar.add_cookie_str(&cookie, &url);
+        added += 1;
+    }
+    added
+}
@@ -0,0 +1,266 @@
+pub mod mpd;
+
+use crate::download::checksum;
+use crate::download::http::HttpHelper;
+use crate::download::media::{self, MediaSettings, StreamInfo, StreamVariant, Track};
+use crate::download::{DownloadContext, DownloadError, DownloadMeta, DownloadResult, Downloader};
+use crate::storage::{self, DownloadType};
+use async_trait::async_trait;
+use mpd::{Manifest, Representation, TrackKind};
+use reqwest::Url;
+use std::path::{Path, PathBuf};
+use tauri::Emitter;
+
+async fn fetch_manifest(url: &str, http: &HttpHelper) -> DownloadResult<Manifest> {
+    let base = Url::parse(url).map_err(|e| DownloadError::InvalidUrl(e.to_string()))?;
+    mpd::parse(&media::fetch_text(url, http).await?, &base)
+}
+
+/// Highest bandwidth of the given kind wins; ties go to the first listed.
+fn default_representation(representations: &[Representation], kind: TrackKind) -> Option<usize> {
+    representations.iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, r)| r.kind == kind)
+        .max_by_key(|(_, r)| r.bandwidth)
+        .map(|(i, _)| i)
+}
+
+/// Container extension for a track written on its own.
+fn track_extension(rep: &Representation) -> &'static str {
+    match (rep.kind, rep.mime_type.as_deref()) {
+        (_, Some(mime)) if mime.ends_with("/webm") => "webm",
+        (TrackKind::Audio, _) => "m4a",
+        (TrackKind::Video, _) => "mp4",
+    }
+}
+
+/// ffmpeg output format matching the extension of the file being saved.
+fn mux_format(save_path: &str) -> &'static str {
+    match Path::new(save_path).extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).as_deref() {
+        Some("mkv") => "matroska",
+        Some("webm") => "webm",
+        _ => "mp4",
+    }
+}
+
+/// `movie.mp4` -> `movie.audio.m4a`
+fn sibling_path(save_path: &str, label: &str, extension: &str) -> PathBuf {
+    let path = Path::new(save_path);
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!("{}.{}.{}", stem, label, extension))
+}
+
+/// One track to download: its representation in each period, in order.
+struct Selection {
+    kind: TrackKind,
+    representations: Vec<Representation>,
+}
+
+impl Selection {
+    fn segments(&self) -> Vec<media::MediaSegment> {
+        self.representations.iter().flat_map(|r| r.segments.iter().cloned()).collect()
+    }
+}
+
+pub struct DashDownloader;
+
+impl DashDownloader {
+    /// Lists the audio and video representations of the first period.
+    pub async fn inspect(url: &str, http: &HttpHelper) -> DownloadResult<StreamInfo> {
+        let manifest = fetch_manifest(url, http).await?;
+        let representations = &manifest.periods[0].representations;
+        Ok(StreamInfo {
+            variants: representations.iter()
+                .enumerate()
+                .map(|(index, r)| StreamVariant {
+                    index,
+                    kind: Some(r.kind.as_str()),
+                    bandwidth: r.bandwidth,
+                    resolution: r.width.zip(r.height).map(|(w, h)| format!("{}x{}", w, h)),
+                    codecs: r.codecs.clone(),
+                })
+                .collect(),
+            default_variants: [TrackKind::Video, TrackKind::Audio].into_iter()
+                .filter_map(|kind| default_representation(representations, kind))
+                .collect(),
+            duration: manifest.duration,
+        })
+    }
+
+    /// Picks one video and one audio track. `choice` indexes the first
+    /// period's representations; later periods use the representation with
+    /// the same id, or their best one.
+    fn select(manifest: &Manifest, choice: &[usize]) -> DownloadResult<Vec<Selection>> {
+        let first = &manifest.periods[0].representations;
+        if let Some(&bad) = choice.iter().find(|&&i| i >= first.len()) {
+            return Err(DownloadError::InvalidUrl(format!("Manifest has no representation {}", bad)));
+        }
+
+        let mut selections = Vec::new();
+        for kind in [TrackKind::Video, TrackKind::Audio] {
+            let chosen = choice.iter()
+                .copied()
+                .find(|&i| first[i].kind == kind)
+                .or_else(|| default_representation(first, kind));
+            let Some(chosen) = chosen else {
+                continue;
+            };
+            let id = &first[chosen].id;
+            eprintln!("[DASH] Using {} representation {} ({} bps)", kind.as_str(), id, first[chosen].bandwidth);
+
+            let mut representations = vec![first[chosen].clone()];
+            for period in &manifest.periods[1..] {
+                let reps = &period.representations;
+                let matching = reps.iter()
+                    .position(|r| r.kind == kind && &r.id == id)
+                    .or_else(|| default_representation(reps, kind));
+                if let Some(index) = matching {
+                    representations.push(reps[index].clone());
+                }
+            }
+            selections.push(Selection { kind, representations });
+        }
+        Ok(selections)
+    }
+
+    /// Joins video and audio into `output` with ffmpeg. Returns false when
+    /// ffmpeg isn't available or fails, leaving the tracks as they are.
+    async fn mux(video: &Path, audio: &Path, output: &Path, format: &str, settings: &MediaSettings) -> bool {
+        let program = settings.ffmpeg_path.as_deref().unwrap_or("ffmpeg");
+        let result = tokio::process::Command::new(program)
+            .args(["-y", "-loglevel", "error", "-i"])
+            .arg(video)
+            .arg("-i")
+            .arg(audio)
+            .args(["-map", "0:v", "-map", "1:a", "-c", "copy", "-f", format])
+            .arg(output)
+            .output()
+            .await;
+        match result {
+            Ok(out) if out.status.success() => true,
+            Ok(out) => {
+                eprintln!("[DASH] ffmpeg failed: {}", String::from_utf8_lossy(&out.stderr).trim());
+                let _ = tokio::fs::remove_file(output).await;
+                false
+            }
+            Err(e) => {
+                eprintln!("[DASH] Can't run {}: {}; keeping separate tracks", program, e);
+                false
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Downloader for DashDownloader {
+    fn download_type(&self) -> DownloadType {
+        DownloadType::Dash
+    }
+
+    fn detect(&self, url: &str) -> bool {
+        let lower = url.to_ascii_lowercase();
+        let path = lower.split(['?', '#']).next().unwrap_or_default();
+        path.ends_with(".mpd")
+    }
+
+    async fn analyze(&self, url: &str, _http: &HttpHelper) -> DownloadResult<Option<DownloadMeta>> {
+        if !self.detect(url) {
+            return Ok(None);
+        }
+        let parsed = Url::parse(url).map_err(|e| DownloadError::InvalidUrl(e.to_string()))?;
+        let stem = parsed.path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .and_then(|name| Path::new(name).file_stem())
+            .map(|stem| format!("{}.mp4", stem.to_string_lossy()));
+
+        Ok(Some(DownloadMeta {
+            download_type: DownloadType::Dash,
+            direct_url: url.to_string(),
+            original_url: None,
+            suggested_filename: stem,
+            ..Default::default()
+        }))
+    }
+
+    async fn run(&self, ctx: DownloadContext) -> DownloadResult<()> {
+        eprintln!("[DASH] Starting download: id={}, url={}", ctx.id, ctx.url);
+        let settings = storage::load_settings(&ctx.app).map(|s| s.media).unwrap_or_default();
+
+        let manifest = fetch_manifest(&ctx.url, &ctx.http).await?;
+        if manifest.dynamic {
+            return Err(DownloadError::InvalidUrl(
+                "Live DASH streams aren't supported; only on-demand manifests can be downloaded".to_string(),
+            ));
+        }
+        let selections = Self::select(&manifest, ctx.files.as_deref().unwrap_or_default())?;
+
+        // Each track resumes from its own `.fdm` file and segment directory
+        let tracks: Vec<Track> = selections.iter()
+            .map(|s| Track {
+                segments: s.segments(),
+                output: PathBuf::from(format!("{}.{}.fdm", ctx.save_path, s.kind.as_str())),
+            })
+            .collect();
+        eprintln!(
+            "[DASH] {}: {} segments in {} track(s), {} period(s)",
+            ctx.id,
+            tracks.iter().map(|t| t.segments.len()).sum::<usize>(),
+            tracks.len(),
+            manifest.periods.len()
+        );
+        media::download(&ctx, &tracks).await?;
+
+        let file_path = PathBuf::from(&ctx.save_path);
+        let temp_path = PathBuf::from(format!("{}.fdm", ctx.save_path));
+        // Besides the main file, an audio track that couldn't be muxed is kept next to it
+        let mut extra = None;
+        match tracks.as_slice() {
+            [video, audio] => {
+                let muxed = settings.mux_tracks
+                    && Self::mux(&video.output, &audio.output, &temp_path, mux_format(&ctx.save_path), &settings).await;
+                if muxed {
+                    eprintln!("[DASH] {}: muxed video and audio", ctx.id);
+                    let _ = tokio::fs::remove_file(&video.output).await;
+                    let _ = tokio::fs::remove_file(&audio.output).await;
+                } else {
+                    let rep = &selections[1].representations[0];
+                    extra = Some((audio.output.clone(), sibling_path(&ctx.save_path, "audio", track_extension(rep))));
+                    tokio::fs::rename(&video.output, &temp_path)
+                        .await
+                        .map_err(|e| DownloadError::IoError(format!("Failed to rename file: {}", e)))?;
+                }
+            }
+            [only] => {
+                tokio::fs::rename(&only.output, &temp_path)
+                    .await
+                    .map_err(|e| DownloadError::IoError(format!("Failed to rename file: {}", e)))?;
+            }
+            _ => return Err(DownloadError::InvalidUrl("DASH manifest has no audio or video".to_string())),
+        }
+
+        let checksum_result = match ctx.checksum.as_ref() {
+            Some(expected) => {
+                let actual = checksum::hash_file(&temp_path, expected.algorithm).await?;
+                Some(checksum::verify(expected, actual, &temp_path).await?)
+            }
+            None => None,
+        };
+        tokio::fs::rename(&temp_path, &file_path)
+            .await
+            .map_err(|e| DownloadError::IoError(format!("Failed to rename file: {}", e)))?;
+        if let Some((from, to)) = extra {
+            tokio::fs::rename(&from, &to)
+                .await
+                .map_err(|e| DownloadError::IoError(format!("Failed to rename file: {}", e)))?;
+            eprintln!("[DASH] {}: audio saved separately as {}", ctx.id, to.display());
+        }
+
+        let _ = ctx.app.emit("download://complete", serde_json::json!({
+            "id": ctx.id,
+            "checksum": checksum_result,
+        }));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,366 @@
+use crate::download::media::MediaSegment;
+use crate::download::DownloadError;
+use reqwest::Url;
+use roxmltree::Node;
+
+/// More segments than any real period has (a day of 1 s segments is 86,400);
+/// a hostile manifest could otherwise ask for billions
+const MAX_SEGMENTS: u64 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrackKind {
+    Video,
+    Audio,
+}
+
+impl TrackKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrackKind::Video => "video",
+            TrackKind::Audio => "audio",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Representation {
+    pub id: String,
+    pub kind: TrackKind,
+    pub bandwidth: u64,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub codecs: Option<String>,
+    pub mime_type: Option<String>,
+    /// Initialization segment first, then the media segments in order
+    pub segments: Vec<MediaSegment>,
+}
+
+pub struct Period {
+    pub representations: Vec<Representation>,
+}
+
+pub struct Manifest {
+    pub periods: Vec<Period>,
+    pub duration: Option<f64>,
+    /// `type="dynamic"`, i.e. a live stream
+    pub dynamic: bool,
+}
+
+fn invalid(msg: impl Into<String>) -> DownloadError {
+    DownloadError::InvalidUrl(msg.into())
+}
+
+/// ISO 8601 duration as used by MPD attributes, e.g. `PT1H2M3.5S`.
+pub fn parse_duration(value: &str) -> Option<f64> {
+    let rest = value.trim().strip_prefix('P')?;
+    let (date, time) = rest.split_once('T').unwrap_or((rest, ""));
+    let mut seconds = 0.0;
+    for (part, units) in [(date, [("Y", 31_536_000.0), ("M", 2_592_000.0), ("W", 604_800.0), ("D", 86_400.0)].as_slice()),
+                          (time, [("H", 3_600.0), ("M", 60.0), ("S", 1.0)].as_slice())] {
+        let mut number = String::new();
+        for c in part.chars() {
+            if c.is_ascii_digit() || c == '.' {
+                number.push(c);
+                continue;
+            }
+            let (_, factor) = units.iter().find(|(u, _)| u.starts_with(c))?;
+            seconds += number.parse::<f64>().ok()? * factor;
+            number.clear();
+        }
+    }
+    Some(seconds)
+}
+
+/// Expands `$RepresentationID$`, `$Number$`, `$Bandwidth$`, `$Time$` (with
+/// optional `%0Nd` widths) and `$$` in a SegmentTemplate URL.
+fn fill_template(template: &str, id: &str, bandwidth: u64, number: u64, time: u64) -> String {
+    let mut out = String::new();
+    for (i, part) in template.split('$').enumerate() {
+        if i % 2 == 0 {
+            out.push_str(part);
+            continue;
+        }
+        if part.is_empty() {
+            out.push('$');
+            continue;
+        }
+        let (name, format) = part.split_once('%').unwrap_or((part, ""));
+        let width = format.trim_start_matches('0').trim_end_matches('d').parse().unwrap_or(0);
+        let value = match name {
+            "RepresentationID" => Some(id.to_string()),
+            "Number" => Some(format!("{:0width$}", number, width = width)),
+            "Bandwidth" => Some(format!("{:0width$}", bandwidth, width = width)),
+            "Time" => Some(format!("{:0width$}", time, width = width)),
+            _ => None,
+        };
+        match value {
+            Some(value) => out.push_str(&value),
+            None => {
+                out.push('$');
+                out.push_str(part);
+                out.push('$');
+            }
+        }
+    }
+    out
+}
+
+/// `start-end` (inclusive), as in `mediaRange` and `range` attributes
+fn parse_range(value: Option<&str>) -> Option<(u64, u64)> {
+    let (start, end) = value?.split_once('-')?;
+    Some((start.trim().parse().ok()?, end.trim().parse().ok()?))
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn children<'a, 'input: 'a>(node: Node<'a, 'input>, name: &'a str) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children().filter(move |n| n.has_tag_name(name))
+}
+
+fn attr_u64(node: Node, name: &str) -> Option<u64> {
+    node.attribute(name).and_then(|v| v.trim().parse().ok())
+}
+
+/// Applies a `BaseURL` child of `node`, if any, on top of `base`.
+fn base_url(node: Node, base: &Url) -> Result<Url, DownloadError> {
+    match child(node, "BaseURL").and_then(|n| n.text()) {
+        Some(text) => base.join(text.trim()).map_err(|e| invalid(format!("Bad BaseURL {}: {}", text, e))),
+        None => Ok(base.clone()),
+    }
+}
+
+/// SegmentTemplate attributes; a Representation's template inherits what
+/// it doesn't set from the AdaptationSet's.
+#[derive(Clone, Default)]
+struct Template {
+    media: Option<String>,
+    initialization: Option<String>,
+    start_number: Option<u64>,
+    timescale: Option<u64>,
+    duration: Option<u64>,
+    /// `S` elements: start time, duration, repeat count
+    timeline: Option<Vec<(Option<u64>, u64, i64)>>,
+}
+
+impl Template {
+    fn merge(&self, node: Option<Node>) -> Option<Self> {
+        let Some(node) = node else {
+            return (self.media.is_some() || self.initialization.is_some()).then(|| self.clone());
+        };
+        let timeline = child(node, "SegmentTimeline").map(|tl| {
+            children(tl, "S")
+                .map(|s| {
+                    let repeat = s.attribute("r").and_then(|r| r.parse().ok()).unwrap_or(0);
+                    (attr_u64(s, "t"), attr_u64(s, "d").unwrap_or(0), repeat)
+                })
+                .collect()
+        });
+        Some(Self {
+            media: node.attribute("media").map(str::to_string).or_else(|| self.media.clone()),
+            initialization: node.attribute("initialization").map(str::to_string).or_else(|| self.initialization.clone()),
+            start_number: attr_u64(node, "startNumber").or(self.start_number),
+            timescale: attr_u64(node, "timescale").or(self.timescale),
+            duration: attr_u64(node, "duration").or(self.duration),
+            timeline: timeline.or_else(|| self.timeline.clone()),
+        })
+    }
+
+    /// (number, time) of every segment in the period
+    fn numbers(&self, period_duration: Option<f64>) -> Result<Vec<(u64, u64)>, DownloadError> {
+        let start = self.start_number.unwrap_or(1);
+        let timescale = self.timescale.unwrap_or(1).max(1);
+        let period_end = period_duration.map(|d| (d * timescale as f64) as u64);
+
+        if let Some(timeline) = &self.timeline {
+            let mut out = Vec::new();
+            let mut time = 0;
+            for (i, &(t, d, r)) in timeline.iter().enumerate() {
+                time = t.unwrap_or(time);
+                if d == 0 {
+                    return Err(invalid("SegmentTimeline entry without a duration"));
+                }
+                // r = -1 repeats until the next S element or the end of the period
+                let repeats = if r >= 0 {
+                    r as u64
+                } else {
+                    let until = timeline.get(i + 1).and_then(|next| next.0).or(period_end)
+                        .ok_or_else(|| invalid("Open-ended SegmentTimeline without a period duration"))?;
+                    until.saturating_sub(time).div_ceil(d).saturating_sub(1)
+                };
+                if (out.len() as u64).saturating_add(repeats) >= MAX_SEGMENTS {
+                    return Err(too_many_segments());
+                }
+                for _ in 0..=repeats {
+                    out.push((start.saturating_add(out.len() as u64), time));
+                    time = time.checked_add(d).ok_or_else(|| invalid("SegmentTimeline runs past the end of time"))?;
+                }
+            }
+            return Ok(out);
+        }
+
+        let duration = self.duration.filter(|&d| d > 0)
+            .ok_or_else(|| invalid("SegmentTemplate has neither a duration nor a timeline"))?;
+        let count = period_end
+            .map(|end| end.div_ceil(duration))
+            .ok_or_else(|| invalid("Can't count segments without a period duration"))?;
+        if count > MAX_SEGMENTS {
+            return Err(too_many_segments());
+        }
+        Ok((0..count).map(|i| (start.saturating_add(i), i * duration)).collect())
+    }
+}
+
+fn too_many_segments() -> DownloadError {
+    invalid(format!("Manifest lists more than {} segments", MAX_SEGMENTS))
+}
+
+fn kind_of(rep: Node, set: Node) -> Option<TrackKind> {
+    let describe = [rep.attribute("mimeType"), set.attribute("mimeType"), set.attribute("contentType"), rep.attribute("contentType")];
+    for value in describe.into_iter().flatten() {
+        if value.starts_with("video") {
+            return Some(TrackKind::Video);
+        }
+        if value.starts_with("audio") {
+            return Some(TrackKind::Audio);
+        }
+    }
+    None
+}
+
+fn parse_representation(
+    rep: Node,
+    set: Node,
+    kind: TrackKind,
+    base: &Url,
+    set_template: &Template,
+    period_duration: Option<f64>,
+) -> Result<Representation, DownloadError> {
+    let id = rep.attribute("id").unwrap_or_default().to_string();
+    let bandwidth = attr_u64(rep, "bandwidth").unwrap_or(0);
+    let base = base_url(rep, base)?;
+    let join = |uri: &str| base.join(uri).map(|u| u.to_string()).map_err(|e| invalid(format!("Bad segment URL {}: {}", uri, e)));
+    let segment = |url: String, range: Option<(u64, u64)>| MediaSegment { url, range, aes128: None };
+
+    let mut segments = Vec::new();
+    let list = child(rep, "SegmentList").or_else(|| child(set, "SegmentList"));
+    if let Some(template) = set_template.merge(child(rep, "SegmentTemplate")) {
+        if let Some(init) = &template.initialization {
+            segments.push(segment(join(&fill_template(init, &id, bandwidth, 0, 0))?, None));
+        }
+        let media = template.media.as_deref().ok_or_else(|| invalid("SegmentTemplate without a media URL"))?;
+        for (number, time) in template.numbers(period_duration)? {
+            segments.push(segment(join(&fill_template(media, &id, bandwidth, number, time))?, None));
+        }
+    } else if let Some(list) = list {
+        if let Some(init) = child(list, "Initialization") {
+            let url = match init.attribute("sourceURL") {
+                Some(source) => join(source)?,
+                None => base.to_string(),
+            };
+            segments.push(segment(url, parse_range(init.attribute("range"))));
+        }
+        for entry in children(list, "SegmentURL") {
+            let url = match entry.attribute("media") {
+                Some(media) => join(media)?,
+                None => base.to_string(),
+            };
+            segments.push(segment(url, parse_range(entry.attribute("mediaRange"))));
+        }
+    } else {
+        // SegmentBase or nothing: the whole BaseURL is the track
+        segments.push(segment(base.to_string(), None));
+    }
+
+    Ok(Representation {
+        id,
+        kind,
+        bandwidth,
+        width: attr_u64(rep, "width"),
+        height: attr_u64(rep, "height"),
+        codecs: rep.attribute("codecs").or_else(|| set.attribute("codecs")).map(str::to_string),
+        mime_type: rep.attribute("mimeType").or_else(|| set.attribute("mimeType")).map(str::to_string),
+        segments,
+    })
+}
+
+pub fn parse(text: &str, manifest_url: &Url) -> Result<Manifest, DownloadError> {
+    let doc = roxmltree::Document::parse(text).map_err(|e| invalid(format!("Not a DASH manifest: {}", e)))?;
+    let mpd = doc.root_element();
+    if !mpd.has_tag_name("MPD") {
+        return Err(invalid("Not a DASH manifest"));
+    }
+    let duration = mpd.attribute("mediaPresentationDuration").and_then(parse_duration);
+    let dynamic = mpd.attribute("type") == Some("dynamic");
+    let base = base_url(mpd, manifest_url)?;
+
+    let period_nodes: Vec<Node> = children(mpd, "Period").collect();
+    let mut periods = Vec::new();
+    for (i, period) in period_nodes.iter().enumerate() {
+        let start = period.attribute("start").and_then(parse_duration).unwrap_or(0.0);
+        let next_start = period_nodes.get(i + 1).and_then(|p| p.attribute("start")).and_then(parse_duration);
+        let period_duration = period.attribute("duration").and_then(parse_duration)
+            .or_else(|| next_start.map(|next| next - start))
+            .or_else(|| duration.map(|total| total - start));
+        let period_base = base_url(*period, &base)?;
+
+        let mut representations = Vec::new();
+        for set in children(*period, "AdaptationSet") {
+            let set_base = base_url(set, &period_base)?;
+            let set_template = Template::default().merge(child(set, "SegmentTemplate")).unwrap_or_default();
+            for rep in children(set, "Representation") {
+                // Subtitles and other text tracks aren't downloaded
+                let Some(kind) = kind_of(rep, set) else {
+                    continue;
+                };
+                representations.push(parse_representation(rep, set, kind, &set_base, &set_template, period_duration)?);
+            }
+        }
+        periods.push(Period { representations });
+    }
+
+    if periods.iter().all(|p| p.representations.is_empty()) {
+        return Err(invalid("DASH manifest has no audio or video"));
+    }
+    Ok(Manifest { periods, duration, dynamic })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(duration: Option<u64>, timeline: Option<Vec<(Option<u64>, u64, i64)>>) -> Template {
+        Template {
+            media: Some("seg-$Number$.m4s".to_string()),
+            initialization: None,
+            start_number: Some(5),
+            timescale: Some(1000),
+            duration,
+            timeline,
+        }
+    }
+
+    #[test]
+    fn numbers_from_duration_and_timeline() {
+        let numbers = template(Some(4000), None).numbers(Some(10.0)).unwrap();
+        assert_eq!(numbers, [(5, 0), (6, 4000), (7, 8000)]);
+
+        // r = -1 repeats up to the end of the period
+        let numbers = template(None, Some(vec![(Some(100), 2000, 1), (None, 1000, -1)])).numbers(Some(6.1)).unwrap();
+        assert_eq!(numbers, [(5, 100), (6, 2100), (7, 4100), (8, 5100)]);
+    }
+
+    #[test]
+    fn caps_segment_count() {
+        let error = template(Some(1), None).numbers(Some(86_400.0 * 365.0)).unwrap_err();
+        assert!(matches!(error, DownloadError::InvalidUrl(ref msg) if msg.contains("more than 100000")));
+
+        let huge = template(None, Some(vec![(Some(0), 1, i64::MAX)]));
+        assert!(huge.numbers(None).is_err());
+        let open_ended = template(None, Some(vec![(Some(0), 1, -1)]));
+        assert!(open_ended.numbers(Some(1e12)).is_err());
+        let overflow = template(None, Some(vec![(Some(u64::MAX - 1), 1, 3)]));
+        assert!(overflow.numbers(None).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+
+/// Used when neither the server nor the URL offer a usable name
+pub const FALLBACK_NAME: &str = "download";
+
+/// Longest name kept, in bytes; most filesystems stop at 255
+const MAX_NAME_LEN: usize = 200;
+
+/// Picks a file name the way browsers do: `Content-Disposition` first, then
+/// the last path segment of the URL after redirects. A name without an
+/// extension gets one from `Content-Type`.
+pub fn resolve(disposition: Option<&str>, url: &Url, content_type: Option<&str>) -> String {
+    let name = disposition
+        .and_then(from_content_disposition)
+        .or_else(|| from_url(url))
+        .map(|name| sanitize(&name))
+        .unwrap_or_else(|| FALLBACK_NAME.to_string());
+
+    match content_type.and_then(extension_for_mime) {
+        Some(ext) if !has_extension(&name) => format!("{}.{}", name, ext),
+        _ => name,
+    }
+}
+
+/// File name from a `Content-Disposition` value. The RFC 5987
+/// `filename*=UTF-8''...` form wins over plain `filename=`, as RFC 6266 asks.
+pub fn from_content_disposition(value: &str) -> Option<String> {
+    let mut plain = None;
+    let mut extended = None;
+    for (name, value) in parameters(value) {
+        match name.to_ascii_lowercase().as_str() {
+            "filename*" if extended.is_none() => extended = decode_ext_value(&value),
+            "filename" if plain.is_none() => plain = Some(value),
+            _ => {}
+        }
+    }
+    extended.or(plain).filter(|name| !name.trim().is_empty())
+}
+
+/// Last non-empty path segment of `url`, percent-decoded.
+pub fn from_url(url: &Url) -> Option<String> {
+    let segment = url.path_segments()?.rev().find(|s| !s.is_empty())?;
+    let name = percent_decode_str(segment).decode_utf8_lossy().into_owned();
+    (!name.trim().is_empty()).then_some(name)
+}
+
+/// Turns a name sent by a server or a site into a single path component
+/// that is valid on Windows, macOS and Linux.
+pub fn sanitize(name: &str) -> String {
+    // Separators go too: a server never gets to pick the directory
+    let cleaned: String = name.trim()
+        .chars()
+        .map(|c| if matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') || c.is_control() { '_' } else { c })
+        .collect();
+    // Leading dots would hide the file, trailing ones are dropped by Windows
+    let cleaned = cleaned.trim_start_matches(['.', ' ']).trim_end_matches(['.', ' ']);
+    if cleaned.is_empty() {
+        return FALLBACK_NAME.to_string();
+    }
+
+    // Windows reserves device names whatever the extension: `CON.tar.gz`
+    let device = cleaned.split('.').next().unwrap_or_default().trim_end();
+    let reserved = matches!(
+        device.to_ascii_uppercase().as_str(),
+        "CON" | "PRN" | "AUX" | "NUL"
+            | "COM1" | "COM2" | "COM3" | "COM4" | "COM5" | "COM6" | "COM7" | "COM8" | "COM9"
+            | "LPT1" | "LPT2" | "LPT3" | "LPT4" | "LPT5" | "LPT6" | "LPT7" | "LPT8" | "LPT9"
+    );
+    let cleaned = if reserved { format!("_{}", cleaned) } else { cleaned.to_string() };
+
+    let (stem, ext) = split_extension(&cleaned);
+
+    let ext = ext.map(|ext| format!(".{}", ext)).unwrap_or_default();
+    let mut end = MAX_NAME_LEN.saturating_sub(ext.len()).min(stem.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", stem[..end].trim_end_matches(['.', ' ']), ext)
+}
+
+/// Extension for the common types servers send files with; generic types
+/// like `application/octet-stream` have none.
+pub fn extension_for_mime(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    let ext = match mime.as_str() {
+        "text/html" => "html",
+        "text/plain" => "txt",
+        "text/csv" => "csv",
+        "text/css" => "css",
+        "text/javascript" | "application/javascript" => "js",
+        "application/json" => "json",
+        "application/xml" | "text/xml" => "xml",
+        "application/pdf" => "pdf",
+        "application/epub+zip" => "epub",
+        "application/msword" => "doc",
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => "docx",
+        "application/vnd.ms-excel" => "xls",
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => "xlsx",
+        "application/vnd.ms-powerpoint" => "ppt",
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation" => "pptx",
+        "application/zip" | "application/x-zip-compressed" => "zip",
+        "application/x-7z-compressed" => "7z",
+        "application/vnd.rar" | "application/x-rar-compressed" => "rar",
+        "application/gzip" | "application/x-gzip" => "gz",
+        "application/x-bzip2" => "bz2",
+        "application/x-xz" => "xz",
+        "application/x-tar" => "tar",
+        "application/x-iso9660-image" => "iso",
+        "application/x-bittorrent" => "torrent",
+        "application/vnd.android.package-archive" => "apk",
+        "application/x-msdownload" | "application/vnd.microsoft.portable-executable" => "exe",
+        "application/x-msi" | "application/x-ms-installer" => "msi",
+        "application/x-apple-diskimage" => "dmg",
+        "application/vnd.debian.binary-package" => "deb",
+        "application/x-rpm" => "rpm",
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "image/avif" => "avif",
+        "audio/mpeg" => "mp3",
+        "audio/mp4" => "m4a",
+        "audio/ogg" => "ogg",
+        "audio/wav" | "audio/x-wav" => "wav",
+        "audio/flac" => "flac",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "video/x-matroska" => "mkv",
+        "video/quicktime" => "mov",
+        "video/x-msvideo" => "avi",
+        "application/vnd.apple.mpegurl" | "application/x-mpegurl" => "m3u8",
+        "application/dash+xml" => "mpd",
+        "application/metalink4+xml" => "meta4",
+        _ => return None,
+    };
+    Some(ext)
+}
+
+fn split_extension(name: &str) -> (&str, Option<&str>) {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && is_extension(ext) => (stem, Some(ext)),
+        _ => (name, None),
+    }
+}
+
+fn has_extension(name: &str) -> bool {
+    split_extension(name).1.is_some()
+}
+
+fn is_extension(ext: &str) -> bool {
+    (1..=10).contains(&ext.len()) && ext.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// `name=value` pairs after the disposition type. Values may be tokens or
+/// quoted strings, which can contain `;` and backslash escapes.
+fn parameters(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let Some((_, mut rest)) = value.split_once(';') else {
+        return params;
+    };
+    loop {
+        rest = rest.trim_start_matches([';', ' ', '\t']);
+        let Some(eq) = rest.find('=') else {
+            break;
+        };
+        // Text without `=` before the next `;` is not a parameter
+        let name = rest[..eq].rsplit(';').next().unwrap_or_default().trim().to_string();
+        rest = rest[eq + 1..].trim_start();
+
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    c => value.push(c),
+                }
+            }
+            rest = &quoted[end..];
+            value
+        } else {
+            let end = rest.find(';').unwrap_or(rest.len());
+            let value = rest[..end].trim().to_string();
+            rest = &rest[end..];
+            value
+        };
+        params.push((name, value));
+    }
+    params
+}
+
+/// Decodes an RFC 5987 `charset'language'percent-encoded` value.
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let (charset, _language, encoded) = (parts.next()?, parts.next()?, parts.next()?);
+    let bytes = percent_decode_str(encoded);
+    match charset.to_ascii_lowercase().as_str() {
+        "utf-8" => bytes.decode_utf8().ok().map(|name| name.into_owned()),
+        // Latin-1 bytes are the first 256 code points
+        "iso-8859-1" => Some(bytes.map(char::from).collect()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_names() {
+        let url = |s: &str| Url::parse(s).unwrap();
+        let cases = [
+            // Content-Disposition beats the URL
+            (Some("attachment; filename=\"report.pdf\""), "https://e.com/dl?id=1", None, "report.pdf"),
+            (None, "https://e.com/files/r%C3%A9sum%C3%A9%20final.pdf", None, "résumé final.pdf"),
+            (None, "https://e.com/files/archive/", None, "archive"),
+            // An extension comes from Content-Type only when the name has none
+            (None, "https://e.com/get/12345", Some("application/pdf; charset=binary"), "12345.pdf"),
+            (None, "https://e.com/a.tar.gz", Some("application/gzip"), "a.tar.gz"),
+            (None, "https://e.com/data", Some("application/octet-stream"), "data"),
+            (Some("inline"), "https://e.com/", Some("text/html"), "download.html"),
+            (Some("attachment; filename=\"../../.bashrc\""), "https://e.com/x", None, "_.._.bashrc"),
+        ];
+        for (disposition, link, content_type, expected) in cases {
+            assert_eq!(resolve(disposition, &url(link), content_type), expected, "{:?} {}", disposition, link);
+        }
+    }
+
+    #[test]
+    fn sanitizes_names() {
+        let cases = [
+            ("report.pdf", "report.pdf"),
+            ("  spaced  ", "spaced"),
+            ("../../etc/passwd", "_.._etc_passwd"),
+            ("a\\b/c:d*e?f\"g<h>i|j", "a_b_c_d_e_f_g_h_i_j"),
+            ("tab\there\n.txt", "tab_here_.txt"),
+            ("...hidden", "hidden"),
+            ("trailing. . .", "trailing"),
+            ("..", FALLBACK_NAME),
+            ("", FALLBACK_NAME),
+            ("CON", "_CON"),
+            ("con.tar.gz", "_con.tar.gz"),
+            ("LPT9 .txt", "_LPT9.txt"),
+            ("CONSOLE.txt", "CONSOLE.txt"),
+            ("COM10", "COM10"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(sanitize(name), expected, "{:?}", name);
+        }
+
+        // Long names keep their extension and stay on a char boundary
+        let long = sanitize(&("é".repeat(300) + ".zip"));
+        assert!(long.len() <= MAX_NAME_LEN && long.ends_with(".zip"), "{}", long);
+        let no_ext = sanitize(&"x".repeat(500));
+        assert_eq!(no_ext.len(), MAX_NAME_LEN);
+        // Not an extension: too long, or not alphanumeric
+        assert_eq!(sanitize(&format!("{}.{}", "a".repeat(250), "b".repeat(20))).len(), MAX_NAME_LEN);
+    }
+
+    #[test]
+    fn disposition_parameters() {
+        let cases = [
+            ("attachment; filename=plain.bin", Some("plain.bin")),
+            ("attachment;filename=\"a \\\"q\\\"; b.txt\"", Some("a \"q\"; b.txt")),
+            ("attachment; junk; filename=x.bin", Some("x.bin")),
+            ("attachment; FILENAME=\"upper.txt\"", Some("upper.txt")),
+            // RFC 5987 values win over plain ones wherever they appear
+            ("attachment; filename*=UTF-8''%E2%82%AC%20rates.txt; filename=\"x.txt\"", Some("€ rates.txt")),
+            ("attachment; filename=\"x.txt\"; filename*=utf-8'en'%C3%A9t%C3%A9.txt", Some("été.txt")),
+            ("attachment; filename*=iso-8859-1'en'%A3%20rates.txt", Some("£ rates.txt")),
+            // Unknown charsets and broken UTF-8 fall back to the plain name
+            ("attachment; filename*=koi8-r''%C1; filename=fallback.txt", Some("fallback.txt")),
+            ("attachment; filename*=UTF-8''%FF%FE; filename=fallback.txt", Some("fallback.txt")),
+            ("attachment; filename*=no-quotes", None),
+            ("attachment; filename=\"   \"", None),
+            ("attachment; filename=\"unterminated", Some("unterminated")),
+            ("inline", None),
+            ("", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(from_content_disposition(value).as_deref(), expected, "{}", value);
+        }
+
+        assert_eq!(parameters("attachment; a=1; b=\"x;y\" ; c = 3"), [
+            ("a".to_string(), "1".to_string()),
+            ("b".to_string(), "x;y".to_string()),
+            ("c".to_string(), "3".to_string()),
+        ]);
+    }
+}
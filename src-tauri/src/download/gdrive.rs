@@ -1,14 +1,30 @@
-use crate::download::{DownloadContext, DownloadError, DownloadMeta, DownloadResult, Downloader};
-use crate::download::http::HttpHelper;
+use crate::download::checksum;
+use crate::download::filename;
+use crate::download::{ChildDownload, DownloadContext, DownloadError, DownloadMeta, DownloadResult, Downloader};
+use crate::download::http::{if_range_validator, HttpHelper};
 use crate::storage::DownloadType;
 use async_trait::async_trait;
-use reqwest::StatusCode;
+use reqwest::{Response, StatusCode, Url};
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
 use tauri::Emitter;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::AsyncWriteExt;
 use futures_util::StreamExt;
 
+/// Confirmation pages followed before giving up on a download
+const MAX_CONFIRMATIONS: usize = 2;
+
+/// Levels of subfolders followed below a shared folder
+const MAX_FOLDER_DEPTH: usize = 16;
+
+/// An item of a folder listing.
+enum FolderEntry {
+    File { id: String, name: String },
+    Folder { id: String, name: String },
+}
+
 pub struct GDriveDownloader;
 
 impl GDriveDownloader {
@@ -17,112 +33,348 @@ impl GDriveDownloader {
         url.contains("takeout-download-drive.usercontent.google.com")
     }
 
-    fn extract_filename_from_header(header_value: &str) -> Option<String> {
-        if let Some(start) = header_value.find("filename=\"") {
-            let start = start + 10;
-            if let Some(end) = header_value[start..].find('"') {
-                return Some(header_value[start..start + end].to_string());
-            }
+    /// File ID of a share link (`/file/d/<id>/view`) or an `open?id=` /
+    /// `uc?id=` link on drive.google.com or docs.google.com.
+    fn extract_file_id(url: &str) -> Option<String> {
+        let parsed = Url::parse(url).ok()?;
+        let host = parsed.host_str()?;
+        if host != "drive.google.com" && host != "docs.google.com" {
+            return None;
         }
-        if let Some(start) = header_value.find("filename=") {
-            let start = start + 9;
-            let end = header_value[start..].find(';').unwrap_or(header_value.len() - start);
-            let filename = header_value[start..start + end].trim();
-            if !filename.is_empty() {
-                return Some(filename.to_string());
+        let segments: Vec<&str> = parsed.path_segments()?.collect();
+        // Account-switched links put `/u/<n>` in between: `/file/u/0/d/<id>`
+        if let Some(file) = segments.iter().position(|s| *s == "file") {
+            if let Some(d) = segments[file..].iter().position(|s| *s == "d") {
+                return segments.get(file + d + 1).filter(|id| !id.is_empty()).map(|id| id.to_string());
             }
         }
+        if matches!(segments.last(), Some(&"open") | Some(&"uc")) {
+            return parsed.query_pairs()
+                .find(|(key, _)| key == "id")
+                .map(|(_, id)| id.into_owned())
+                .filter(|id| !id.is_empty());
+        }
         None
     }
-}
 
-#[async_trait]
-impl Downloader for GDriveDownloader {
-    fn detect(url: &str) -> bool {
-        // Only support direct download URLs and Takeout URLs
-        url.contains("drive.usercontent.google.com/download") ||
-        url.contains("takeout-download-drive.usercontent.google.com")
+    /// Folder ID of `/drive/folders/<id>` (also `/drive/u/<n>/folders/<id>`)
+    /// or `embeddedfolderview?id=<id>` on drive.google.com.
+    fn extract_folder_id(url: &str) -> Option<String> {
+        let parsed = Url::parse(url).ok()?;
+        if parsed.host_str()? != "drive.google.com" {
+            return None;
+        }
+        let segments: Vec<&str> = parsed.path_segments()?.collect();
+        if let Some(folders) = segments.iter().position(|s| *s == "folders") {
+            return segments.get(folders + 1).filter(|id| !id.is_empty()).map(|id| id.to_string());
+        }
+        if segments.last() == Some(&"embeddedfolderview") {
+            return parsed.query_pairs()
+                .find(|(key, _)| key == "id")
+                .map(|(_, id)| id.into_owned())
+                .filter(|id| !id.is_empty());
+        }
+        None
     }
 
-    async fn analyze(url: &str, http: &HttpHelper) -> DownloadResult<Option<DownloadMeta>> {
-        eprintln!("[GDrive] Analyzing URL: {}", url);
-        if !Self::detect(url) {
-            return Ok(None);
+    fn share_url(file_id: &str) -> String {
+        format!("https://drive.google.com/file/d/{}/view", file_id)
+    }
+
+    fn direct_url(file_id: &str) -> String {
+        format!("https://drive.usercontent.google.com/download?id={}&export=download", file_id)
+    }
+
+    /// Value of `name="..."` inside a single HTML tag.
+    fn html_attr<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+        let needle = format!(" {}=\"", name);
+        let start = tag.find(&needle)? + needle.len();
+        let end = tag[start..].find('"')?;
+        Some(&tag[start..start + end])
+    }
+
+    fn html_unescape(value: &str) -> String {
+        value.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&")
+    }
+
+    /// Folder title and entries from the key-less embedded folder view,
+    /// which renders each item as a `flip-entry` linking to the file or
+    /// subfolder.
+    fn parse_folder_listing(body: &str) -> (Option<String>, Vec<FolderEntry>) {
+        let title = body.find("<title>")
+            .and_then(|start| {
+                let rest = &body[start + 7..];
+                rest.find("</title>").map(|end| Self::html_unescape(rest[..end].trim()))
+            })
+            .filter(|title| !title.is_empty());
+
+        let entries = body.split("<div class=\"flip-entry\"")
+            .skip(1)
+            .filter_map(|entry| {
+                let href_start = entry.find("href=\"")? + 6;
+                let href = Self::html_unescape(&entry[href_start..href_start + entry[href_start..].find('"')?]);
+                let name_start = entry.find("class=\"flip-entry-title\">")? + 25;
+                let name = Self::html_unescape(entry[name_start..name_start + entry[name_start..].find('<')?].trim());
+                if let Some(id) = Self::extract_folder_id(&href) {
+                    Some(FolderEntry::Folder { id, name })
+                } else {
+                    Self::extract_file_id(&href).map(|id| FolderEntry::File { id, name })
+                }
+            })
+            .collect();
+        (title, entries)
+    }
+
+    async fn list_folder(http: &HttpHelper, folder_id: &str) -> DownloadResult<(Option<String>, Vec<FolderEntry>)> {
+        let url = format!("https://drive.google.com/embeddedfolderview?id={}", folder_id);
+        let response = http.client()
+            .get(&url)
+            .send()
+            .await
+            .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+        match response.status() {
+            StatusCode::OK => {}
+            StatusCode::NOT_FOUND | StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => {
+                return Err(DownloadError::AccessDenied(
+                    "This Google Drive folder doesn't exist or isn't shared publicly.".to_string()
+                ));
+            }
+            _ => return Err(DownloadError::from_status(&response)),
+        }
+        let body = response.text().await.map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+        let listing = Self::parse_folder_listing(&body);
+        if listing.1.is_empty() && (body.contains("ServiceLogin") || body.contains("accounts.google.com")) {
+            return Err(DownloadError::AccessDenied(
+                "This Google Drive folder isn't shared publicly.".to_string()
+            ));
+        }
+        Ok(listing)
+    }
+
+    /// Walks a shared folder breadth-first. Returns its name and every file
+    /// below it, with paths relative to the folder.
+    async fn enumerate_folder(http: &HttpHelper, folder_id: &str) -> DownloadResult<(String, Vec<ChildDownload>)> {
+        let mut root_name = None;
+        let mut children = Vec::new();
+        let mut used_paths = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut pending = VecDeque::from([(folder_id.to_string(), PathBuf::new(), 0)]);
+
+        while let Some((id, prefix, depth)) = pending.pop_front() {
+            // Shortcuts can make a folder show up more than once
+            if !visited.insert(id.clone()) {
+                continue;
+            }
+            let (title, entries) = Self::list_folder(http, &id).await?;
+            if root_name.is_none() {
+                root_name = Some(title.unwrap_or_else(|| id.clone()));
+            }
+            eprintln!("[GDrive] Folder {}: {} entries", id, entries.len());
+
+            for entry in entries {
+                match entry {
+                    FolderEntry::Folder { id, name } if depth < MAX_FOLDER_DEPTH => {
+                        pending.push_back((id, prefix.join(filename::sanitize(&name)), depth + 1));
+                    }
+                    FolderEntry::Folder { name, .. } => {
+                        eprintln!("[GDrive] Skipping {}: folders nested too deep", name);
+                    }
+                    FolderEntry::File { id, name } => {
+                        // Drive allows duplicate names; keep every file
+                        let name = filename::sanitize(&name);
+                        let mut relative_path = prefix.join(&name);
+                        let mut copy = 1;
+                        while !used_paths.insert(relative_path.clone()) {
+                            let (stem, ext) = match name.rfind('.').filter(|&dot| dot > 0) {
+                                Some(dot) => (&name[..dot], &name[dot..]),
+                                None => (name.as_str(), ""),
+                            };
+                            relative_path = prefix.join(format!("{} ({}){}", stem, copy, ext));
+                            copy += 1;
+                        }
+                        children.push(ChildDownload { url: Self::share_url(&id), relative_path });
+                    }
+                }
+            }
         }
 
-        // Only accept direct download URLs
-        if !Self::is_direct_link(url) && !url.contains("takeout.google.com") {
+        if children.is_empty() {
             return Err(DownloadError::InvalidUrl(
-                "Only direct Google Drive download URLs are supported.\n\nSupported formats:\n• https://drive.usercontent.google.com/download?id=...\n• Google Takeout URLs\n\nShare links (drive.google.com/file/d/.../view) are not supported.\nTo download from browser, copy the URL from the browser's download manager, not the share link.".to_string()
+                "This Google Drive folder is empty or isn't shared publicly.".to_string()
             ));
         }
+        let root_name = filename::sanitize(&root_name.unwrap_or_else(|| folder_id.to_string()));
+        Ok((root_name, children))
+    }
 
-        Ok(Some(DownloadMeta {
-            download_type: DownloadType::GoogleDrive,
-            direct_url: url.to_string(),
-            original_url: Some(url.to_string()), // Store the URL for copying later
-            suggested_filename: None,
-        }))
+    /// URL behind the "can't scan this file for viruses" page Drive shows
+    /// for large files: the `download-form` action with its hidden inputs
+    /// (`id`, `export`, `confirm`, `uuid`), or the older `confirm=` link.
+    fn confirmation_url(body: &str) -> Option<String> {
+        if let Some(marker) = body.find("id=\"download-form\"") {
+            let start = body[..marker].rfind("<form")?;
+            let end = body[start..].find("</form>").map(|e| start + e).unwrap_or(body.len());
+            let form = &body[start..end];
+            let form_tag = &form[..form.find('>')?];
+            let mut url = Url::parse(&Self::html_unescape(Self::html_attr(form_tag, "action")?)).ok()?;
+            {
+                let mut query = url.query_pairs_mut();
+                for input in form.split("<input").skip(1) {
+                    let tag = &input[..input.find('>').unwrap_or(input.len())];
+                    if Self::html_attr(tag, "type") != Some("hidden") {
+                        continue;
+                    }
+                    if let (Some(name), Some(value)) = (Self::html_attr(tag, "name"), Self::html_attr(tag, "value")) {
+                        query.append_pair(name, &Self::html_unescape(value));
+                    }
+                }
+            }
+            return Some(url.to_string());
+        }
+
+        body.split("href=\"")
+            .skip(1)
+            .filter_map(|rest| rest.find('"').map(|end| Self::html_unescape(&rest[..end])))
+            .find(|href| href.contains("confirm=") && href.contains("export=download"))
+            .and_then(|href| Url::parse("https://drive.google.com/").ok()?.join(&href).ok())
+            .map(|url| url.to_string())
     }
 
-    async fn run(ctx: DownloadContext) -> DownloadResult<()> {
-        let DownloadContext { id, url, save_path, app, http, original_url: _, downloaded_bytes } = ctx;
-        eprintln!("[GDrive] Starting download: id={}, url={}, path={}", id, url, save_path);
+    /// Explains an HTML reply that isn't a confirmation page.
+    fn html_error(body: &str) -> DownloadError {
+        // Save HTML for debugging
+        if let Some(temp_dir) = std::env::temp_dir().to_str() {
+            let debug_path = format!("{}\\gdrive_response.html", temp_dir);
+            if let Err(e) = std::fs::write(&debug_path, body) {
+                eprintln!("[GDrive] Failed to save debug HTML: {}", e);
+            } else {
+                eprintln!("[GDrive] Saved HTML response to: {}", debug_path);
+            }
+        }
 
-        let response = if downloaded_bytes > 0 {
-            eprintln!("[GDrive] Resuming download from byte {}", downloaded_bytes);
-            http.client()
-                .get(&url)
-                .header("Range", format!("bytes={}-", downloaded_bytes))
-                .send()
+        if body.contains("Too many users have viewed or downloaded this file") || body.contains("Quota exceeded") {
+            return DownloadError::AccessDenied(
+                "Google Drive's download quota for this file is exceeded.\n\nTry again later, or make a copy of the file in your own Drive.".to_string()
+            );
+        }
+
+        // Check if it's an authentication issue
+        if body.contains("signin") || body.contains("ServiceLogin") || body.contains("accounts.google.com") {
+            return DownloadError::AccessDenied(
+                "This file requires Google account authentication.\n\nThe download URL may have expired or requires login.\nPlease copy a fresh download URL from your browser's download manager.".to_string()
+            );
+        }
+
+        DownloadError::AccessDenied(
+            "Received HTML instead of file content.\n\nPossible causes:\n• The download URL has expired\n• The file requires authentication\n• The file is not publicly accessible\n\nPlease copy a fresh download URL from your browser's download manager.".to_string()
+        )
+    }
+
+    async fn request(http: &HttpHelper, url: &str, downloaded_bytes: u64, if_range: Option<&str>) -> DownloadResult<Response> {
+        if downloaded_bytes > 0 {
+            http.download_range_request(url, downloaded_bytes, if_range)
                 .await
-                .map_err(|e| DownloadError::NetworkError(e.to_string()))?
+                .map_err(DownloadError::NetworkError)
         } else {
-            eprintln!("[GDrive] Starting fresh download");
             http.client()
-                .get(&url)
+                .get(url)
                 .send()
                 .await
-                .map_err(|e| DownloadError::NetworkError(e.to_string()))?
-        };
-        eprintln!("[GDrive] Response status: {}", response.status());
+                .map_err(|e| DownloadError::NetworkError(e.to_string()))
+        }
+    }
 
-        let response = if response.status() == StatusCode::OK {
+    /// Requests the file, following the virus-scan confirmation page when
+    /// Drive answers with it instead of the content.
+    async fn open(http: &HttpHelper, url: &str, downloaded_bytes: u64, if_range: Option<&str>) -> DownloadResult<Response> {
+        let mut url = url.to_string();
+        for _ in 0..=MAX_CONFIRMATIONS {
+            let response = Self::request(http, &url, downloaded_bytes, if_range).await?;
+            eprintln!("[GDrive] Response status: {}", response.status());
+            if response.status() != StatusCode::OK {
+                return Ok(response);
+            }
             let content_type = response.headers()
                 .get("content-type")
                 .and_then(|v| v.to_str().ok())
                 .unwrap_or("");
             eprintln!("[GDrive] Content-Type: {}", content_type);
-            
-            if content_type.contains("text/html") {
-                let body = response.text().await.map_err(|e| DownloadError::NetworkError(e.to_string()))?;
-                
-                // Save HTML for debugging
-                if let Some(temp_dir) = std::env::temp_dir().to_str() {
-                    let debug_path = format!("{}\\gdrive_response.html", temp_dir);
-                    if let Err(e) = std::fs::write(&debug_path, &body) {
-                        eprintln!("[GDrive] Failed to save debug HTML: {}", e);
-                    } else {
-                        eprintln!("[GDrive] Saved HTML response to: {}", debug_path);
-                    }
-                }
-                
-                // Check if it's an authentication issue
-                if body.contains("signin") || body.contains("ServiceLogin") || body.contains("accounts.google.com") {
-                    return Err(DownloadError::AccessDenied(
-                        "This file requires Google account authentication.\n\nThe download URL may have expired or requires login.\nPlease copy a fresh download URL from your browser's download manager.".to_string()
-                    ));
-                }
-                
-                return Err(DownloadError::AccessDenied(
-                    "Received HTML instead of file content.\n\nPossible causes:\n• The download URL has expired\n• The file requires authentication\n• The file is not publicly accessible\n\nPlease copy a fresh download URL from your browser's download manager.".to_string()
-                ));
-            } else {
-                response
+            if !content_type.contains("text/html") {
+                return Ok(response);
             }
-        } else {
-            response
+
+            let body = response.text().await.map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+            url = Self::confirmation_url(&body).ok_or_else(|| Self::html_error(&body))?;
+            eprintln!("[GDrive] Following virus-scan confirmation: {}", url);
+        }
+        Err(DownloadError::AccessDenied(
+            "Google Drive kept asking to confirm the download.".to_string()
+        ))
+    }
+}
+
+#[async_trait]
+impl Downloader for GDriveDownloader {
+    fn download_type(&self) -> DownloadType {
+        DownloadType::GoogleDrive
+    }
+
+    fn detect(&self, url: &str) -> bool {
+        Self::is_direct_link(url) || Self::extract_file_id(url).is_some() || Self::extract_folder_id(url).is_some()
+    }
+
+    async fn analyze(&self, url: &str, http: &HttpHelper) -> DownloadResult<Option<DownloadMeta>> {
+        eprintln!("[GDrive] Analyzing URL: {}", url);
+        if !self.detect(url) {
+            return Ok(None);
+        }
+
+        // Folders are downloaded file by file
+        if let Some(folder_id) = Self::extract_folder_id(url) {
+            let (name, children) = Self::enumerate_folder(http, &folder_id).await?;
+            eprintln!("[GDrive] Folder {} ({}): {} files", folder_id, name, children.len());
+            return Ok(Some(DownloadMeta {
+                download_type: DownloadType::GoogleDrive,
+                direct_url: url.to_string(),
+                original_url: Some(url.to_string()),
+                suggested_filename: Some(name),
+                children,
+            }));
+        }
+
+        // Share links become the direct download URL for the same file
+        let direct_url = match Self::extract_file_id(url) {
+            Some(file_id) if !Self::is_direct_link(url) => {
+                eprintln!("[GDrive] Share link for file {}", file_id);
+                Self::direct_url(&file_id)
+            }
+            _ => url.to_string(),
         };
+
+        Ok(Some(DownloadMeta {
+            download_type: DownloadType::GoogleDrive,
+            direct_url,
+            original_url: Some(url.to_string()), // Store the URL for copying later
+            suggested_filename: None,
+            ..Default::default()
+        }))
+    }
+
+    async fn run(&self, ctx: DownloadContext) -> DownloadResult<()> {
+        let DownloadContext { id, url, save_path, app, http, downloaded_bytes, etag, last_modified, limiter, checksum, history, .. } = ctx;
+        eprintln!("[GDrive] Starting download: id={}, url={}, path={}", id, url, save_path);
+
+        if downloaded_bytes > 0 {
+            eprintln!("[GDrive] Resuming download from byte {}", downloaded_bytes);
+        } else {
+            eprintln!("[GDrive] Starting fresh download");
+        }
+        let if_range = if_range_validator(etag.as_deref(), last_modified.as_deref());
+        let response = Self::open(&http, &url, downloaded_bytes, if_range.as_deref()).await?;
         eprintln!("[GDrive] Final response status after processing: {}", response.status());
 
         match response.status() {
@@ -137,13 +389,35 @@ impl Downloader for GDriveDownloader {
                     "File has changed on server. Cannot resume download.".to_string()
                 ));
             }
-            status => {
-                return Err(DownloadError::NetworkError(
-                    format!("Server returned: {}", status)
-                ));
+            _ => {
+                return Err(DownloadError::from_status(&response));
             }
         }
 
+        // A full 200 reply to a range request means the stored bytes are stale
+        let downloaded_bytes = if downloaded_bytes > 0 && response.status() == StatusCode::OK {
+            eprintln!("[GDrive] Server sent the full file, discarding {} partial bytes", downloaded_bytes);
+            0
+        } else {
+            downloaded_bytes
+        };
+
+        let response_etag = response.headers().get("etag")
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string());
+        let response_last_modified = response.headers().get("last-modified")
+            .and_then(|v| v.to_str().ok())
+            .map(|s| s.to_string());
+        let _ = app.emit("download://metadata", serde_json::json!({
+            "id": id,
+            "etag": response_etag,
+            "last_modified": response_last_modified,
+        }));
+        history.update(&id, |item| {
+            item.etag = response_etag.clone();
+            item.last_modified = response_last_modified.clone();
+        });
+
         let total_size = response.headers()
             .get("content-length")
             .and_then(|v| v.to_str().ok())
@@ -151,10 +425,10 @@ impl Downloader for GDriveDownloader {
             .map(|len| len + downloaded_bytes);
         eprintln!("[GDrive] Total size: {:?}", total_size);
 
-        let filename = response.headers()
-            .get("content-disposition")
-            .and_then(|v| v.to_str().ok())
-            .and_then(Self::extract_filename_from_header);
+        let filename = HttpHelper::content_disposition(response.headers())
+            .as_deref()
+            .and_then(filename::from_content_disposition)
+            .map(|name| filename::sanitize(&name));
         eprintln!("[GDrive] Extracted filename: {:?}", filename);
         
         // Use extracted filename if available and save_path is a directory or generic
@@ -176,6 +450,19 @@ impl Downloader for GDriveDownloader {
         } else {
             save_path.clone()
         };
+        // Recorded now rather than on completion: a resume looks for the
+        // partial file next to the path in history
+        let final_file = std::path::Path::new(&final_path);
+        if final_path != save_path {
+            history.update(&id, |item| {
+                if let Some(parent) = final_file.parent() {
+                    item.path = parent.to_string_lossy().to_string();
+                }
+                if let Some(name) = final_file.file_name() {
+                    item.filename = name.to_string_lossy().to_string();
+                }
+            });
+        }
         
         // Add .fdm extension for incomplete downloads
         let temp_path = format!("{}.fdm", final_path);
@@ -193,6 +480,8 @@ impl Downloader for GDriveDownloader {
                 .map_err(|e| DownloadError::IoError(e.to_string()))?
         };
 
+        let temp_file_path = std::path::Path::new(&temp_path);
+        let mut hasher = checksum::resume_hasher(checksum.as_ref(), temp_file_path, downloaded_bytes).await?;
         let mut stream = response.bytes_stream();
         let mut downloaded = downloaded_bytes;
         let mut last_emit = Instant::now();
@@ -204,10 +493,14 @@ impl Downloader for GDriveDownloader {
             file.write_all(&chunk)
                 .await
                 .map_err(|e| DownloadError::IoError(e.to_string()))?;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&chunk);
+            }
             
             let chunk_size = chunk.len() as u64;
             downloaded += chunk_size;
             bytes_since_last_emit += chunk_size;
+            limiter.consume(chunk_size).await;
 
             if last_emit.elapsed() >= Duration::from_millis(100) {
                 let elapsed_secs = last_emit.elapsed().as_secs_f64();
@@ -224,6 +517,7 @@ impl Downloader for GDriveDownloader {
                     "speed": speed,
                     "filename": filename,
                 }));
+                history.checkpoint(&id, downloaded, total_size);
                 last_emit = Instant::now();
                 bytes_since_last_emit = 0;
             }
@@ -231,6 +525,13 @@ impl Downloader for GDriveDownloader {
 
         file.flush().await.map_err(|e| DownloadError::IoError(e.to_string()))?;
         drop(file);
+
+        let checksum_result = match (checksum.as_ref(), hasher) {
+            (Some(expected), Some(hasher)) => {
+                Some(checksum::verify(expected, hasher.finalize_hex(), temp_file_path).await?)
+            }
+            _ => None,
+        };
         
         // Remove .fdm extension by renaming to final path
         eprintln!("[GDrive] Renaming {} to {}", temp_path, final_path);
@@ -240,17 +541,24 @@ impl Downloader for GDriveDownloader {
         
         eprintln!("[GDrive] Download complete: {} bytes", downloaded);
 
+        history.update(&id, |item| item.downloaded = downloaded);
+
         let _ = app.emit("download://complete", serde_json::json!({
             "id": id,
             "path": final_path,
             "filename": filename,
+            "checksum": checksum_result,
         }));
 
         Ok(())
     }
 
-    async fn refresh_url(_original_url: &str, _http: &HttpHelper) -> DownloadResult<Option<String>> {
-        // Direct download URLs don't support refresh - they are time-limited URLs
-        Ok(None)
+    async fn refresh_url(&self, original_url: &str, _http: &HttpHelper) -> DownloadResult<Option<String>> {
+        // Share links map to a stable download URL; direct download URLs are
+        // time-limited and can't be refreshed
+        if Self::is_direct_link(original_url) {
+            return Ok(None);
+        }
+        Ok(Self::extract_file_id(original_url).map(|file_id| Self::direct_url(&file_id)))
     }
 }
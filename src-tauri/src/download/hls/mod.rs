@@ -0,0 +1,177 @@
+pub mod playlist;
+
+use crate::download::checksum;
+use crate::download::http::HttpHelper;
+use crate::download::media::{self, MediaSegment, StreamInfo, StreamVariant, Track};
+use crate::download::{DownloadContext, DownloadError, DownloadMeta, DownloadResult, Downloader};
+use crate::storage::DownloadType;
+use async_trait::async_trait;
+use playlist::{MediaPlaylist, Playlist, Variant};
+use reqwest::Url;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tauri::Emitter;
+
+async fn fetch_playlist(url: &str, http: &HttpHelper) -> DownloadResult<Playlist> {
+    let base = Url::parse(url).map_err(|e| DownloadError::InvalidUrl(e.to_string()))?;
+    playlist::parse(&media::fetch_text(url, http).await?, &base)
+}
+
+/// Highest bandwidth wins; ties go to the first listed.
+fn default_variant(variants: &[Variant]) -> usize {
+    variants.iter()
+        .enumerate()
+        .rev()
+        .max_by_key(|(_, v)| v.bandwidth)
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+pub struct HlsDownloader;
+
+impl HlsDownloader {
+    /// Lists the variants of a master playlist; a media playlist is its own single variant.
+    pub async fn inspect(url: &str, http: &HttpHelper) -> DownloadResult<StreamInfo> {
+        match fetch_playlist(url, http).await? {
+            Playlist::Master(variants) => Ok(StreamInfo {
+                default_variants: vec![default_variant(&variants)],
+                variants: variants.into_iter()
+                    .enumerate()
+                    .map(|(index, v)| StreamVariant {
+                        index,
+                        kind: None,
+                        bandwidth: v.bandwidth,
+                        resolution: v.resolution,
+                        codecs: v.codecs,
+                    })
+                    .collect(),
+                duration: None,
+            }),
+            Playlist::Media(media) => Ok(StreamInfo {
+                variants: vec![StreamVariant { index: 0, kind: None, bandwidth: 0, resolution: None, codecs: None }],
+                default_variants: vec![0],
+                duration: Some(media.duration),
+            }),
+        }
+    }
+
+    /// Resolves the media playlist to download; `choice` indexes the master's variants.
+    async fn media_playlist(url: &str, choice: Option<usize>, http: &HttpHelper) -> DownloadResult<MediaPlaylist> {
+        let variants = match fetch_playlist(url, http).await? {
+            Playlist::Media(media) => return Ok(media),
+            Playlist::Master(variants) => variants,
+        };
+        let index = choice.unwrap_or_else(|| default_variant(&variants));
+        let variant = variants.get(index)
+            .ok_or_else(|| DownloadError::InvalidUrl(format!("Playlist has no variant {}", index)))?;
+        eprintln!(
+            "[HLS] Using variant {} ({} bps{})",
+            index,
+            variant.bandwidth,
+            variant.resolution.as_deref().map(|r| format!(", {}", r)).unwrap_or_default()
+        );
+        match fetch_playlist(&variant.uri, http).await? {
+            Playlist::Media(media) => Ok(media),
+            Playlist::Master(_) => Err(DownloadError::InvalidUrl("Variant points to another master playlist".to_string())),
+        }
+    }
+
+    /// Fetches each distinct AES-128 key once.
+    async fn fetch_keys(media: &MediaPlaylist, http: &HttpHelper) -> DownloadResult<HashMap<String, [u8; 16]>> {
+        let mut keys = HashMap::new();
+        for key in media.segments.iter().filter_map(|s| s.key.as_ref()) {
+            if keys.contains_key(&key.uri) {
+                continue;
+            }
+            let response = http.download_stream_request(&key.uri)
+                .await
+                .map_err(DownloadError::NetworkError)?;
+            if !response.status().is_success() {
+                return Err(DownloadError::from_status(&response));
+            }
+            let bytes = response.bytes()
+                .await
+                .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+            let key_bytes: [u8; 16] = bytes.as_ref()
+                .try_into()
+                .map_err(|_| DownloadError::Other(format!("HLS key is {} bytes, expected 16", bytes.len())))?;
+            keys.insert(key.uri.clone(), key_bytes);
+        }
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl Downloader for HlsDownloader {
+    fn download_type(&self) -> DownloadType {
+        DownloadType::Hls
+    }
+
+    fn detect(&self, url: &str) -> bool {
+        let lower = url.to_ascii_lowercase();
+        let path = lower.split(['?', '#']).next().unwrap_or_default();
+        path.ends_with(".m3u8")
+    }
+
+    async fn analyze(&self, url: &str, _http: &HttpHelper) -> DownloadResult<Option<DownloadMeta>> {
+        if !self.detect(url) {
+            return Ok(None);
+        }
+        let parsed = Url::parse(url).map_err(|e| DownloadError::InvalidUrl(e.to_string()))?;
+        let stem = parsed.path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .and_then(|name| Path::new(name).file_stem())
+            .map(|stem| format!("{}.ts", stem.to_string_lossy()));
+
+        Ok(Some(DownloadMeta {
+            download_type: DownloadType::Hls,
+            direct_url: url.to_string(),
+            original_url: None,
+            suggested_filename: stem,
+            ..Default::default()
+        }))
+    }
+
+    async fn run(&self, ctx: DownloadContext) -> DownloadResult<()> {
+        let choice = ctx.files.as_ref().and_then(|f| f.first().copied());
+        eprintln!("[HLS] Starting download: id={}, url={}", ctx.id, ctx.url);
+
+        let media = Self::media_playlist(&ctx.url, choice, &ctx.http).await?;
+        if !media.ended {
+            return Err(DownloadError::InvalidUrl(
+                "Live HLS streams aren't supported; only finished playlists can be downloaded".to_string(),
+            ));
+        }
+        let keys = Self::fetch_keys(&media, &ctx.http).await?;
+        let segments: Vec<MediaSegment> = media.segments.iter()
+            .map(|s| MediaSegment {
+                url: s.uri.clone(),
+                range: s.byte_range,
+                aes128: s.key.as_ref().and_then(|k| keys.get(&k.uri)).map(|key| (*key, s.iv())),
+            })
+            .collect();
+        eprintln!("[HLS] {}: {} segments, {:.0}s", ctx.id, segments.len(), media.duration);
+
+        let file_path = PathBuf::from(&ctx.save_path);
+        let temp_path = PathBuf::from(format!("{}.fdm", ctx.save_path));
+        media::download(&ctx, &[Track { segments, output: temp_path.clone() }]).await?;
+
+        let checksum_result = match ctx.checksum.as_ref() {
+            Some(expected) => {
+                let actual = checksum::hash_file(&temp_path, expected.algorithm).await?;
+                Some(checksum::verify(expected, actual, &temp_path).await?)
+            }
+            None => None,
+        };
+        tokio::fs::rename(&temp_path, &file_path)
+            .await
+            .map_err(|e| DownloadError::IoError(format!("Failed to rename file: {}", e)))?;
+
+        let _ = ctx.app.emit("download://complete", serde_json::json!({
+            "id": ctx.id,
+            "checksum": checksum_result,
+        }));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+use crate::download::DownloadError;
+use reqwest::Url;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub uri: String,
+    pub bandwidth: u64,
+    pub resolution: Option<String>,
+    pub codecs: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Key {
+    pub uri: String,
+    /// Explicit IV; without one the media sequence number is used
+    pub iv: Option<[u8; 16]>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub uri: String,
+    pub sequence: u64,
+    /// Start and inclusive end, from `EXT-X-BYTERANGE` or `EXT-X-MAP`
+    pub byte_range: Option<(u64, u64)>,
+    pub key: Option<Key>,
+}
+
+impl Segment {
+    /// AES-128 IV: the explicit one, or the sequence number as a 128-bit big-endian integer.
+    pub fn iv(&self) -> [u8; 16] {
+        self.key.as_ref().and_then(|k| k.iv).unwrap_or_else(|| {
+            let mut iv = [0u8; 16];
+            iv[8..].copy_from_slice(&self.sequence.to_be_bytes());
+            iv
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaPlaylist {
+    /// Media segments in play order; initialization sections (`EXT-X-MAP`)
+    /// appear before the segments they apply to
+    pub segments: Vec<Segment>,
+    pub duration: f64,
+    /// `EXT-X-ENDLIST` was present, i.e. this isn't a live stream
+    pub ended: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum Playlist {
+    Master(Vec<Variant>),
+    Media(MediaPlaylist),
+}
+
+fn invalid(msg: &str) -> DownloadError {
+    DownloadError::InvalidUrl(msg.to_string())
+}
+
+/// Splits an attribute list (`A=1,B="x,y",C=0x10`), unquoting quoted values.
+fn attributes(list: &str) -> HashMap<String, String> {
+    let mut attrs = HashMap::new();
+    let mut rest = list.trim();
+    while !rest.is_empty() {
+        let Some((key, after)) = rest.split_once('=') else {
+            break;
+        };
+        let (value, remaining) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                let tail = quoted.get(end + 1..).unwrap_or_default();
+                (&quoted[..end], tail)
+            }
+            None => {
+                let end = after.find(',').unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+        attrs.insert(key.trim().to_ascii_uppercase(), value.to_string());
+        rest = remaining.trim_start_matches(',').trim_start();
+    }
+    attrs
+}
+
+fn parse_iv(value: &str) -> Option<[u8; 16]> {
+    let hex = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X"))?;
+    let bytes = hex::decode(format!("{:0>32}", hex)).ok()?;
+    bytes.try_into().ok()
+}
+
+/// `<length>[@<offset>]`; without an offset the range follows the previous one
+fn parse_byte_range(value: &str, next_offset: u64) -> Option<(u64, u64)> {
+    let (length, offset) = match value.split_once('@') {
+        Some((length, offset)) => (length.trim().parse::<u64>().ok()?, offset.trim().parse().ok()?),
+        None => (value.trim().parse::<u64>().ok()?, next_offset),
+    };
+    let end = offset.checked_add(length.checked_sub(1)?)?;
+    Some((offset, end))
+}
+
+pub fn parse(text: &str, base: &Url) -> Result<Playlist, DownloadError> {
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+    if lines.next().map(|l| l.trim_start_matches('\u{feff}')) != Some("#EXTM3U") {
+        return Err(invalid("Not an HLS playlist"));
+    }
+    let resolve = |uri: &str| -> Result<String, DownloadError> {
+        base.join(uri)
+            .map(|u| u.to_string())
+            .map_err(|e| DownloadError::InvalidUrl(format!("Bad playlist URI {}: {}", uri, e)))
+    };
+
+    let mut variants = Vec::new();
+    let mut pending_variant: Option<HashMap<String, String>> = None;
+
+    let mut segments = Vec::new();
+    let mut sequence = 0u64;
+    let mut duration = 0.0;
+    let mut ended = false;
+    let mut key: Option<Key> = None;
+    let mut map: Option<Segment> = None;
+    let mut byte_range: Option<(u64, u64)> = None;
+    let mut next_offset = 0u64;
+    let mut is_media = false;
+
+    for line in lines {
+        if let Some(tag) = line.strip_prefix('#') {
+            let (name, value) = tag.split_once(':').unwrap_or((tag, ""));
+            match name {
+                "EXT-X-STREAM-INF" => pending_variant = Some(attributes(value)),
+                "EXT-X-MEDIA-SEQUENCE" => sequence = value.trim().parse().unwrap_or(0),
+                "EXTINF" => {
+                    is_media = true;
+                    duration += value.split(',').next().and_then(|d| d.trim().parse::<f64>().ok()).unwrap_or(0.0);
+                }
+                "EXT-X-ENDLIST" => ended = true,
+                "EXT-X-BYTERANGE" => {
+                    byte_range = Some(parse_byte_range(value, next_offset).ok_or_else(|| invalid("Bad EXT-X-BYTERANGE"))?);
+                }
+                "EXT-X-KEY" => {
+                    let attrs = attributes(value);
+                    key = match attrs.get("METHOD").map(String::as_str) {
+                        Some("NONE") => None,
+                        Some("AES-128") => Some(Key {
+                            uri: resolve(attrs.get("URI").ok_or_else(|| invalid("EXT-X-KEY without URI"))?)?,
+                            iv: attrs.get("IV").and_then(|iv| parse_iv(iv)),
+                        }),
+                        Some(other) => {
+                            return Err(DownloadError::Other(format!("Unsupported HLS encryption: {}", other)));
+                        }
+                        None => return Err(invalid("EXT-X-KEY without METHOD")),
+                    };
+                }
+                "EXT-X-MAP" => {
+                    let attrs = attributes(value);
+                    let uri = resolve(attrs.get("URI").ok_or_else(|| invalid("EXT-X-MAP without URI"))?)?;
+                    let range = match attrs.get("BYTERANGE") {
+                        Some(r) => Some(parse_byte_range(r, 0).ok_or_else(|| invalid("Bad EXT-X-MAP byte range"))?),
+                        None => None,
+                    };
+                    let init = Segment { uri, sequence, byte_range: range, key: key.clone() };
+                    // Only emit the section again when it changes, e.g. after a discontinuity
+                    if map.as_ref().map(|m| (&m.uri, m.byte_range)) != Some((&init.uri, init.byte_range)) {
+                        segments.push(init.clone());
+                        map = Some(init);
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        // A URI line closes the preceding variant or segment tags
+        if let Some(attrs) = pending_variant.take() {
+            variants.push(Variant {
+                uri: resolve(line)?,
+                bandwidth: attrs.get("BANDWIDTH").and_then(|b| b.parse().ok()).unwrap_or(0),
+                resolution: attrs.get("RESOLUTION").cloned(),
+                codecs: attrs.get("CODECS").cloned(),
+            });
+        } else {
+            if let Some((_, end)) = byte_range {
+                next_offset = end.saturating_add(1);
+            }
+            segments.push(Segment {
+                uri: resolve(line)?,
+                sequence,
+                byte_range: byte_range.take(),
+                key: key.clone(),
+            });
+            sequence = sequence.wrapping_add(1);
+        }
+    }
+
+    if !variants.is_empty() {
+        Ok(Playlist::Master(variants))
+    } else if is_media || !segments.is_empty() {
+        Ok(Playlist::Media(MediaPlaylist { segments, duration, ended }))
+    } else {
+        Err(invalid("HLS playlist has no variants or segments"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Url {
+        Url::parse("https://cdn.example.com/video/master.m3u8").unwrap()
+    }
+
+    fn media(text: &str) -> MediaPlaylist {
+        match parse(text, &base()).unwrap() {
+            Playlist::Media(media) => media,
+            Playlist::Master(_) => panic!("expected a media playlist"),
+        }
+    }
+
+    #[test]
+    fn master_playlist() {
+        let text = "\u{feff}#EXTM3U\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=1280000,RESOLUTION=640x360,CODECS=\"avc1.4d401e,mp4a.40.2\"\n\
+            low/index.m3u8\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=5000000,RESOLUTION=1920x1080\n\
+            https://other.example.com/high.m3u8\n";
+        let Playlist::Master(variants) = parse(text, &base()).unwrap() else {
+            panic!("expected a master playlist");
+        };
+        assert_eq!(variants.len(), 2);
+        assert_eq!(variants[0].uri, "https://cdn.example.com/video/low/index.m3u8");
+        assert_eq!(variants[0].bandwidth, 1_280_000);
+        assert_eq!(variants[0].resolution.as_deref(), Some("640x360"));
+        assert_eq!(variants[0].codecs.as_deref(), Some("avc1.4d401e,mp4a.40.2"));
+        assert_eq!(variants[1].uri, "https://other.example.com/high.m3u8");
+        assert_eq!(variants[1].codecs, None);
+    }
+
+    #[test]
+    fn media_playlist() {
+        let playlist = media("#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:7\n#EXTINF:4.0,\nseg7.ts\n#EXTINF:2.5,title\nseg8.ts\n#EXT-X-ENDLIST\n");
+        assert_eq!(playlist.segments.iter().map(|s| (s.uri.as_str(), s.sequence)).collect::<Vec<_>>(), [
+            ("https://cdn.example.com/video/seg7.ts", 7),
+            ("https://cdn.example.com/video/seg8.ts", 8),
+        ]);
+        assert_eq!(playlist.duration, 6.5);
+        assert!(playlist.ended);
+
+        assert!(!media("#EXTM3U\n#EXTINF:4,\nlive.ts\n").ended);
+        let wrapped = media(&format!("#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:{}\n#EXTINF:1,\na.ts\n#EXTINF:1,\nb.ts\n", u64::MAX));
+        assert_eq!(wrapped.segments.iter().map(|s| s.sequence).collect::<Vec<_>>(), [u64::MAX, 0]);
+        for text in ["", "seg.ts", "#EXTM3U\n", "#EXTM3U\n#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"k\"\n#EXTINF:1,\na.ts"] {
+            assert!(parse(text, &base()).is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn attribute_lists() {
+        let attrs = attributes("BANDWIDTH=100, codecs=\"a,b\",URI=\"key?x=1,y=2\",IV=0x10,EMPTY=\"\"");
+        assert_eq!(attrs.get("BANDWIDTH").map(String::as_str), Some("100"));
+        assert_eq!(attrs.get("CODECS").map(String::as_str), Some("a,b"));
+        assert_eq!(attrs.get("URI").map(String::as_str), Some("key?x=1,y=2"));
+        assert_eq!(attrs.get("IV").map(String::as_str), Some("0x10"));
+        assert_eq!(attrs.get("EMPTY").map(String::as_str), Some(""));
+        assert_eq!(attributes("URI=\"unterminated").get("URI").map(String::as_str), Some("unterminated"));
+        assert!(attributes("").is_empty());
+    }
+
+    #[test]
+    fn ivs() {
+        let mut short = [0u8; 16];
+        short[15] = 0x10;
+        assert_eq!(parse_iv("0x10"), Some(short));
+        assert_eq!(parse_iv("0X000102030405060708090A0B0C0D0E0F"), Some(std::array::from_fn(|i| i as u8)));
+        assert_eq!(parse_iv("10"), None);
+        assert_eq!(parse_iv("0xzz"), None);
+        assert_eq!(parse_iv(&format!("0x{}", "1".repeat(34))), None);
+
+        let segment = |iv| Segment {
+            uri: String::new(),
+            sequence: 0x0102,
+            byte_range: None,
+            key: Some(Key { uri: String::new(), iv }),
+        };
+        let mut from_sequence = [0u8; 16];
+        from_sequence[14..].copy_from_slice(&[1, 2]);
+        assert_eq!(segment(None).iv(), from_sequence);
+        assert_eq!(segment(Some(short)).iv(), short);
+    }
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(parse_byte_range("100@50", 0), Some((50, 149)));
+        assert_eq!(parse_byte_range(" 100 ", 150), Some((150, 249)));
+        assert_eq!(parse_byte_range("0@10", 0), None);
+        assert_eq!(parse_byte_range("x@10", 0), None);
+        assert_eq!(parse_byte_range(&format!("2@{}", u64::MAX), 0), None);
+        assert_eq!(parse_byte_range("1", u64::MAX), Some((u64::MAX, u64::MAX)));
+
+        // Ranges without an offset continue where the previous one ended
+        let playlist = media("#EXTM3U\n#EXTINF:1,\n#EXT-X-BYTERANGE:100@0\nall.ts\n#EXTINF:1,\n#EXT-X-BYTERANGE:50\nall.ts\n#EXTINF:1,\n#EXT-X-BYTERANGE:25@1000\nall.ts\n#EXTINF:1,\n#EXT-X-BYTERANGE:5\nall.ts\n");
+        assert_eq!(playlist.segments.iter().map(|s| s.byte_range).collect::<Vec<_>>(), [
+            Some((0, 99)), Some((100, 149)), Some((1000, 1024)), Some((1025, 1029)),
+        ]);
+        assert!(parse("#EXTM3U\n#EXT-X-BYTERANGE:abc\n#EXTINF:1,\na.ts", &base()).is_err());
+    }
+
+    #[test]
+    fn keys_and_init_sections() {
+        let playlist = media("#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:3\n\
+            #EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"720@0\"\n\
+            #EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.com/k1\",IV=0x01\n\
+            #EXTINF:4,\na.m4s\n\
+            #EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"720@0\"\n\
+            #EXTINF:4,\nb.m4s\n\
+            #EXT-X-KEY:METHOD=NONE\n\
+            #EXT-X-DISCONTINUITY\n\
+            #EXT-X-MAP:URI=\"init2.mp4\"\n\
+            #EXTINF:4,\nc.m4s\n");
+        let uris: Vec<&str> = playlist.segments.iter()
+            .map(|s| s.uri.trim_start_matches("https://cdn.example.com/video/"))
+            .collect();
+        // The repeated, unchanged map isn't emitted twice
+        assert_eq!(uris, ["init.mp4", "a.m4s", "b.m4s", "init2.mp4", "c.m4s"]);
+        assert_eq!(playlist.segments[0].byte_range, Some((0, 719)));
+        assert_eq!(playlist.segments[0].key, None);
+
+        let key = playlist.segments[1].key.as_ref().unwrap();
+        assert_eq!(key.uri, "https://keys.example.com/k1");
+        assert_eq!(key.iv.map(|iv| iv[15]), Some(1));
+        assert_eq!(playlist.segments[1].sequence, 3);
+        assert_eq!(playlist.segments[2].key, playlist.segments[1].key);
+        assert_eq!(playlist.segments[4].key, None);
+
+        assert!(parse("#EXTM3U\n#EXT-X-KEY:METHOD=AES-128\n#EXTINF:1,\na.ts", &base()).is_err());
+        assert!(parse("#EXTM3U\n#EXT-X-MAP:BYTERANGE=\"1@0\"\n#EXTINF:1,\na.ts", &base()).is_err());
+    }
+}
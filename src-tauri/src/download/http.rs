@@ -1,38 +1,320 @@
-use reqwest::header::{CONTENT_LENGTH, ETAG, ACCEPT_RANGES, RANGE};
-use reqwest::Client;
+use crate::download::auth::Authenticator;
+use crate::download::cookies;
+use crate::download::filename;
+use crate::storage::credentials;
+use reqwest::cookie::Jar;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, ACCEPT_RANGES, IF_RANGE, LAST_MODIFIED, RANGE, REFERER};
+use reqwest::{Client, ClientBuilder, NoProxy, Proxy, RequestBuilder, Response, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36";
 
 #[derive(Clone)]
 pub struct HttpHelper {
     client: Client,
+    auth: Arc<Authenticator>,
 }
 
 #[derive(Debug, Clone)]
 pub struct DownloadMetadata {
     pub size: Option<u64>,
-    #[allow(dead_code)]
     pub etag: Option<String>,
+    pub last_modified: Option<String>,
     pub accept_ranges: bool,
 }
 
+impl DownloadMetadata {
+    pub fn if_range(&self) -> Option<String> {
+        if_range_validator(self.etag.as_deref(), self.last_modified.as_deref())
+    }
+
+    /// Whether the server still reports the validators stored for a partial
+    /// file. Missing values on either side can't prove a change, so they match.
+    pub fn matches(&self, etag: Option<&str>, last_modified: Option<&str>) -> bool {
+        if let (Some(stored), Some(current)) = (etag, self.etag.as_deref()) {
+            return stored == current;
+        }
+        if let (Some(stored), Some(current)) = (last_modified, self.last_modified.as_deref()) {
+            return stored == current;
+        }
+        true
+    }
+}
+
+/// What the server reports about a file before it is downloaded.
+#[derive(Serialize, Debug, Clone)]
+pub struct RemoteFile {
+    /// After redirects
+    pub url: String,
+    pub filename: String,
+    pub size: Option<u64>,
+    pub content_type: Option<String>,
+    pub resumable: bool,
+}
+
+/// Picks the value for an `If-Range` header. Weak ETags are not allowed
+/// there, so those fall back to Last-Modified.
+pub fn if_range_validator(etag: Option<&str>, last_modified: Option<&str>) -> Option<String> {
+    etag.filter(|e| !e.starts_with("W/"))
+        .or(last_modified)
+        .map(|s| s.to_string())
+}
+
+/// Splits `Content-Range: bytes <start>-<end>/<total>` (or `bytes */<total>`
+/// on a 416) into its range and total parts.
+fn content_range_parts(headers: &HeaderMap) -> Option<(&str, &str)> {
+    let value = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    value.trim().strip_prefix("bytes ")?.split_once('/')
+}
+
+/// The start offset and the total size from `Content-Range`.
+fn content_range(headers: &HeaderMap) -> (Option<u64>, Option<u64>) {
+    let Some((range, total)) = content_range_parts(headers) else {
+        return (None, None);
+    };
+    let start = range.split_once('-').and_then(|(start, _)| start.trim().parse().ok());
+    (start, total.trim().parse().ok())
+}
+
+/// First and last (inclusive) byte of a 206 reply. Multipart replies carry
+/// no `Content-Range` header, so they have none.
+pub fn content_range_span(headers: &HeaderMap) -> Option<(u64, u64)> {
+    let (range, _) = content_range_parts(headers)?;
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    (start <= end).then_some((start, end))
+}
+
+pub fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    content_range(headers).0
+}
+
+pub fn content_range_total(headers: &HeaderMap) -> Option<u64> {
+    content_range(headers).1
+}
+
+/// Proxy for HTTP(S) traffic, set globally in settings or per download.
+/// A login for the proxy comes from its URL or, failing that, from the
+/// credential store entry saved for the proxy's address.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct ProxySettings {
+    /// `http://`, `https://`, `socks5://` or `socks5h://` (DNS through the
+    /// proxy); `None` connects directly
+    pub url: Option<String>,
+    /// Hosts reached without the proxy, in `NO_PROXY` syntax
+    /// (`localhost`, `.corp.example`, `10.0.0.0/8`)
+    pub bypass: Vec<String>,
+    /// Without a `url`, use `HTTP_PROXY` / `HTTPS_PROXY` / `ALL_PROXY` /
+    /// `NO_PROXY` from the environment
+    pub use_system: bool,
+}
+
+impl Default for ProxySettings {
+    fn default() -> Self {
+        Self {
+            url: None,
+            bypass: Vec::new(),
+            use_system: true,
+        }
+    }
+}
+
+impl ProxySettings {
+    fn apply(&self, builder: ClientBuilder) -> Result<ClientBuilder, String> {
+        let Some(url) = self.url.as_deref().map(str::trim).filter(|url| !url.is_empty()) else {
+            // reqwest picks up the environment variables unless told not to
+            return Ok(if self.use_system { builder } else { builder.no_proxy() });
+        };
+
+        let parsed = Url::parse(url).map_err(|e| format!("Invalid proxy URL {}: {}", url, e))?;
+        if !matches!(parsed.scheme(), "http" | "https" | "socks5" | "socks5h") {
+            return Err(format!("Unsupported proxy type: {}", parsed.scheme()));
+        }
+        // A login in the URL is sent as is
+        let mut proxy = Proxy::all(parsed.as_str()).map_err(|e| format!("Invalid proxy URL {}: {}", url, e))?;
+        if parsed.username().is_empty() {
+            if let Some(login) = credentials::origin_of(&parsed).and_then(|origin| credentials::lookup(&origin)) {
+                proxy = proxy.basic_auth(&login.username, &login.password);
+            }
+        }
+        proxy = proxy.no_proxy(NoProxy::from_string(&self.bypass.join(",")));
+        eprintln!("[Proxy] Using {}://{}", parsed.scheme(), parsed.host_str().unwrap_or_default());
+        Ok(builder.proxy(proxy))
+    }
+}
+
+/// Extra request data for one download, e.g. the session cookie of a
+/// portal that gates its files behind a login.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct RequestOptions {
+    /// Sent with every request
+    pub headers: BTreeMap<String, String>,
+    /// `name=value; name2=value2`, sent to the download's host
+    pub cookies: Option<String>,
+    /// Netscape `cookies.txt` exported from a browser; read again on resume
+    pub cookies_file: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    /// Download id whose keychain entry holds the cookies and auth headers
+    /// left out of this copy; set only on what history stores
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keychain_ref: Option<String>,
+}
+
+/// The parts of `RequestOptions` that log a user in, kept in the keychain
+/// rather than in history.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct RequestSecrets {
+    pub headers: BTreeMap<String, String>,
+    pub cookies: Option<String>,
+}
+
+/// Headers that carry a session or a key, by name.
+fn is_secret_header(name: &str) -> bool {
+    let name = name.trim().to_ascii_lowercase();
+    matches!(name.as_str(), "authorization" | "proxy-authorization" | "cookie")
+        || ["auth", "token", "secret", "session", "key", "password"].iter().any(|word| name.contains(word))
+}
+
+impl RequestOptions {
+    /// Copy without cookies and auth-like headers, safe to persist and show.
+    pub fn without_secrets(&self) -> Self {
+        Self {
+            headers: self.headers.iter()
+                .filter(|(name, _)| !is_secret_header(name))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            cookies: None,
+            ..self.clone()
+        }
+    }
+
+    fn secrets(&self) -> Option<RequestSecrets> {
+        let secrets = RequestSecrets {
+            headers: self.headers.iter()
+                .filter(|(name, _)| is_secret_header(name))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            cookies: self.cookies.clone().filter(|c| !c.trim().is_empty()),
+        };
+        (!secrets.headers.is_empty() || secrets.cookies.is_some()).then_some(secrets)
+    }
+
+    /// Moves the secrets into the keychain under download `id` and returns
+    /// what history may store. If the keychain refuses they are dropped, so a
+    /// resume goes without them rather than leaving them on disk.
+    pub fn stash_secrets(&self, id: &str) -> Self {
+        let mut stored = self.without_secrets();
+        stored.keychain_ref = None;
+        if let Some(secrets) = self.secrets() {
+            match credentials::save_request(id, &secrets) {
+                Ok(()) => stored.keychain_ref = Some(id.to_string()),
+                Err(e) => eprintln!("[Credentials] Can't save cookies and headers of {}: {}", id, e),
+            }
+        }
+        stored
+    }
+
+    /// Puts back what `stash_secrets` moved to the keychain.
+    pub fn restore_secrets(&self) -> Self {
+        let mut request = self.clone();
+        if let Some(secrets) = self.keychain_ref.as_deref().and_then(credentials::lookup_request) {
+            request.headers.extend(secrets.headers);
+            request.cookies = secrets.cookies;
+        }
+        request
+    }
+
+    /// Whether this copy still holds anything `stash_secrets` would move.
+    pub fn has_secrets(&self) -> bool {
+        self.secrets().is_some()
+    }
+
+    fn apply(&self, builder: ClientBuilder, url: &str) -> Result<ClientBuilder, String> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            let name = HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| format!("Invalid header name: {}", name))?;
+            let value = HeaderValue::from_str(value.trim())
+                .map_err(|_| format!("Invalid value for header {}", name))?;
+            headers.insert(name, value);
+        }
+        if let Some(referer) = &self.referer {
+            let value = HeaderValue::from_str(referer.trim()).map_err(|_| format!("Invalid referer: {}", referer))?;
+            headers.insert(REFERER, value);
+        }
+
+        let jar = Jar::default();
+        if let (Some(cookies), Ok(url)) = (&self.cookies, Url::parse(url)) {
+            cookies::add_cookie_string(&jar, cookies, &url);
+        }
+        if let Some(path) = &self.cookies_file {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| format!("Can't read cookies file {}: {}", path, e))?;
+            let count = cookies::add_netscape_file(&jar, &text);
+            eprintln!("[HTTP] Loaded {} cookies from {}", count, path);
+        }
+
+        Ok(builder
+            .default_headers(headers)
+            .cookie_provider(Arc::new(jar))
+            .user_agent(self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT)))
+    }
+}
+
 impl HttpHelper {
-    pub fn new() -> Self {
-        let client = Client::builder()
-            .cookie_store(true)
-            .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36")
+    /// Client for downloading `url` through `proxy`, sending `request`'s
+    /// headers and cookies on top of the ones servers set along the way.
+    pub fn with_options(url: &str, proxy: &ProxySettings, request: &RequestOptions) -> Result<Self, String> {
+        let builder = request.apply(Client::builder(), url)?;
+        let client = proxy.apply(builder)?
             .build()
-            .unwrap_or_else(|_| Client::new());
-        
-        Self { client }
+            .map_err(|e| e.to_string())?;
+        let auth = Arc::new(Authenticator::new(url));
+        // Look the server's saved login up now, off the async threads
+        if let (Ok(runtime), Ok(parsed)) = (tokio::runtime::Handle::try_current(), Url::parse(url)) {
+            let auth = auth.clone();
+            runtime.spawn(async move { auth.resolve(&parsed).await });
+        }
+        Ok(Self { client, auth })
     }
 
     pub fn client(&self) -> &Client {
         &self.client
     }
 
+    /// Sends `request` with the login or token known for its server, and
+    /// answers one Basic or Digest challenge with a saved login (from the
+    /// URL, the credential store or `.netrc`).
+    pub async fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let mut request = request.build()?;
+        // Usually done already by `with_options`; other servers (mirrors,
+        // checksum files) are looked up on first use
+        self.auth.resolve(request.url()).await;
+        self.auth.prepare(&mut request);
+        let retry = request.try_clone();
+        let response = self.client.execute(request).await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        let Some(mut retry) = retry else {
+            return Ok(response);
+        };
+        if !self.auth.respond(&mut retry, response.headers()) {
+            return Ok(response);
+        }
+        self.client.execute(retry).await
+    }
+
     pub async fn get_metadata(&self, url: &str) -> Result<DownloadMetadata, String> {
         // Try HEAD request, but don't fail if server doesn't support it
         // Some servers (like direct file download servers) return 405 or fail on HEAD
-        match self.client.head(url).send().await {
+        match self.send(self.client.head(url)).await {
             Ok(res) => {
                 let headers = res.headers();
 
@@ -44,6 +326,10 @@ impl HttpHelper {
                     .and_then(|val| val.to_str().ok())
                     .map(|s| s.to_string());
 
+                let last_modified = headers.get(LAST_MODIFIED)
+                    .and_then(|val| val.to_str().ok())
+                    .map(|s| s.to_string());
+
                 let accept_ranges = headers.get(ACCEPT_RANGES)
                     .and_then(|val| val.to_str().ok())
                     .map(|s| s == "bytes")
@@ -52,6 +338,7 @@ impl HttpHelper {
                 Ok(DownloadMetadata {
                     size,
                     etag,
+                    last_modified,
                     accept_ranges,
                 })
             },
@@ -61,26 +348,176 @@ impl HttpHelper {
                 Ok(DownloadMetadata {
                     size: None,
                     etag: None,
+                    last_modified: None,
                     accept_ranges: false,
                 })
             }
         }
     }
 
-    pub async fn download_range_request(&self, url: &str, start: u64, _end: u64) -> Result<reqwest::Response, String> {
+    /// Name, size and type of the file at `url`. Servers that reject HEAD
+    /// are asked for the first byte instead.
+    pub async fn probe(&self, url: &str) -> Result<RemoteFile, String> {
+        let response = match self.send(self.client.head(url)).await {
+            Ok(res) if res.status().is_success() => res,
+            _ => self.range_request(url, "bytes=0-0".to_string(), None).await?,
+        };
+        let status = response.status();
+        if !status.is_success() {
+            return Err(format!("Server returned HTTP {}", status));
+        }
+
+        let headers = response.headers();
+        let size = if status == StatusCode::PARTIAL_CONTENT {
+            content_range_total(headers)
+        } else {
+            headers.get(CONTENT_LENGTH)
+                .and_then(|val| val.to_str().ok())
+                .and_then(|val| val.parse::<u64>().ok())
+        };
+        let resumable = status == StatusCode::PARTIAL_CONTENT
+            || headers.get(ACCEPT_RANGES).and_then(|val| val.to_str().ok()) == Some("bytes");
+
+        Ok(RemoteFile {
+            url: response.url().to_string(),
+            filename: Self::filename_of(&response),
+            size,
+            content_type: headers.get(CONTENT_TYPE)
+                .and_then(|val| val.to_str().ok())
+                .map(|s| s.to_string()),
+            resumable,
+        })
+    }
+
+    /// Name to save `response`'s body under, from its `Content-Disposition`,
+    /// final URL and `Content-Type`.
+    pub fn filename_of(response: &Response) -> String {
+        let headers = response.headers();
+        let disposition = Self::content_disposition(headers);
+        let content_type = headers.get(CONTENT_TYPE).and_then(|val| val.to_str().ok());
+        filename::resolve(disposition.as_deref(), response.url(), content_type)
+    }
+
+    /// Some servers send the name as raw UTF-8, which `to_str` rejects.
+    pub fn content_disposition(headers: &HeaderMap) -> Option<String> {
+        headers.get(CONTENT_DISPOSITION).map(|val| String::from_utf8_lossy(val.as_bytes()).into_owned())
+    }
+
+    pub async fn download_range_request(&self, url: &str, start: u64, if_range: Option<&str>) -> Result<reqwest::Response, String> {
         // Use open-ended range format "bytes=start-" for proper resume support
-        let range_header = format!("bytes={}-", start);
-        self.client.get(url)
-            .header(RANGE, range_header)
-            .send()
+        self.range_request(url, format!("bytes={}-", start), if_range).await
+    }
+
+    pub async fn download_segment_request(&self, url: &str, start: u64, end: u64, if_range: Option<&str>) -> Result<reqwest::Response, String> {
+        // Closed range ("bytes=start-end", end inclusive) so each connection only fetches its own slice
+        self.range_request(url, format!("bytes={}-{}", start, end), if_range).await
+    }
+
+    async fn range_request(&self, url: &str, range_header: String, if_range: Option<&str>) -> Result<reqwest::Response, String> {
+        // With If-Range the server answers 200 with the full body when the
+        // file changed, instead of a 206 slice of the new content
+        let mut request = self.client.get(url).header(RANGE, range_header);
+        if let Some(validator) = if_range {
+            request = request.header(IF_RANGE, validator);
+        }
+        self.send(request)
             .await
             .map_err(|e| e.to_string())
     }
 
     pub async fn download_stream_request(&self, url: &str) -> Result<reqwest::Response, String> {
-        self.client.get(url)
-            .send()
+        self.send(self.client.get(url))
             .await
             .map_err(|e| e.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_secrets() {
+        let request = RequestOptions {
+            headers: BTreeMap::from([
+                ("Authorization".to_string(), "Bearer abc".to_string()),
+                ("X-Api-Key".to_string(), "k".to_string()),
+                ("X-Session-Id".to_string(), "s".to_string()),
+                ("Accept-Language".to_string(), "en".to_string()),
+            ]),
+            cookies: Some("sid=1".to_string()),
+            cookies_file: Some("/tmp/cookies.txt".to_string()),
+            referer: Some("https://example.com/".to_string()),
+            ..Default::default()
+        };
+        assert!(request.has_secrets());
+
+        let public = request.without_secrets();
+        assert_eq!(public.headers.keys().collect::<Vec<_>>(), ["Accept-Language"]);
+        assert_eq!(public.cookies, None);
+        assert_eq!(public.cookies_file, request.cookies_file);
+        assert_eq!(public.referer, request.referer);
+        assert!(!public.has_secrets());
+
+        let secrets = request.secrets().unwrap();
+        assert_eq!(secrets.headers.len(), 3);
+        assert_eq!(secrets.cookies.as_deref(), Some("sid=1"));
+        assert!(RequestOptions::default().secrets().is_none());
+    }
+
+    fn content_range_of(value: &str) -> (Option<u64>, Option<u64>) {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_RANGE, value.parse().unwrap());
+        content_range(&headers)
+    }
+
+    #[test]
+    fn if_range_prefers_strong_etags() {
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        assert_eq!(if_range_validator(Some("\"abc\""), Some(date)).as_deref(), Some("\"abc\""));
+        // Weak ETags can't be used with If-Range (RFC 9110 13.1.5)
+        assert_eq!(if_range_validator(Some("W/\"abc\""), Some(date)).as_deref(), Some(date));
+        assert_eq!(if_range_validator(Some("W/\"abc\""), None), None);
+        assert_eq!(if_range_validator(None, None), None);
+
+        let meta = DownloadMetadata {
+            size: Some(10),
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+            accept_ranges: true,
+        };
+        assert_eq!(meta.if_range().as_deref(), Some("\"abc\""));
+        assert!(meta.matches(Some("\"abc\""), None));
+        assert!(meta.matches(None, Some(date)));
+        assert!(!meta.matches(Some("\"def\""), None));
+    }
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(content_range_of("bytes 0-99/100"), (Some(0), Some(100)));
+        assert_eq!(content_range_of("bytes 100-199/1000"), (Some(100), Some(1000)));
+        assert_eq!(content_range_of(" bytes 5-9/10 "), (Some(5), Some(10)));
+        // Total unknown
+        assert_eq!(content_range_of("bytes 100-199/*"), (Some(100), None));
+        // Sent with 416 Range Not Satisfiable
+        assert_eq!(content_range_of("bytes */1000"), (None, Some(1000)));
+        assert_eq!(content_range_of("bytes 18446744073709551615-18446744073709551615/18446744073709551616"), (Some(u64::MAX), None));
+        assert_eq!(content_range_of("items 0-9/10"), (None, None));
+        assert_eq!(content_range_of("bytes 0-9"), (None, None));
+        assert_eq!(content_range_of("bytes x-9/10"), (None, Some(10)));
+        assert_eq!(content_range_total(&HeaderMap::new()), None);
+        assert_eq!(content_range_start(&HeaderMap::new()), None);
+
+        let span_of = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_RANGE, value.parse().unwrap());
+            content_range_span(&headers)
+        };
+        assert_eq!(span_of("bytes 100-199/1000"), Some((100, 199)));
+        assert_eq!(span_of("bytes 100-199/*"), Some((100, 199)));
+        assert_eq!(span_of("bytes */1000"), None);
+        assert_eq!(span_of("bytes 200-100/1000"), None);
+        assert_eq!(span_of("bytes 100-/1000"), None);
+        assert_eq!(content_range_span(&HeaderMap::new()), None);
+    }
+}
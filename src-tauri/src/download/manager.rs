@@ -1,15 +1,25 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
 use tauri::{AppHandle, Emitter};
 use uuid::Uuid;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::fs::OpenOptions;
 use tokio::io::{AsyncWriteExt, AsyncSeekExt, SeekFrom};
 use futures_util::StreamExt;
-use super::http::HttpHelper;
+use super::http::{content_range_start, content_range_total, if_range_validator, DownloadMetadata, HttpHelper, ProxySettings, RequestOptions};
 use serde::Serialize;
-use crate::download::{Downloader, DownloadContext, DownloadError, DownloadMeta};
-use crate::download::gdrive::GDriveDownloader;
-use crate::storage::DownloadType;
+use crate::download::{Downloader, DownloadContext, DownloadError, DownloadMeta, DownloadResult};
+use crate::download::checksum;
+use crate::download::filename;
+use crate::download::queue::{self, DownloadQueue, DownloadState, QueueEntry};
+use crate::download::ratelimit::{RateLimiter, TokenBucket};
+use crate::download::registry::DownloaderRegistry;
+use crate::download::retry::RetryPolicy;
+use crate::download::segments::{self, SegmentPlan};
+use crate::download::torrent;
+use async_trait::async_trait;
+use crate::storage::{self, credentials, DownloadHistoryItem, DownloadType};
+use crate::storage::history::HistoryStore;
 
 #[derive(Clone, Serialize)]
 pub struct ProgressEvent {
@@ -19,99 +29,567 @@ pub struct ProgressEvent {
     pub speed: u64,
 }
 
+/// How one download talks to its server; kept in history so a resume
+/// connects the same way.
+#[derive(Clone, Default)]
+pub struct NetworkOptions {
+    /// Replaces the proxy from settings
+    pub proxy: Option<ProxySettings>,
+    pub request: Option<RequestOptions>,
+}
+
+/// What a URL points at, learned without starting the download.
+#[derive(Serialize, Debug)]
+pub struct UrlAnalysis {
+    pub download_type: DownloadType,
+    /// Already safe to save under; `None` when only the download reveals it
+    pub filename: Option<String>,
+    pub size: Option<u64>,
+    pub content_type: Option<String>,
+    /// Where plain HTTP downloads end up after redirects
+    pub final_url: Option<String>,
+    pub resumable: bool,
+    /// Files a folder URL expands to
+    pub children: usize,
+}
+
+/// A URL resolved before the manager is locked: what it points at and, for
+/// a folder, what each of its files points at, in the same order.
+pub struct Resolved {
+    meta: DownloadMeta,
+    children: Vec<DownloadResult<DownloadMeta>>,
+}
+
 pub struct DownloadManager {
     app: Option<AppHandle>,
-    tasks: HashMap<String, tokio::task::AbortHandle>,
+    /// Queued and running tasks; each removes itself when it ends
+    tasks: Arc<Mutex<HashMap<String, tokio::task::AbortHandle>>>,
+    queue: DownloadQueue,
+    global_limit: Arc<TokenBucket>,
+    limits: HashMap<String, Arc<TokenBucket>>,
+    retry: RetryPolicy,
+    proxy: ProxySettings,
+    history: HistoryStore,
+    downloaders: Arc<DownloaderRegistry>,
 }
 
 impl DownloadManager {
     pub fn new() -> Self {
         Self {
             app: None,
-            tasks: HashMap::new(),
+            tasks: Arc::new(Mutex::new(HashMap::new())),
+            queue: DownloadQueue::new(queue::DEFAULT_MAX_CONCURRENT),
+            global_limit: Arc::new(TokenBucket::new(None)),
+            limits: HashMap::new(),
+            retry: RetryPolicy::default(),
+            proxy: ProxySettings::default(),
+            history: HistoryStore::default(),
+            downloaders: Arc::new(DownloaderRegistry::with_defaults()),
         }
     }
     
     pub fn init(&mut self, app: AppHandle) {
+        if let Ok(settings) = storage::load_settings(&app) {
+            self.queue.set_limit(settings.max_concurrent_downloads);
+            self.global_limit.set_rate(settings.speed_limit);
+            self.retry = settings.retry;
+            self.proxy = settings.proxy;
+        }
+        self.history.load(&app);
         self.app = Some(app);
     }
+
+    pub fn history(&self) -> &HistoryStore {
+        &self.history
+    }
+
+    pub fn set_max_concurrent(&mut self, limit: usize) {
+        self.queue.set_limit(limit);
+    }
+
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry = policy;
+    }
+
+    pub fn set_proxy(&mut self, proxy: ProxySettings) {
+        self.proxy = proxy;
+    }
+
+    /// HTTP client for downloading `url`, through the settings' proxy unless
+    /// the download has its own.
+    pub fn http(&self, url: &str, network: &NetworkOptions) -> Result<HttpHelper, String> {
+        HttpHelper::with_options(
+            url,
+            network.proxy.as_ref().unwrap_or(&self.proxy),
+            &network.request.clone().unwrap_or_default(),
+        )
+    }
+
+    pub fn set_global_speed_limit(&mut self, bytes_per_sec: Option<u64>) {
+        self.global_limit.set_rate(bytes_per_sec);
+    }
+
+    /// Caps a single download. Takes effect on the next chunk if it is running,
+    /// and is kept for the id so it also applies after a resume.
+    pub fn set_speed_limit(&mut self, id: String, bytes_per_sec: Option<u64>) {
+        self.limits.entry(id)
+            .or_insert_with(|| Arc::new(TokenBucket::new(None)))
+            .set_rate(bytes_per_sec);
+    }
+
+    fn limiter_for(&mut self, id: &str) -> RateLimiter {
+        let local = self.limits.entry(id.to_string())
+            .or_insert_with(|| Arc::new(TokenBucket::new(None)))
+            .clone();
+        RateLimiter::new(self.global_limit.clone(), local)
+    }
+
+    pub fn queue_entries(&self) -> Vec<QueueEntry> {
+        self.queue.entries()
+    }
     
-    pub async fn download(&mut self, url: String, path: String) -> Result<String, String> {
+    /// Does the network round trips `download` needs, one per file for a
+    /// folder, so callers can run it without holding the manager.
+    pub async fn resolve(url: &str, http: &HttpHelper, downloaders: &DownloaderRegistry) -> DownloadResult<Resolved> {
+        let meta = downloaders.analyze(url, http).await?;
+        let mut children = Vec::with_capacity(meta.children.len());
+        for child in &meta.children {
+            children.push(downloaders.analyze(&child.url, http).await);
+        }
+        Ok(Resolved { meta, children })
+    }
+
+    /// Starts a download for `url`, as found by `resolve`.
+    pub async fn download(
+        &mut self,
+        url: String,
+        path: String,
+        checksum: Option<String>,
+        files: Option<Vec<usize>>,
+        network: NetworkOptions,
+        resolved: DownloadResult<Resolved>,
+    ) -> Result<String, String> {
         let id = Uuid::new_v4().to_string();
         let app = self.app.clone().ok_or("App not initialized")?;
-        let http = HttpHelper::new();
-
-        let meta: DownloadMeta = if GDriveDownloader::detect(&url) {
-            match GDriveDownloader::analyze(&url, &http).await {
-                Ok(Some(m)) => m,
-                Ok(None) => {
-                    DownloadMeta {
-                        download_type: DownloadType::Http,
-                        direct_url: url.clone(),
-                        original_url: None,
-                        suggested_filename: None,
-                    }
-                }
-                Err(e) => {
-                    let _ = app.emit("download://error", serde_json::json!({
-                        "id": id,
-                        "error": e.to_string(),
-                    }));
-                    return Err(e.to_string());
-                }
-            }
-        } else {
-            DownloadMeta {
-                download_type: DownloadType::Http,
-                direct_url: url.clone(),
-                original_url: None,
-                suggested_filename: None,
+
+        let resolved = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                let _ = app.emit("download://error", serde_json::json!({
+                    "id": id,
+                    "error": e.to_string(),
+                }));
+                return Err(e.to_string());
             }
         };
 
-        let task_id = id.clone();
-        let task_url = meta.direct_url.clone();
-        let task_path = path.clone();
-        let task_app = app.clone();
-        let task_original_url = meta.original_url.clone();
-        let download_type = meta.download_type.clone();
+        if !resolved.meta.children.is_empty() {
+            return self.download_group(id, url, path, resolved, network).await;
+        }
+        let meta = resolved.meta;
 
-        let handle = tokio::spawn(async move {
-            let ctx = DownloadContext {
-                id: task_id.clone(),
-                url: task_url,
-                save_path: task_path,
-                app: task_app.clone(),
-                http: HttpHelper::new(),
-                original_url: task_original_url,
-                downloaded_bytes: 0,
-            };
+        self.start(&id, url, path, &meta, checksum, files, network, None)?;
 
-            let result = match download_type {
-                DownloadType::GoogleDrive => GDriveDownloader::run(ctx).await,
-                _ => FileDownloader::run_legacy(ctx).await,
-            };
+        Ok(serde_json::json!({
+            "id": id,
+            "download_type": meta.download_type.as_str(),
+            "original_url": meta.original_url,
+            "direct_url": meta.direct_url,
+        }).to_string())
+    }
 
-            if let Err(e) = result {
-                eprintln!("Download error for {}: {}", task_id, e);
-                let _ = task_app.emit("download://error", (task_id.clone(), e.to_string()));
-            }
+    /// Shared with callers that do network I/O after releasing the manager.
+    pub fn downloaders(&self) -> Arc<DownloaderRegistry> {
+        self.downloaders.clone()
+    }
+
+    /// Resolves `url` the way `download` would, plus a HEAD request for
+    /// plain HTTP files, so the save dialog can be prefilled. Takes what it
+    /// needs from the manager up front so the manager isn't held meanwhile.
+    pub async fn analyze(url: &str, http: &HttpHelper, downloaders: &DownloaderRegistry) -> Result<UrlAnalysis, String> {
+        let meta = downloaders.analyze(url, http).await.map_err(|e| e.to_string())?;
+
+        let mut analysis = UrlAnalysis {
+            download_type: meta.download_type.clone(),
+            filename: meta.suggested_filename.as_deref().map(filename::sanitize),
+            size: None,
+            content_type: None,
+            final_url: None,
+            resumable: false,
+            children: meta.children.len(),
+        };
+        if meta.download_type == DownloadType::Http {
+            let file = http.probe(&meta.direct_url).await?;
+            eprintln!("[Analyze] {} -> {} ({:?} bytes)", url, file.filename, file.size);
+            analysis.filename = Some(file.filename);
+            analysis.size = file.size;
+            analysis.content_type = file.content_type;
+            analysis.final_url = Some(file.url);
+            analysis.resumable = file.resumable;
+        }
+        Ok(analysis)
+    }
+
+    /// Records a new download in history and queues it.
+    #[allow(clippy::too_many_arguments)]
+    fn start(
+        &mut self,
+        id: &str,
+        url: String,
+        path: String,
+        meta: &DownloadMeta,
+        checksum: Option<String>,
+        files: Option<Vec<usize>>,
+        network: NetworkOptions,
+        parent_id: Option<String>,
+    ) -> Result<(), String> {
+        let app = self.app.clone().ok_or("App not initialized")?;
+        let http = self.http(&meta.direct_url, &network)?;
+        let save_path = Path::new(&path);
+        let now = storage::timestamp();
+        self.history.insert(DownloadHistoryItem {
+            id: id.to_string(),
+            url,
+            path: save_path.parent().map(|p| p.to_string_lossy().to_string()).unwrap_or_default(),
+            filename: save_path.file_name().map(|f| f.to_string_lossy().to_string()).unwrap_or_default(),
+            total: None,
+            downloaded: 0,
+            status: DownloadState::Queued.history_status().to_string(),
+            etag: None,
+            last_modified: None,
+            created_at: now.clone(),
+            updated_at: now,
+            download_type: meta.download_type.clone(),
+            original_url: meta.original_url.clone(),
+            checksum: checksum.clone(),
+            error: None,
+            selected_files: files.clone(),
+            parent_id,
+            proxy: network.proxy,
+            request: network.request.map(|request| request.stash_secrets(id)),
+        });
+
+        let ctx = DownloadContext {
+            id: id.to_string(),
+            url: meta.direct_url.clone(),
+            save_path: path,
+            app,
+            http,
+            original_url: meta.original_url.clone(),
+            downloaded_bytes: 0,
+            etag: None,
+            last_modified: None,
+            limiter: self.limiter_for(id),
+            checksum: None,
+            history: self.history.clone(),
+            files,
+        };
+        self.spawn(ctx, meta.download_type.clone(), checksum);
+        Ok(())
+    }
+
+    /// Queues every file of a folder as its own download under a parent
+    /// entry `id`. The folder is recreated next to `path`, named after the
+    /// source folder.
+    async fn download_group(
+        &mut self,
+        id: String,
+        url: String,
+        path: String,
+        resolved: Resolved,
+        network: NetworkOptions,
+    ) -> Result<String, String> {
+        let Resolved { meta, children: child_metas } = resolved;
+        let app = self.app.clone().ok_or("App not initialized")?;
+        let save_path = Path::new(&path);
+        let parent_dir = save_path.parent().map(Path::to_path_buf).unwrap_or_default();
+        // The save dialog is prefilled with the source folder's name, so
+        // what comes back is what the user settled on
+        let folder_name = save_path.file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .or_else(|| meta.suggested_filename.clone())
+            .unwrap_or_default();
+        let root = parent_dir.join(&folder_name);
+        eprintln!("[Group] {}: {} files into {}", id, meta.children.len(), root.display());
+
+        // Everything that can fail outright happens before the group is
+        // recorded, so a failure leaves nothing behind in history
+        let dirs: BTreeSet<PathBuf> = meta.children.iter()
+            .filter_map(|child| root.join(&child.relative_path).parent().map(Path::to_path_buf))
+            .collect();
+        for dir in &dirs {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+
+        let now = storage::timestamp();
+        self.history.insert(DownloadHistoryItem {
+            id: id.clone(),
+            url: url.clone(),
+            path: parent_dir.to_string_lossy().to_string(),
+            filename: folder_name.clone(),
+            total: None,
+            downloaded: 0,
+            status: DownloadState::Queued.history_status().to_string(),
+            etag: None,
+            last_modified: None,
+            created_at: now.clone(),
+            updated_at: now,
+            download_type: meta.download_type.clone(),
+            original_url: meta.original_url.clone(),
+            checksum: None,
+            error: None,
+            selected_files: None,
+            parent_id: None,
+            proxy: network.proxy.clone(),
+            request: network.request.as_ref().map(|request| request.stash_secrets(&id)),
         });
+        transition(&self.queue, &self.history, &app, &id, DownloadState::Queued);
+
+        let mut children = Vec::new();
+        for (child, child_meta) in meta.children.iter().zip(child_metas) {
+            let child_id = Uuid::new_v4().to_string();
+            let child_path = root.join(&child.relative_path).to_string_lossy().to_string();
+
+            let queued = match child_meta {
+                Ok(child_meta) => self
+                    .start(&child_id, child.url.clone(), child_path.clone(), &child_meta, None, None, network.clone(), Some(id.clone()))
+                    .map(|()| child_meta.download_type),
+                Err(e) => Err(e.to_string()),
+            };
+            let child_type = match queued {
+                Ok(child_type) => child_type,
+                Err(e) => {
+                    // Keep it in the group as failed so it can be retried later
+                    eprintln!("[Group] {}: can't queue {}: {}", id, child.url, e);
+                    self.record_failed_child(&id, &child_id, &child.url, &child_path, meta.download_type.clone(), e);
+                    meta.download_type.clone()
+                }
+            };
 
-        self.tasks.insert(id.clone(), handle.abort_handle());
+            let child_path = Path::new(&child_path);
+            children.push(serde_json::json!({
+                "id": child_id,
+                "url": child.url,
+                "path": child_path.parent().map(|p| p.to_string_lossy().to_string()).unwrap_or_default(),
+                "filename": child_path.file_name().map(|f| f.to_string_lossy().to_string()).unwrap_or_default(),
+                "download_type": child_type.as_str(),
+            }));
+        }
 
         Ok(serde_json::json!({
             "id": id,
             "download_type": meta.download_type.as_str(),
             "original_url": meta.original_url,
             "direct_url": meta.direct_url,
+            "path": parent_dir.to_string_lossy(),
+            "filename": folder_name,
+            "children": children,
+        }).to_string())
+    }
+
+    fn record_failed_child(&self, parent_id: &str, id: &str, url: &str, path: &str, download_type: DownloadType, error: String) {
+        let Some(app) = &self.app else {
+            return;
+        };
+        let parent = self.history.get(parent_id);
+        let save_path = Path::new(path);
+        let now = storage::timestamp();
+        self.history.insert(DownloadHistoryItem {
+            id: id.to_string(),
+            url: url.to_string(),
+            path: save_path.parent().map(|p| p.to_string_lossy().to_string()).unwrap_or_default(),
+            filename: save_path.file_name().map(|f| f.to_string_lossy().to_string()).unwrap_or_default(),
+            total: None,
+            downloaded: 0,
+            status: DownloadState::Failed.history_status().to_string(),
+            etag: None,
+            last_modified: None,
+            created_at: now.clone(),
+            updated_at: now,
+            download_type,
+            original_url: Some(url.to_string()),
+            checksum: None,
+            error: Some(error),
+            selected_files: None,
+            parent_id: Some(parent_id.to_string()),
+            proxy: parent.as_ref().and_then(|parent| parent.proxy.clone()),
+            request: parent.and_then(|parent| parent.request),
+        });
+        transition(&self.queue, &self.history, app, id, DownloadState::Failed);
+    }
+
+    /// Continues a paused or failed download under its original id, using the
+    /// entry recorded in history.
+    pub async fn resume(&mut self, id: String) -> Result<String, String> {
+        let children = self.history.children(&id);
+        if children.is_empty() {
+            return self.resume_one(id).await;
+        }
+
+        // A folder resumes every file that isn't finished or already running
+        let item = self.history.get(&id).ok_or("Download not found in history")?;
+        for child in children {
+            let running = matches!(self.queue.state(&child.id), Some(DownloadState::Queued | DownloadState::Active));
+            if running || child.status == DownloadState::Completed.history_status() {
+                continue;
+            }
+            if let Err(e) = self.resume_one(child.id.clone()).await {
+                eprintln!("[Group] {}: can't resume {}: {}", id, child.id, e);
+            }
+        }
+
+        Ok(serde_json::json!({
+            "id": id,
+            "download_type": item.download_type.as_str(),
+            "original_url": item.original_url,
+            "direct_url": item.url,
+        }).to_string())
+    }
+
+    async fn resume_one(&mut self, id: String) -> Result<String, String> {
+        let app = self.app.clone().ok_or("App not initialized")?;
+
+        if matches!(self.queue.state(&id), Some(DownloadState::Queued | DownloadState::Active)) {
+            return Err("Download is already running".to_string());
+        }
+
+        let item = self.history.get(&id).ok_or("Download not found in history")?;
+
+        let save_path = PathBuf::from(&item.path)
+            .join(&item.filename)
+            .to_string_lossy()
+            .to_string();
+
+        let network = NetworkOptions {
+            proxy: item.proxy.clone(),
+            request: item.request.as_ref().map(RequestOptions::restore_secrets),
+        };
+        let url = match item.download_type {
+            DownloadType::GoogleDrive => {
+                // Share links map back to their download URL
+                let original = item.original_url.clone().unwrap_or_else(|| item.url.clone());
+                let refreshed = match self.downloaders.for_type(&item.download_type) {
+                    Some(downloader) => downloader.refresh_url(&original, &self.http(&original, &network)?).await.ok().flatten(),
+                    None => None,
+                };
+                refreshed.unwrap_or(original)
+            }
+            _ => item.url.clone(),
+        };
+        let http = self.http(&url, &network)?;
+
+        // The .fdm file is the source of truth; history only gets periodic
+        // checkpoints and can lag behind what actually reached the disk
+        let on_disk = tokio::fs::metadata(format!("{}.fdm", save_path))
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0);
+        eprintln!("[Resume] {}: history reports {} bytes, {} bytes on disk", id, item.downloaded, on_disk);
+
+        let ctx = DownloadContext {
+            id: id.clone(),
+            url: url.clone(),
+            save_path,
+            app,
+            http,
+            original_url: item.original_url.clone(),
+            downloaded_bytes: on_disk,
+            etag: item.etag.clone(),
+            last_modified: item.last_modified.clone(),
+            limiter: self.limiter_for(&id),
+            checksum: None,
+            history: self.history.clone(),
+            files: item.selected_files.clone(),
+        };
+        self.spawn(ctx, item.download_type.clone(), item.checksum.clone());
+
+        Ok(serde_json::json!({
+            "id": id,
+            "download_type": item.download_type.as_str(),
+            "original_url": item.original_url,
+            "direct_url": url,
         }).to_string())
     }
 
+    fn spawn(&mut self, ctx: DownloadContext, download_type: DownloadType, checksum_spec: Option<String>) {
+        let id = ctx.id.clone();
+        let task_id = ctx.id.clone();
+        let task_app = ctx.app.clone();
+        let queue = self.queue.clone();
+        let history = self.history.clone();
+
+        transition(&queue, &history, &task_app, &task_id, DownloadState::Queued);
+
+        let policy = self.retry.clone();
+        let downloader = self.downloaders.for_type(&download_type);
+        let tasks = self.tasks.clone();
+
+        // Held until the handle is stored, so a task that ends right away
+        // can't remove its entry before it exists
+        let mut running = self.tasks.lock().unwrap();
+        let handle = tokio::spawn(async move {
+            // Wait for a free slot; dropped with the task if it is paused while queued
+            let _slot = queue.acquire().await;
+            transition(&queue, &history, &task_app, &task_id, DownloadState::Active);
+
+            let result = match downloader {
+                Some(downloader) => run_with_retry(ctx, downloader.as_ref(), checksum_spec, &policy).await,
+                None => Err(DownloadError::Other(format!("No downloader for {} downloads", download_type.as_str()))),
+            };
+
+            match result {
+                Ok(()) => {
+                    history.update(&task_id, |item| item.error = None);
+                    transition(&queue, &history, &task_app, &task_id, DownloadState::Completed);
+                }
+                Err(e) => {
+                    eprintln!("Download error for {}: {}", task_id, e);
+                    history.update(&task_id, |item| item.error = Some(e.to_string()));
+                    transition(&queue, &history, &task_app, &task_id, DownloadState::Failed);
+                    if let DownloadError::AuthRequired { proxy, realm } = &e {
+                        // Lets the UI ask for a login, save it and resume
+                        let _ = task_app.emit("download://auth-required", serde_json::json!({
+                            "id": task_id,
+                            "proxy": proxy,
+                            "realm": realm,
+                        }));
+                    }
+                    let _ = task_app.emit("download://error", (task_id.clone(), e.to_string()));
+                }
+            }
+
+            // Unless a resume has already replaced it with a new task
+            let mut tasks = tasks.lock().unwrap();
+            if tasks.get(&task_id).is_some_and(|handle| handle.id() == tokio::task::id()) {
+                tasks.remove(&task_id);
+            }
+        });
+
+        running.insert(id, handle.abort_handle());
+    }
+
     pub fn pause(&mut self, id: String) -> Result<(), String> {
-        if let Some(handle) = self.tasks.remove(&id) {
+        let children = self.history.children(&id);
+        if !children.is_empty() {
+            // Pausing a folder pauses whatever of it is still running
+            for child in children {
+                if matches!(self.queue.state(&child.id), Some(DownloadState::Queued | DownloadState::Active)) {
+                    let _ = self.pause(child.id);
+                }
+            }
+            return Ok(());
+        }
+        // A finished torrent that is still seeding just stops uploading
+        if torrent::stop_seeding(&id) {
+            return Ok(());
+        }
+        let handle = self.tasks.lock().unwrap().remove(&id);
+        if let Some(handle) = handle {
             handle.abort();
             if let Some(app) = &self.app {
+                transition(&self.queue, &self.history, app, &id, DownloadState::Paused);
                 let _ = app.emit("download://paused", id);
             }
             Ok(())
@@ -119,18 +597,179 @@ impl DownloadManager {
             Err("Task not found".to_string())
         }
     }
+
+    /// Stops anything still running for `id` and drops it from history.
+    pub fn remove(&mut self, id: &str) {
+        for child in self.history.children(id) {
+            self.remove(&child.id);
+        }
+        if let Some(handle) = self.tasks.lock().unwrap().remove(id) {
+            handle.abort();
+        }
+        self.limits.remove(id);
+        torrent::stop_seeding(id);
+        if let Err(e) = credentials::delete_request(id) {
+            eprintln!("[Credentials] Failed to delete cookies and headers of {}: {}", id, e);
+        }
+        self.history.remove(id);
+    }
+}
+
+/// Records a state change in the queue (which notifies the UI) and in history.
+fn transition(queue: &DownloadQueue, history: &HistoryStore, app: &AppHandle, id: &str, state: DownloadState) {
+    queue.set_state(app, id, state);
+    history.set_status(id, state.history_status());
+    if let Some(parent_id) = history.get(id).and_then(|item| item.parent_id) {
+        update_group(queue, history, app, &parent_id);
+    }
+}
+
+/// Derives a folder entry's state and byte counts from its files: running
+/// while any file runs, then waiting, failed, paused, and completed once
+/// every file is.
+fn update_group(queue: &DownloadQueue, history: &HistoryStore, app: &AppHandle, parent_id: &str) {
+    let children = history.children(parent_id);
+    let state = [DownloadState::Active, DownloadState::Queued, DownloadState::Failed, DownloadState::Paused]
+        .into_iter()
+        .find(|state| children.iter().any(|child| child.status == state.history_status()))
+        .unwrap_or(DownloadState::Completed);
+
+    let downloaded = children.iter().map(|child| child.downloaded).sum();
+    let total = children.iter().map(|child| child.total).sum::<Option<u64>>();
+    history.update(parent_id, |item| {
+        item.downloaded = downloaded;
+        item.total = total;
+    });
+    if queue.state(parent_id) != Some(state) {
+        transition(queue, history, app, parent_id, state);
+    }
+}
+
+async fn run_with_retry(
+    mut ctx: DownloadContext,
+    downloader: &dyn Downloader,
+    checksum_spec: Option<String>,
+    policy: &RetryPolicy,
+) -> Result<(), DownloadError> {
+    if let Some(spec) = checksum_spec {
+        let filename = Path::new(&ctx.save_path)
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default();
+        ctx.checksum = Some(checksum::resolve(&spec, &filename, &ctx.http).await?);
+    }
+
+    let mut attempt = 1;
+    loop {
+        let mut attempt_ctx = ctx.clone();
+        if attempt > 1 {
+            // Validators the failed attempt recorded, so a file that changed
+            // on the server in between isn't appended to the old bytes
+            if let Some(item) = ctx.history.get(&ctx.id) {
+                attempt_ctx.etag = item.etag;
+                attempt_ctx.last_modified = item.last_modified;
+                // The failed attempt may have settled on another name (Drive's
+                // Content-Disposition), and its partial file sits under that one
+                if !item.filename.is_empty() {
+                    attempt_ctx.save_path = PathBuf::from(&item.path).join(&item.filename).to_string_lossy().to_string();
+                }
+            }
+            // Continue from whatever the failed attempt left in the .fdm file
+            attempt_ctx.downloaded_bytes = tokio::fs::metadata(format!("{}.fdm", attempt_ctx.save_path))
+                .await
+                .map(|m| m.len())
+                .unwrap_or(0);
+        }
+
+        match downloader.run(attempt_ctx).await {
+            Err(e) if attempt < policy.max_attempts && policy.is_retryable(&e) => {
+                let delay = policy.delay(attempt, &e);
+                eprintln!("[Retry] {}: attempt {} failed ({}), retrying in {:?}", ctx.id, attempt, e, delay);
+                let _ = ctx.app.emit("download://retry", serde_json::json!({
+                    "id": ctx.id,
+                    "attempt": attempt,
+                    "max_attempts": policy.max_attempts,
+                    "delay_ms": delay.as_millis() as u64,
+                    "error": e.to_string(),
+                }));
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            other => return other,
+        }
+    }
 }
 
+/// Plain HTTP(S); registered last so it takes any URL nothing else claimed.
 pub struct FileDownloader;
+
+#[async_trait]
+impl Downloader for FileDownloader {
+    fn download_type(&self) -> DownloadType {
+        DownloadType::Http
+    }
+
+    fn detect(&self, _url: &str) -> bool {
+        true
+    }
+
+    async fn analyze(&self, url: &str, _http: &HttpHelper) -> DownloadResult<Option<DownloadMeta>> {
+        Ok(Some(DownloadMeta {
+            download_type: DownloadType::Http,
+            direct_url: url.to_string(),
+            original_url: None,
+            suggested_filename: None,
+            ..Default::default()
+        }))
+    }
+
+    async fn run(&self, ctx: DownloadContext) -> DownloadResult<()> {
+        Self::run_legacy(ctx).await
+    }
+}
+
 impl FileDownloader {
     pub async fn run_legacy(ctx: DownloadContext) -> Result<(), DownloadError> {
-        let DownloadContext { id, url, save_path: path, app, http, original_url: _, downloaded_bytes: _ } = ctx;
         // 1. Get metadata
-        let meta = http.get_metadata(&url).await.map_err(|e| DownloadError::NetworkError(e))?;
+        let meta = ctx.http.get_metadata(&ctx.url).await.map_err(DownloadError::NetworkError)?;
+        let _ = ctx.app.emit("download://metadata", serde_json::json!({
+            "id": ctx.id,
+            "total": meta.size,
+            "etag": meta.etag,
+            "last_modified": meta.last_modified,
+        }));
+        ctx.history.update(&ctx.id, |item| {
+            item.total = meta.size;
+            item.etag = meta.etag.clone();
+            item.last_modified = meta.last_modified.clone();
+        });
         
         // 2. Check file - use .fdm extension for incomplete downloads
-        let file_path = PathBuf::from(&path);
-        let temp_path = PathBuf::from(format!("{}.fdm", path));
+        let file_path = PathBuf::from(&ctx.save_path);
+        let temp_path = PathBuf::from(format!("{}.fdm", ctx.save_path));
+        let parts_path = segments::parts_path(&ctx.save_path);
+
+        if !meta.matches(ctx.etag.as_deref(), ctx.last_modified.as_deref()) && temp_path.exists() {
+            // Partial data belongs to an older version of the file
+            eprintln!("[Resume] {}: file changed on server, restarting from scratch", ctx.id);
+            let _ = tokio::fs::remove_file(&parts_path).await;
+            let _ = tokio::fs::remove_file(&temp_path).await;
+        }
+
+        // Split into parallel byte ranges when the server allows it, unless an
+        // older single-stream partial file is already on disk
+        if segments::should_segment(meta.size, meta.accept_ranges) && (!temp_path.exists() || parts_path.exists()) {
+            return Self::run_segmented(&ctx, &meta).await;
+        }
+
+        let DownloadContext { id, url, app, http, limiter, checksum, history, etag, last_modified, .. } = ctx;
+
+        if parts_path.exists() {
+            // Preallocated segmented file can't be appended to; start over
+            let _ = tokio::fs::remove_file(&parts_path).await;
+            let _ = tokio::fs::remove_file(&temp_path).await;
+        }
+
         let mut downloaded = 0;
         if temp_path.exists() {
              downloaded = tokio::fs::metadata(&temp_path).await.map(|m| m.len()).unwrap_or(0);
@@ -147,12 +786,23 @@ impl FileDownloader {
         // If file exists and server supports range, resume.
         // If file exists but no range support, restart (truncate).
         
-        let response = if downloaded > 0 && meta.accept_ranges {
-            if let Some(total) = meta.size {
-                http.download_range_request(&url, downloaded, total).await.map_err(|e| DownloadError::NetworkError(e))?
-            } else {
-                http.download_stream_request(&url).await.map_err(|e| DownloadError::NetworkError(e))?
+        let mut response = if downloaded > 0 && meta.accept_ranges {
+            // The validator the partial bytes were fetched under; the HEAD
+            // reply only stands in when none was stored
+            let if_range = if_range_validator(etag.as_deref(), last_modified.as_deref()).or_else(|| meta.if_range());
+            let response = http.download_range_request(&url, downloaded, if_range.as_deref())
+                .await
+                .map_err(DownloadError::NetworkError)?;
+
+            if response.status() == reqwest::StatusCode::OK {
+                // Either the range was ignored or If-Range no longer matched;
+                // both mean the body is the complete file
+                eprintln!("[Resume] {}: server sent the full file, discarding {} partial bytes", id, downloaded);
+                file.set_len(0).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+                file.seek(SeekFrom::Start(0)).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+                downloaded = 0;
             }
+            response
         } else {
             if downloaded > 0 {
                 // Truncate
@@ -163,8 +813,47 @@ impl FileDownloader {
             http.download_stream_request(&url).await.map_err(|e| DownloadError::NetworkError(e))?
         };
         
-        let mut stream = response.bytes_stream();
-        let total = meta.size;
+        let mut already_complete = false;
+        if downloaded > 0 {
+            let restart = match response.status() {
+                reqwest::StatusCode::RANGE_NOT_SATISFIABLE => {
+                    // Nothing past the end: the partial file may already be all of it
+                    let size = content_range_total(response.headers()).or(meta.size);
+                    already_complete = size == Some(downloaded);
+                    !already_complete
+                }
+                reqwest::StatusCode::PARTIAL_CONTENT => {
+                    content_range_start(response.headers()) != Some(downloaded)
+                }
+                _ => false,
+            };
+            if restart {
+                eprintln!("[Resume] {}: server can't continue at byte {} ({}), restarting", id, downloaded, response.status());
+                file.set_len(0).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+                file.seek(SeekFrom::Start(0)).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+                downloaded = 0;
+                response = http.download_stream_request(&url).await.map_err(DownloadError::NetworkError)?;
+            }
+        }
+
+        let status = response.status();
+        if already_complete {
+            eprintln!("[Resume] {}: all {} bytes already on disk", id, downloaded);
+        } else if status == reqwest::StatusCode::FORBIDDEN {
+            return Err(DownloadError::AccessDenied("The server refused access to this file.".to_string()));
+        } else if !status.is_success() {
+            // An error page must not end up as the file. 5xx, 408 and 429 are
+            // retried from the .fdm file; 401 and 407 ask for a login
+            return Err(DownloadError::from_status(&response));
+        }
+
+        let mut hasher = checksum::resume_hasher(checksum.as_ref(), &temp_path, downloaded).await?;
+        let mut stream = if already_complete {
+            futures_util::stream::empty().boxed()
+        } else {
+            response.bytes_stream().boxed()
+        };
+        let total = meta.size.or(already_complete.then_some(downloaded));
         
         use std::time::Instant;
         let mut last_emit = Instant::now();
@@ -173,9 +862,13 @@ impl FileDownloader {
         while let Some(item) = stream.next().await {
             let chunk = item.map_err(|e| DownloadError::NetworkError(e.to_string()))?;
             file.write_all(&chunk).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&chunk);
+            }
             let len = chunk.len() as u64;
             downloaded += len;
             bytes_since_emit += len;
+            limiter.consume(len).await;
             
             // Limit event emission to every 100ms or so
             if last_emit.elapsed().as_millis() > 100 {
@@ -186,6 +879,7 @@ impl FileDownloader {
                     total,
                     speed,
                 });
+                history.checkpoint(&id, downloaded, total);
                 last_emit = Instant::now();
                 bytes_since_emit = 0;
             }
@@ -198,16 +892,96 @@ impl FileDownloader {
             total,
             speed: 0,
         });
+        history.checkpoint(&id, downloaded, total);
         
         // Close file and rename from .fdm to final name
         drop(file);
+        let checksum_result = match (checksum.as_ref(), hasher) {
+            (Some(expected), Some(hasher)) => {
+                Some(checksum::verify(expected, hasher.finalize_hex(), &temp_path).await?)
+            }
+            _ => None,
+        };
         tokio::fs::rename(&temp_path, &file_path)
             .await
             .map_err(|e| DownloadError::IoError(format!("Failed to rename file: {}", e)))?;
         
         // Emit completion event
-        let _ = app.emit("download://complete", id);
+        let _ = app.emit("download://complete", serde_json::json!({
+            "id": id,
+            "checksum": checksum_result,
+        }));
         
         Ok(())
     }
+
+    async fn run_segmented(ctx: &DownloadContext, meta: &DownloadMetadata) -> Result<(), DownloadError> {
+        let DownloadContext { id, save_path: path, app, checksum, history, .. } = ctx;
+        let file_path = PathBuf::from(path);
+        let temp_path = PathBuf::from(format!("{}.fdm", path));
+        let parts_path = segments::parts_path(path);
+        let total = meta.size.unwrap_or_default();
+
+        let plan = match SegmentPlan::load(&parts_path).await {
+            Some(plan) if plan.total == total
+                && temp_path.exists()
+                && meta.matches(plan.etag.as_deref(), plan.last_modified.as_deref()) => {
+                eprintln!("[Segments] Resuming {} at {} of {} bytes", id, plan.downloaded(), total);
+                plan
+            }
+            _ => {
+                // Preallocate so every segment can write at its own offset
+                let file = tokio::fs::File::create(&temp_path)
+                    .await
+                    .map_err(|e| DownloadError::IoError(e.to_string()))?;
+                file.set_len(total).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+                drop(file);
+
+                let plan = SegmentPlan::split(meta);
+                plan.save(&parts_path).await?;
+                plan
+            }
+        };
+
+        let downloaded = match segments::download(ctx, plan).await {
+            Ok(downloaded) => downloaded,
+            Err(DownloadError::ResumeNotPossible(msg)) => {
+                // File changed mid-download; drop the stale layout so the next attempt starts clean
+                let _ = tokio::fs::remove_file(&parts_path).await;
+                let _ = tokio::fs::remove_file(&temp_path).await;
+                return Err(DownloadError::ResumeNotPossible(msg));
+            }
+            Err(e) => return Err(e),
+        };
+
+        let _ = app.emit("download://progress", ProgressEvent {
+            id: id.to_string(),
+            downloaded,
+            total: Some(total),
+            speed: 0,
+        });
+        history.checkpoint(id, downloaded, Some(total));
+
+        let _ = tokio::fs::remove_file(&parts_path).await;
+
+        // Segments finish out of order, so the digest is taken over the finished file
+        let checksum_result = match checksum {
+            Some(expected) => {
+                let actual = checksum::hash_file(&temp_path, expected.algorithm).await?;
+                Some(checksum::verify(expected, actual, &temp_path).await?)
+            }
+            None => None,
+        };
+
+        tokio::fs::rename(&temp_path, &file_path)
+            .await
+            .map_err(|e| DownloadError::IoError(format!("Failed to rename file: {}", e)))?;
+
+        let _ = app.emit("download://complete", serde_json::json!({
+            "id": id,
+            "checksum": checksum_result,
+        }));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,261 @@
+use crate::download::http::HttpHelper;
+use crate::download::manager::ProgressEvent;
+use crate::download::{DownloadContext, DownloadError, DownloadResult};
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tauri::Emitter;
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinSet;
+
+/// Segments fetched at the same time
+pub const CONCURRENT_SEGMENTS: usize = 6;
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+/// One piece of a segmented stream (HLS, DASH), fetched on its own and
+/// concatenated with the others in order.
+#[derive(Clone)]
+pub struct MediaSegment {
+    pub url: String,
+    /// Start and inclusive end, when the segment is a slice of a larger file
+    pub range: Option<(u64, u64)>,
+    /// AES-128-CBC key and IV
+    pub aes128: Option<([u8; 16], [u8; 16])>,
+}
+
+/// The segments of one output file, e.g. the video or audio of a DASH stream.
+pub struct Track {
+    pub segments: Vec<MediaSegment>,
+    pub output: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MediaSettings {
+    /// Join separate video and audio tracks into one file with ffmpeg
+    pub mux_tracks: bool,
+    /// ffmpeg binary; `None` looks it up on `PATH`
+    pub ffmpeg_path: Option<String>,
+}
+
+impl Default for MediaSettings {
+    fn default() -> Self {
+        Self {
+            mux_tracks: true,
+            ffmpeg_path: None,
+        }
+    }
+}
+
+/// A selectable rendition, listed before the download starts.
+#[derive(Serialize)]
+pub struct StreamVariant {
+    pub index: usize,
+    /// `video` or `audio` for streams with separate tracks; `None` when muxed
+    pub kind: Option<&'static str>,
+    pub bandwidth: u64,
+    pub resolution: Option<String>,
+    pub codecs: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct StreamInfo {
+    pub variants: Vec<StreamVariant>,
+    /// Indices picked when the user doesn't choose, one per track kind
+    pub default_variants: Vec<usize>,
+    pub duration: Option<f64>,
+}
+
+/// Fetches a playlist or manifest.
+pub async fn fetch_text(url: &str, http: &HttpHelper) -> DownloadResult<String> {
+    let response = http.download_stream_request(url)
+        .await
+        .map_err(DownloadError::NetworkError)?;
+    if !response.status().is_success() {
+        return Err(DownloadError::from_status(&response));
+    }
+    response.text()
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))
+}
+
+/// Finished segments are kept here until they are joined, so a resumed
+/// download only fetches what is missing.
+fn segments_dir(output: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.segments", output.display()))
+}
+
+fn segment_path(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!("{:06}.seg", index))
+}
+
+/// Identifies the segment list, so parts left by a different variant or a
+/// changed playlist are discarded instead of mixed in.
+fn fingerprint(segments: &[MediaSegment]) -> String {
+    let mut hasher = Sha256::new();
+    for segment in segments {
+        hasher.update(segment.url.as_bytes());
+        if let Some((start, end)) = segment.range {
+            hasher.update(format!("@{}-{}", start, end).as_bytes());
+        }
+        hasher.update(b"\n");
+    }
+    hex::encode(hasher.finalize())
+}
+
+async fn fetch_segment(ctx: &DownloadContext, segment: &MediaSegment, path: &Path) -> DownloadResult<u64> {
+    let response = match segment.range {
+        Some((start, end)) => ctx.http.download_segment_request(&segment.url, start, end, None).await,
+        None => ctx.http.download_stream_request(&segment.url).await,
+    }
+    .map_err(DownloadError::NetworkError)?;
+    if !response.status().is_success() {
+        return Err(DownloadError::from_status(&response));
+    }
+    let ignored_range = response.status() == reqwest::StatusCode::OK;
+
+    let mut data = Vec::new();
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+        ctx.limiter.consume(chunk.len() as u64).await;
+        data.extend_from_slice(&chunk);
+    }
+
+    if let (Some((start, end)), true) = (segment.range, ignored_range) {
+        // Server sent the whole resource; cut the slice out ourselves
+        data = data.get(start as usize..=end as usize)
+            .ok_or_else(|| DownloadError::NetworkError("Segment is shorter than its byte range".to_string()))?
+            .to_vec();
+    }
+    if let Some((key, iv)) = segment.aes128 {
+        data = Aes128CbcDec::new(&key.into(), &iv.into())
+            .decrypt_padded_vec_mut::<Pkcs7>(&data)
+            .map_err(|_| DownloadError::Other(format!("Couldn't decrypt segment {}", segment.url)))?;
+    }
+
+    let part = path.with_extension("part");
+    tokio::fs::write(&part, &data).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+    tokio::fs::rename(&part, path).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+    Ok(data.len() as u64)
+}
+
+fn emit_progress(ctx: &DownloadContext, done: usize, total: usize, bytes: u64, speed: u64) {
+    // Byte total is only an estimate: the average segment so far times the count
+    let estimate = (done > 0).then(|| bytes / done as u64 * total as u64);
+    let _ = ctx.app.emit("download://progress", ProgressEvent {
+        id: ctx.id.clone(),
+        downloaded: bytes,
+        total: estimate,
+        speed,
+    });
+    let _ = ctx.app.emit("download://segments", serde_json::json!({
+        "id": ctx.id,
+        "done": done,
+        "total": total,
+    }));
+    ctx.history.checkpoint(&ctx.id, bytes, estimate);
+}
+
+/// Prepares a track's segment directory, discarding parts that belong to a
+/// different segment list. Returns which segments still need fetching and
+/// the size of the ones already there.
+async fn prepare(track: &Track) -> DownloadResult<(Vec<usize>, u64)> {
+    let dir = segments_dir(&track.output);
+    let manifest = dir.join("manifest");
+    let print = fingerprint(&track.segments);
+    if tokio::fs::read_to_string(&manifest).await.ok().as_deref() != Some(print.as_str()) {
+        let _ = tokio::fs::remove_dir_all(&dir).await;
+    }
+    tokio::fs::create_dir_all(&dir).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+    tokio::fs::write(&manifest, &print).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+
+    let mut pending = Vec::new();
+    let mut bytes = 0;
+    for index in 0..track.segments.len() {
+        match tokio::fs::metadata(segment_path(&dir, index)).await {
+            Ok(meta) => bytes += meta.len(),
+            Err(_) => pending.push(index),
+        }
+    }
+    Ok((pending, bytes))
+}
+
+/// Concatenates a track's segments into its output file and drops the parts.
+async fn join(track: &Track) -> DownloadResult<u64> {
+    let dir = segments_dir(&track.output);
+    let mut file = tokio::fs::File::create(&track.output).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+    let mut written = 0;
+    for index in 0..track.segments.len() {
+        let data = tokio::fs::read(segment_path(&dir, index))
+            .await
+            .map_err(|e| DownloadError::IoError(e.to_string()))?;
+        file.write_all(&data).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+        written += data.len() as u64;
+    }
+    file.flush().await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    Ok(written)
+}
+
+/// Fetches every segment of every track (several at once, skipping ones
+/// finished by an earlier attempt) and joins each track into its output.
+/// Progress covers all tracks together. Returns the total size written.
+pub async fn download(ctx: &DownloadContext, tracks: &[Track]) -> DownloadResult<u64> {
+    let total: usize = tracks.iter().map(|t| t.segments.len()).sum();
+    let mut pending = Vec::new();
+    let mut bytes = 0;
+    for (track_index, track) in tracks.iter().enumerate() {
+        let (missing, on_disk) = prepare(track).await?;
+        bytes += on_disk;
+        pending.extend(missing.into_iter().map(|index| (track_index, index)));
+    }
+    let mut done = total - pending.len();
+    if done > 0 {
+        eprintln!("[Media] {}: {} of {} segments already on disk", ctx.id, done, total);
+    }
+    emit_progress(ctx, done, total, bytes, 0);
+
+    let mut pending = pending.into_iter();
+    let mut tasks = JoinSet::new();
+    let started = Instant::now();
+    let bytes_at_start = bytes;
+    loop {
+        while tasks.len() < CONCURRENT_SEGMENTS {
+            let Some((track_index, index)) = pending.next() else {
+                break;
+            };
+            let ctx = ctx.clone();
+            let track = &tracks[track_index];
+            let segment = track.segments[index].clone();
+            let path = segment_path(&segments_dir(&track.output), index);
+            tasks.spawn(async move { fetch_segment(&ctx, &segment, &path).await });
+        }
+        let Some(result) = tasks.join_next().await else {
+            break;
+        };
+        let len = result.map_err(|e| DownloadError::Other(e.to_string()))??;
+        done += 1;
+        bytes += len;
+        let speed = ((bytes - bytes_at_start) as f64 / started.elapsed().as_secs_f64().max(0.001)) as u64;
+        emit_progress(ctx, done, total, bytes, speed);
+    }
+
+    let mut written = 0;
+    for track in tracks {
+        written += join(track).await?;
+    }
+
+    let _ = ctx.app.emit("download://progress", ProgressEvent {
+        id: ctx.id.clone(),
+        downloaded: written,
+        total: Some(written),
+        speed: 0,
+    });
+    ctx.history.checkpoint(&ctx.id, written, Some(written));
+    Ok(written)
+}
@@ -0,0 +1,272 @@
+use crate::download::checksum::{ExpectedChecksum, HashAlgorithm};
+use crate::download::DownloadError;
+use roxmltree::Node;
+use std::path::{Component, Path};
+
+/// Piece hashes, in file order; the last piece may be shorter.
+#[derive(Debug, Clone)]
+pub struct Pieces {
+    pub length: u64,
+    pub algorithm: HashAlgorithm,
+    pub hashes: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MetalinkFile {
+    /// Relative path, possibly with directories
+    pub name: String,
+    pub size: Option<u64>,
+    /// Strongest whole-file hash listed
+    pub hash: Option<ExpectedChecksum>,
+    pub pieces: Option<Pieces>,
+    /// HTTP(S) mirrors, most preferred first
+    pub mirrors: Vec<String>,
+}
+
+fn invalid(msg: impl Into<String>) -> DownloadError {
+    DownloadError::InvalidUrl(msg.into())
+}
+
+/// Strongest first
+fn strength(algorithm: HashAlgorithm) -> u8 {
+    match algorithm {
+        HashAlgorithm::Blake3 => 0,
+        HashAlgorithm::Sha256 => 1,
+        HashAlgorithm::Sha1 => 2,
+        HashAlgorithm::Md5 => 3,
+    }
+}
+
+fn text(node: Node) -> String {
+    node.text().unwrap_or_default().trim().to_string()
+}
+
+/// Names must stay inside the download folder (RFC 5854 section 4.1.2.1).
+fn safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && Path::new(name).components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// Whole-file `hash` elements, skipping the ones inside `pieces`.
+fn file_hash(file: Node) -> Option<ExpectedChecksum> {
+    file.descendants()
+        .filter(|n| n.has_tag_name("hash") && !n.parent().is_some_and(|p| p.has_tag_name("pieces")))
+        // Unsupported algorithms (e.g. sha-512) fail to parse and are skipped
+        .filter_map(|n| ExpectedChecksum::parse(&format!("{}:{}", n.attribute("type")?, text(n))).ok())
+        .min_by_key(|hash| strength(hash.algorithm))
+}
+
+fn pieces(file: Node) -> Result<Option<Pieces>, DownloadError> {
+    let Some(node) = file.descendants().find(|n| n.has_tag_name("pieces")) else {
+        return Ok(None);
+    };
+    let Some(algorithm) = node.attribute("type").and_then(HashAlgorithm::from_name) else {
+        // An unknown piece hash only costs us early verification
+        return Ok(None);
+    };
+    let length = node.attribute("length")
+        .and_then(|l| l.parse().ok())
+        .filter(|&l| l > 0)
+        .ok_or_else(|| invalid("Metalink pieces without a length"))?;
+    let hashes = node.children()
+        .filter(|n| n.has_tag_name("hash"))
+        .map(|n| text(n).to_ascii_lowercase())
+        .collect();
+    Ok(Some(Pieces { length, algorithm, hashes }))
+}
+
+/// HTTP(S) URLs sorted by preference. Metalink 4 uses `priority` (1 is best,
+/// missing is worst); Metalink 3 uses `preference` (100 is best).
+fn mirrors(file: Node) -> Vec<String> {
+    let mut urls: Vec<(u64, String)> = file.descendants()
+        .filter(|n| n.has_tag_name("url"))
+        .filter_map(|n| {
+            let url = text(n);
+            let lower = url.to_ascii_lowercase();
+            if !(lower.starts_with("http://") || lower.starts_with("https://")) {
+                return None;
+            }
+            let rank = match (n.attribute("priority"), n.attribute("preference")) {
+                (Some(priority), _) => priority.parse().unwrap_or(u64::MAX),
+                (None, Some(preference)) => 100u64.saturating_sub(preference.parse().unwrap_or(0)),
+                (None, None) => u64::MAX,
+            };
+            Some((rank, url))
+        })
+        .collect();
+    // Stable, so equally ranked mirrors keep document order
+    urls.sort_by_key(|(rank, _)| *rank);
+    urls.into_iter().map(|(_, url)| url).collect()
+}
+
+/// Parses Metalink 4 (RFC 5854, `.meta4`) and Metalink 3 (`.metalink`) documents.
+pub fn parse(xml: &str) -> Result<Vec<MetalinkFile>, DownloadError> {
+    let doc = roxmltree::Document::parse(xml).map_err(|e| invalid(format!("Not a metalink: {}", e)))?;
+    if !doc.root_element().has_tag_name("metalink") {
+        return Err(invalid("Not a metalink"));
+    }
+
+    let mut files = Vec::new();
+    for file in doc.descendants().filter(|n| n.has_tag_name("file")) {
+        let name = file.attribute("name").unwrap_or_default().to_string();
+        if !safe_name(&name) {
+            return Err(invalid(format!("Metalink file name is not a safe relative path: {:?}", name)));
+        }
+        let mirrors = mirrors(file);
+        if mirrors.is_empty() {
+            return Err(invalid(format!("Metalink lists no HTTP mirrors for {}", name)));
+        }
+        files.push(MetalinkFile {
+            size: file.children().find(|n| n.has_tag_name("size")).and_then(|n| text(n).parse().ok()),
+            hash: file_hash(file),
+            pieces: pieces(file)?,
+            mirrors,
+            name,
+        });
+    }
+
+    if files.is_empty() {
+        return Err(invalid("Metalink lists no files"));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA1: &str = "a9993e364706816aba3e25717850c26c9cd0d89d";
+    const SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    fn meta4(name: &str, body: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="{}">{}</file>
+</metalink>"#,
+            name, body
+        )
+    }
+
+    #[test]
+    fn metalink_4() {
+        let xml = meta4("dir/example.iso", &format!(r#"
+    <size>1048577</size>
+    <hash type="md5">900150983cd24fb0d6963f7d28e17f72</hash>
+    <hash type="sha-1">{SHA1}</hash>
+    <hash type="sha-256">{}</hash>
+    <hash type="sha-512">ddaf35a1</hash>
+    <pieces length="524288" type="sha-1">
+      <hash>{}</hash>
+      <hash>{SHA1}</hash>
+      <hash>{SHA1}</hash>
+    </pieces>
+    <url>http://unranked.example.com/example.iso</url>
+    <url priority="2">https://second.example.com/example.iso</url>
+    <url priority="1">http://first.example.com/example.iso</url>
+    <url priority="1">ftp://ftp.example.com/example.iso</url>
+    <metaurl mediatype="torrent">http://example.com/example.torrent</metaurl>"#,
+            SHA256.to_ascii_uppercase(), SHA1.to_ascii_uppercase()));
+
+        let files = parse(&xml).unwrap();
+        assert_eq!(files.len(), 1);
+        let file = &files[0];
+        assert_eq!(file.name, "dir/example.iso");
+        assert_eq!(file.size, Some(1048577));
+
+        // Strongest supported whole-file hash; sha-512 isn't supported
+        let hash = file.hash.as_ref().unwrap();
+        assert_eq!(hash.algorithm, HashAlgorithm::Sha256);
+        assert_eq!(hash.value, SHA256);
+
+        let pieces = file.pieces.as_ref().unwrap();
+        assert_eq!((pieces.length, pieces.algorithm), (524288, HashAlgorithm::Sha1));
+        assert_eq!(pieces.hashes, [SHA1; 3]);
+
+        assert_eq!(file.mirrors, [
+            "http://first.example.com/example.iso",
+            "https://second.example.com/example.iso",
+            "http://unranked.example.com/example.iso",
+        ]);
+    }
+
+    #[test]
+    fn metalink_3() {
+        let xml = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink version="3.0" xmlns="http://www.metalinker.org/">
+  <files>
+    <file name="a.bin">
+      <size>10</size>
+      <verification>
+        <hash type="sha1">{SHA1}</hash>
+        <pieces length="4" type="sha1">
+          <hash piece="0">{SHA1}</hash>
+        </pieces>
+      </verification>
+      <resources>
+        <url type="http" preference="10">http://worst.example.com/a.bin</url>
+        <url type="http" preference="100">http://best.example.com/a.bin</url>
+        <url type="http">http://unranked.example.com/a.bin</url>
+        <url type="http" preference="50">http://middle.example.com/a.bin</url>
+      </resources>
+    </file>
+    <file name="b.bin">
+      <resources><url>https://example.com/b.bin</url></resources>
+    </file>
+  </files>
+</metalink>"#);
+
+        let files = parse(&xml).unwrap();
+        assert_eq!(files.len(), 2);
+        // The piece hash must not stand in for the whole-file one
+        let hash = files[0].hash.as_ref().unwrap();
+        assert_eq!((hash.algorithm, hash.value.as_str()), (HashAlgorithm::Sha1, SHA1));
+        assert_eq!(files[0].pieces.as_ref().unwrap().length, 4);
+        assert_eq!(files[0].mirrors, [
+            "http://best.example.com/a.bin",
+            "http://middle.example.com/a.bin",
+            "http://worst.example.com/a.bin",
+            "http://unranked.example.com/a.bin",
+        ]);
+        assert_eq!((files[1].size.is_none(), files[1].hash.is_none(), files[1].pieces.is_none()), (true, true, true));
+    }
+
+    #[test]
+    fn hashes_only_inside_pieces_are_not_whole_file() {
+        let xml = meta4("a.bin", &format!(r#"
+    <pieces length="4" type="sha-1"><hash>{SHA1}</hash></pieces>
+    <url>http://example.com/a.bin</url>"#));
+        let file = &parse(&xml).unwrap()[0];
+        assert!(file.hash.is_none());
+        assert_eq!(file.pieces.as_ref().unwrap().hashes.len(), 1);
+
+        // Unknown piece algorithms are ignored rather than rejected
+        let xml = meta4("a.bin", r#"<pieces length="4" type="sha-512"><hash>00</hash></pieces><url>http://example.com/a.bin</url>"#);
+        assert!(parse(&xml).unwrap()[0].pieces.is_none());
+    }
+
+    #[test]
+    fn rejects_bad_pieces_and_names() {
+        for length in [r#"length="0""#, r#"length="x""#, ""] {
+            let xml = meta4("a.bin", &format!(r#"<pieces {} type="sha-1"><hash>{SHA1}</hash></pieces><url>http://example.com/a.bin</url>"#, length));
+            assert!(parse(&xml).is_err(), "{}", length);
+        }
+
+        let url = "<url>http://example.com/a.bin</url>";
+        for name in ["../a.bin", "dir/../../a.bin", "/etc/passwd", "./a.bin", ""] {
+            let error = parse(&meta4(name, url)).unwrap_err();
+            assert!(error.to_string().contains("safe relative path"), "{}: {}", name, error);
+        }
+        assert!(safe_name("dir/sub/a.bin"));
+        assert!(!safe_name("dir/.."));
+    }
+
+    #[test]
+    fn rejects_documents_without_files_or_mirrors() {
+        assert!(parse("<html/>").is_err());
+        assert!(parse("not xml").is_err());
+        assert!(parse(r#"<metalink xmlns="urn:ietf:params:xml:ns:metalink"/>"#).is_err());
+        let error = parse(&meta4("a.bin", "<url>ftp://example.com/a.bin</url>")).unwrap_err();
+        assert!(error.to_string().contains("no HTTP mirrors"), "{}", error);
+    }
+}
@@ -0,0 +1,449 @@
+pub mod document;
+
+use crate::download::checksum::{self, ChecksumResult, HashAlgorithm, StreamHasher};
+use crate::download::http::HttpHelper;
+use crate::download::manager::ProgressEvent;
+use crate::download::media;
+use crate::download::ratelimit::RateLimiter;
+use crate::download::{DownloadContext, DownloadError, DownloadMeta, DownloadResult, Downloader};
+use crate::storage::DownloadType;
+use async_trait::async_trait;
+use document::MetalinkFile;
+use futures_util::StreamExt;
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::Emitter;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use tokio::task::JoinSet;
+
+/// Pieces fetched at the same time, across all mirrors
+const MAX_CONNECTIONS: usize = 8;
+/// Connections open to any one mirror
+const CONNECTIONS_PER_MIRROR: usize = 2;
+/// Piece size when the metalink doesn't list piece hashes
+const DEFAULT_PIECE_LENGTH: u64 = 4 * 1024 * 1024;
+/// Transient errors in a row before a mirror is given up on
+const MAX_MIRROR_FAILURES: u32 = 3;
+
+/// Finished pieces of one file, persisted as `<file>.fdm.pieces`.
+#[derive(Serialize, Deserialize)]
+struct PieceState {
+    size: u64,
+    piece_length: u64,
+    done: Vec<bool>,
+}
+
+impl PieceState {
+    async fn load(path: &Path) -> Option<Self> {
+        let content = tokio::fs::read_to_string(path).await.ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    async fn save(&self, path: &Path) -> DownloadResult<()> {
+        let content = serde_json::to_string(self).map_err(|e| DownloadError::Other(e.to_string()))?;
+        tokio::fs::write(path, content)
+            .await
+            .map_err(|e| DownloadError::IoError(e.to_string()))
+    }
+}
+
+fn pieces_path(temp_path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.pieces", temp_path.display()))
+}
+
+struct Mirror {
+    url: String,
+    active: usize,
+    failures: u32,
+    dropped: bool,
+}
+
+/// Least busy usable mirror; ties go to the more preferred one.
+fn pick_mirror(mirrors: &[Mirror]) -> Option<usize> {
+    mirrors.iter()
+        .enumerate()
+        .filter(|(_, m)| !m.dropped && m.active < CONNECTIONS_PER_MIRROR)
+        .min_by_key(|(i, m)| (m.active, *i))
+        .map(|(i, _)| i)
+}
+
+/// Worth trying the same mirror again; anything else drops it.
+fn is_transient(error: &DownloadError) -> bool {
+    match error {
+        DownloadError::NetworkError(_) => true,
+        DownloadError::HttpStatus { status, .. } => matches!(status, 408 | 429 | 500..=599),
+        _ => false,
+    }
+}
+
+/// Reads a metalink from an HTTP(S) URL, a `file://` URL or a local path.
+async fn fetch_document(url: &str, http: &HttpHelper) -> DownloadResult<Vec<MetalinkFile>> {
+    let local = match Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "file" => Some(
+            parsed.to_file_path().map_err(|_| DownloadError::InvalidUrl(format!("Bad file URL: {}", url)))?,
+        ),
+        Ok(_) => None,
+        Err(_) => Some(PathBuf::from(url)),
+    };
+    let xml = match local {
+        Some(path) => tokio::fs::read_to_string(&path)
+            .await
+            .map_err(|e| DownloadError::IoError(format!("Can't read {}: {}", path.display(), e)))?,
+        None => media::fetch_text(url, http).await?,
+    };
+    document::parse(&xml)
+}
+
+/// Fetches one piece (`range` is `None` for a file of unknown size), checks
+/// it against its hash and writes it at its offset in `temp_path`.
+async fn fetch_piece(
+    http: HttpHelper,
+    url: String,
+    temp_path: PathBuf,
+    range: Option<(u64, u64)>,
+    expected: Option<(HashAlgorithm, String)>,
+    limiter: RateLimiter,
+    counter: Arc<AtomicU64>,
+) -> DownloadResult<u64> {
+    let response = match range {
+        Some((start, end)) => http.download_segment_request(&url, start, end, None).await,
+        None => http.download_stream_request(&url).await,
+    }
+    .map_err(DownloadError::NetworkError)?;
+    if !response.status().is_success() {
+        return Err(DownloadError::from_status(&response));
+    }
+    if range.is_some() && response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(DownloadError::ResumeNotPossible(format!("{} ignored the range request", url)));
+    }
+
+    let wanted = range.map(|(start, end)| end + 1 - start);
+    let mut data = Vec::new();
+    let mut stream = response.bytes_stream();
+    let mut failure = None;
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                failure = Some(DownloadError::NetworkError(e.to_string()));
+                break;
+            }
+        };
+        let take = wanted.map(|w| (w - data.len() as u64).min(chunk.len() as u64) as usize).unwrap_or(chunk.len());
+        data.extend_from_slice(&chunk[..take]);
+        counter.fetch_add(take as u64, Ordering::Relaxed);
+        limiter.consume(take as u64).await;
+        if wanted == Some(data.len() as u64) {
+            break;
+        }
+    }
+    if failure.is_none() && wanted.is_some_and(|w| (data.len() as u64) < w) {
+        failure = Some(DownloadError::NetworkError(format!("{} closed the connection mid-piece", url)));
+    }
+    if let (None, Some((algorithm, hash))) = (&failure, expected) {
+        let mut hasher = StreamHasher::new(algorithm);
+        hasher.update(&data);
+        let actual = hasher.finalize_hex();
+        if actual != hash {
+            failure = Some(DownloadError::ChecksumMismatch { expected: hash, actual });
+        }
+    }
+    if let Some(e) = failure {
+        // Progress only counts pieces that make it to disk
+        counter.fetch_sub(data.len() as u64, Ordering::Relaxed);
+        return Err(e);
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(&temp_path)
+        .await
+        .map_err(|e| DownloadError::IoError(e.to_string()))?;
+    file.seek(SeekFrom::Start(range.map(|(start, _)| start).unwrap_or(0)))
+        .await
+        .map_err(|e| DownloadError::IoError(e.to_string()))?;
+    file.write_all(&data).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+    file.flush().await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+    Ok(data.len() as u64)
+}
+
+/// Shared progress of all files in one metalink download.
+struct Progress {
+    downloaded: Arc<AtomicU64>,
+    total: Option<u64>,
+    last_emit: Instant,
+    last_downloaded: u64,
+}
+
+impl Progress {
+    fn emit(&mut self, ctx: &DownloadContext) {
+        let downloaded = self.downloaded.load(Ordering::Relaxed);
+        let elapsed = self.last_emit.elapsed().as_secs_f64();
+        let speed = if elapsed > 0.0 {
+            (downloaded.saturating_sub(self.last_downloaded) as f64 / elapsed) as u64
+        } else {
+            0
+        };
+        let _ = ctx.app.emit("download://progress", ProgressEvent {
+            id: ctx.id.clone(),
+            downloaded,
+            total: self.total,
+            speed,
+        });
+        ctx.history.checkpoint(&ctx.id, downloaded, self.total);
+        self.last_emit = Instant::now();
+        self.last_downloaded = downloaded;
+    }
+}
+
+pub struct MetalinkDownloader;
+
+impl MetalinkDownloader {
+    /// Downloads `file` into `temp_path`, spreading its pieces over the
+    /// mirrors and moving a piece to another mirror when one fails.
+    async fn download_file(
+        ctx: &DownloadContext,
+        file: &MetalinkFile,
+        temp_path: &Path,
+        progress: &mut Progress,
+    ) -> DownloadResult<()> {
+        let state_path = pieces_path(temp_path);
+        let piece_length = file.pieces.as_ref().map(|p| p.length).unwrap_or(DEFAULT_PIECE_LENGTH);
+        // Without a size the whole file is one piece from one mirror at a time
+        let count = match file.size {
+            Some(size) => size.div_ceil(piece_length) as usize,
+            None => 1,
+        };
+        let hashes = file.pieces.as_ref().filter(|p| {
+            let usable = p.hashes.len() == count && file.size.is_some();
+            if !usable {
+                eprintln!("[Metalink] {}: {} piece hashes for {} pieces, ignoring them", file.name, p.hashes.len(), count);
+            }
+            usable
+        });
+
+        let on_disk = tokio::fs::metadata(temp_path).await.map(|m| m.len()).ok();
+        let mut state = match (PieceState::load(&state_path).await, file.size) {
+            (Some(state), Some(size))
+                if state.size == size && state.piece_length == piece_length
+                    && state.done.len() == count && on_disk == Some(size) => state,
+            _ => {
+                let created = tokio::fs::File::create(temp_path)
+                    .await
+                    .map_err(|e| DownloadError::IoError(e.to_string()))?;
+                if let Some(size) = file.size {
+                    created.set_len(size).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+                }
+                PieceState { size: file.size.unwrap_or_default(), piece_length, done: vec![false; count] }
+            }
+        };
+        let piece_range = |index: usize| {
+            file.size.map(|size| {
+                let start = index as u64 * piece_length;
+                (start, (start + piece_length).min(size) - 1)
+            })
+        };
+        for (index, _) in state.done.iter().enumerate().filter(|(_, done)| **done) {
+            if let Some((start, end)) = piece_range(index) {
+                progress.downloaded.fetch_add(end + 1 - start, Ordering::Relaxed);
+            }
+        }
+
+        let mut pending: VecDeque<usize> = (0..count).filter(|&i| !state.done[i]).collect();
+        let mut mirrors: Vec<Mirror> = file.mirrors.iter()
+            .map(|url| Mirror { url: url.clone(), active: 0, failures: 0, dropped: false })
+            .collect();
+        eprintln!(
+            "[Metalink] {}: {} of {} pieces to fetch from {} mirrors",
+            file.name, pending.len(), count, mirrors.len()
+        );
+
+        let mut tasks = JoinSet::new();
+        let mut ticker = tokio::time::interval(Duration::from_millis(100));
+        let mut last_error = None;
+        loop {
+            while tasks.len() < MAX_CONNECTIONS && !pending.is_empty() {
+                let Some(mirror) = pick_mirror(&mirrors) else {
+                    break;
+                };
+                let Some(index) = pending.pop_front() else {
+                    break;
+                };
+                mirrors[mirror].active += 1;
+                let expected = hashes.map(|p| (p.algorithm, p.hashes[index].clone()));
+                let fetch = fetch_piece(
+                    ctx.http.clone(),
+                    mirrors[mirror].url.clone(),
+                    temp_path.to_path_buf(),
+                    piece_range(index),
+                    expected,
+                    ctx.limiter.clone(),
+                    progress.downloaded.clone(),
+                );
+                tasks.spawn(async move { (mirror, index, fetch.await) });
+            }
+            if tasks.is_empty() {
+                if pending.is_empty() {
+                    break;
+                }
+                let _ = state.save(&state_path).await;
+                return Err(last_error.unwrap_or_else(|| DownloadError::Other("No usable mirrors".to_string())));
+            }
+
+            tokio::select! {
+                joined = tasks.join_next() => {
+                    let Some(joined) = joined else {
+                        continue;
+                    };
+                    let (mirror, index, result) = joined.map_err(|e| DownloadError::Other(e.to_string()))?;
+                    let entry = &mut mirrors[mirror];
+                    entry.active -= 1;
+                    match result {
+                        Ok(_) => {
+                            entry.failures = 0;
+                            state.done[index] = true;
+                            if file.size.is_some() {
+                                state.save(&state_path).await?;
+                            }
+                        }
+                        Err(e) => {
+                            pending.push_front(index);
+                            entry.failures += 1;
+                            if entry.dropped {
+                                // Another connection to it already failed
+                            } else if !is_transient(&e) || entry.failures >= MAX_MIRROR_FAILURES {
+                                entry.dropped = true;
+                                eprintln!("[Metalink] Dropping mirror {}: {}", entry.url, e);
+                            } else {
+                                eprintln!("[Metalink] Piece {} from {} failed: {}", index, entry.url, e);
+                            }
+                            last_error = Some(e);
+                        }
+                    }
+                }
+                _ = ticker.tick() => progress.emit(ctx),
+            }
+        }
+
+        let _ = tokio::fs::remove_file(&state_path).await;
+        Ok(())
+    }
+
+    /// A finished file from an earlier attempt, recognised by its size and hash.
+    async fn already_done(file: &MetalinkFile, path: &Path) -> bool {
+        let Ok(meta) = tokio::fs::metadata(path).await else {
+            return false;
+        };
+        if file.size != Some(meta.len()) {
+            return false;
+        }
+        match &file.hash {
+            Some(hash) => checksum::hash_file(path, hash.algorithm).await.is_ok_and(|actual| actual == hash.value),
+            None => false,
+        }
+    }
+}
+
+#[async_trait]
+impl Downloader for MetalinkDownloader {
+    fn download_type(&self) -> DownloadType {
+        DownloadType::Metalink
+    }
+
+    fn detect(&self, url: &str) -> bool {
+        let lower = url.to_ascii_lowercase();
+        let path = lower.split(['?', '#']).next().unwrap_or_default();
+        path.ends_with(".meta4") || path.ends_with(".metalink")
+    }
+
+    async fn analyze(&self, url: &str, http: &HttpHelper) -> DownloadResult<Option<DownloadMeta>> {
+        if !self.detect(url) {
+            return Ok(None);
+        }
+        let files = fetch_document(url, http).await?;
+        let name = match files.as_slice() {
+            [only] => Path::new(&only.name).file_name().map(|n| n.to_string_lossy().into_owned()),
+            _ => None,
+        };
+        eprintln!("[Metalink] {} lists {} file(s)", url, files.len());
+
+        Ok(Some(DownloadMeta {
+            download_type: DownloadType::Metalink,
+            direct_url: url.to_string(),
+            original_url: None,
+            suggested_filename: name,
+            ..Default::default()
+        }))
+    }
+
+    async fn run(&self, ctx: DownloadContext) -> DownloadResult<()> {
+        eprintln!("[Metalink] Starting download: id={}, url={}", ctx.id, ctx.url);
+        let files = fetch_document(&ctx.url, &ctx.http).await?;
+        let selected: Vec<&MetalinkFile> = match ctx.files.as_deref() {
+            Some(indices) => indices.iter()
+                .map(|&i| files.get(i).ok_or_else(|| DownloadError::InvalidUrl(format!("Metalink has no file {}", i))))
+                .collect::<DownloadResult<_>>()?,
+            None => files.iter().collect(),
+        };
+
+        // A single file goes where the user asked; several keep their
+        // metalink names under the chosen folder
+        let root = Path::new(&ctx.save_path).parent().map(Path::to_path_buf).unwrap_or_default();
+        let targets: Vec<PathBuf> = match selected.as_slice() {
+            [_] => vec![PathBuf::from(&ctx.save_path)],
+            _ => selected.iter().map(|f| root.join(&f.name)).collect(),
+        };
+        if ctx.checksum.is_some() && selected.len() > 1 {
+            eprintln!("[Metalink] {}: ignoring the user checksum for a multi-file download", ctx.id);
+        }
+
+        let total = selected.iter().map(|f| f.size).sum::<Option<u64>>();
+        ctx.history.update(&ctx.id, |item| item.total = total);
+        let mut progress = Progress {
+            downloaded: Arc::new(AtomicU64::new(0)),
+            total,
+            last_emit: Instant::now(),
+            last_downloaded: 0,
+        };
+
+        let mut checksum_result: Option<ChecksumResult> = None;
+        for (file, target) in selected.iter().zip(&targets) {
+            if Self::already_done(file, target).await {
+                eprintln!("[Metalink] {} is already complete", target.display());
+                progress.downloaded.fetch_add(file.size.unwrap_or_default(), Ordering::Relaxed);
+                continue;
+            }
+            if let Some(parent) = target.parent() {
+                tokio::fs::create_dir_all(parent).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+            }
+            let temp_path = PathBuf::from(format!("{}.fdm", target.display()));
+            Self::download_file(&ctx, file, &temp_path, &mut progress).await?;
+
+            // The metalink's own hash catches corruption piece hashes can't
+            // (or that no piece hashes were listed for)
+            let user = ctx.checksum.as_ref().filter(|_| selected.len() == 1);
+            for expected in [file.hash.as_ref(), user].into_iter().flatten() {
+                let actual = checksum::hash_file(&temp_path, expected.algorithm).await?;
+                checksum_result = Some(checksum::verify(expected, actual, &temp_path).await?);
+            }
+            tokio::fs::rename(&temp_path, target)
+                .await
+                .map_err(|e| DownloadError::IoError(format!("Failed to rename file: {}", e)))?;
+            eprintln!("[Metalink] Finished {}", target.display());
+        }
+        progress.emit(&ctx);
+
+        let _ = ctx.app.emit("download://complete", serde_json::json!({
+            "id": ctx.id,
+            "checksum": checksum_result.filter(|_| selected.len() == 1),
+        }));
+
+        Ok(())
+    }
+}
@@ -1,10 +1,32 @@
 pub mod manager;
+pub mod media;
+pub mod auth;
+pub mod checksum;
+pub mod cookies;
+pub mod dash;
+pub mod filename;
+pub mod ftp;
 pub mod http;
 pub mod gdrive;
+pub mod hls;
+pub mod metalink;
+pub mod partfile;
+pub mod queue;
+pub mod ratelimit;
+pub mod registry;
+pub mod retry;
+pub mod segments;
+pub mod sftp;
+pub mod torrent;
 
 use crate::storage::DownloadType;
+use crate::storage::history::HistoryStore;
+use std::path::PathBuf;
+use std::time::Duration;
 use tauri::AppHandle;
 use crate::download::http::HttpHelper;
+use crate::download::ratelimit::RateLimiter;
+use crate::download::checksum::ExpectedChecksum;
 
 pub type DownloadResult<T> = Result<T, DownloadError>;
 
@@ -16,9 +38,34 @@ pub enum DownloadError {
     InvalidUrl(String),
     Cancelled,
     ResumeNotPossible(String),
+    /// Error status from the server; `retry_after` comes from the Retry-After header
+    HttpStatus { status: u16, retry_after: Option<Duration> },
+    ChecksumMismatch { expected: String, actual: String },
+    /// 401 or 407 that saved logins couldn't satisfy
+    AuthRequired { proxy: bool, realm: Option<String> },
     Other(String),
 }
 
+impl DownloadError {
+    pub fn from_status(response: &reqwest::Response) -> Self {
+        match response.status() {
+            reqwest::StatusCode::UNAUTHORIZED => return DownloadError::AuthRequired {
+                proxy: false,
+                realm: auth::realm(response.headers(), reqwest::header::WWW_AUTHENTICATE),
+            },
+            reqwest::StatusCode::PROXY_AUTHENTICATION_REQUIRED => return DownloadError::AuthRequired {
+                proxy: true,
+                realm: auth::realm(response.headers(), reqwest::header::PROXY_AUTHENTICATE),
+            },
+            _ => {}
+        }
+        DownloadError::HttpStatus {
+            status: response.status().as_u16(),
+            retry_after: retry::retry_after(response.headers()),
+        }
+    }
+}
+
 impl std::fmt::Display for DownloadError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -28,6 +75,17 @@ impl std::fmt::Display for DownloadError {
             DownloadError::InvalidUrl(msg) => write!(f, "Invalid URL: {}", msg),
             DownloadError::Cancelled => write!(f, "Download cancelled"),
             DownloadError::ResumeNotPossible(msg) => write!(f, "Cannot resume: {}", msg),
+            DownloadError::HttpStatus { status, .. } => write!(f, "Server returned HTTP {}", status),
+            DownloadError::ChecksumMismatch { expected, actual } => {
+                write!(f, "Checksum mismatch: expected {}, got {}", expected, actual)
+            }
+            DownloadError::AuthRequired { proxy, realm } => {
+                write!(f, "{} requires a login", if *proxy { "Proxy" } else { "Server" })?;
+                match realm {
+                    Some(realm) => write!(f, " ({})", realm),
+                    None => Ok(()),
+                }
+            }
             DownloadError::Other(msg) => write!(f, "{}", msg),
         }
     }
@@ -35,6 +93,7 @@ impl std::fmt::Display for DownloadError {
 
 impl std::error::Error for DownloadError {}
 
+#[derive(Clone)]
 pub struct DownloadContext {
     pub id: String,
     pub url: String,
@@ -43,26 +102,52 @@ pub struct DownloadContext {
     pub http: HttpHelper,
     pub original_url: Option<String>,
     pub downloaded_bytes: u64,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub limiter: RateLimiter,
+    pub checksum: Option<ExpectedChecksum>,
+    pub history: HistoryStore,
+    /// What to fetch from a source with several parts: file indexes of a
+    /// multi-file torrent or metalink, or the variant of an HLS stream.
+    /// `None` lets the downloader decide (all files, best variant).
+    pub files: Option<Vec<usize>>,
 }
 
+#[derive(Default)]
 pub struct DownloadMeta {
     pub download_type: DownloadType,
     pub direct_url: String,
     pub original_url: Option<String>,
     pub suggested_filename: Option<String>,
+    /// Set for folders: each entry is queued as its own download, grouped
+    /// under this one
+    pub children: Vec<ChildDownload>,
+}
+
+/// One file of a folder-like source.
+pub struct ChildDownload {
+    pub url: String,
+    /// Where the file goes, relative to the folder the user picked
+    pub relative_path: PathBuf,
 }
 
+/// A protocol or site handler. Implementations are stored as trait objects
+/// in a `DownloaderRegistry`, which asks them in priority order whether
+/// they handle a URL.
 #[async_trait::async_trait]
 pub trait Downloader: Send + Sync {
-    fn detect(url: &str) -> bool where Self: Sized;
-    
-    async fn analyze(url: &str, http: &HttpHelper) -> DownloadResult<Option<DownloadMeta>> 
-    where Self: Sized;
-    
-    async fn run(ctx: DownloadContext) -> DownloadResult<()> where Self: Sized;
-    
-    async fn refresh_url(original_url: &str, http: &HttpHelper) -> DownloadResult<Option<String>>
-    where Self: Sized {
+    /// Type recorded in history for downloads this handler starts; resuming
+    /// looks the handler up by it.
+    fn download_type(&self) -> DownloadType;
+
+    fn detect(&self, url: &str) -> bool;
+
+    /// `Ok(None)` passes the URL on to the next downloader in the registry.
+    async fn analyze(&self, url: &str, http: &HttpHelper) -> DownloadResult<Option<DownloadMeta>>;
+
+    async fn run(&self, ctx: DownloadContext) -> DownloadResult<()>;
+
+    async fn refresh_url(&self, _original_url: &str, _http: &HttpHelper) -> DownloadResult<Option<String>> {
         Ok(None)
     }
 }
@@ -0,0 +1,123 @@
+use crate::download::checksum::{self, ExpectedChecksum, StreamHasher};
+use crate::download::manager::ProgressEvent;
+use crate::download::ratelimit::RateLimiter;
+use crate::download::{DownloadContext, DownloadError, DownloadResult};
+use crate::storage::history::HistoryStore;
+use std::path::PathBuf;
+use std::time::Instant;
+use tauri::{AppHandle, Emitter};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, SeekFrom};
+
+/// The `.fdm` file of a download read front to back over one connection
+/// (FTP, SFTP). Takes care of what those share: rate limiting, progress
+/// events and checkpoints, the running checksum, and the rename into place.
+pub struct PartFile {
+    id: String,
+    app: AppHandle,
+    history: HistoryStore,
+    limiter: RateLimiter,
+    checksum: Option<ExpectedChecksum>,
+    hasher: Option<StreamHasher>,
+    file: File,
+    temp_path: PathBuf,
+    file_path: PathBuf,
+    downloaded: u64,
+    total: Option<u64>,
+    last_emit: Instant,
+    bytes_since_emit: u64,
+}
+
+impl PartFile {
+    /// The `.fdm` file next to `ctx.save_path`, cut to the first `downloaded`
+    /// bytes, which are hashed again if a checksum is expected.
+    pub async fn open(ctx: &DownloadContext, downloaded: u64, total: Option<u64>) -> DownloadResult<Self> {
+        let file_path = PathBuf::from(&ctx.save_path);
+        let temp_path = PathBuf::from(format!("{}.fdm", ctx.save_path));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&temp_path)
+            .await
+            .map_err(|e| DownloadError::IoError(e.to_string()))?;
+        file.set_len(downloaded).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+        file.seek(SeekFrom::Start(downloaded)).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+        let hasher = checksum::resume_hasher(ctx.checksum.as_ref(), &temp_path, downloaded).await?;
+
+        Ok(Self {
+            id: ctx.id.clone(),
+            app: ctx.app.clone(),
+            history: ctx.history.clone(),
+            limiter: ctx.limiter.clone(),
+            checksum: ctx.checksum.clone(),
+            hasher,
+            file,
+            temp_path,
+            file_path,
+            downloaded,
+            total,
+            last_emit: Instant::now(),
+            bytes_since_emit: 0,
+        })
+    }
+
+    /// Appends `chunk`, waiting on the speed limit afterwards.
+    pub async fn write(&mut self, chunk: &[u8]) -> DownloadResult<()> {
+        self.file.write_all(chunk).await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(chunk);
+        }
+        let len = chunk.len() as u64;
+        self.downloaded += len;
+        self.bytes_since_emit += len;
+        self.limiter.consume(len).await;
+
+        if self.last_emit.elapsed().as_millis() > 100 {
+            let speed = (self.bytes_since_emit as f64 / self.last_emit.elapsed().as_secs_f64()) as u64;
+            self.progress(speed);
+            self.last_emit = Instant::now();
+            self.bytes_since_emit = 0;
+        }
+        Ok(())
+    }
+
+    fn progress(&self, speed: u64) {
+        let _ = self.app.emit("download://progress", ProgressEvent {
+            id: self.id.clone(),
+            downloaded: self.downloaded,
+            total: self.total,
+            speed,
+        });
+        self.history.checkpoint(&self.id, self.downloaded, self.total);
+    }
+
+    /// Checks the size and checksum, moves the file into place and reports
+    /// the download complete.
+    pub async fn finish(mut self) -> DownloadResult<()> {
+        if let Some(total) = self.total.filter(|&t| t != self.downloaded) {
+            return Err(DownloadError::NetworkError(format!(
+                "Transfer ended at {} of {} bytes", self.downloaded, total
+            )));
+        }
+        self.progress(0);
+
+        self.file.flush().await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+        drop(self.file);
+        let checksum_result = match (self.checksum.as_ref(), self.hasher) {
+            (Some(expected), Some(hasher)) => {
+                Some(checksum::verify(expected, hasher.finalize_hex(), &self.temp_path).await?)
+            }
+            _ => None,
+        };
+        tokio::fs::rename(&self.temp_path, &self.file_path)
+            .await
+            .map_err(|e| DownloadError::IoError(format!("Failed to rename file: {}", e)))?;
+
+        let _ = self.app.emit("download://complete", serde_json::json!({
+            "id": self.id,
+            "checksum": checksum_result,
+        }));
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Emitter};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub const DEFAULT_MAX_CONCURRENT: usize = 3;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadState {
+    Queued,
+    Active,
+    Paused,
+    Completed,
+    Failed,
+}
+
+impl DownloadState {
+    /// Status string stored in history, in the frontend's vocabulary
+    pub fn history_status(&self) -> &'static str {
+        match self {
+            DownloadState::Queued => "pending",
+            DownloadState::Active => "downloading",
+            DownloadState::Paused => "paused",
+            DownloadState::Completed => "completed",
+            DownloadState::Failed => "error",
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct QueueEntry {
+    pub id: String,
+    pub state: DownloadState,
+}
+
+/// Limits how many downloads run at once. Every task waits for a slot before
+/// touching the network; the semaphore is FIFO, so queued downloads start in
+/// the order they were added as slots free up.
+#[derive(Clone)]
+pub struct DownloadQueue {
+    slots: Arc<Semaphore>,
+    limit: Arc<AtomicUsize>,
+    /// Slots to retire once they are released, after the limit was lowered
+    /// while more downloads than the new limit were running
+    debt: Arc<AtomicUsize>,
+    states: Arc<Mutex<HashMap<String, DownloadState>>>,
+}
+
+/// Held by a running download; gives its slot back (or retires it) on drop,
+/// which also covers tasks aborted by `pause`.
+pub struct QueueSlot {
+    permit: Option<OwnedSemaphorePermit>,
+    debt: Arc<AtomicUsize>,
+}
+
+impl Drop for QueueSlot {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            let repaid = self.debt
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |d| d.checked_sub(1))
+                .is_ok();
+            if repaid {
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl DownloadQueue {
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Self {
+            slots: Arc::new(Semaphore::new(limit)),
+            limit: Arc::new(AtomicUsize::new(limit)),
+            debt: Arc::new(AtomicUsize::new(0)),
+            states: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        let limit = limit.max(1);
+        let previous = self.limit.swap(limit, Ordering::SeqCst);
+
+        if limit > previous {
+            let mut extra = limit - previous;
+            // Cancel pending retirements before handing out new slots
+            while extra > 0 && self.debt
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |d| d.checked_sub(1))
+                .is_ok()
+            {
+                extra -= 1;
+            }
+            self.slots.add_permits(extra);
+        } else if limit < previous {
+            let surplus = previous - limit;
+            let forgotten = self.slots.forget_permits(surplus);
+            self.debt.fetch_add(surplus - forgotten, Ordering::SeqCst);
+        }
+        eprintln!("[Queue] Concurrent download limit set to {}", limit);
+    }
+
+    pub async fn acquire(&self) -> QueueSlot {
+        let permit = self.slots.clone()
+            .acquire_owned()
+            .await
+            .expect("download queue semaphore is never closed");
+        QueueSlot {
+            permit: Some(permit),
+            debt: self.debt.clone(),
+        }
+    }
+
+    pub fn set_state(&self, app: &AppHandle, id: &str, state: DownloadState) {
+        if let Ok(mut states) = self.states.lock() {
+            states.insert(id.to_string(), state);
+        }
+        let _ = app.emit("download://state", QueueEntry {
+            id: id.to_string(),
+            state,
+        });
+    }
+
+    pub fn state(&self, id: &str) -> Option<DownloadState> {
+        self.states.lock().ok()?.get(id).copied()
+    }
+
+    pub fn entries(&self) -> Vec<QueueEntry> {
+        self.states.lock()
+            .map(|states| states.iter()
+                .map(|(id, state)| QueueEntry { id: id.clone(), state: *state })
+                .collect())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,88 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token bucket measured in bytes. A rate of `None` means unlimited.
+///
+/// The bucket holds at most one second worth of tokens, so an idle download
+/// can't burst far above its cap when it resumes.
+pub struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    rate: Option<u64>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Option<u64>) -> Self {
+        let rate = rate.filter(|r| *r > 0);
+        Self {
+            state: Mutex::new(BucketState {
+                rate,
+                tokens: rate.unwrap_or(0) as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        if let Ok(mut state) = self.state.lock() {
+            let rate = rate.filter(|r| *r > 0);
+            state.rate = rate;
+            state.tokens = state.tokens.min(rate.unwrap_or(0) as f64);
+            state.last_refill = Instant::now();
+        }
+    }
+
+    /// Takes `bytes` tokens and returns how long the caller has to wait until
+    /// the bucket has paid them back. Chunks larger than the bucket simply
+    /// push it into debt, which later callers wait out as well.
+    fn reserve(&self, bytes: u64) -> Duration {
+        let Ok(mut state) = self.state.lock() else {
+            return Duration::ZERO;
+        };
+        let Some(rate) = state.rate else {
+            return Duration::ZERO;
+        };
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * rate as f64).min(rate as f64);
+        state.last_refill = now;
+        state.tokens -= bytes as f64;
+
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / rate as f64)
+        }
+    }
+
+    pub async fn consume(&self, bytes: u64) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Global cap shared by every task plus the cap of a single download.
+/// Cloned into each connection of that download, so segments share one budget.
+#[derive(Clone)]
+pub struct RateLimiter {
+    global: Arc<TokenBucket>,
+    local: Arc<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(global: Arc<TokenBucket>, local: Arc<TokenBucket>) -> Self {
+        Self { global, local }
+    }
+
+    pub async fn consume(&self, bytes: u64) {
+        self.local.consume(bytes).await;
+        self.global.consume(bytes).await;
+    }
+}
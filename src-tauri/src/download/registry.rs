@@ -0,0 +1,83 @@
+use crate::download::dash::DashDownloader;
+use crate::download::ftp::FtpDownloader;
+use crate::download::gdrive::GDriveDownloader;
+use crate::download::hls::HlsDownloader;
+use crate::download::http::HttpHelper;
+use crate::download::manager::FileDownloader;
+use crate::download::metalink::MetalinkDownloader;
+use crate::download::sftp::SftpDownloader;
+use crate::download::torrent::{MagnetDownloader, TorrentDownloader};
+use crate::download::{DownloadError, DownloadMeta, DownloadResult, Downloader};
+use crate::storage::DownloadType;
+use std::sync::Arc;
+
+/// Priorities of the built-in downloaders. Site handlers go before protocol
+/// handlers, and plain HTTP catches whatever is left.
+pub const PRIORITY_SITE: i32 = 100;
+pub const PRIORITY_PROTOCOL: i32 = 50;
+pub const PRIORITY_FALLBACK: i32 = 0;
+
+struct Registration {
+    priority: i32,
+    downloader: Arc<dyn Downloader>,
+}
+
+/// Downloaders tried in descending priority; equal priorities keep
+/// registration order.
+#[derive(Default)]
+pub struct DownloaderRegistry {
+    entries: Vec<Registration>,
+}
+
+impl DownloaderRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry with every downloader that ships with the app.
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::new();
+        registry.register(PRIORITY_SITE, Arc::new(GDriveDownloader));
+        registry.register(PRIORITY_PROTOCOL, Arc::new(TorrentDownloader));
+        registry.register(PRIORITY_PROTOCOL, Arc::new(MagnetDownloader));
+        registry.register(PRIORITY_PROTOCOL, Arc::new(FtpDownloader));
+        registry.register(PRIORITY_PROTOCOL, Arc::new(SftpDownloader));
+        registry.register(PRIORITY_PROTOCOL, Arc::new(HlsDownloader));
+        registry.register(PRIORITY_PROTOCOL, Arc::new(DashDownloader));
+        registry.register(PRIORITY_PROTOCOL, Arc::new(MetalinkDownloader));
+        registry.register(PRIORITY_FALLBACK, Arc::new(FileDownloader));
+        registry
+    }
+
+    pub fn register(&mut self, priority: i32, downloader: Arc<dyn Downloader>) {
+        let at = self.entries
+            .iter()
+            .position(|entry| entry.priority < priority)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(at, Registration { priority, downloader });
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn Downloader>> {
+        self.entries.iter().map(|entry| &entry.downloader)
+    }
+
+    /// Asks each downloader that detects the URL to analyze it, stopping at
+    /// the first that accepts it or fails.
+    pub async fn analyze(&self, url: &str, http: &HttpHelper) -> DownloadResult<DownloadMeta> {
+        for downloader in self.iter().filter(|d| d.detect(url)) {
+            if let Some(meta) = downloader.analyze(url, http).await? {
+                return Ok(meta);
+            }
+        }
+        Err(DownloadError::InvalidUrl(format!("No downloader supports {}", url)))
+    }
+
+    /// Handler for a download recorded with `download_type`. Types without
+    /// a registered handler go to the lowest-priority one.
+    pub fn for_type(&self, download_type: &DownloadType) -> Option<Arc<dyn Downloader>> {
+        self.iter()
+            .find(|d| d.download_type() == *download_type)
+            .or_else(|| self.entries.last().map(|entry| &entry.downloader))
+            .cloned()
+    }
+}
@@ -0,0 +1,91 @@
+use crate::download::DownloadError;
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total tries including the first one; 1 disables retrying
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Randomize each delay so parallel downloads don't retry in lockstep
+    pub jitter: bool,
+    pub retryable_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay_ms: 1_000,
+            max_delay_ms: 60_000,
+            jitter: true,
+            retryable_statuses: vec![408, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_retryable(&self, error: &DownloadError) -> bool {
+        match error {
+            DownloadError::NetworkError(_) => true,
+            DownloadError::HttpStatus { status, .. } => self.retryable_statuses.contains(status),
+            _ => false,
+        }
+    }
+
+    /// Delay before attempt `attempt + 1`. A server-provided Retry-After wins
+    /// over the computed backoff, up to `max_delay_ms` so a server can't park
+    /// a download for days.
+    pub fn delay(&self, attempt: u32, error: &DownloadError) -> Duration {
+        if let DownloadError::HttpStatus { retry_after: Some(wait), .. } = error {
+            return (*wait).min(Duration::from_millis(self.max_delay_ms));
+        }
+
+        let exp = self.base_delay_ms.saturating_mul(1u64 << attempt.saturating_sub(1).min(20));
+        let capped = exp.min(self.max_delay_ms);
+        let millis = if self.jitter && capped > 1 {
+            // "Equal jitter": keep half the delay, randomize the other half
+            capped / 2 + rand::thread_rng().gen_range(0..=capped / 2)
+        } else {
+            capped
+        };
+        Duration::from_millis(millis)
+    }
+}
+
+/// Parses `Retry-After` as either delay-seconds or an HTTP-date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let wait = date.with_timezone(&chrono::Utc) - chrono::Utc::now();
+    Some(wait.to_std().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_after_is_capped() {
+        let policy = RetryPolicy { jitter: false, ..Default::default() };
+        let after = |secs| DownloadError::HttpStatus { status: 503, retry_after: Some(Duration::from_secs(secs)) };
+        assert_eq!(policy.delay(1, &after(5)), Duration::from_secs(5));
+        assert_eq!(policy.delay(1, &after(86_400)), Duration::from_millis(policy.max_delay_ms));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let policy = RetryPolicy { jitter: false, ..Default::default() };
+        let error = DownloadError::NetworkError("reset".to_string());
+        let delays: Vec<u64> = (1..=8).map(|attempt| policy.delay(attempt, &error).as_millis() as u64).collect();
+        assert_eq!(delays, [1_000, 2_000, 4_000, 8_000, 16_000, 32_000, 60_000, 60_000]);
+    }
+}
@@ -0,0 +1,340 @@
+use crate::download::http::{content_range_span, if_range_validator, DownloadMetadata, HttpHelper};
+use crate::download::manager::ProgressEvent;
+use crate::download::ratelimit::RateLimiter;
+use crate::download::{DownloadContext, DownloadError};
+use futures_util::StreamExt;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::Emitter;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, SeekFrom};
+use tokio::task::JoinSet;
+
+/// Maximum number of parallel connections used for one file
+pub const SEGMENT_COUNT: u64 = 8;
+
+/// Smallest byte range worth giving its own connection
+pub const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+/// How often the segment layout is written next to the `.fdm` file
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Segment {
+    pub start: u64,
+    /// Inclusive, matching the HTTP `Range` header
+    pub end: u64,
+    pub downloaded: u64,
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    fn is_complete(&self) -> bool {
+        self.downloaded >= self.len()
+    }
+}
+
+/// Byte ranges of a segmented download, persisted as `<file>.fdm.parts`
+/// so a paused download can pick every segment up where it stopped.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentPlan {
+    pub total: u64,
+    pub segments: Vec<Segment>,
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub last_modified: Option<String>,
+}
+
+impl SegmentPlan {
+    pub fn split(meta: &DownloadMetadata) -> Self {
+        let total = meta.size.unwrap_or_default();
+        // An empty file has nothing to fetch, and no last byte to end on
+        let count = if total == 0 { 0 } else { (total / MIN_SEGMENT_SIZE).clamp(1, SEGMENT_COUNT) };
+        let base = total.checked_div(count).unwrap_or_default();
+
+        let mut segments = Vec::with_capacity(count as usize);
+        let mut start = 0;
+        for i in 0..count {
+            // Last segment absorbs the remainder
+            let end = if i == count - 1 { total - 1 } else { start + base - 1 };
+            segments.push(Segment { start, end, downloaded: 0 });
+            start = end + 1;
+        }
+
+        Self {
+            total,
+            segments,
+            etag: meta.etag.clone(),
+            last_modified: meta.last_modified.clone(),
+        }
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.segments.iter().map(|s| s.downloaded.min(s.len())).sum()
+    }
+
+    pub async fn load(path: &Path) -> Option<Self> {
+        let content = tokio::fs::read_to_string(path).await.ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<(), DownloadError> {
+        let content = serde_json::to_string(self).map_err(|e| DownloadError::Other(e.to_string()))?;
+        tokio::fs::write(path, content)
+            .await
+            .map_err(|e| DownloadError::IoError(e.to_string()))
+    }
+
+    fn snapshot(&self, counters: &[Arc<AtomicU64>]) -> Self {
+        let mut plan = self.clone();
+        for (segment, counter) in plan.segments.iter_mut().zip(counters) {
+            segment.downloaded = counter.load(Ordering::Relaxed);
+        }
+        plan
+    }
+}
+
+pub fn parts_path(save_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.fdm.parts", save_path))
+}
+
+/// Segmenting needs a known size and byte-range support, and only pays off
+/// when there is more than one segment's worth of data.
+pub fn should_segment(size: Option<u64>, accept_ranges: bool) -> bool {
+    accept_ranges && size.map(|s| s >= MIN_SEGMENT_SIZE * 2).unwrap_or(false)
+}
+
+/// Fetches all unfinished segments of `plan` in parallel, writing each one at
+/// its offset in the `.fdm` file. Returns the total number of bytes on disk.
+///
+/// Dropping the returned future (e.g. when the task is paused) drops the
+/// `JoinSet`, which aborts every segment connection with it.
+pub async fn download(ctx: &DownloadContext, plan: SegmentPlan) -> Result<u64, DownloadError> {
+    let DownloadContext { id, url, save_path, app, http, limiter, history, .. } = ctx;
+    let temp_path = PathBuf::from(format!("{}.fdm", save_path));
+    let parts_path = parts_path(save_path);
+
+    let counters: Vec<Arc<AtomicU64>> = plan.segments.iter()
+        .map(|s| Arc::new(AtomicU64::new(s.downloaded)))
+        .collect();
+
+    let if_range = if_range_validator(plan.etag.as_deref(), plan.last_modified.as_deref());
+    let mut tasks = JoinSet::new();
+    for (segment, counter) in plan.segments.iter().zip(&counters) {
+        if segment.is_complete() {
+            continue;
+        }
+        tasks.spawn(fetch_segment(
+            http.clone(),
+            url.to_string(),
+            temp_path.clone(),
+            segment.clone(),
+            counter.clone(),
+            if_range.clone(),
+            limiter.clone(),
+        ));
+    }
+    eprintln!("[Segments] {} started with {} active connections", id, tasks.len());
+
+    let total = Some(plan.total);
+    let mut ticker = tokio::time::interval(Duration::from_millis(100));
+    let mut last_emit = Instant::now();
+    let mut last_checkpoint = Instant::now();
+    let mut last_downloaded = plan.downloaded();
+
+    loop {
+        tokio::select! {
+            joined = tasks.join_next() => {
+                let result = match joined {
+                    None => break,
+                    Some(Ok(result)) => result,
+                    Some(Err(e)) => Err(DownloadError::Other(e.to_string())),
+                };
+                if let Err(e) = result {
+                    // Keep what the other segments already wrote for the next attempt
+                    let _ = plan.snapshot(&counters).save(&parts_path).await;
+                    return Err(e);
+                }
+            }
+            _ = ticker.tick() => {
+                let snapshot = plan.snapshot(&counters);
+                let downloaded = snapshot.downloaded();
+                let elapsed = last_emit.elapsed().as_secs_f64();
+                let speed = if elapsed > 0.0 {
+                    (downloaded.saturating_sub(last_downloaded) as f64 / elapsed) as u64
+                } else {
+                    0
+                };
+
+                let _ = app.emit("download://progress", ProgressEvent {
+                    id: id.to_string(),
+                    downloaded,
+                    total,
+                    speed,
+                });
+                history.checkpoint(id, downloaded, total);
+                last_emit = Instant::now();
+                last_downloaded = downloaded;
+
+                if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+                    snapshot.save(&parts_path).await?;
+                    last_checkpoint = Instant::now();
+                }
+            }
+        }
+    }
+
+    Ok(plan.snapshot(&counters).downloaded())
+}
+
+async fn fetch_segment(
+    http: HttpHelper,
+    url: String,
+    temp_path: PathBuf,
+    segment: Segment,
+    counter: Arc<AtomicU64>,
+    if_range: Option<String>,
+    limiter: RateLimiter,
+) -> Result<(), DownloadError> {
+    let offset = segment.start + counter.load(Ordering::Relaxed);
+    let response = http.download_segment_request(&url, offset, segment.end, if_range.as_deref())
+        .await
+        .map_err(DownloadError::NetworkError)?;
+
+    if response.status().is_client_error() || response.status().is_server_error() {
+        return Err(DownloadError::from_status(&response));
+    }
+
+    // A 200 here means the whole file is coming back on every connection,
+    // or that If-Range no longer matches because the file changed
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(DownloadError::ResumeNotPossible(
+            format!("Server ignored range request: {}", response.status())
+        ));
+    }
+    // Bytes are written at `offset`, so they must start there and stay
+    // inside the segment; multipart replies have no single range at all
+    match content_range_span(response.headers()) {
+        Some((start, end)) if start == offset && end <= segment.end => {}
+        other => {
+            return Err(DownloadError::ResumeNotPossible(format!(
+                "Server sent range {:?} for bytes {}-{}", other, offset, segment.end
+            )));
+        }
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(&temp_path)
+        .await
+        .map_err(|e| DownloadError::IoError(e.to_string()))?;
+    file.seek(SeekFrom::Start(offset))
+        .await
+        .map_err(|e| DownloadError::IoError(e.to_string()))?;
+
+    let remaining = segment.end + 1 - offset;
+    let mut written = 0u64;
+    let mut stream = response.bytes_stream();
+
+    while let Some(item) = stream.next().await {
+        let chunk = item.map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+        // Never spill into the next segment, even if the server over-delivers
+        let take = (remaining - written).min(chunk.len() as u64) as usize;
+        file.write_all(&chunk[..take])
+            .await
+            .map_err(|e| DownloadError::IoError(e.to_string()))?;
+        written += take as u64;
+        counter.fetch_add(take as u64, Ordering::Relaxed);
+        limiter.consume(take as u64).await;
+
+        if written >= remaining {
+            break;
+        }
+    }
+
+    file.flush().await.map_err(|e| DownloadError::IoError(e.to_string()))?;
+
+    if written < remaining {
+        return Err(DownloadError::NetworkError(
+            format!("Connection closed with {} bytes of segment remaining", remaining - written)
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan(size: u64) -> SegmentPlan {
+        SegmentPlan::split(&DownloadMetadata {
+            size: Some(size),
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+            accept_ranges: true,
+        })
+    }
+
+    /// Segments must tile `0..total` exactly, in order.
+    fn assert_covers(plan: &SegmentPlan) {
+        let mut next = 0;
+        for segment in &plan.segments {
+            assert_eq!(segment.start, next);
+            assert!(segment.end >= segment.start);
+            next = segment.end + 1;
+        }
+        assert_eq!(next, plan.total);
+    }
+
+    #[test]
+    fn small_files_get_one_segment() {
+        for size in [1, 7, SEGMENT_COUNT - 1, MIN_SEGMENT_SIZE - 1, 2 * MIN_SEGMENT_SIZE - 1] {
+            let plan = plan(size);
+            assert_eq!(plan.segments.len(), 1, "size {}", size);
+            assert_covers(&plan);
+        }
+        assert!(plan(0).segments.is_empty());
+        assert_eq!(plan(0).downloaded(), 0);
+        assert_eq!(plan(1).etag.as_deref(), Some("\"abc\""));
+    }
+
+    #[test]
+    fn last_segment_takes_the_remainder() {
+        let size = SEGMENT_COUNT * MIN_SEGMENT_SIZE * 3 + 5;
+        let capped = plan(size);
+        assert_eq!(capped.segments.len() as u64, SEGMENT_COUNT);
+        assert_covers(&capped);
+        let base = size / SEGMENT_COUNT;
+        let (last, rest) = capped.segments.split_last().unwrap();
+        assert!(rest.iter().all(|s| s.len() == base));
+        assert_eq!(last.len(), base + size % SEGMENT_COUNT);
+
+        // Below the cap, one segment per whole MiB
+        let uncapped = plan(3 * MIN_SEGMENT_SIZE + 1);
+        assert_eq!(uncapped.segments.len(), 3);
+        assert_covers(&uncapped);
+        assert_eq!(uncapped.segments[2].len(), MIN_SEGMENT_SIZE + 1);
+    }
+
+    #[test]
+    fn downloaded_ignores_overshoot() {
+        let mut plan = plan(4 * MIN_SEGMENT_SIZE);
+        plan.segments[0].downloaded = MIN_SEGMENT_SIZE + 100;
+        plan.segments[1].downloaded = 10;
+        assert_eq!(plan.downloaded(), MIN_SEGMENT_SIZE + 10);
+        assert!(plan.segments[0].is_complete());
+        assert!(!plan.segments[1].is_complete());
+        assert!(!should_segment(Some(2 * MIN_SEGMENT_SIZE - 1), true));
+        assert!(should_segment(Some(2 * MIN_SEGMENT_SIZE), true));
+        assert!(!should_segment(Some(2 * MIN_SEGMENT_SIZE), false));
+        assert!(!should_segment(None, true));
+    }
+}
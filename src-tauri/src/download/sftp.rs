@@ -0,0 +1,397 @@
+use crate::download::http::HttpHelper;
+use crate::download::partfile::PartFile;
+use crate::download::{DownloadContext, DownloadError, DownloadMeta, DownloadResult, Downloader};
+use crate::storage::{self, credentials, DownloadType};
+use async_trait::async_trait;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use ssh2::{CheckResult, ErrorCode, HashType, KnownHostFileKind, Session};
+use std::io::{Read, Seek};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tauri::{Emitter, Manager};
+use tokio::sync::mpsc;
+
+const DEFAULT_PORT: u16 = 22;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+/// libssh2 timeout for any single blocking call
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Chunks buffered between the SSH thread and the file writer
+const CHANNEL_DEPTH: usize = 8;
+/// Keys tried from `~/.ssh` when no identity file is configured
+const DEFAULT_IDENTITIES: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SshSettings {
+    /// Private key tried after the SSH agent; `None` tries the usual files in `~/.ssh`
+    pub identity_file: Option<String>,
+    /// Trust and record the key of a server seen for the first time, like
+    /// OpenSSH's `StrictHostKeyChecking=accept-new`; otherwise such servers
+    /// are refused until their key is in `~/.ssh/known_hosts`
+    pub accept_new_host_keys: bool,
+}
+
+impl Default for SshSettings {
+    fn default() -> Self {
+        Self {
+            identity_file: None,
+            accept_new_host_keys: true,
+        }
+    }
+}
+
+/// libssh2 reports SFTP status codes and session errors through the same type.
+fn ssh_err(e: ssh2::Error) -> DownloadError {
+    match e.code() {
+        // SSH_FX_NO_SUCH_FILE
+        ErrorCode::SFTP(2) => DownloadError::Other(format!("No such file: {}", e.message())),
+        // SSH_FX_PERMISSION_DENIED
+        ErrorCode::SFTP(3) => DownloadError::AccessDenied(e.message().to_string()),
+        ErrorCode::SFTP(_) => DownloadError::Other(e.message().to_string()),
+        ErrorCode::Session(_) => DownloadError::NetworkError(e.message().to_string()),
+    }
+}
+
+struct SshTarget {
+    host: String,
+    port: u16,
+    path: PathBuf,
+    username: String,
+    /// From the URL; tried before anything else
+    password: Option<String>,
+    /// From the credential store; used as the key passphrase and as a last resort
+    stored_password: Option<String>,
+}
+
+impl SshTarget {
+    /// `sftp://[user[:password]@]host[:port]/path`. Paths are absolute;
+    /// `/~/path` is relative to the remote home directory.
+    fn parse(url: &str) -> DownloadResult<Self> {
+        let parsed = reqwest::Url::parse(url).map_err(|e| DownloadError::InvalidUrl(e.to_string()))?;
+        if !matches!(parsed.scheme(), "sftp" | "scp") {
+            return Err(DownloadError::InvalidUrl(format!("Not an SFTP URL: {}", parsed.scheme())));
+        }
+        let host = parsed.host_str()
+            .ok_or_else(|| DownloadError::InvalidUrl("SFTP URL has no host".to_string()))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+
+        let path = percent_decode_str(parsed.path()).decode_utf8_lossy().to_string();
+        if path.is_empty() || path.ends_with('/') {
+            return Err(DownloadError::InvalidUrl("SFTP URL points to a directory, not a file".to_string()));
+        }
+        let path = match path.strip_prefix("/~/") {
+            Some(relative) => PathBuf::from(relative),
+            None => PathBuf::from(path),
+        };
+
+        let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().to_string();
+        let stored = credentials::origin_of(&parsed).and_then(|o| credentials::lookup(&o));
+        let username = if !parsed.username().is_empty() {
+            decode(parsed.username())
+        } else if let Some(saved) = &stored {
+            saved.username.clone()
+        } else {
+            std::env::var("USER")
+                .or_else(|_| std::env::var("USERNAME"))
+                .map_err(|_| DownloadError::InvalidUrl("SFTP URL has no user name".to_string()))?
+        };
+
+        Ok(Self {
+            host,
+            port: parsed.port().unwrap_or(DEFAULT_PORT),
+            path,
+            username,
+            password: parsed.password().map(decode),
+            stored_password: stored.map(|c| c.password),
+        })
+    }
+
+    /// Host as written in known_hosts files
+    fn known_hosts_name(&self) -> String {
+        if self.port == DEFAULT_PORT {
+            self.host.clone()
+        } else {
+            format!("[{}]:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Where host keys are looked up and where new ones are recorded.
+struct KeyFiles {
+    /// `~/.ssh/known_hosts`, only read
+    user_known_hosts: Option<PathBuf>,
+    /// Hosts first seen by this app
+    app_known_hosts: PathBuf,
+    identities: Vec<PathBuf>,
+    accept_new: bool,
+}
+
+impl KeyFiles {
+    fn new(ctx: &DownloadContext, settings: &SshSettings) -> DownloadResult<Self> {
+        let home = ctx.app.path().home_dir().ok();
+        let app_dir = ctx.app.path()
+            .app_data_dir()
+            .map_err(|e| DownloadError::IoError(e.to_string()))?;
+        let ssh_dir = home.map(|h| h.join(".ssh"));
+
+        let identities = match &settings.identity_file {
+            Some(file) => vec![PathBuf::from(file)],
+            None => ssh_dir.iter()
+                .flat_map(|dir| DEFAULT_IDENTITIES.iter().map(move |name| dir.join(name)))
+                .collect(),
+        };
+        Ok(Self {
+            user_known_hosts: ssh_dir.map(|dir| dir.join("known_hosts")),
+            app_known_hosts: app_dir.join("known_hosts"),
+            identities,
+            accept_new: settings.accept_new_host_keys,
+        })
+    }
+}
+
+/// OpenSSH-style `SHA256:<base64>` fingerprint of the server's host key.
+fn fingerprint(session: &Session) -> String {
+    use base64::Engine;
+    let hash = session.host_key_hash(HashType::Sha256).unwrap_or_default();
+    format!("SHA256:{}", base64::engine::general_purpose::STANDARD_NO_PAD.encode(hash))
+}
+
+/// Rejects a changed host key. A host seen for the first time is trusted and
+/// recorded if `accept_new_host_keys` is on, and its fingerprint returned so
+/// the user can be told.
+fn verify_host_key(session: &Session, target: &SshTarget, keys: &KeyFiles) -> DownloadResult<Option<String>> {
+    let (key, key_type) = session.host_key()
+        .ok_or_else(|| DownloadError::NetworkError("Server sent no host key".to_string()))?;
+    let mut known = session.known_hosts().map_err(ssh_err)?;
+    for file in keys.user_known_hosts.iter().chain([&keys.app_known_hosts]) {
+        if file.exists() {
+            let _ = known.read_file(file, KnownHostFileKind::OpenSSH);
+        }
+    }
+
+    match known.check_port(&target.host, target.port, key) {
+        CheckResult::Match => Ok(None),
+        CheckResult::Mismatch => Err(DownloadError::AccessDenied(format!(
+            "Host key for {} has changed; refusing to connect", target.known_hosts_name()
+        ))),
+        CheckResult::NotFound if !keys.accept_new => Err(DownloadError::AccessDenied(format!(
+            "Unknown host key for {} ({}); add it to known_hosts or allow new host keys in settings",
+            target.known_hosts_name(), fingerprint(session)
+        ))),
+        CheckResult::NotFound => {
+            let mut app_known = session.known_hosts().map_err(ssh_err)?;
+            if keys.app_known_hosts.exists() {
+                let _ = app_known.read_file(&keys.app_known_hosts, KnownHostFileKind::OpenSSH);
+            }
+            app_known.add(&target.known_hosts_name(), key, "added by Fastah", key_type.into())
+                .map_err(ssh_err)?;
+            if let Some(parent) = keys.app_known_hosts.parent() {
+                let _ = std::fs::create_dir_all(parent);
+            }
+            if let Err(e) = app_known.write_file(&keys.app_known_hosts, KnownHostFileKind::OpenSSH) {
+                eprintln!("[SFTP] Failed to record host key: {}", e);
+            }
+            let fingerprint = fingerprint(session);
+            eprintln!("[SFTP] Trusting new host key for {}: {}", target.known_hosts_name(), fingerprint);
+            Ok(Some(fingerprint))
+        }
+        CheckResult::Failure => Err(DownloadError::Other("Couldn't check the server's host key".to_string())),
+    }
+}
+
+/// Tries the URL password, the SSH agent, key files and finally a stored
+/// password, stopping at the first that the server accepts.
+fn authenticate(session: &Session, target: &SshTarget, keys: &KeyFiles) -> DownloadResult<()> {
+    let user = target.username.as_str();
+    if let Some(password) = &target.password {
+        let _ = session.userauth_password(user, password);
+    }
+
+    if !session.authenticated() {
+        if let Ok(mut agent) = session.agent() {
+            if agent.connect().is_ok() && agent.list_identities().is_ok() {
+                for identity in agent.identities().unwrap_or_default() {
+                    if agent.userauth(user, &identity).is_ok() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    for key in keys.identities.iter().filter(|k| k.exists()) {
+        if session.authenticated() {
+            break;
+        }
+        if session.userauth_pubkey_file(user, None, key, target.stored_password.as_deref()).is_ok() {
+            eprintln!("[SFTP] Authenticated with {}", key.display());
+        }
+    }
+
+    if let (false, Some(password)) = (session.authenticated(), &target.stored_password) {
+        let _ = session.userauth_password(user, password);
+    }
+
+    if session.authenticated() {
+        Ok(())
+    } else {
+        Err(DownloadError::AccessDenied(format!(
+            "SSH login failed for {}@{}", user, target.known_hosts_name()
+        )))
+    }
+}
+
+/// The session, and the fingerprint of the host key if it was new.
+fn connect(target: &SshTarget, keys: &KeyFiles) -> DownloadResult<(Session, Option<String>)> {
+    let addrs = (target.host.as_str(), target.port)
+        .to_socket_addrs()
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+    let tcp = addrs
+        .filter_map(|addr| TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).ok())
+        .next()
+        .ok_or_else(|| DownloadError::NetworkError(format!("Couldn't connect to {}", target.known_hosts_name())))?;
+
+    let mut session = Session::new().map_err(ssh_err)?;
+    session.set_tcp_stream(tcp);
+    session.set_timeout(IO_TIMEOUT.as_millis() as u32);
+    session.handshake().map_err(ssh_err)?;
+    let new_key = verify_host_key(&session, target, keys)?;
+    authenticate(&session, target, keys)?;
+    Ok((session, new_key))
+}
+
+/// Size and modification time, the latter in the RFC 2822 form HTTP uses.
+fn stat(session: &Session, path: &Path) -> DownloadResult<(Option<u64>, Option<String>)> {
+    let stat = session.sftp().map_err(ssh_err)?.stat(path).map_err(ssh_err)?;
+    if stat.is_dir() {
+        return Err(DownloadError::InvalidUrl("SFTP URL points to a directory, not a file".to_string()));
+    }
+    let modified = stat.mtime
+        .and_then(|t| chrono::DateTime::from_timestamp(t as i64, 0))
+        .map(|t| t.to_rfc2822());
+    Ok((stat.size, modified))
+}
+
+/// Streams `path` from `offset` into `chunks` until EOF or until the
+/// receiver is dropped because the download was paused.
+fn stream(
+    session: Session,
+    path: PathBuf,
+    offset: u64,
+    chunks: mpsc::Sender<DownloadResult<Vec<u8>>>,
+) -> DownloadResult<()> {
+    let sftp = session.sftp().map_err(ssh_err)?;
+    let mut file = sftp.open(&path).map_err(ssh_err)?;
+    file.seek(std::io::SeekFrom::Start(offset))
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+
+    loop {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let n = file.read(&mut buf).map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.truncate(n);
+        if chunks.blocking_send(Ok(buf)).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+pub struct SftpDownloader;
+
+#[async_trait]
+impl Downloader for SftpDownloader {
+    fn download_type(&self) -> DownloadType {
+        DownloadType::Sftp
+    }
+
+    /// `scp://` URLs are accepted too and fetched over SFTP, which every
+    /// OpenSSH server offers and which, unlike SCP, can resume.
+    fn detect(&self, url: &str) -> bool {
+        let lower = url.get(..7).unwrap_or_default().to_ascii_lowercase();
+        lower.starts_with("sftp://") || lower.starts_with("scp://")
+    }
+
+    async fn analyze(&self, url: &str, _http: &HttpHelper) -> DownloadResult<Option<DownloadMeta>> {
+        if !self.detect(url) {
+            return Ok(None);
+        }
+        let target = SshTarget::parse(url)?;
+        let filename = target.path.file_name().map(|f| f.to_string_lossy().to_string());
+
+        Ok(Some(DownloadMeta {
+            download_type: DownloadType::Sftp,
+            direct_url: url.to_string(),
+            original_url: None,
+            suggested_filename: filename,
+            ..Default::default()
+        }))
+    }
+
+    async fn run(&self, ctx: DownloadContext) -> DownloadResult<()> {
+        let settings = storage::load_settings(&ctx.app).map(|s| s.ssh).unwrap_or_default();
+        let keys = KeyFiles::new(&ctx, &settings)?;
+        let target = SshTarget::parse(&ctx.url)?;
+        let host = target.known_hosts_name();
+        eprintln!("[SFTP] Starting download: id={}, host={}, path={}", ctx.id, host, target.path.display());
+
+        let path = target.path.clone();
+        let (session, new_key, (total, modified)) = tokio::task::spawn_blocking(move || {
+            let (session, new_key) = connect(&target, &keys)?;
+            let stat = stat(&session, &target.path)?;
+            Ok::<_, DownloadError>((session, new_key, stat))
+        })
+        .await
+        .map_err(|e| DownloadError::Other(e.to_string()))??;
+
+        if let Some(fingerprint) = new_key {
+            let _ = ctx.app.emit("download://host-key", serde_json::json!({
+                "id": ctx.id,
+                "host": host,
+                "fingerprint": fingerprint,
+            }));
+        }
+        let _ = ctx.app.emit("download://metadata", serde_json::json!({
+            "id": ctx.id,
+            "total": total,
+            "etag": null,
+            "last_modified": modified,
+        }));
+        ctx.history.update(&ctx.id, |item| {
+            item.total = total;
+            item.last_modified = modified.clone();
+        });
+
+        let temp_path = format!("{}.fdm", ctx.save_path);
+        let mut downloaded = tokio::fs::metadata(&temp_path).await.map(|m| m.len()).unwrap_or(0);
+        let changed = matches!((&ctx.last_modified, &modified), (Some(known), Some(now)) if known != now);
+        if downloaded > 0 && (changed || total.is_some_and(|t| downloaded > t)) {
+            eprintln!("[Resume] {}: file changed on server, restarting from scratch", ctx.id);
+            downloaded = 0;
+        }
+        if downloaded > 0 {
+            eprintln!("[SFTP] Resuming download from byte {}", downloaded);
+        }
+        let mut part = PartFile::open(&ctx, downloaded, total).await?;
+
+        let (chunk_tx, mut chunk_rx) = mpsc::channel(CHANNEL_DEPTH);
+        let error_tx = chunk_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = stream(session, path, downloaded, chunk_tx) {
+                let _ = error_tx.blocking_send(Err(e));
+            }
+        });
+
+        while let Some(chunk) = chunk_rx.recv().await {
+            part.write(&chunk?).await?;
+        }
+        part.finish().await
+    }
+}
+
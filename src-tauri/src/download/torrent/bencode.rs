@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Nesting limit so a hostile peer or tracker can't blow the stack
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(map) => map.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(v) => out.extend_from_slice(format!("i{}e", v).as_bytes()),
+            Value::Bytes(v) => {
+                out.extend_from_slice(format!("{}:", v.len()).as_bytes());
+                out.extend_from_slice(v);
+            }
+            Value::List(items) => {
+                out.push(b'l');
+                for item in items {
+                    item.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(map) => {
+                // BTreeMap keeps keys sorted, as the spec requires
+                out.push(b'd');
+                for (key, value) in map {
+                    out.extend_from_slice(format!("{}:", key.len()).as_bytes());
+                    out.extend_from_slice(key);
+                    value.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Result<u8, String> {
+        self.data.get(self.pos).copied().ok_or_else(|| "Unexpected end of bencode data".to_string())
+    }
+
+    fn read_until(&mut self, end: u8) -> Result<&'a [u8], String> {
+        let rest = &self.data[self.pos..];
+        let len = rest.iter().position(|&b| b == end)
+            .ok_or_else(|| "Unterminated bencode token".to_string())?;
+        self.pos += len + 1;
+        Ok(&rest[..len])
+    }
+
+    fn parse_int(&mut self) -> Result<i64, String> {
+        self.pos += 1;
+        let digits = self.read_until(b'e')?;
+        std::str::from_utf8(digits).ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| "Invalid bencode integer".to_string())
+    }
+
+    fn parse_bytes(&mut self) -> Result<&'a [u8], String> {
+        let len: usize = std::str::from_utf8(self.read_until(b':')?).ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| "Invalid bencode string length".to_string())?;
+        let end = self.pos.checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| "Bencode string runs past the end".to_string())?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err("Bencode nested too deeply".to_string());
+        }
+        match self.peek()? {
+            b'i' => self.parse_int().map(Value::Int),
+            b'0'..=b'9' => self.parse_bytes().map(|b| Value::Bytes(b.to_vec())),
+            b'l' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                while self.peek()? != b'e' {
+                    items.push(self.parse_value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(items))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut map = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.parse_bytes()?.to_vec();
+                    let value = self.parse_value(depth + 1)?;
+                    map.insert(key, value);
+                }
+                self.pos += 1;
+                Ok(Value::Dict(map))
+            }
+            other => Err(format!("Unexpected bencode byte 0x{:02x}", other)),
+        }
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Value, String> {
+    decode_prefix(data).map(|(value, _)| value)
+}
+
+/// Decodes one value from the start of `data`, returning it and the number of
+/// bytes it took. `ut_metadata` messages carry raw piece data after the dict.
+pub fn decode_prefix(data: &[u8]) -> Result<(Value, usize), String> {
+    let mut parser = Parser { data, pos: 0 };
+    let value = parser.parse_value(0)?;
+    Ok((value, parser.pos))
+}
+
+/// Byte range of `key`'s value in a top-level dictionary. The info hash is
+/// taken over the exact bytes of `info`, which re-encoding wouldn't preserve
+/// for non-canonical torrents.
+pub fn dict_value_span(data: &[u8], key: &str) -> Result<Option<Range<usize>>, String> {
+    let mut parser = Parser { data, pos: 0 };
+    if parser.peek()? != b'd' {
+        return Err("Expected a bencode dictionary".to_string());
+    }
+    parser.pos += 1;
+    while parser.peek()? != b'e' {
+        let current = parser.parse_bytes()?;
+        let start = parser.pos;
+        parser.parse_value(1)?;
+        if current == key.as_bytes() {
+            return Ok(Some(start..parser.pos));
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = b"d4:infod6:lengthi-12e4:name3:abce4:listli1e2:xyee";
+        let value = decode(data).unwrap();
+        assert_eq!(value.get("info").and_then(|i| i.get("length")).and_then(Value::as_int), Some(-12));
+        assert_eq!(value.get("info").and_then(|i| i.get("name")).and_then(Value::as_str), Some("abc"));
+        assert_eq!(value.get("list").and_then(Value::as_list).map(<[Value]>::len), Some(2));
+        assert_eq!(value.encode(), data);
+    }
+
+    #[test]
+    fn malformed() {
+        for data in [&b"i12"[..], b"5:abc", b"x", b"l1:a", b"ie", b"99999999999999999999999:a", b"d1:a"] {
+            assert!(decode(data).is_err(), "{:?}", String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn depth_limit() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(decode(&nested(MAX_DEPTH + 1)).is_ok());
+        assert_eq!(decode(&nested(MAX_DEPTH + 2)), Err("Bencode nested too deeply".to_string()));
+        assert!(decode(&nested(100_000)).is_err());
+    }
+
+    #[test]
+    fn prefix_and_span() {
+        let (value, used) = decode_prefix(b"d8:msg_typei1eeRAW").unwrap();
+        assert_eq!(value.get("msg_type").and_then(Value::as_int), Some(1));
+        assert_eq!(used, 15);
+
+        // Non-canonical integer: the span has to keep the original bytes
+        let data = b"d8:announce3:url4:infod6:lengthi007eee";
+        let span = dict_value_span(data, "info").unwrap().unwrap();
+        assert_eq!(&data[span], b"d6:lengthi007ee");
+        assert_eq!(dict_value_span(data, "missing").unwrap(), None);
+        assert!(dict_value_span(b"li1ee", "info").is_err());
+    }
+}
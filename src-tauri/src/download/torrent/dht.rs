@@ -0,0 +1,225 @@
+use super::bencode::{self, Value};
+use super::metainfo::InfoHash;
+use super::tracker::parse_compact_v4;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+const BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+    "dht.libtorrent.org:25401",
+];
+/// Queries in flight at once during a lookup
+const ALPHA: usize = 8;
+/// The lookup ends once this many closest nodes have all answered
+const K: usize = 8;
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(30);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const MAX_QUERIES: usize = 400;
+/// How often a running torrent looks for new peers
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+type NodeId = [u8; 20];
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0u8; 20];
+    for i in 0..20 {
+        d[i] = a[i] ^ b[i];
+    }
+    d
+}
+
+struct Candidate {
+    addr: SocketAddr,
+    queried: bool,
+    responded: bool,
+    token: Option<Vec<u8>>,
+}
+
+/// Client-only DHT node: it looks up peers and announces itself but doesn't
+/// keep a routing table or answer queries, so it says so with `ro` (BEP 43).
+pub struct Dht {
+    socket: UdpSocket,
+    node_id: NodeId,
+    next_transaction: u16,
+}
+
+fn query(transaction: u16, method: &str, mut args: BTreeMap<Vec<u8>, Value>, node_id: &NodeId) -> Vec<u8> {
+    args.insert(b"id".to_vec(), Value::Bytes(node_id.to_vec()));
+    let mut msg = BTreeMap::new();
+    msg.insert(b"t".to_vec(), Value::Bytes(transaction.to_be_bytes().to_vec()));
+    msg.insert(b"y".to_vec(), Value::Bytes(b"q".to_vec()));
+    msg.insert(b"q".to_vec(), Value::Bytes(method.as_bytes().to_vec()));
+    msg.insert(b"a".to_vec(), Value::Dict(args));
+    msg.insert(b"ro".to_vec(), Value::Int(1));
+    Value::Dict(msg).encode()
+}
+
+/// Compact node info: 20-byte id followed by a 6-byte IPv4 address.
+fn parse_nodes(data: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    data.chunks_exact(26)
+        .map(|c| {
+            let id: NodeId = c[..20].try_into().expect("chunk is 26 bytes");
+            let addr = SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::new(c[20], c[21], c[22], c[23]),
+                u16::from_be_bytes([c[24], c[25]]),
+            ));
+            (id, addr)
+        })
+        .filter(|(_, addr)| addr.port() != 0)
+        .collect()
+}
+
+impl Dht {
+    pub async fn bind() -> std::io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let mut node_id = [0u8; 20];
+        for byte in node_id.iter_mut() {
+            *byte = rand::random();
+        }
+        Ok(Self { socket, node_id, next_transaction: 0 })
+    }
+
+    async fn send(&mut self, addr: SocketAddr, method: &str, args: BTreeMap<Vec<u8>, Value>) -> u16 {
+        let transaction = self.next_transaction;
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+        let _ = self.socket.send_to(&query(transaction, method, args, &self.node_id), addr).await;
+        transaction
+    }
+
+    /// Iterative `get_peers` lookup (BEP 5). Peers are streamed into `peers`
+    /// as they are found; with `announce_port` we also announce ourselves to
+    /// the closest nodes. Returns how many peers were found.
+    pub async fn find_peers(
+        &mut self,
+        info_hash: &InfoHash,
+        announce_port: Option<u16>,
+        peers: &mpsc::UnboundedSender<SocketAddr>,
+    ) -> usize {
+        let mut candidates: BTreeMap<NodeId, Candidate> = BTreeMap::new();
+        let mut in_flight: HashMap<u16, (Option<NodeId>, Instant)> = HashMap::new();
+        let mut found: HashSet<SocketAddr> = HashSet::new();
+        let mut queries = 0;
+        let started = Instant::now();
+
+        let get_peers_args = || {
+            let mut args = BTreeMap::new();
+            args.insert(b"info_hash".to_vec(), Value::Bytes(info_hash.to_vec()));
+            args
+        };
+
+        // Bootstrap routers have no known id; their answers seed the candidates
+        for host in BOOTSTRAP_NODES {
+            if let Ok(mut addrs) = tokio::net::lookup_host(host).await {
+                if let Some(addr) = addrs.find(SocketAddr::is_ipv4) {
+                    let t = self.send(addr, "get_peers", get_peers_args()).await;
+                    in_flight.insert(t, (None, Instant::now()));
+                    queries += 1;
+                }
+            }
+        }
+
+        let mut buf = vec![0u8; 2048];
+        while started.elapsed() < LOOKUP_TIMEOUT && queries < MAX_QUERIES {
+            in_flight.retain(|_, (_, sent)| sent.elapsed() < QUERY_TIMEOUT);
+
+            let closest_done = candidates.values().take(K).all(|c| c.queried);
+            if in_flight.is_empty() && (closest_done || candidates.is_empty()) {
+                break;
+            }
+
+            let next: Vec<(NodeId, SocketAddr)> = candidates.iter()
+                .filter(|(_, c)| !c.queried)
+                .take(ALPHA.saturating_sub(in_flight.len()))
+                .map(|(d, c)| (*d, c.addr))
+                .collect();
+            for (dist, addr) in next {
+                if let Some(c) = candidates.get_mut(&dist) {
+                    c.queried = true;
+                }
+                let t = self.send(addr, "get_peers", get_peers_args()).await;
+                in_flight.insert(t, (Some(dist), Instant::now()));
+                queries += 1;
+            }
+
+            let (len, _) = match tokio::time::timeout(Duration::from_millis(500), self.socket.recv_from(&mut buf)).await {
+                Ok(Ok(received)) => received,
+                _ => continue,
+            };
+            let Ok(reply) = bencode::decode(&buf[..len]) else {
+                continue;
+            };
+            let Some(t) = reply.get("t").and_then(Value::as_bytes).filter(|t| t.len() == 2) else {
+                continue;
+            };
+            let Some((dist, _)) = in_flight.remove(&u16::from_be_bytes([t[0], t[1]])) else {
+                continue;
+            };
+            let Some(r) = reply.get("r") else {
+                continue;
+            };
+
+            if let Some(c) = dist.and_then(|d| candidates.get_mut(&d)) {
+                c.responded = true;
+                c.token = r.get("token").and_then(Value::as_bytes).map(<[u8]>::to_vec);
+            }
+            for value in r.get("values").and_then(Value::as_list).unwrap_or_default() {
+                for addr in value.as_bytes().map(parse_compact_v4).unwrap_or_default() {
+                    if found.insert(addr) {
+                        let _ = peers.send(addr);
+                    }
+                }
+            }
+            if let Some(nodes) = r.get("nodes").and_then(Value::as_bytes) {
+                for (id, addr) in parse_nodes(nodes) {
+                    candidates.entry(distance(&id, info_hash)).or_insert(Candidate {
+                        addr,
+                        queried: false,
+                        responded: false,
+                        token: None,
+                    });
+                }
+            }
+        }
+
+        if let Some(port) = announce_port {
+            let targets: Vec<(SocketAddr, Vec<u8>)> = candidates.values()
+                .filter(|c| c.responded)
+                .filter_map(|c| Some((c.addr, c.token.clone()?)))
+                .take(K)
+                .collect();
+            for (addr, token) in targets {
+                let mut args = get_peers_args();
+                args.insert(b"port".to_vec(), Value::Int(port as i64));
+                args.insert(b"token".to_vec(), Value::Bytes(token));
+                args.insert(b"implied_port".to_vec(), Value::Int(0));
+                self.send(addr, "announce_peer", args).await;
+            }
+        }
+
+        eprintln!("[DHT] Lookup finished: {} queries, {} peers", queries, found.len());
+        found.len()
+    }
+}
+
+/// Repeats the lookup for as long as the torrent runs.
+pub async fn lookup_loop(info_hash: InfoHash, announce_port: Option<u16>, peers: mpsc::UnboundedSender<SocketAddr>) {
+    let mut dht = match Dht::bind().await {
+        Ok(dht) => dht,
+        Err(e) => {
+            eprintln!("[DHT] Failed to open socket: {}", e);
+            return;
+        }
+    };
+    loop {
+        dht.find_peers(&info_hash, announce_port, &peers).await;
+        if peers.is_closed() {
+            return;
+        }
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+    }
+}
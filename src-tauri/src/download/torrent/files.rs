@@ -0,0 +1,92 @@
+use super::metainfo::Metainfo;
+use crate::download::DownloadError;
+use sha1::{Digest, Sha1};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
+
+/// Maps the torrent's continuous byte stream onto the files below `root`.
+///
+/// Pieces are written straight to their final location (there is no `.fdm`
+/// stage) so they can be served while the rest is still downloading. A piece
+/// that straddles a selected and an unselected file also writes its bytes of
+/// the unselected one, which keeps every stored piece verifiable on recheck.
+#[derive(Clone)]
+pub struct TorrentFiles {
+    root: PathBuf,
+    meta: Arc<Metainfo>,
+}
+
+fn io_err(e: std::io::Error) -> DownloadError {
+    DownloadError::IoError(e.to_string())
+}
+
+impl TorrentFiles {
+    pub fn new(root: PathBuf, meta: Arc<Metainfo>) -> Self {
+        Self { root, meta }
+    }
+
+    fn path(&self, file: usize) -> PathBuf {
+        self.root.join(&self.meta.files[file].path)
+    }
+
+    /// Where the torrent's top-level file or folder ends up.
+    pub fn content_path(&self) -> PathBuf {
+        self.root.join(&self.meta.name)
+    }
+
+    pub async fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>, DownloadError> {
+        let mut data = Vec::with_capacity(len as usize);
+        for (file, file_offset, span) in self.meta.spans(offset, len) {
+            let mut handle = tokio::fs::File::open(self.path(file)).await.map_err(io_err)?;
+            handle.seek(SeekFrom::Start(file_offset)).await.map_err(io_err)?;
+            let start = data.len();
+            data.resize(start + span as usize, 0);
+            handle.read_exact(&mut data[start..]).await.map_err(io_err)?;
+        }
+        Ok(data)
+    }
+
+    pub async fn write_piece(&self, index: usize, data: &[u8]) -> Result<(), DownloadError> {
+        let offset = index as u64 * self.meta.piece_length;
+        let mut written = 0usize;
+        for (file, file_offset, span) in self.meta.spans(offset, data.len() as u64) {
+            let path = self.path(file);
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await.map_err(io_err)?;
+            }
+            let mut handle = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .await
+                .map_err(io_err)?;
+            handle.seek(SeekFrom::Start(file_offset)).await.map_err(io_err)?;
+            handle.write_all(&data[written..written + span as usize]).await.map_err(io_err)?;
+            written += span as usize;
+        }
+        Ok(())
+    }
+
+    /// Re-hashes a piece already on disk; used to pick up where a paused or
+    /// interrupted download left off.
+    pub async fn verify_piece(&self, index: usize) -> bool {
+        let offset = index as u64 * self.meta.piece_length;
+        let size = self.meta.piece_size(index);
+        for (file, file_offset, span) in self.meta.spans(offset, size) {
+            let on_disk = tokio::fs::metadata(self.path(file))
+                .await
+                .map(|m| m.len())
+                .unwrap_or(0);
+            if on_disk < file_offset + span {
+                return false;
+            }
+        }
+        match self.read(offset, size).await {
+            Ok(data) => Sha1::digest(&data)[..] == self.meta.pieces[index],
+            Err(_) => false,
+        }
+    }
+}
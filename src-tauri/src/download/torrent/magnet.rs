@@ -0,0 +1,138 @@
+use super::metainfo::InfoHash;
+use crate::download::DownloadError;
+use std::net::SocketAddr;
+
+#[derive(Debug, Clone)]
+pub struct MagnetLink {
+    pub info_hash: InfoHash,
+    /// `dn`: display name, only a hint until the metadata arrives
+    pub name: Option<String>,
+    /// `tr` parameters, each its own tier
+    pub trackers: Vec<String>,
+    /// `x.pe` peer addresses
+    pub peers: Vec<SocketAddr>,
+}
+
+pub fn is_magnet(url: &str) -> bool {
+    url.get(..8).map(|s| s.eq_ignore_ascii_case("magnet:?")).unwrap_or(false)
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let decoded = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match decoded {
+                    Some(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+/// RFC 4648 base32, used by older magnet links for the info hash.
+fn base32_decode(value: &str) -> Option<Vec<u8>> {
+    let mut bits = 0u64;
+    let mut count = 0;
+    let mut out = Vec::new();
+    for c in value.chars() {
+        let v = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            _ => return None,
+        };
+        bits = (bits << 5) | v;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn parse_info_hash(xt: &str) -> Option<InfoHash> {
+    let hash = xt.strip_prefix("urn:btih:")?;
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).ok()?,
+        32 => base32_decode(hash)?,
+        _ => return None,
+    };
+    bytes.try_into().ok()
+}
+
+impl MagnetLink {
+    pub fn parse(uri: &str) -> Result<Self, DownloadError> {
+        if !is_magnet(uri) {
+            return Err(DownloadError::InvalidUrl(format!("Not a magnet link: {}", uri)));
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for pair in uri[8..].split('&') {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(value);
+            match key {
+                "xt" => info_hash = info_hash.or_else(|| parse_info_hash(&value)),
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => peers.extend(value.parse::<SocketAddr>().ok()),
+                _ => {}
+            }
+        }
+
+        let info_hash = info_hash
+            .ok_or_else(|| DownloadError::InvalidUrl("Magnet link has no BitTorrent info hash".to_string()))?;
+        Ok(Self { info_hash, name, trackers, peers })
+    }
+
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        self.trackers.iter().map(|t| vec![t.clone()]).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_links() {
+        let hash = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+        let link = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:btih:{}&dn=Some+File%20%C3%A9&tr=udp%3A%2F%2Ft1%3A80&tr=http%3A%2F%2Ft2%2Fa&x.pe=127.0.0.1:6881&x.pe=bad",
+            hash.to_uppercase()
+        )).unwrap();
+        assert_eq!(hex::encode(link.info_hash), hash);
+        assert_eq!(link.name.as_deref(), Some("Some File é"));
+        assert_eq!(link.tracker_tiers(), vec![vec!["udp://t1:80".to_string()], vec!["http://t2/a".to_string()]]);
+        assert_eq!(link.peers, vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn base32_hash() {
+        let link = MagnetLink::parse("MAGNET:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(hex::encode(link.info_hash), "c12fe1c06bba254a9dc9f519b335aa7c1367a88a");
+    }
+
+    #[test]
+    fn rejects_bad_links() {
+        assert!(MagnetLink::parse("http://example.com").is_err());
+        assert!(MagnetLink::parse("magnet:?dn=name").is_err());
+        assert!(MagnetLink::parse("magnet:?xt=urn:btih:1234").is_err());
+        assert!(MagnetLink::parse("magnet:?xt=urn:sha1:c12fe1c06bba254a9dc9f519b335aa7c1367a88a").is_err());
+    }
+}
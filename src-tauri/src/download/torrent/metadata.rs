@@ -0,0 +1,200 @@
+use super::bencode::{self, Value};
+use super::metainfo::InfoHash;
+use super::peer::{self, Message, METADATA_PIECE_SIZE, UT_METADATA_ID};
+use crate::download::DownloadError;
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+/// Peers asked for metadata at the same time
+const PARALLEL_PEERS: usize = 8;
+const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Info dictionaries are rarely over a few hundred KiB; refuse anything absurd
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+fn net_err(msg: &str) -> DownloadError {
+    DownloadError::NetworkError(msg.to_string())
+}
+
+/// Downloads the info dictionary from one peer over `ut_metadata` (BEP 9)
+/// and checks it against the info hash.
+async fn fetch_from_peer(addr: SocketAddr, info_hash: InfoHash, peer_id: [u8; 20]) -> Result<Vec<u8>, DownloadError> {
+    let stream = TcpStream::connect(addr)
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+    let (mut reader, mut writer) = stream.into_split();
+
+    peer::write_handshake(&mut writer, &info_hash, &peer_id).await?;
+    let handshake = peer::read_handshake(&mut reader).await?;
+    if handshake.info_hash != info_hash {
+        return Err(net_err("Peer is on a different torrent"));
+    }
+    if !handshake.supports_extensions() {
+        return Err(net_err("Peer doesn't support the extension protocol"));
+    }
+    peer::write_message(&mut writer, &peer::extended_handshake(None)).await?;
+
+    // Wait for the peer's extension handshake to learn its ut_metadata id and the size
+    let (their_id, size) = loop {
+        if let Message::Extended { id: 0, payload } = peer::read_message(&mut reader).await? {
+            let dict = bencode::decode(&payload).map_err(|e| net_err(&e))?;
+            let their_id = dict.get("m")
+                .and_then(|m| m.get("ut_metadata"))
+                .and_then(Value::as_int)
+                .filter(|&id| id > 0 && id < 256)
+                .ok_or_else(|| net_err("Peer doesn't share metadata"))?;
+            let size = dict.get("metadata_size")
+                .and_then(Value::as_int)
+                .filter(|&s| s > 0 && (s as usize) <= MAX_METADATA_SIZE)
+                .ok_or_else(|| net_err("Peer didn't report a usable metadata size"))?;
+            break (their_id as u8, size as usize);
+        }
+    };
+
+    let pieces = size.div_ceil(METADATA_PIECE_SIZE);
+    for piece in 0..pieces {
+        peer::write_message(&mut writer, &peer::metadata_message(their_id, 0, piece, None)).await?;
+    }
+
+    let mut metadata = vec![0u8; size];
+    let mut received = HashSet::new();
+    while received.len() < pieces {
+        let Message::Extended { id: UT_METADATA_ID, payload } = peer::read_message(&mut reader).await? else {
+            continue;
+        };
+        let (header, consumed) = bencode::decode_prefix(&payload).map_err(|e| net_err(&e))?;
+        match header.get("msg_type").and_then(Value::as_int) {
+            Some(1) => {}
+            Some(2) => return Err(net_err("Peer rejected the metadata request")),
+            _ => continue,
+        }
+        let piece = header.get("piece")
+            .and_then(Value::as_int)
+            .filter(|&p| p >= 0 && (p as usize) < pieces)
+            .ok_or_else(|| net_err("Peer sent an unknown metadata piece"))? as usize;
+
+        let data = &payload[consumed..];
+        let start = piece * METADATA_PIECE_SIZE;
+        let expected = METADATA_PIECE_SIZE.min(size - start);
+        if data.len() != expected {
+            return Err(net_err("Metadata piece has the wrong size"));
+        }
+        metadata[start..start + expected].copy_from_slice(data);
+        received.insert(piece);
+    }
+
+    if Sha1::digest(&metadata)[..] != info_hash {
+        return Err(net_err("Metadata doesn't match the info hash"));
+    }
+    Ok(metadata)
+}
+
+/// Asks peers from `peers` for the info dictionary until one delivers a
+/// valid copy. Every address seen is returned too, so the download can
+/// start with them instead of waiting for the next announce.
+pub async fn fetch(
+    info_hash: InfoHash,
+    peer_id: [u8; 20],
+    mut peers: mpsc::UnboundedReceiver<SocketAddr>,
+    timeout: Duration,
+) -> Result<(Vec<u8>, Vec<SocketAddr>), DownloadError> {
+    let mut seen: Vec<SocketAddr> = Vec::new();
+    let mut backlog: Vec<SocketAddr> = Vec::new();
+    let mut attempts: JoinSet<Result<Vec<u8>, DownloadError>> = JoinSet::new();
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+
+    loop {
+        while attempts.len() < PARALLEL_PEERS {
+            let Some(addr) = backlog.pop() else {
+                break;
+            };
+            attempts.spawn(async move {
+                tokio::time::timeout(PEER_TIMEOUT, fetch_from_peer(addr, info_hash, peer_id))
+                    .await
+                    .map_err(|_| net_err("Peer timed out"))?
+            });
+        }
+
+        tokio::select! {
+            Some(addr) = peers.recv() => {
+                if !seen.contains(&addr) {
+                    seen.push(addr);
+                    backlog.push(addr);
+                }
+            }
+            Some(result) = attempts.join_next(), if !attempts.is_empty() => {
+                if let Ok(Ok(metadata)) = result {
+                    eprintln!("[Magnet] Got metadata ({} bytes) after trying {} peers", metadata.len(), seen.len());
+                    return Ok((metadata, seen));
+                }
+            }
+            _ = &mut deadline => {
+                return Err(DownloadError::NetworkError(format!(
+                    "Couldn't get torrent metadata from {} peers", seen.len()
+                )));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Peer that answers every `ut_metadata` request with `served`.
+    async fn metadata_peer(info_hash: InfoHash, served: Vec<u8>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.into_split();
+            peer::read_handshake(&mut reader).await.unwrap();
+            peer::write_handshake(&mut writer, &info_hash, &peer::generate_peer_id()).await.unwrap();
+            peer::write_message(&mut writer, &peer::extended_handshake(Some(served.len()))).await.unwrap();
+            while let Ok(message) = peer::read_message(&mut reader).await {
+                let Message::Extended { id: UT_METADATA_ID, payload } = message else {
+                    continue;
+                };
+                let request = bencode::decode(&payload).unwrap();
+                let piece = request.get("piece").and_then(Value::as_int).unwrap() as usize;
+                let start = piece * METADATA_PIECE_SIZE;
+                let chunk = &served[start..served.len().min(start + METADATA_PIECE_SIZE)];
+                // The fetcher announced itself with UT_METADATA_ID
+                let reply = peer::metadata_message(UT_METADATA_ID, 1, piece, Some((chunk, served.len())));
+                peer::write_message(&mut writer, &reply).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn checks_info_hash() {
+        // Spans three metadata pieces
+        let metadata: Vec<u8> = (0..40_000u32).map(|i| (i % 256) as u8).collect();
+        let info_hash: InfoHash = Sha1::digest(&metadata).into();
+
+        let addr = metadata_peer(info_hash, metadata.clone()).await;
+        let fetched = fetch_from_peer(addr, info_hash, peer::generate_peer_id()).await.unwrap();
+        assert_eq!(fetched, metadata);
+
+        let mut tampered = metadata.clone();
+        tampered[20_000] ^= 1;
+        let addr = metadata_peer(info_hash, tampered).await;
+        let err = fetch_from_peer(addr, info_hash, peer::generate_peer_id()).await.unwrap_err();
+        assert!(err.to_string().contains("doesn't match the info hash"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn gives_up_at_the_deadline() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tx.send("127.0.0.1:9".parse().unwrap()).unwrap();
+        let result = fetch([0; 20], peer::generate_peer_id(), rx, Duration::from_millis(300)).await;
+        assert!(result.is_err());
+    }
+}
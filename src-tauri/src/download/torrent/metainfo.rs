@@ -0,0 +1,314 @@
+use super::bencode::{self, Value};
+use crate::download::DownloadError;
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use std::path::PathBuf;
+
+pub type InfoHash = [u8; 20];
+
+/// Largest piece length accepted; each piece is held in memory while it is
+/// verified, and real torrents stay well below this
+const MAX_PIECE_LENGTH: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentFile {
+    pub index: usize,
+    /// Relative to the download folder, already sanitized
+    pub path: PathBuf,
+    pub length: u64,
+    /// Position of the file's first byte in the torrent's byte stream
+    #[serde(skip)]
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Metainfo {
+    pub info_hash: InfoHash,
+    pub name: String,
+    pub piece_length: u64,
+    pub pieces: Vec<[u8; 20]>,
+    pub files: Vec<TorrentFile>,
+    pub total: u64,
+    /// Tiers of announce URLs (BEP 12); a plain `announce` is a single tier
+    pub trackers: Vec<Vec<String>>,
+    /// Private torrents may only get peers from their trackers (BEP 27)
+    pub private: bool,
+    /// Raw bencoded info dictionary, served to peers over `ut_metadata`
+    pub info_bytes: Vec<u8>,
+}
+
+fn invalid(msg: &str) -> DownloadError {
+    DownloadError::Other(format!("Invalid torrent: {}", msg))
+}
+
+/// Drops empty, `.` and `..` components so a torrent can't write outside the
+/// download folder.
+fn sanitize_component(component: &str) -> Option<String> {
+    let cleaned: String = component.chars()
+        .map(|c| if matches!(c, '/' | '\\' | ':' | '\0') { '_' } else { c })
+        .collect();
+    match cleaned.trim() {
+        "" | "." | ".." => None,
+        _ => Some(cleaned),
+    }
+}
+
+impl Metainfo {
+    /// Parses a whole `.torrent` file.
+    pub fn parse(data: &[u8]) -> Result<Self, DownloadError> {
+        let root = bencode::decode(data).map_err(|e| invalid(&e))?;
+        let span = bencode::dict_value_span(data, "info")
+            .map_err(|e| invalid(&e))?
+            .ok_or_else(|| invalid("missing info dictionary"))?;
+
+        let mut trackers: Vec<Vec<String>> = root.get("announce-list")
+            .and_then(Value::as_list)
+            .map(|tiers| tiers.iter()
+                .filter_map(Value::as_list)
+                .map(|tier| tier.iter().filter_map(|t| t.as_str().map(str::to_string)).collect::<Vec<_>>())
+                .filter(|tier| !tier.is_empty())
+                .collect())
+            .unwrap_or_default();
+        if trackers.is_empty() {
+            if let Some(announce) = root.get("announce").and_then(Value::as_str) {
+                trackers.push(vec![announce.to_string()]);
+            }
+        }
+
+        Self::from_info(&data[span], trackers)
+    }
+
+    /// Builds metainfo from a bare info dictionary, as fetched from peers for
+    /// magnet links.
+    pub fn from_info(info_bytes: &[u8], trackers: Vec<Vec<String>>) -> Result<Self, DownloadError> {
+        let info = bencode::decode(info_bytes).map_err(|e| invalid(&e))?;
+        let info_hash: InfoHash = Sha1::digest(info_bytes).into();
+
+        let name = info.get("name.utf-8")
+            .or_else(|| info.get("name"))
+            .and_then(Value::as_str)
+            .and_then(sanitize_component)
+            .unwrap_or_else(|| hex::encode(info_hash));
+
+        let piece_length = info.get("piece length")
+            .and_then(Value::as_int)
+            .filter(|&len| len > 0)
+            .ok_or_else(|| invalid("missing piece length"))? as u64;
+        if piece_length > MAX_PIECE_LENGTH {
+            return Err(invalid("piece length too large"));
+        }
+
+        let pieces_raw = info.get("pieces")
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| invalid("missing piece hashes"))?;
+        if pieces_raw.len() % 20 != 0 {
+            return Err(invalid("piece hashes are not a multiple of 20 bytes"));
+        }
+        let pieces: Vec<[u8; 20]> = pieces_raw.chunks_exact(20)
+            .map(|chunk| chunk.try_into().expect("chunks_exact yields 20 bytes"))
+            .collect();
+
+        let mut files = Vec::new();
+        let mut offset = 0u64;
+        match (info.get("length").and_then(Value::as_int), info.get("files").and_then(Value::as_list)) {
+            (Some(length), _) => {
+                files.push(TorrentFile {
+                    index: 0,
+                    path: PathBuf::from(&name),
+                    length: length.max(0) as u64,
+                    offset: 0,
+                });
+                offset = length.max(0) as u64;
+            }
+            (None, Some(list)) => {
+                for (index, entry) in list.iter().enumerate() {
+                    let length = entry.get("length")
+                        .and_then(Value::as_int)
+                        .ok_or_else(|| invalid("file without length"))?
+                        .max(0) as u64;
+                    let components = entry.get("path.utf-8")
+                        .or_else(|| entry.get("path"))
+                        .and_then(Value::as_list)
+                        .ok_or_else(|| invalid("file without path"))?;
+
+                    // Multi-file torrents live in a folder named after the torrent
+                    let mut path = PathBuf::from(&name);
+                    for component in components.iter().filter_map(Value::as_str).filter_map(sanitize_component) {
+                        path.push(component);
+                    }
+                    files.push(TorrentFile { index, path, length, offset });
+                    offset = offset.checked_add(length).ok_or_else(|| invalid("total size overflows"))?;
+                }
+            }
+            (None, None) => return Err(invalid("neither length nor files given")),
+        }
+
+        let expected_pieces = offset.div_ceil(piece_length);
+        if expected_pieces != pieces.len() as u64 {
+            return Err(invalid("piece count doesn't match total size"));
+        }
+
+        Ok(Self {
+            info_hash,
+            name,
+            piece_length,
+            pieces,
+            files,
+            total: offset,
+            trackers,
+            private: info.get("private").and_then(Value::as_int) == Some(1),
+            info_bytes: info_bytes.to_vec(),
+        })
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len()
+    }
+
+    pub fn piece_size(&self, index: usize) -> u64 {
+        let start = index as u64 * self.piece_length;
+        (self.total - start).min(self.piece_length)
+    }
+
+    /// Pieces overlapping file `index`, as a half-open range.
+    pub fn pieces_for_file(&self, index: usize) -> std::ops::Range<usize> {
+        let file = &self.files[index];
+        if file.length == 0 {
+            return 0..0;
+        }
+        let first = file.offset / self.piece_length;
+        let last = (file.offset + file.length - 1) / self.piece_length;
+        first as usize..last as usize + 1
+    }
+
+    /// Files touched by the byte range `[offset, offset + len)`, as
+    /// `(file index, offset inside the file, length)`.
+    pub fn spans(&self, offset: u64, len: u64) -> Vec<(usize, u64, u64)> {
+        let end = offset + len;
+        self.files.iter()
+            .filter(|f| f.length > 0 && f.offset < end && f.offset + f.length > offset)
+            .map(|f| {
+                let start = offset.max(f.offset);
+                let stop = end.min(f.offset + f.length);
+                (f.index, start - f.offset, stop - start)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    /// Bencoded info dictionary for `files`, each a path and its content.
+    /// A single unnested file makes a single-file torrent.
+    pub(in crate::download::torrent) fn info_dict(name: &str, piece_length: usize, files: &[(&[&str], &[u8])]) -> Vec<u8> {
+        let key = |s: &str| s.as_bytes().to_vec();
+        let data: Vec<u8> = files.iter().flat_map(|(_, content)| content.iter().copied()).collect();
+        let pieces: Vec<u8> = data.chunks(piece_length).flat_map(|c| Sha1::digest(c).to_vec()).collect();
+
+        let mut info = BTreeMap::new();
+        info.insert(key("name"), Value::Bytes(key(name)));
+        info.insert(key("piece length"), Value::Int(piece_length as i64));
+        info.insert(key("pieces"), Value::Bytes(pieces));
+        if let [(path, content)] = files {
+            if path.is_empty() {
+                info.insert(key("length"), Value::Int(content.len() as i64));
+                return Value::Dict(info).encode();
+            }
+        }
+        let list = files.iter()
+            .map(|(path, content)| {
+                let mut file = BTreeMap::new();
+                file.insert(key("length"), Value::Int(content.len() as i64));
+                file.insert(key("path"), Value::List(path.iter().map(|p| Value::Bytes(key(p))).collect()));
+                Value::Dict(file)
+            })
+            .collect();
+        info.insert(key("files"), Value::List(list));
+        Value::Dict(info).encode()
+    }
+
+    #[test]
+    fn sanitizes_components() {
+        assert_eq!(sanitize_component(".."), None);
+        assert_eq!(sanitize_component("."), None);
+        assert_eq!(sanitize_component(" "), None);
+        assert_eq!(sanitize_component("a/b\\c:d").as_deref(), Some("a_b_c_d"));
+        assert_eq!(sanitize_component("file.txt").as_deref(), Some("file.txt"));
+    }
+
+    #[test]
+    fn single_file() {
+        let content = vec![7u8; 40_000];
+        let info = info_dict("single.bin", 16_384, &[(&[], &content)]);
+        let announce = "http://tracker/ann";
+        let mut torrent = format!("d8:announce{}:{}4:info", announce.len(), announce).into_bytes();
+        torrent.extend_from_slice(&info);
+        torrent.push(b'e');
+
+        let meta = Metainfo::parse(&torrent).unwrap();
+        assert_eq!(meta.info_hash, <[u8; 20]>::from(Sha1::digest(&info)));
+        assert_eq!(meta.trackers, vec![vec!["http://tracker/ann".to_string()]]);
+        assert_eq!((meta.total, meta.piece_count()), (40_000, 3));
+        assert_eq!(meta.piece_size(2), 40_000 - 2 * 16_384);
+        assert_eq!(meta.files[0].path, PathBuf::from("single.bin"));
+        assert_eq!(meta.info_bytes, info);
+    }
+
+    #[test]
+    fn multi_file_paths_and_spans() {
+        let info = info_dict("demo", 100, &[
+            (&["..", "etc", "passwd"], &[1; 150]),
+            (&["empty"], &[]),
+            (&["sub", "b.bin"], &[2; 120]),
+        ]);
+        let meta = Metainfo::from_info(&info, Vec::new()).unwrap();
+        let paths: Vec<PathBuf> = meta.files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(paths, [PathBuf::from("demo/etc/passwd"), PathBuf::from("demo/empty"), PathBuf::from("demo/sub/b.bin")]);
+        assert_eq!(meta.pieces_for_file(0), 0..2);
+        assert_eq!(meta.pieces_for_file(1), 0..0);
+        assert_eq!(meta.pieces_for_file(2), 1..3);
+        assert_eq!(meta.spans(140, 20), vec![(0, 140, 10), (2, 0, 10)]);
+    }
+
+    #[test]
+    fn piece_count_must_match() {
+        let mut info = bencode::decode(&info_dict("x", 100, &[(&[], &[0; 250])])).unwrap();
+        if let Value::Dict(map) = &mut info {
+            map.insert(b"length".to_vec(), Value::Int(350));
+        }
+        let err = Metainfo::from_info(&info.encode(), Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("piece count"), "{}", err);
+
+        let bad_hashes = b"d6:lengthi1e4:name1:x12:piece lengthi1e6:pieces3:abce";
+        assert!(Metainfo::from_info(bad_hashes, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn rejects_huge_pieces() {
+        let mut info = bencode::decode(&info_dict("x", 100, &[(&[], &[0; 250])])).unwrap();
+        if let Value::Dict(map) = &mut info {
+            map.insert(b"piece length".to_vec(), Value::Int(1 << 40));
+        }
+        let err = Metainfo::from_info(&info.encode(), Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("piece length"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_overflowing_sizes() {
+        let mut info = bencode::decode(&info_dict("x", 100, &[(&["a"], &[0; 100])])).unwrap();
+        if let Value::Dict(map) = &mut info {
+            let file = |name: &str| {
+                let mut file = BTreeMap::new();
+                file.insert(b"length".to_vec(), Value::Int(i64::MAX));
+                file.insert(b"path".to_vec(), Value::List(vec![Value::Bytes(name.as_bytes().to_vec())]));
+                Value::Dict(file)
+            };
+            map.insert(b"files".to_vec(), Value::List(vec![file("a"), file("b"), file("c")]));
+        }
+        let err = Metainfo::from_info(&info.encode(), Vec::new()).unwrap_err();
+        assert!(err.to_string().contains("overflows"), "{}", err);
+    }
+}
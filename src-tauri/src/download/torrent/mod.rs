@@ -0,0 +1,471 @@
+pub mod bencode;
+pub mod dht;
+pub mod files;
+pub mod magnet;
+pub mod metadata;
+pub mod metainfo;
+pub mod peer;
+pub mod session;
+pub mod tracker;
+
+use crate::download::http::HttpHelper;
+use crate::download::{DownloadContext, DownloadError, DownloadMeta, DownloadResult, Downloader};
+use crate::storage::{self, DownloadType};
+use async_trait::async_trait;
+use files::TorrentFiles;
+use magnet::MagnetLink;
+use metainfo::{InfoHash, Metainfo, TorrentFile};
+use serde::{Deserialize, Serialize};
+use session::{Session, Swarm};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tauri::Manager;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::AbortHandle;
+
+/// How long a magnet link may take to produce its metadata
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(120);
+/// `left` reported to trackers while the size is still unknown
+const METADATA_PLACEHOLDER_LEFT: u64 = 16 * 1024;
+/// How long metadata resolved for an inspected magnet link is kept
+const RESOLVED_TTL: Duration = Duration::from_secs(30 * 60);
+/// Most inspected magnet links whose metadata is kept at once
+const RESOLVED_LIMIT: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TorrentSettings {
+    /// Port for incoming peer connections; a random one is used if it's taken
+    pub listen_port: u16,
+    pub max_peers: usize,
+    /// Stop seeding once uploaded bytes reach this multiple of the download
+    /// size. `None` seeds until paused, `0` doesn't seed at all.
+    pub seed_ratio_limit: Option<f64>,
+    /// Find peers through the mainline DHT in addition to trackers
+    pub dht: bool,
+}
+
+impl Default for TorrentSettings {
+    fn default() -> Self {
+        Self {
+            listen_port: 6881,
+            max_peers: 50,
+            seed_ratio_limit: Some(1.0),
+            dht: true,
+        }
+    }
+}
+
+/// File list shown before a torrent is added, so the user can pick files.
+#[derive(Serialize)]
+pub struct TorrentInfo {
+    pub name: String,
+    pub info_hash: String,
+    pub total: u64,
+    pub files: Vec<TorrentFile>,
+}
+
+impl From<&Metainfo> for TorrentInfo {
+    fn from(meta: &Metainfo) -> Self {
+        Self {
+            name: meta.name.clone(),
+            info_hash: hex::encode(meta.info_hash),
+            total: meta.total,
+            files: meta.files.clone(),
+        }
+    }
+}
+
+/// Torrents that finished downloading and are still seeding. The manager's
+/// task for them has already completed, so pausing or removing goes through here.
+static SEEDERS: LazyLock<Mutex<HashMap<String, AbortHandle>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Stops seeding `id`; returns false if it wasn't seeding.
+pub fn stop_seeding(id: &str) -> bool {
+    let handle = SEEDERS.lock().ok().and_then(|mut seeders| seeders.remove(id));
+    match handle {
+        Some(handle) => {
+            handle.abort();
+            eprintln!("[Torrent] Stopped seeding {}", id);
+            true
+        }
+        None => false,
+    }
+}
+
+fn unregister_seeder(id: &str) {
+    if let Ok(mut seeders) = SEEDERS.lock() {
+        seeders.remove(id);
+    }
+}
+
+/// Metadata resolved by `inspect_torrent` for a magnet link, kept until the
+/// download is actually started so it isn't fetched twice.
+static RESOLVED: LazyLock<Mutex<ResolvedCache<Metainfo>>> =
+    LazyLock::new(|| Mutex::new(ResolvedCache::new(RESOLVED_TTL, RESOLVED_LIMIT)));
+
+/// Links that are inspected but never downloaded would otherwise stay for
+/// the life of the app, so entries expire after `ttl` and the oldest is
+/// dropped once `limit` are held.
+struct ResolvedCache<V> {
+    entries: HashMap<InfoHash, (Instant, V)>,
+    ttl: Duration,
+    limit: usize,
+}
+
+impl<V: Clone> ResolvedCache<V> {
+    fn new(ttl: Duration, limit: usize) -> Self {
+        Self { entries: HashMap::new(), ttl, limit }
+    }
+
+    fn get(&mut self, info_hash: &InfoHash) -> Option<V> {
+        self.expire();
+        self.entries.get(info_hash).map(|(_, value)| value.clone())
+    }
+
+    fn insert(&mut self, info_hash: InfoHash, value: V) {
+        self.expire();
+        if self.entries.len() >= self.limit && !self.entries.contains_key(&info_hash) {
+            let oldest = self.entries.iter().min_by_key(|(_, (added, _))| *added).map(|(hash, _)| *hash);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(info_hash, (Instant::now(), value));
+    }
+
+    fn remove(&mut self, info_hash: &InfoHash) {
+        self.entries.remove(info_hash);
+    }
+
+    fn expire(&mut self) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, (added, _)| added.elapsed() < ttl);
+    }
+}
+
+/// Gets the info dictionary for a magnet link from peers found through its
+/// `x.pe` hints, its trackers and the DHT.
+pub async fn resolve_magnet(link: &MagnetLink, http: &HttpHelper) -> Result<(Metainfo, Vec<SocketAddr>), DownloadError> {
+    if let Some(meta) = RESOLVED.lock().ok().and_then(|mut cache| cache.get(&link.info_hash)) {
+        return Ok((meta, Vec::new()));
+    }
+    eprintln!("[Magnet] Resolving {}", hex::encode(link.info_hash));
+
+    let peer_id = peer::generate_peer_id();
+    let (peer_tx, peer_rx) = mpsc::unbounded_channel();
+    for addr in &link.peers {
+        let _ = peer_tx.send(*addr);
+    }
+
+    let trackers = link.trackers.clone();
+    let info_hash = link.info_hash;
+    let tracker_peers = peer_tx.clone();
+    let tracker_http = http.clone();
+    let tracker_task = tokio::spawn(async move {
+        for url in trackers {
+            let params = tracker::Announce {
+                info_hash: &info_hash,
+                peer_id: &peer_id,
+                port: TorrentSettings::default().listen_port,
+                uploaded: 0,
+                downloaded: 0,
+                // Size is unknown until the metadata arrives; some trackers
+                // hide seeders from peers reporting nothing left
+                left: METADATA_PLACEHOLDER_LEFT,
+                event: tracker::AnnounceEvent::Started,
+            };
+            match tracker::announce(&url, &params, &tracker_http).await {
+                Ok(response) => {
+                    for addr in response.peers {
+                        let _ = tracker_peers.send(addr);
+                    }
+                }
+                Err(e) => eprintln!("[Magnet] Announce to {} failed: {}", url, e),
+            }
+        }
+    });
+    let dht_task = tokio::spawn(dht::lookup_loop(link.info_hash, None, peer_tx));
+
+    let result = metadata::fetch(link.info_hash, peer_id, peer_rx, RESOLVE_TIMEOUT).await;
+    tracker_task.abort();
+    dht_task.abort();
+
+    let (info, peers) = result?;
+    let meta = Metainfo::from_info(&info, link.tracker_tiers())?;
+    if let Ok(mut cache) = RESOLVED.lock() {
+        cache.insert(link.info_hash, meta.clone());
+    }
+    Ok((meta, peers))
+}
+
+/// Aborts the session if the download is paused before it completes.
+struct SessionGuard(Option<AbortHandle>);
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        if let Some(handle) = self.0.take() {
+            handle.abort();
+        }
+    }
+}
+
+pub struct TorrentDownloader;
+
+impl TorrentDownloader {
+    /// Reads a `.torrent` from an http(s) URL, a `file://` URL or a local path.
+    pub async fn fetch(url: &str, http: &HttpHelper) -> Result<Metainfo, DownloadError> {
+        let data = if url.starts_with("http://") || url.starts_with("https://") {
+            let response = http.send(http.client().get(url))
+                .await
+                .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+            if !response.status().is_success() {
+                return Err(DownloadError::from_status(&response));
+            }
+            response.bytes()
+                .await
+                .map_err(|e| DownloadError::NetworkError(e.to_string()))?
+                .to_vec()
+        } else {
+            let path = url.strip_prefix("file://").unwrap_or(url);
+            tokio::fs::read(path)
+                .await
+                .map_err(|e| DownloadError::IoError(e.to_string()))?
+        };
+        Metainfo::parse(&data)
+    }
+
+    /// Keeps the info dictionary under the app data folder, so resuming and
+    /// seeding don't depend on the original URL still working.
+    fn cache_path(ctx: &DownloadContext) -> Result<PathBuf, DownloadError> {
+        let dir = ctx.app.path()
+            .app_data_dir()
+            .map_err(|e| DownloadError::IoError(e.to_string()))?;
+        Ok(dir.join("torrents").join(format!("{}.torrent", ctx.id)))
+    }
+
+    pub async fn save_cached(ctx: &DownloadContext, meta: &Metainfo) -> Result<(), DownloadError> {
+        let path = Self::cache_path(ctx)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| DownloadError::IoError(e.to_string()))?;
+        }
+
+        // A minimal .torrent: the original info bytes plus the trackers
+        let trackers = bencode::Value::List(meta.trackers.iter()
+            .map(|tier| bencode::Value::List(tier.iter()
+                .map(|url| bencode::Value::Bytes(url.as_bytes().to_vec()))
+                .collect()))
+            .collect());
+        let mut data = b"d13:announce-list".to_vec();
+        data.extend_from_slice(&trackers.encode());
+        data.extend_from_slice(b"4:info");
+        data.extend_from_slice(&meta.info_bytes);
+        data.push(b'e');
+
+        tokio::fs::write(&path, data)
+            .await
+            .map_err(|e| DownloadError::IoError(e.to_string()))
+    }
+
+    pub async fn load_cached(ctx: &DownloadContext) -> Option<Metainfo> {
+        let data = tokio::fs::read(Self::cache_path(ctx).ok()?).await.ok()?;
+        Metainfo::parse(&data).ok()
+    }
+
+    /// Runs the swarm until the selected files are complete, then leaves it
+    /// seeding in the background.
+    pub async fn start(
+        ctx: &DownloadContext,
+        meta: Metainfo,
+        extra_peers: Option<mpsc::UnboundedReceiver<SocketAddr>>,
+    ) -> DownloadResult<()> {
+        let settings = storage::load_settings(&ctx.app)
+            .map(|s| s.torrent)
+            .unwrap_or_default();
+        let root = Path::new(&ctx.save_path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        let dht = settings.dht && !meta.private;
+        let meta = Arc::new(meta);
+        let swarm = Arc::new(Swarm::new(
+            meta.clone(),
+            TorrentFiles::new(root.clone(), meta.clone()),
+            ctx.files.as_deref(),
+            ctx.limiter.clone(),
+        ));
+        ctx.history.update(&ctx.id, |item| {
+            item.path = root.to_string_lossy().to_string();
+            item.filename = meta.name.clone();
+            item.total = Some(swarm.wanted_total());
+        });
+        swarm.recheck().await;
+
+        let session = Session {
+            id: ctx.id.clone(),
+            app: ctx.app.clone(),
+            http: ctx.http.clone(),
+            history: ctx.history.clone(),
+            swarm,
+            settings,
+            dht,
+            peers: extra_peers,
+        };
+        let (done_tx, done_rx) = oneshot::channel();
+        let task = tokio::spawn(session.run(done_tx));
+        let mut guard = SessionGuard(Some(task.abort_handle()));
+
+        match done_rx.await {
+            Ok(Ok(())) => {
+                let handle = guard.0.take().expect("guard holds the session");
+                if !task.is_finished() {
+                    if let Ok(mut seeders) = SEEDERS.lock() {
+                        seeders.insert(ctx.id.clone(), handle);
+                    }
+                }
+                Ok(())
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(DownloadError::Other("Torrent session ended unexpectedly".to_string())),
+        }
+    }
+}
+
+#[async_trait]
+impl Downloader for TorrentDownloader {
+    fn download_type(&self) -> DownloadType {
+        DownloadType::Torrent
+    }
+
+    fn detect(&self, url: &str) -> bool {
+        let lower = url.to_ascii_lowercase();
+        let path = lower.split(['?', '#']).next().unwrap_or_default();
+        path.ends_with(".torrent")
+    }
+
+    async fn analyze(&self, url: &str, http: &HttpHelper) -> DownloadResult<Option<DownloadMeta>> {
+        if !self.detect(url) {
+            return Ok(None);
+        }
+        let meta = Self::fetch(url, http).await?;
+        eprintln!("[Torrent] {} is {} ({} files, {} bytes)", url, meta.name, meta.files.len(), meta.total);
+
+        Ok(Some(DownloadMeta {
+            download_type: DownloadType::Torrent,
+            direct_url: url.to_string(),
+            original_url: None,
+            suggested_filename: Some(meta.name),
+            ..Default::default()
+        }))
+    }
+
+    async fn run(&self, ctx: DownloadContext) -> DownloadResult<()> {
+        let meta = match Self::load_cached(&ctx).await {
+            Some(meta) => meta,
+            None => {
+                let meta = Self::fetch(&ctx.url, &ctx.http).await?;
+                if let Err(e) = Self::save_cached(&ctx, &meta).await {
+                    eprintln!("[Torrent] Failed to cache metainfo: {}", e);
+                }
+                meta
+            }
+        };
+        eprintln!("[Torrent] Starting {}: {}", ctx.id, meta.name);
+        Self::start(&ctx, meta, None).await
+    }
+}
+
+pub struct MagnetDownloader;
+
+#[async_trait]
+impl Downloader for MagnetDownloader {
+    fn download_type(&self) -> DownloadType {
+        DownloadType::Magnet
+    }
+
+    fn detect(&self, url: &str) -> bool {
+        magnet::is_magnet(url)
+    }
+
+    async fn analyze(&self, url: &str, _http: &HttpHelper) -> DownloadResult<Option<DownloadMeta>> {
+        if !self.detect(url) {
+            return Ok(None);
+        }
+        let link = MagnetLink::parse(url)?;
+
+        Ok(Some(DownloadMeta {
+            download_type: DownloadType::Magnet,
+            direct_url: url.to_string(),
+            original_url: None,
+            suggested_filename: link.name,
+            ..Default::default()
+        }))
+    }
+
+    async fn run(&self, ctx: DownloadContext) -> DownloadResult<()> {
+        let link = MagnetLink::parse(&ctx.url)?;
+        let (meta, peers) = match TorrentDownloader::load_cached(&ctx).await {
+            Some(meta) => (meta, Vec::new()),
+            None => {
+                let (meta, peers) = resolve_magnet(&link, &ctx.http).await?;
+                if let Err(e) = TorrentDownloader::save_cached(&ctx, &meta).await {
+                    eprintln!("[Torrent] Failed to cache metainfo: {}", e);
+                }
+                if let Ok(mut cache) = RESOLVED.lock() {
+                    cache.remove(&link.info_hash);
+                }
+                (meta, peers)
+            }
+        };
+
+        // Peers that had the metadata most likely have the data as well
+        let (peer_tx, peer_rx) = mpsc::unbounded_channel();
+        for addr in peers {
+            let _ = peer_tx.send(addr);
+        }
+        eprintln!("[Torrent] Starting {} from magnet: {}", ctx.id, meta.name);
+        TorrentDownloader::start(&ctx, meta, Some(peer_rx)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolved_cache_is_bounded() {
+        let mut cache = ResolvedCache::new(Duration::from_secs(60), 2);
+        cache.insert([1; 20], "a");
+        std::thread::sleep(Duration::from_millis(2));
+        cache.insert([2; 20], "b");
+        std::thread::sleep(Duration::from_millis(2));
+        // Replacing an entry doesn't evict another
+        cache.insert([2; 20], "b2");
+        assert_eq!(cache.get(&[1; 20]), Some("a"));
+        assert_eq!(cache.get(&[2; 20]), Some("b2"));
+
+        cache.insert([3; 20], "c");
+        assert_eq!(cache.get(&[1; 20]), None);
+        assert_eq!(cache.get(&[2; 20]), Some("b2"));
+        assert_eq!(cache.get(&[3; 20]), Some("c"));
+
+        cache.remove(&[3; 20]);
+        assert_eq!(cache.get(&[3; 20]), None);
+    }
+
+    #[test]
+    fn resolved_cache_expires() {
+        let mut cache = ResolvedCache::new(Duration::from_millis(20), 8);
+        cache.insert([1; 20], 1);
+        assert_eq!(cache.get(&[1; 20]), Some(1));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(cache.get(&[1; 20]), None);
+        assert!(cache.entries.is_empty());
+    }
+}
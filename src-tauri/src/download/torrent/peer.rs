@@ -0,0 +1,276 @@
+use super::bencode::Value;
+use super::metainfo::InfoHash;
+use crate::download::DownloadError;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const PROTOCOL: &[u8] = b"BitTorrent protocol";
+/// Anything larger than a bitfield for a huge torrent or a 16 KiB block is
+/// treated as a broken or hostile peer
+const MAX_MESSAGE_LEN: usize = 2 * 1024 * 1024;
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Reserved bit 20 from the right: the peer speaks the extension protocol (BEP 10)
+const EXTENSION_BIT: (usize, u8) = (5, 0x10);
+/// Our id for `ut_metadata` messages, announced in the extended handshake
+pub const UT_METADATA_ID: u8 = 1;
+/// Metadata is exchanged in pieces of this size (BEP 9)
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request { index: u32, begin: u32, length: u32 },
+    Piece { index: u32, begin: u32, data: Vec<u8> },
+    Cancel { index: u32, begin: u32, length: u32 },
+    Extended { id: u8, payload: Vec<u8> },
+    /// DHT port and anything newer we don't act on
+    Other,
+}
+
+fn be_u32(data: &[u8], at: usize) -> Result<u32, DownloadError> {
+    data.get(at..at + 4)
+        .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| DownloadError::NetworkError("Truncated peer message".to_string()))
+}
+
+impl Message {
+    fn decode(payload: &[u8]) -> Result<Self, DownloadError> {
+        let Some((&kind, body)) = payload.split_first() else {
+            return Ok(Message::KeepAlive);
+        };
+        Ok(match kind {
+            0 => Message::Choke,
+            1 => Message::Unchoke,
+            2 => Message::Interested,
+            3 => Message::NotInterested,
+            4 => Message::Have(be_u32(body, 0)?),
+            5 => Message::Bitfield(body.to_vec()),
+            6 => Message::Request { index: be_u32(body, 0)?, begin: be_u32(body, 4)?, length: be_u32(body, 8)? },
+            7 => Message::Piece {
+                index: be_u32(body, 0)?,
+                begin: be_u32(body, 4)?,
+                data: body.get(8..).unwrap_or_default().to_vec(),
+            },
+            8 => Message::Cancel { index: be_u32(body, 0)?, begin: be_u32(body, 4)?, length: be_u32(body, 8)? },
+            20 if !body.is_empty() => Message::Extended { id: body[0], payload: body[1..].to_vec() },
+            _ => Message::Other,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            Message::KeepAlive | Message::Other => {}
+            Message::Choke => body.push(0),
+            Message::Unchoke => body.push(1),
+            Message::Interested => body.push(2),
+            Message::NotInterested => body.push(3),
+            Message::Have(index) => {
+                body.push(4);
+                body.extend_from_slice(&index.to_be_bytes());
+            }
+            Message::Bitfield(bits) => {
+                body.push(5);
+                body.extend_from_slice(bits);
+            }
+            Message::Request { index, begin, length } | Message::Cancel { index, begin, length } => {
+                body.push(if matches!(self, Message::Request { .. }) { 6 } else { 8 });
+                body.extend_from_slice(&index.to_be_bytes());
+                body.extend_from_slice(&begin.to_be_bytes());
+                body.extend_from_slice(&length.to_be_bytes());
+            }
+            Message::Piece { index, begin, data } => {
+                body.push(7);
+                body.extend_from_slice(&index.to_be_bytes());
+                body.extend_from_slice(&begin.to_be_bytes());
+                body.extend_from_slice(data);
+            }
+            Message::Extended { id, payload } => {
+                body.push(20);
+                body.push(*id);
+                body.extend_from_slice(payload);
+            }
+        }
+        let mut framed = (body.len() as u32).to_be_bytes().to_vec();
+        framed.extend_from_slice(&body);
+        framed
+    }
+}
+
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Message, DownloadError> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(DownloadError::NetworkError(format!("Peer sent a {} byte message", len)));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+    Message::decode(&payload)
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, message: &Message) -> Result<(), DownloadError> {
+    writer.write_all(&message.encode())
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))
+}
+
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: InfoHash,
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_BIT.0] & EXTENSION_BIT.1 != 0
+    }
+}
+
+/// Extension handshake advertising `ut_metadata`, plus the metadata size
+/// when we have it to share.
+pub fn extended_handshake(metadata_size: Option<usize>) -> Message {
+    let mut m = BTreeMap::new();
+    m.insert(b"ut_metadata".to_vec(), Value::Int(UT_METADATA_ID as i64));
+    let mut dict = BTreeMap::new();
+    dict.insert(b"m".to_vec(), Value::Dict(m));
+    dict.insert(b"v".to_vec(), Value::Bytes(b"Fastah".to_vec()));
+    if let Some(size) = metadata_size {
+        dict.insert(b"metadata_size".to_vec(), Value::Int(size as i64));
+    }
+    Message::Extended { id: 0, payload: Value::Dict(dict).encode() }
+}
+
+/// A `ut_metadata` message: `msg_type` 0 requests, 1 carries data, 2 rejects.
+pub fn metadata_message(their_id: u8, msg_type: i64, piece: usize, data: Option<(&[u8], usize)>) -> Message {
+    let mut dict = BTreeMap::new();
+    dict.insert(b"msg_type".to_vec(), Value::Int(msg_type));
+    dict.insert(b"piece".to_vec(), Value::Int(piece as i64));
+    if let Some((_, total)) = data {
+        dict.insert(b"total_size".to_vec(), Value::Int(total as i64));
+    }
+    let mut payload = Value::Dict(dict).encode();
+    if let Some((bytes, _)) = data {
+        payload.extend_from_slice(bytes);
+    }
+    Message::Extended { id: their_id, payload }
+}
+
+pub async fn write_handshake<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    info_hash: &InfoHash,
+    peer_id: &[u8; 20],
+) -> Result<(), DownloadError> {
+    let mut reserved = [0u8; 8];
+    reserved[EXTENSION_BIT.0] |= EXTENSION_BIT.1;
+
+    let mut buf = Vec::with_capacity(68);
+    buf.push(PROTOCOL.len() as u8);
+    buf.extend_from_slice(PROTOCOL);
+    buf.extend_from_slice(&reserved);
+    buf.extend_from_slice(info_hash);
+    buf.extend_from_slice(peer_id);
+    writer.write_all(&buf)
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))
+}
+
+pub async fn read_handshake<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Handshake, DownloadError> {
+    let mut buf = [0u8; 68];
+    reader.read_exact(&mut buf)
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+    if buf[0] as usize != PROTOCOL.len() || &buf[1..20] != PROTOCOL {
+        return Err(DownloadError::NetworkError("Not a BitTorrent peer".to_string()));
+    }
+    Ok(Handshake {
+        reserved: buf[20..28].try_into().unwrap(),
+        info_hash: buf[28..48].try_into().unwrap(),
+        peer_id: buf[48..68].try_into().unwrap(),
+    })
+}
+
+/// Random peer id in Azureus style, `-FD0100-` followed by 12 random bytes.
+pub fn generate_peer_id() -> [u8; 20] {
+    let mut id = [0u8; 20];
+    id[..8].copy_from_slice(b"-FD0100-");
+    for byte in id[8..].iter_mut() {
+        *byte = rand::random();
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(message: Message) -> Message {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &message).await.unwrap();
+        read_message(&mut buf.as_slice()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn message_framing() {
+        assert!(matches!(round_trip(Message::KeepAlive).await, Message::KeepAlive));
+        assert!(matches!(round_trip(Message::Unchoke).await, Message::Unchoke));
+        assert!(matches!(round_trip(Message::Have(7)).await, Message::Have(7)));
+        assert!(matches!(
+            round_trip(Message::Request { index: 1, begin: 16384, length: 16384 }).await,
+            Message::Request { index: 1, begin: 16384, length: 16384 }
+        ));
+        assert!(matches!(
+            round_trip(Message::Cancel { index: 1, begin: 2, length: 3 }).await,
+            Message::Cancel { index: 1, begin: 2, length: 3 }
+        ));
+        let Message::Piece { index: 3, begin: 0, data } = round_trip(Message::Piece { index: 3, begin: 0, data: vec![9; 100] }).await else {
+            panic!("not a piece");
+        };
+        assert_eq!(data, vec![9; 100]);
+        let Message::Extended { id: 2, payload } = round_trip(Message::Extended { id: 2, payload: b"de".to_vec() }).await else {
+            panic!("not an extended message");
+        };
+        assert_eq!(payload, b"de");
+
+        let mut buf = Vec::new();
+        write_message(&mut buf, &Message::Bitfield(vec![0xf0, 0x01])).await.unwrap();
+        assert_eq!(buf, [0, 0, 0, 3, 5, 0xf0, 0x01]);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_messages() {
+        let oversized = ((MAX_MESSAGE_LEN + 1) as u32).to_be_bytes();
+        assert!(read_message(&mut &oversized[..]).await.is_err());
+        // `Have` without its index
+        assert!(read_message(&mut &[0, 0, 0, 2, 4, 0][..]).await.is_err());
+        // Cut off mid-message
+        assert!(read_message(&mut &[0, 0, 0, 5, 4][..]).await.is_err());
+        assert!(matches!(read_message(&mut &[0, 0, 0, 3, 9, 0x1a, 0xe1][..]).await, Ok(Message::Other)));
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let mut buf = Vec::new();
+        let peer_id = generate_peer_id();
+        write_handshake(&mut buf, &[5; 20], &peer_id).await.unwrap();
+        assert_eq!(buf.len(), 68);
+        let handshake = read_handshake(&mut buf.as_slice()).await.unwrap();
+        assert!(handshake.supports_extensions());
+        assert_eq!((handshake.info_hash, handshake.peer_id), ([5; 20], peer_id));
+        assert!(peer_id.starts_with(b"-FD0100-"));
+
+        buf[1] = b'X';
+        assert!(read_handshake(&mut buf.as_slice()).await.is_err());
+    }
+}
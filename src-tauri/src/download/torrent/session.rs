@@ -0,0 +1,880 @@
+use super::bencode::{self, Value};
+use super::dht;
+use super::files::TorrentFiles;
+use super::metainfo::Metainfo;
+use super::peer::{self, Handshake, Message, METADATA_PIECE_SIZE, UT_METADATA_ID};
+use super::tracker::{self, Announce, AnnounceEvent};
+use super::TorrentSettings;
+use crate::download::http::HttpHelper;
+use crate::download::manager::ProgressEvent;
+use crate::download::ratelimit::RateLimiter;
+use crate::download::DownloadError;
+use crate::storage::history::HistoryStore;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+
+const BLOCK_SIZE: u32 = 16 * 1024;
+/// Outstanding block requests per peer
+const PIPELINE: usize = 8;
+/// Largest block we serve; requests above this are a protocol violation
+const MAX_REQUEST: u32 = 128 * 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// A peer that unchoked us but sends nothing for this long is dropped
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(90);
+/// Peers sending this many corrupt pieces are disconnected
+const MAX_HASH_FAILURES: u32 = 3;
+const TICK: Duration = Duration::from_millis(500);
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct PieceState {
+    /// Verified and written to disk
+    have: Vec<bool>,
+    wanted: Vec<bool>,
+    /// Pieces being fetched and by how many peers (more than one in endgame)
+    active: HashMap<usize, usize>,
+    /// How many connected peers have each piece, for rarest-first
+    availability: Vec<u32>,
+    /// Pieces in the order they completed; peers announce them with `Have`
+    completed: Vec<u32>,
+}
+
+/// State shared by every connection of one torrent.
+pub struct Swarm {
+    pub meta: Arc<Metainfo>,
+    pub files: TorrentFiles,
+    pub peer_id: [u8; 20],
+    limiter: RateLimiter,
+    pieces: Mutex<PieceState>,
+    /// Verified bytes of wanted pieces
+    downloaded: AtomicU64,
+    /// Raw payload bytes received this session, for speed and tracker stats
+    received: AtomicU64,
+    uploaded: AtomicU64,
+    wanted_total: u64,
+}
+
+impl Swarm {
+    /// `selection` lists file indexes to download; `None` or an empty list
+    /// means everything.
+    pub fn new(meta: Arc<Metainfo>, files: TorrentFiles, selection: Option<&[usize]>, limiter: RateLimiter) -> Self {
+        let count = meta.piece_count();
+        let mut wanted = vec![false; count];
+        let selected: Vec<usize> = match selection {
+            Some(list) if !list.is_empty() => list.iter().copied().filter(|&i| i < meta.files.len()).collect(),
+            _ => (0..meta.files.len()).collect(),
+        };
+        for file in selected {
+            for piece in meta.pieces_for_file(file) {
+                wanted[piece] = true;
+            }
+        }
+        let wanted_total = (0..count).filter(|&i| wanted[i]).map(|i| meta.piece_size(i)).sum();
+
+        Self {
+            files,
+            peer_id: peer::generate_peer_id(),
+            limiter,
+            pieces: Mutex::new(PieceState {
+                have: vec![false; count],
+                wanted,
+                availability: vec![0; count],
+                ..Default::default()
+            }),
+            downloaded: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            uploaded: AtomicU64::new(0),
+            wanted_total,
+            meta,
+        }
+    }
+
+    /// Hashes whatever is already on disk so a resumed torrent only fetches
+    /// what is missing.
+    pub async fn recheck(&self) {
+        let mut found = 0;
+        for index in 0..self.meta.piece_count() {
+            if self.files.verify_piece(index).await {
+                self.mark_have(index);
+                found += 1;
+            }
+        }
+        if found > 0 {
+            eprintln!("[Torrent] Recheck found {} of {} pieces on disk", found, self.meta.piece_count());
+        }
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn wanted_total(&self) -> u64 {
+        self.wanted_total
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.pieces.lock()
+            .map(|p| p.wanted.iter().zip(&p.have).all(|(&w, &h)| !w || h))
+            .unwrap_or(false)
+    }
+
+    fn has_piece(&self, index: usize) -> bool {
+        self.pieces.lock().map(|p| p.have.get(index).copied().unwrap_or(false)).unwrap_or(false)
+    }
+
+    fn mark_have(&self, index: usize) {
+        if let Ok(mut p) = self.pieces.lock() {
+            if p.have[index] {
+                return;
+            }
+            p.have[index] = true;
+            p.active.remove(&index);
+            p.completed.push(index as u32);
+            if p.wanted[index] {
+                self.downloaded.fetch_add(self.meta.piece_size(index), Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn release(&self, index: usize) {
+        if let Ok(mut p) = self.pieces.lock() {
+            if let Some(count) = p.active.get_mut(&index) {
+                *count -= 1;
+                if *count == 0 {
+                    p.active.remove(&index);
+                }
+            }
+        }
+    }
+
+    /// Rarest wanted piece the peer has that nobody is fetching yet. Once
+    /// every missing piece is in flight (endgame) pieces are shared between
+    /// peers and the first verified copy wins.
+    fn pick(&self, peer_has: &[bool]) -> Option<usize> {
+        let mut p = self.pieces.lock().ok()?;
+        let candidates = || (0..p.have.len())
+            .filter(|&i| p.wanted[i] && !p.have[i] && peer_has.get(i).copied().unwrap_or(false));
+
+        let fresh = candidates()
+            .filter(|i| !p.active.contains_key(i))
+            .min_by_key(|&i| p.availability[i]);
+        let choice = fresh.or_else(|| candidates().min_by_key(|i| p.active.get(i).copied().unwrap_or(0)))?;
+
+        *p.active.entry(choice).or_insert(0) += 1;
+        Some(choice)
+    }
+
+    fn wants_from(&self, peer_has: &[bool]) -> bool {
+        self.pieces.lock()
+            .map(|p| (0..p.have.len()).any(|i| p.wanted[i] && !p.have[i] && peer_has.get(i).copied().unwrap_or(false)))
+            .unwrap_or(false)
+    }
+
+    fn add_availability(&self, index: usize, delta: i32) {
+        if let Ok(mut p) = self.pieces.lock() {
+            if let Some(count) = p.availability.get_mut(index) {
+                *count = count.saturating_add_signed(delta);
+            }
+        }
+    }
+
+    fn bitfield(&self) -> Option<Vec<u8>> {
+        let p = self.pieces.lock().ok()?;
+        if !p.have.iter().any(|&h| h) {
+            return None;
+        }
+        let mut bits = vec![0u8; p.have.len().div_ceil(8)];
+        for (i, _) in p.have.iter().enumerate().filter(|(_, &h)| h) {
+            bits[i / 8] |= 0x80 >> (i % 8);
+        }
+        Some(bits)
+    }
+
+    fn completed_since(&self, cursor: usize) -> Vec<u32> {
+        self.pieces.lock()
+            .map(|p| p.completed.get(cursor..).unwrap_or_default().to_vec())
+            .unwrap_or_default()
+    }
+}
+
+struct PieceJob {
+    index: usize,
+    buf: Vec<u8>,
+    next_offset: u32,
+    pending: HashSet<u32>,
+}
+
+struct PeerConn {
+    swarm: Arc<Swarm>,
+    writer: OwnedWriteHalf,
+    has: Vec<bool>,
+    peer_choking: bool,
+    am_choking: bool,
+    am_interested: bool,
+    job: Option<PieceJob>,
+    have_cursor: usize,
+    last_block: Instant,
+    last_sent: Instant,
+    hash_failures: u32,
+    /// The peer's id for `ut_metadata`, once it sent its extension handshake
+    metadata_id: Option<u8>,
+}
+
+impl PeerConn {
+    async fn send(&mut self, message: Message) -> Result<(), DownloadError> {
+        self.last_sent = Instant::now();
+        peer::write_message(&mut self.writer, &message).await
+    }
+
+    async fn handle(&mut self, message: Message) -> Result<(), DownloadError> {
+        match message {
+            Message::Choke => {
+                // The peer drops our outstanding requests when it chokes
+                self.peer_choking = true;
+                if let Some(job) = self.job.take() {
+                    self.swarm.release(job.index);
+                }
+            }
+            Message::Unchoke => {
+                self.peer_choking = false;
+                self.last_block = Instant::now();
+            }
+            Message::Interested if self.am_choking && self.swarm.bitfield().is_some() => {
+                self.am_choking = false;
+                self.send(Message::Unchoke).await?;
+            }
+            Message::Have(index) => {
+                let index = index as usize;
+                if index < self.has.len() && !self.has[index] {
+                    self.has[index] = true;
+                    self.swarm.add_availability(index, 1);
+                }
+                self.update_interest().await?;
+            }
+            Message::Bitfield(bits) => {
+                for index in 0..self.has.len() {
+                    let set = bits.get(index / 8).map(|b| b & (0x80 >> (index % 8)) != 0).unwrap_or(false);
+                    if set && !self.has[index] {
+                        self.has[index] = true;
+                        self.swarm.add_availability(index, 1);
+                    }
+                }
+                self.update_interest().await?;
+            }
+            Message::Request { index, begin, length } => self.serve(index, begin, length).await?,
+            Message::Piece { index, begin, data } => self.receive(index, begin, data).await?,
+            Message::Extended { id: 0, payload } => {
+                self.metadata_id = bencode::decode(&payload).ok()
+                    .and_then(|d| d.get("m")?.get("ut_metadata")?.as_int())
+                    .filter(|&id| id > 0 && id < 256)
+                    .map(|id| id as u8);
+            }
+            Message::Extended { id: UT_METADATA_ID, payload } => self.serve_metadata(&payload).await?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn update_interest(&mut self) -> Result<(), DownloadError> {
+        let wants = self.swarm.wants_from(&self.has);
+        if wants != self.am_interested {
+            self.am_interested = wants;
+            self.send(if wants { Message::Interested } else { Message::NotInterested }).await?;
+        }
+        Ok(())
+    }
+
+    async fn serve(&mut self, index: u32, begin: u32, length: u32) -> Result<(), DownloadError> {
+        let piece = index as usize;
+        if self.am_choking || !self.swarm.has_piece(piece) {
+            return Ok(());
+        }
+        if length > MAX_REQUEST || begin as u64 + length as u64 > self.swarm.meta.piece_size(piece) {
+            return Err(DownloadError::NetworkError("Peer requested an invalid block".to_string()));
+        }
+        let offset = piece as u64 * self.swarm.meta.piece_length + begin as u64;
+        let data = self.swarm.files.read(offset, length as u64).await?;
+        self.send(Message::Piece { index, begin, data }).await?;
+        self.swarm.uploaded.fetch_add(length as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Answers `ut_metadata` requests so magnet users can get the info
+    /// dictionary from us.
+    async fn serve_metadata(&mut self, payload: &[u8]) -> Result<(), DownloadError> {
+        let Some(their_id) = self.metadata_id else {
+            return Ok(());
+        };
+        let Ok(request) = bencode::decode(payload) else {
+            return Ok(());
+        };
+        if request.get("msg_type").and_then(Value::as_int) != Some(0) {
+            return Ok(());
+        }
+        let Some(piece) = request.get("piece").and_then(Value::as_int).filter(|&p| p >= 0) else {
+            return Ok(());
+        };
+
+        let info = &self.swarm.meta.info_bytes;
+        let start = piece as usize * METADATA_PIECE_SIZE;
+        let reply = if start < info.len() {
+            let end = (start + METADATA_PIECE_SIZE).min(info.len());
+            peer::metadata_message(their_id, 1, piece as usize, Some((&info[start..end], info.len())))
+        } else {
+            peer::metadata_message(their_id, 2, piece as usize, None)
+        };
+        self.send(reply).await
+    }
+
+    async fn receive(&mut self, index: u32, begin: u32, data: Vec<u8>) -> Result<(), DownloadError> {
+        let Some(job) = self.job.as_mut() else {
+            return Ok(());
+        };
+        if job.index != index as usize || !job.pending.remove(&begin) {
+            return Ok(());
+        }
+        let end = begin as usize + data.len();
+        if end > job.buf.len() {
+            return Err(DownloadError::NetworkError("Peer sent an oversized block".to_string()));
+        }
+        job.buf[begin as usize..end].copy_from_slice(&data);
+        self.last_block = Instant::now();
+        self.swarm.received.fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.swarm.limiter.consume(data.len() as u64).await;
+
+        let finished = job.next_offset as usize >= job.buf.len() && job.pending.is_empty();
+        if finished {
+            let job = self.job.take().expect("job checked above");
+            self.finish(job).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(&mut self, job: PieceJob) -> Result<(), DownloadError> {
+        let index = job.index;
+        if Sha1::digest(&job.buf)[..] != self.swarm.meta.pieces[index] {
+            self.swarm.release(index);
+            self.hash_failures += 1;
+            eprintln!("[Torrent] Piece {} failed verification", index);
+            if self.hash_failures >= MAX_HASH_FAILURES {
+                return Err(DownloadError::NetworkError("Peer keeps sending corrupt data".to_string()));
+            }
+            return Ok(());
+        }
+
+        if self.swarm.has_piece(index) {
+            // Another peer won the endgame race
+            self.swarm.release(index);
+        } else {
+            self.swarm.files.write_piece(index, &job.buf).await?;
+            self.swarm.mark_have(index);
+        }
+        Ok(())
+    }
+
+    /// Keeps `PIPELINE` block requests in flight while the peer lets us.
+    async fn fill_requests(&mut self) -> Result<(), DownloadError> {
+        // Drop a piece someone else finished first
+        if let Some(job) = &self.job {
+            if self.swarm.has_piece(job.index) {
+                let job = self.job.take().expect("job checked above");
+                for begin in job.pending {
+                    let length = BLOCK_SIZE.min(job.buf.len() as u32 - begin);
+                    self.send(Message::Cancel { index: job.index as u32, begin, length }).await?;
+                }
+                self.swarm.release(job.index);
+            }
+        }
+
+        if self.peer_choking || !self.am_interested {
+            return Ok(());
+        }
+        if self.job.is_none() {
+            let Some(index) = self.swarm.pick(&self.has) else {
+                return self.update_interest().await;
+            };
+            self.job = Some(PieceJob {
+                index,
+                buf: vec![0u8; self.swarm.meta.piece_size(index) as usize],
+                next_offset: 0,
+                pending: HashSet::new(),
+            });
+        }
+
+        let mut requests = Vec::new();
+        if let Some(job) = self.job.as_mut() {
+            while job.pending.len() < PIPELINE && (job.next_offset as usize) < job.buf.len() {
+                let begin = job.next_offset;
+                let length = BLOCK_SIZE.min(job.buf.len() as u32 - begin);
+                job.pending.insert(begin);
+                job.next_offset += length;
+                requests.push(Message::Request { index: job.index as u32, begin, length });
+            }
+        }
+        for request in requests {
+            self.send(request).await?;
+        }
+        Ok(())
+    }
+
+    async fn on_tick(&mut self) -> Result<(), DownloadError> {
+        let completed = self.swarm.completed_since(self.have_cursor);
+        self.have_cursor += completed.len();
+        for index in completed {
+            self.send(Message::Have(index)).await?;
+        }
+
+        let waiting = self.job.as_ref().map(|j| !j.pending.is_empty()).unwrap_or(false);
+        if waiting && self.last_block.elapsed() > SNUB_TIMEOUT {
+            return Err(DownloadError::NetworkError("Peer stopped sending data".to_string()));
+        }
+        if self.last_sent.elapsed() > KEEPALIVE_INTERVAL {
+            self.send(Message::KeepAlive).await?;
+        }
+        Ok(())
+    }
+
+    fn cleanup(&mut self) {
+        if let Some(job) = self.job.take() {
+            self.swarm.release(job.index);
+        }
+        for (index, _) in self.has.iter().enumerate().filter(|(_, &h)| h) {
+            self.swarm.add_availability(index, -1);
+        }
+    }
+}
+
+/// Runs the message loop of a connection that has completed its handshake.
+async fn run_peer(
+    swarm: Arc<Swarm>,
+    handshake: Handshake,
+    mut reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+) -> Result<(), DownloadError> {
+    let (tx, mut rx) = mpsc::channel(64);
+    // Reads happen on their own task; a half-read message can't survive `select!`
+    let reader_task = tokio::spawn(async move {
+        while let Ok(message) = peer::read_message(&mut reader).await {
+            if tx.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut conn = PeerConn {
+        has: vec![false; swarm.meta.piece_count()],
+        have_cursor: swarm.completed_since(0).len(),
+        swarm,
+        writer,
+        peer_choking: true,
+        am_choking: true,
+        am_interested: false,
+        job: None,
+        last_block: Instant::now(),
+        last_sent: Instant::now(),
+        hash_failures: 0,
+        metadata_id: None,
+    };
+
+    let result = async {
+        if handshake.supports_extensions() {
+            let size = conn.swarm.meta.info_bytes.len();
+            conn.send(peer::extended_handshake(Some(size))).await?;
+        }
+        if let Some(bits) = conn.swarm.bitfield() {
+            conn.send(Message::Bitfield(bits)).await?;
+        }
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                message = rx.recv() => {
+                    let message = message.ok_or_else(|| DownloadError::NetworkError("Peer disconnected".to_string()))?;
+                    conn.handle(message).await?;
+                }
+                _ = tick.tick() => conn.on_tick().await?,
+            }
+            conn.fill_requests().await?;
+        }
+    }.await;
+
+    reader_task.abort();
+    conn.cleanup();
+    result
+}
+
+fn check_handshake(swarm: &Swarm, handshake: &Handshake) -> Result<(), DownloadError> {
+    if handshake.info_hash != swarm.meta.info_hash {
+        return Err(DownloadError::NetworkError("Peer is on a different torrent".to_string()));
+    }
+    if handshake.peer_id == swarm.peer_id {
+        return Err(DownloadError::NetworkError("Connected to ourselves".to_string()));
+    }
+    Ok(())
+}
+
+async fn connect_peer(swarm: Arc<Swarm>, addr: SocketAddr) -> Result<(), DownloadError> {
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| DownloadError::NetworkError("Connection timed out".to_string()))?
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+    let (mut reader, mut writer) = stream.into_split();
+
+    peer::write_handshake(&mut writer, &swarm.meta.info_hash, &swarm.peer_id).await?;
+    let handshake = tokio::time::timeout(peer::HANDSHAKE_TIMEOUT, peer::read_handshake(&mut reader))
+        .await
+        .map_err(|_| DownloadError::NetworkError("Handshake timed out".to_string()))??;
+    check_handshake(&swarm, &handshake)?;
+    run_peer(swarm, handshake, reader, writer).await
+}
+
+async fn accept_peer(swarm: Arc<Swarm>, stream: TcpStream) -> Result<(), DownloadError> {
+    let (mut reader, mut writer) = stream.into_split();
+    let handshake = tokio::time::timeout(peer::HANDSHAKE_TIMEOUT, peer::read_handshake(&mut reader))
+        .await
+        .map_err(|_| DownloadError::NetworkError("Handshake timed out".to_string()))??;
+    check_handshake(&swarm, &handshake)?;
+    peer::write_handshake(&mut writer, &swarm.meta.info_hash, &swarm.peer_id).await?;
+    run_peer(swarm, handshake, reader, writer).await
+}
+
+async fn accept(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Announces to the first working tracker of every tier. Returns how long to
+/// wait before the next round.
+async fn announce_all(
+    swarm: &Swarm,
+    http: &HttpHelper,
+    port: u16,
+    event: AnnounceEvent,
+    peers: &mpsc::UnboundedSender<SocketAddr>,
+) -> Duration {
+    let mut next: Option<Duration> = None;
+    for tier in &swarm.meta.trackers {
+        for url in tier {
+            let params = Announce {
+                info_hash: &swarm.meta.info_hash,
+                peer_id: &swarm.peer_id,
+                port,
+                uploaded: swarm.uploaded(),
+                downloaded: swarm.received.load(Ordering::Relaxed),
+                left: swarm.wanted_total.saturating_sub(swarm.downloaded()),
+                event,
+            };
+            match tracker::announce(url, &params, http).await {
+                Ok(response) => {
+                    eprintln!("[Torrent] {} returned {} peers", url, response.peers.len());
+                    for addr in response.peers {
+                        let _ = peers.send(addr);
+                    }
+                    next = Some(next.map_or(response.interval, |n| n.min(response.interval)));
+                    break;
+                }
+                Err(e) => eprintln!("[Torrent] Announce to {} failed: {}", url, e),
+            }
+        }
+    }
+    next.unwrap_or(MIN_ANNOUNCE_INTERVAL).max(MIN_ANNOUNCE_INTERVAL)
+}
+
+async fn announce_loop(swarm: Arc<Swarm>, http: HttpHelper, port: u16, peers: mpsc::UnboundedSender<SocketAddr>) {
+    let mut event = AnnounceEvent::Started;
+    let mut was_complete = swarm.is_complete();
+    loop {
+        let wait = announce_all(&swarm, &http, port, event, &peers).await;
+        event = AnnounceEvent::None;
+
+        // Sleep until the next round, but tell trackers right away when we finish
+        let deadline = Instant::now() + wait;
+        while Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            if !was_complete && swarm.is_complete() {
+                was_complete = true;
+                event = AnnounceEvent::Completed;
+                break;
+            }
+        }
+    }
+}
+
+async fn bind_listener(port: u16) -> Option<TcpListener> {
+    match TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            eprintln!("[Torrent] Port {} unavailable ({}), using a random one", port, e);
+            TcpListener::bind(("0.0.0.0", 0)).await.ok()
+        }
+    }
+}
+
+pub struct Session {
+    pub id: String,
+    pub app: AppHandle,
+    pub http: HttpHelper,
+    pub history: HistoryStore,
+    pub swarm: Arc<Swarm>,
+    pub settings: TorrentSettings,
+    /// Look for peers on the DHT too; never for private torrents
+    pub dht: bool,
+    /// Extra peer sources besides trackers and the DHT
+    pub peers: Option<mpsc::UnboundedReceiver<SocketAddr>>,
+}
+
+impl Session {
+    fn seeding_done(&self) -> bool {
+        match self.settings.seed_ratio_limit {
+            None => false,
+            Some(limit) => self.swarm.uploaded() as f64 >= limit * self.swarm.wanted_total() as f64,
+        }
+    }
+
+    /// Downloads until every wanted piece is verified, reports that through
+    /// `done`, then keeps seeding until the ratio limit is reached.
+    pub async fn run(mut self, done: oneshot::Sender<Result<(), DownloadError>>) {
+        let swarm = self.swarm.clone();
+        let listener = bind_listener(self.settings.listen_port).await;
+        let port = listener.as_ref()
+            .and_then(|l| l.local_addr().ok())
+            .map(|a| a.port())
+            .unwrap_or(0);
+
+        let (peer_tx, mut peer_rx) = mpsc::unbounded_channel::<SocketAddr>();
+        if let Some(mut extra) = self.peers.take() {
+            let forward = peer_tx.clone();
+            tokio::spawn(async move {
+                while let Some(addr) = extra.recv().await {
+                    if forward.send(addr).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        let dht = self.dht.then(|| {
+            tokio::spawn(dht::lookup_loop(swarm.meta.info_hash, Some(port), peer_tx.clone()))
+        });
+        let announcer = tokio::spawn(announce_loop(swarm.clone(), self.http.clone(), port, peer_tx));
+
+        let mut peers: JoinSet<SocketAddr> = JoinSet::new();
+        let mut known: HashSet<SocketAddr> = HashSet::new();
+        let mut backlog: VecDeque<SocketAddr> = VecDeque::new();
+        let mut done = Some(done);
+        let mut tick = tokio::time::interval(TICK);
+        let mut last_received = 0u64;
+        let mut last_tick = Instant::now();
+
+        loop {
+            tokio::select! {
+                accepted = accept(listener.as_ref()) => {
+                    if let Ok((stream, addr)) = accepted {
+                        if peers.len() < self.settings.max_peers && known.insert(addr) {
+                            let swarm = swarm.clone();
+                            peers.spawn(async move {
+                                let _ = accept_peer(swarm, stream).await;
+                                addr
+                            });
+                        }
+                    }
+                }
+                Some(addr) = peer_rx.recv() => {
+                    if known.insert(addr) {
+                        backlog.push_back(addr);
+                    }
+                }
+                Some(finished) = peers.join_next(), if !peers.is_empty() => {
+                    // Forget the address so a later announce can bring it back
+                    if let Ok(addr) = finished {
+                        known.remove(&addr);
+                    }
+                }
+                _ = tick.tick() => {
+                    let received = swarm.received.load(Ordering::Relaxed);
+                    let elapsed = last_tick.elapsed().as_secs_f64().max(0.001);
+                    let speed = ((received - last_received) as f64 / elapsed) as u64;
+                    last_received = received;
+                    last_tick = Instant::now();
+
+                    let downloaded = swarm.downloaded();
+                    let total = swarm.wanted_total();
+                    let _ = self.app.emit("download://progress", ProgressEvent {
+                        id: self.id.clone(),
+                        downloaded,
+                        total: Some(total),
+                        speed,
+                    });
+                    self.history.checkpoint(&self.id, downloaded, Some(total));
+
+                    if swarm.is_complete() {
+                        if let Some(done) = done.take() {
+                            self.complete();
+                            let _ = done.send(Ok(()));
+                        }
+                        if self.seeding_done() {
+                            eprintln!("[Torrent] {}: seed ratio reached, stopping", self.id);
+                            break;
+                        }
+                        let _ = self.app.emit("download://seeding", serde_json::json!({
+                            "id": self.id,
+                            "uploaded": swarm.uploaded(),
+                            "peers": peers.len(),
+                        }));
+                    }
+                }
+            }
+
+            while peers.len() < self.settings.max_peers {
+                let Some(addr) = backlog.pop_front() else {
+                    break;
+                };
+                let swarm = swarm.clone();
+                peers.spawn(async move {
+                    let _ = connect_peer(swarm, addr).await;
+                    addr
+                });
+            }
+        }
+
+        announcer.abort();
+        if let Some(dht) = dht {
+            dht.abort();
+        }
+        peers.abort_all();
+        super::unregister_seeder(&self.id);
+
+        // Best effort; trackers drop us after a while anyway
+        let params = Announce {
+            info_hash: &swarm.meta.info_hash,
+            peer_id: &swarm.peer_id,
+            port,
+            uploaded: swarm.uploaded(),
+            downloaded: swarm.received.load(Ordering::Relaxed),
+            left: 0,
+            event: AnnounceEvent::Stopped,
+        };
+        for url in swarm.meta.trackers.iter().filter_map(|tier| tier.first()) {
+            let _ = tokio::time::timeout(Duration::from_secs(5), tracker::announce(url, &params, &self.http)).await;
+        }
+    }
+
+    fn complete(&self) {
+        let content = self.swarm.files.content_path();
+        eprintln!("[Torrent] {} complete: {}", self.id, content.display());
+        let _ = self.app.emit("download://complete", serde_json::json!({
+            "id": self.id,
+            "path": content.to_string_lossy(),
+            "filename": self.swarm.meta.name,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::ratelimit::TokenBucket;
+    use crate::download::torrent::metainfo::tests::info_dict;
+    use std::path::{Path, PathBuf};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(Arc::new(TokenBucket::new(None)), Arc::new(TokenBucket::new(None)))
+    }
+
+    /// HTTP tracker that hands every announce the same compact peer list.
+    async fn tracker(peer: SocketAddr) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let SocketAddr::V4(peer) = peer else {
+            panic!("tracker only serves IPv4 peers");
+        };
+        let mut compact = peer.ip().octets().to_vec();
+        compact.extend_from_slice(&peer.port().to_be_bytes());
+        let mut body = format!("d8:intervali60e5:peers{}:", compact.len()).into_bytes();
+        body.extend_from_slice(&compact);
+        body.push(b'e');
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0u8; 4096];
+                let n = stream.read(&mut request).await.unwrap_or(0);
+                assert!(String::from_utf8_lossy(&request[..n]).contains("info_hash="));
+                let mut response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes();
+                response.extend_from_slice(&body);
+                let _ = stream.write_all(&response).await;
+            }
+        });
+        url
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("torrent-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(root: &Path, path: &str, data: &[u8]) {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn downloads_from_local_seed() {
+        let a: Vec<u8> = (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let b: Vec<u8> = (0..60_000u32).map(|i| (i * 13 % 241) as u8).collect();
+        let info = info_dict("demo", 32_768, &[(&["a.bin"], &a), (&["sub", "b.bin"], &b)]);
+
+        let seed_dir = temp_dir();
+        write(&seed_dir, "demo/a.bin", &a);
+        write(&seed_dir, "demo/sub/b.bin", &b);
+        let seed_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tracker_url = tracker(seed_listener.local_addr().unwrap()).await;
+
+        let meta = Arc::new(Metainfo::from_info(&info, vec![vec![tracker_url.clone()]]).unwrap());
+        let seed = Arc::new(Swarm::new(meta.clone(), TorrentFiles::new(seed_dir.clone(), meta.clone()), None, limiter()));
+        seed.recheck().await;
+        assert!(seed.is_complete());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = seed_listener.accept().await {
+                tokio::spawn(accept_peer(seed.clone(), stream));
+            }
+        });
+
+        let leech_dir = temp_dir();
+        let leech = Arc::new(Swarm::new(meta.clone(), TorrentFiles::new(leech_dir.clone(), meta.clone()), None, limiter()));
+        let http = HttpHelper::with_options(&tracker_url, &Default::default(), &Default::default()).unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let next = announce_all(&leech, &http, 6881, AnnounceEvent::Started, &tx).await;
+        assert_eq!(next, Duration::from_secs(60));
+        let addr = rx.try_recv().unwrap();
+        tokio::spawn(connect_peer(leech.clone(), addr));
+
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while !leech.is_complete() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }).await.expect("download timed out");
+
+        assert_eq!(leech.downloaded(), meta.total);
+        assert_eq!(std::fs::read(leech_dir.join("demo/a.bin")).unwrap(), a);
+        assert_eq!(std::fs::read(leech_dir.join("demo/sub/b.bin")).unwrap(), b);
+        let _ = std::fs::remove_dir_all(seed_dir);
+        let _ = std::fs::remove_dir_all(leech_dir);
+    }
+}
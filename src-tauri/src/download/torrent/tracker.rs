@@ -0,0 +1,236 @@
+use super::bencode::{self, Value};
+use super::metainfo::InfoHash;
+use crate::download::http::HttpHelper;
+use crate::download::DownloadError;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+const UDP_TIMEOUT: Duration = Duration::from_secs(15);
+/// Used when a tracker doesn't say how often it wants to hear from us
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1800);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnounceEvent {
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    fn as_http(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+
+    fn as_udp(&self) -> u32 {
+        match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+}
+
+pub struct Announce<'a> {
+    pub info_hash: &'a InfoHash,
+    pub peer_id: &'a [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+}
+
+pub struct AnnounceResponse {
+    pub interval: Duration,
+    pub peers: Vec<SocketAddr>,
+}
+
+/// Percent-encodes every byte outside the unreserved set; info hashes and
+/// peer ids are raw bytes, not UTF-8.
+fn url_encode_bytes(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|&b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub fn parse_compact_v4(data: &[u8]) -> Vec<SocketAddr> {
+    data.chunks_exact(6)
+        .map(|c| SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::new(c[0], c[1], c[2], c[3]),
+            u16::from_be_bytes([c[4], c[5]]),
+        )))
+        .collect()
+}
+
+pub fn parse_compact_v6(data: &[u8]) -> Vec<SocketAddr> {
+    data.chunks_exact(18)
+        .map(|c| {
+            let ip: [u8; 16] = c[..16].try_into().expect("chunk is 18 bytes");
+            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), u16::from_be_bytes([c[16], c[17]]), 0, 0))
+        })
+        .collect()
+}
+
+pub async fn announce(url: &str, params: &Announce<'_>, http: &HttpHelper) -> Result<AnnounceResponse, DownloadError> {
+    if url.starts_with("udp://") {
+        announce_udp(url, params).await
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        announce_http(url, params, http).await
+    } else {
+        Err(DownloadError::InvalidUrl(format!("Unsupported tracker: {}", url)))
+    }
+}
+
+async fn announce_http(url: &str, params: &Announce<'_>, http: &HttpHelper) -> Result<AnnounceResponse, DownloadError> {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let mut full = format!(
+        "{}{}info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+        url,
+        separator,
+        url_encode_bytes(params.info_hash),
+        url_encode_bytes(params.peer_id),
+        params.port,
+        params.uploaded,
+        params.downloaded,
+        params.left,
+    );
+    if let Some(event) = params.event.as_http() {
+        full.push_str("&event=");
+        full.push_str(event);
+    }
+
+    let response = http.client()
+        .get(&full)
+        .send()
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(DownloadError::from_status(&response));
+    }
+    let body = response.bytes()
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+
+    let root = bencode::decode(&body)
+        .map_err(|e| DownloadError::Other(format!("Bad tracker response: {}", e)))?;
+    if let Some(reason) = root.get("failure reason").and_then(Value::as_str) {
+        return Err(DownloadError::Other(format!("Tracker refused announce: {}", reason)));
+    }
+
+    let interval = root.get("interval")
+        .and_then(Value::as_int)
+        .filter(|&i| i > 0)
+        .map(|i| Duration::from_secs(i as u64))
+        .unwrap_or(DEFAULT_INTERVAL);
+
+    let mut peers = match root.get("peers") {
+        Some(Value::Bytes(compact)) => parse_compact_v4(compact),
+        Some(Value::List(list)) => list.iter()
+            .filter_map(|peer| {
+                let ip = peer.get("ip")?.as_str()?.parse().ok()?;
+                let port = peer.get("port")?.as_int()?;
+                Some(SocketAddr::new(ip, port as u16))
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    if let Some(compact) = root.get("peers6").and_then(Value::as_bytes) {
+        peers.extend(parse_compact_v6(compact));
+    }
+
+    Ok(AnnounceResponse { interval, peers })
+}
+
+async fn udp_exchange(socket: &UdpSocket, request: &[u8], transaction: u32) -> Result<Vec<u8>, DownloadError> {
+    socket.send(request).await.map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+    let mut buf = vec![0u8; 4096];
+    let len = tokio::time::timeout(UDP_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .map_err(|_| DownloadError::NetworkError("Tracker timed out".to_string()))?
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+    buf.truncate(len);
+
+    if buf.len() < 8 || u32::from_be_bytes(buf[4..8].try_into().unwrap()) != transaction {
+        return Err(DownloadError::NetworkError("Malformed tracker reply".to_string()));
+    }
+    if u32::from_be_bytes(buf[0..4].try_into().unwrap()) == 3 {
+        let message = String::from_utf8_lossy(&buf[8..]).to_string();
+        return Err(DownloadError::Other(format!("Tracker refused announce: {}", message)));
+    }
+    Ok(buf)
+}
+
+/// BEP 15: a connect round-trip for a connection id, then the announce.
+async fn announce_udp(url: &str, params: &Announce<'_>) -> Result<AnnounceResponse, DownloadError> {
+    let host = url.trim_start_matches("udp://")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let addr = tokio::net::lookup_host(host)
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?
+        .next()
+        .ok_or_else(|| DownloadError::NetworkError(format!("Cannot resolve tracker {}", host)))?;
+    let bind = if addr.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+    let socket = UdpSocket::bind(bind)
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+    socket.connect(addr)
+        .await
+        .map_err(|e| DownloadError::NetworkError(e.to_string()))?;
+
+    let transaction: u32 = rand::random();
+    let mut connect = Vec::with_capacity(16);
+    connect.extend_from_slice(&UDP_PROTOCOL_ID.to_be_bytes());
+    connect.extend_from_slice(&0u32.to_be_bytes());
+    connect.extend_from_slice(&transaction.to_be_bytes());
+    let reply = udp_exchange(&socket, &connect, transaction).await?;
+    if reply.len() < 16 {
+        return Err(DownloadError::NetworkError("Malformed tracker reply".to_string()));
+    }
+    let connection_id = &reply[8..16];
+
+    let transaction: u32 = rand::random();
+    let mut request = Vec::with_capacity(98);
+    request.extend_from_slice(connection_id);
+    request.extend_from_slice(&1u32.to_be_bytes());
+    request.extend_from_slice(&transaction.to_be_bytes());
+    request.extend_from_slice(params.info_hash);
+    request.extend_from_slice(params.peer_id);
+    request.extend_from_slice(&params.downloaded.to_be_bytes());
+    request.extend_from_slice(&params.left.to_be_bytes());
+    request.extend_from_slice(&params.uploaded.to_be_bytes());
+    request.extend_from_slice(&params.event.as_udp().to_be_bytes());
+    request.extend_from_slice(&0u32.to_be_bytes()); // IP: let the tracker use the sender's
+    request.extend_from_slice(&rand::random::<u32>().to_be_bytes());
+    request.extend_from_slice(&(-1i32).to_be_bytes()); // num_want: default
+    request.extend_from_slice(&params.port.to_be_bytes());
+
+    let reply = udp_exchange(&socket, &request, transaction).await?;
+    if reply.len() < 20 {
+        return Err(DownloadError::NetworkError("Malformed tracker reply".to_string()));
+    }
+    let interval = u32::from_be_bytes(reply[8..12].try_into().unwrap());
+
+    let peers = if addr.is_ipv6() {
+        parse_compact_v6(&reply[20..])
+    } else {
+        parse_compact_v4(&reply[20..])
+    };
+    Ok(AnnounceResponse {
+        interval: if interval > 0 { Duration::from_secs(interval as u64) } else { DEFAULT_INTERVAL },
+        peers,
+    })
+}
@@ -38,8 +38,18 @@ pub fn run() {
         .plugin(tauri_plugin_updater::Builder::new().build())
         .plugin(tauri_plugin_clipboard_manager::init())
         .setup(|app| {
+            // Fall back to the JSON files if the database can't be opened
+            match storage::db::Database::open(app.handle()) {
+                Ok(db) => {
+                    app.manage(std::sync::Arc::new(db));
+                }
+                Err(e) => eprintln!("[Storage] Failed to open database, using JSON files: {}", e),
+            }
+
             let mut manager = download::manager::DownloadManager::new();
             manager.init(app.handle().clone());
+            // Kept apart from the manager's lock for the flush on exit
+            app.manage(manager.history().clone());
             app.manage(tokio::sync::Mutex::new(manager));
             
             // Setup window size and position
@@ -58,14 +68,36 @@ pub fn run() {
             commands::get_system_storage,
             commands::file_exists,
             commands::download_file,
+            commands::inspect_torrent,
+            commands::inspect_stream,
+            commands::analyze_url,
             commands::pause_download,
+            commands::resume_download,
+            commands::get_download_queue,
+            commands::set_download_speed_limit,
             commands::load_settings,
             commands::save_settings,
+            commands::take_storage_recoveries,
             commands::load_download_history,
             commands::save_download_history,
-            commands::clear_download_history
+            commands::clear_download_history,
+            commands::get_download,
+            commands::list_downloads,
+            commands::search_downloads,
+            commands::remove_download,
+            commands::save_credentials,
+            commands::delete_credentials,
+            commands::save_bearer_token,
+            commands::delete_bearer_token
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                if let Some(history) = app.try_state::<storage::history::HistoryStore>() {
+                    history.persist_now();
+                }
+            }
+        });
 }
 
@@ -0,0 +1,100 @@
+use crate::download::http::RequestSecrets;
+use serde::{Deserialize, Serialize};
+
+/// Service name the OS keychain files our entries under.
+const SERVICE: &str = "fastah-dm";
+
+/// A saved login for one server. The password lives in the OS keychain
+/// (Keychain, Credential Manager or Secret Service), never in settings.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Entries are keyed by origin (`scheme://host:port`) so one login covers
+/// every file on that server.
+pub fn origin_of(url: &reqwest::Url) -> Option<String> {
+    let host = url.host_str()?;
+    let port = url.port_or_known_default().map(|p| format!(":{}", p)).unwrap_or_default();
+    Some(format!("{}://{}{}", url.scheme(), host, port))
+}
+
+fn entry(origin: &str) -> Result<keyring::Entry, String> {
+    keyring::Entry::new(SERVICE, origin).map_err(|e| e.to_string())
+}
+
+pub fn lookup(origin: &str) -> Option<Credentials> {
+    match entry(origin).and_then(|e| e.get_password().map_err(|e| e.to_string())) {
+        Ok(secret) => serde_json::from_str(&secret).ok(),
+        Err(_) => None,
+    }
+}
+
+pub fn save(origin: &str, credentials: &Credentials) -> Result<(), String> {
+    let secret = serde_json::to_string(credentials).map_err(|e| e.to_string())?;
+    entry(origin)?.set_password(&secret).map_err(|e| e.to_string())?;
+    eprintln!("[Credentials] Saved login for {}", origin);
+    Ok(())
+}
+
+pub fn delete(origin: &str) -> Result<(), String> {
+    match entry(origin)?.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Bearer tokens live next to logins, under `bearer:<origin>`.
+fn token_entry(origin: &str) -> Result<keyring::Entry, String> {
+    entry(&format!("bearer:{}", origin))
+}
+
+pub fn lookup_token(origin: &str) -> Option<String> {
+    token_entry(origin).and_then(|e| e.get_password().map_err(|e| e.to_string())).ok()
+}
+
+pub fn save_token(origin: &str, token: &str) -> Result<(), String> {
+    token_entry(origin)?.set_password(token).map_err(|e| e.to_string())?;
+    eprintln!("[Credentials] Saved bearer token for {}", origin);
+    Ok(())
+}
+
+pub fn delete_token(origin: &str) -> Result<(), String> {
+    match token_entry(origin)?.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Cookies and auth headers of one download, under `request:<download id>`.
+fn request_entry(id: &str) -> Result<keyring::Entry, String> {
+    entry(&format!("request:{}", id))
+}
+
+pub fn lookup_request(id: &str) -> Option<RequestSecrets> {
+    match request_entry(id).and_then(|e| e.get_password().map_err(|e| e.to_string())) {
+        Ok(secret) => serde_json::from_str(&secret).ok(),
+        Err(_) => None,
+    }
+}
+
+pub fn save_request(id: &str, secrets: &RequestSecrets) -> Result<(), String> {
+    let secret = serde_json::to_string(secrets).map_err(|e| e.to_string())?;
+    request_entry(id)?.set_password(&secret).map_err(|e| e.to_string())
+}
+
+pub fn delete_request(id: &str) -> Result<(), String> {
+    match request_entry(id)?.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
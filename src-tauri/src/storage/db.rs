@@ -0,0 +1,393 @@
+use super::{AppSettings, DownloadHistory, DownloadHistoryItem, DownloadType};
+use super::history::HistoryPage;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use std::sync::Mutex;
+
+/// Applied in order; `PRAGMA user_version` records how many have run.
+/// Never edit a shipped migration, append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    "
+    CREATE TABLE downloads (
+        id TEXT PRIMARY KEY,
+        url TEXT NOT NULL,
+        path TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        total INTEGER,
+        downloaded INTEGER NOT NULL DEFAULT 0,
+        status TEXT NOT NULL,
+        etag TEXT,
+        last_modified TEXT,
+        created_at TEXT NOT NULL,
+        updated_at TEXT NOT NULL,
+        download_type TEXT NOT NULL DEFAULT 'http',
+        original_url TEXT,
+        checksum TEXT,
+        error TEXT
+    );
+    CREATE INDEX idx_downloads_status ON downloads(status);
+    CREATE INDEX idx_downloads_created_at ON downloads(created_at);
+    CREATE INDEX idx_downloads_filename ON downloads(filename);
+    CREATE INDEX idx_downloads_url ON downloads(url);
+
+    CREATE VIRTUAL TABLE downloads_fts USING fts5(
+        filename, url, content='downloads', content_rowid='rowid'
+    );
+    CREATE TRIGGER downloads_fts_insert AFTER INSERT ON downloads BEGIN
+        INSERT INTO downloads_fts(rowid, filename, url) VALUES (new.rowid, new.filename, new.url);
+    END;
+    CREATE TRIGGER downloads_fts_delete AFTER DELETE ON downloads BEGIN
+        INSERT INTO downloads_fts(downloads_fts, rowid, filename, url) VALUES ('delete', old.rowid, old.filename, old.url);
+    END;
+    CREATE TRIGGER downloads_fts_update AFTER UPDATE OF filename, url ON downloads BEGIN
+        INSERT INTO downloads_fts(downloads_fts, rowid, filename, url) VALUES ('delete', old.rowid, old.filename, old.url);
+        INSERT INTO downloads_fts(rowid, filename, url) VALUES (new.rowid, new.filename, new.url);
+    END;
+
+    CREATE TABLE settings (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        data TEXT NOT NULL
+    );
+    ",
+    // 2: file selection for torrents, stored as a JSON array
+    "ALTER TABLE downloads ADD COLUMN selected_files TEXT;",
+    // 3: grouping of folder downloads
+    "ALTER TABLE downloads ADD COLUMN parent_id TEXT;",
+    // 4: per-download proxy override, stored as JSON
+    "ALTER TABLE downloads ADD COLUMN proxy TEXT;",
+    // 5: per-download headers and cookies, stored as JSON
+    "ALTER TABLE downloads ADD COLUMN request TEXT;",
+    // 6: timestamps in the `...T12:00:00.000Z` form JavaScript sends, so date
+    // filters compare as strings
+    "
+    UPDATE downloads SET created_at = strftime('%Y-%m-%dT%H:%M:%fZ', created_at)
+        WHERE strftime('%Y-%m-%dT%H:%M:%fZ', created_at) IS NOT NULL;
+    UPDATE downloads SET updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', updated_at)
+        WHERE strftime('%Y-%m-%dT%H:%M:%fZ', updated_at) IS NOT NULL;
+    CREATE INDEX idx_downloads_parent_id ON downloads(parent_id);
+    ",
+];
+
+const COLUMNS: &str = "id, url, path, filename, total, downloaded, status, etag, last_modified, \
+    created_at, updated_at, download_type, original_url, checksum, error, selected_files, parent_id, proxy, request";
+
+#[derive(Default)]
+pub struct SearchQuery {
+    /// Matched against filename and URL, word prefixes
+    pub text: Option<String>,
+    pub status: Option<String>,
+    /// Bounds on `created_at`, inclusive, in the form `storage::timestamp` writes
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub offset: usize,
+    pub limit: usize,
+}
+
+/// SQLite store for history and settings, kept at `<app data>/fastah.db`.
+pub struct Database {
+    conn: Mutex<Connection>,
+}
+
+fn db_err(e: rusqlite::Error) -> String {
+    e.to_string()
+}
+
+fn row_to_item(row: &Row) -> rusqlite::Result<DownloadHistoryItem> {
+    let download_type: String = row.get(11)?;
+    Ok(DownloadHistoryItem {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        path: row.get(2)?,
+        filename: row.get(3)?,
+        total: row.get::<_, Option<i64>>(4)?.map(|v| v as u64),
+        downloaded: row.get::<_, i64>(5)? as u64,
+        status: row.get(6)?,
+        etag: row.get(7)?,
+        last_modified: row.get(8)?,
+        created_at: row.get(9)?,
+        updated_at: row.get(10)?,
+        download_type: DownloadType::parse(&download_type),
+        original_url: row.get(12)?,
+        checksum: row.get(13)?,
+        error: row.get(14)?,
+        selected_files: row.get::<_, Option<String>>(15)?
+            .and_then(|json| serde_json::from_str(&json).ok()),
+        parent_id: row.get(16)?,
+        proxy: row.get::<_, Option<String>>(17)?
+            .and_then(|json| serde_json::from_str(&json).ok()),
+        request: row.get::<_, Option<String>>(18)?
+            .and_then(|json| serde_json::from_str(&json).ok()),
+    })
+}
+
+/// Quotes every word so user input can't inject FTS5 syntax, and makes each
+/// one a prefix match.
+fn fts_query(text: &str) -> Option<String> {
+    let terms: Vec<String> = text.split_whitespace()
+        .map(|t| format!("\"{}\"*", t.replace('"', "\"\"")))
+        .collect();
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+impl Database {
+    pub fn open(app: &tauri::AppHandle) -> Result<Self, String> {
+        let dir = super::ensure_data_dir(app)?;
+        let conn = Connection::open(dir.join("fastah.db")).map_err(db_err)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")
+            .map_err(db_err)?;
+
+        let db = Self { conn: Mutex::new(conn) };
+        let fresh = db.migrate()?;
+        if fresh {
+            db.import_json(app);
+        }
+        Ok(db)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, String> {
+        let db = Self { conn: Mutex::new(Connection::open_in_memory().map_err(db_err)?) };
+        db.migrate()?;
+        Ok(db)
+    }
+
+    /// Runs pending migrations; returns true if the database was just created.
+    fn migrate(&self) -> Result<bool, String> {
+        let mut conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))
+            .map_err(db_err)? as usize;
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction().map_err(db_err)?;
+            tx.execute_batch(migration).map_err(db_err)?;
+            tx.pragma_update(None, "user_version", (i + 1) as i64).map_err(db_err)?;
+            tx.commit().map_err(db_err)?;
+            eprintln!("[Storage] Applied migration {}", i + 1);
+        }
+        Ok(version == 0)
+    }
+
+    /// One-time import of the JSON files used before the database existed.
+    fn import_json(&self, app: &tauri::AppHandle) {
+        match super::load_settings_json(app) {
+            Ok(Some(settings)) => {
+                if let Err(e) = self.save_settings(&settings) {
+                    eprintln!("[Storage] Failed to import settings.json: {}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("[Storage] Skipping unreadable settings.json: {}", e),
+        }
+
+        match super::load_history_json(app) {
+            Ok(history) if !history.items.is_empty() => {
+                let count = history.items.len();
+                match self.replace_items(&history) {
+                    Ok(()) => eprintln!("[Storage] Imported {} history entries", count),
+                    Err(e) => eprintln!("[Storage] Failed to import history.json: {}", e),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("[Storage] Skipping unreadable history.json: {}", e),
+        }
+    }
+
+    pub fn load_settings(&self) -> Result<Option<AppSettings>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let data: Option<String> = conn.query_row("SELECT data FROM settings WHERE id = 1", [], |row| row.get(0))
+            .optional()
+            .map_err(db_err)?;
+        data.map(|d| serde_json::from_str(&d).map_err(|e| e.to_string()))
+            .transpose()
+    }
+
+    pub fn save_settings(&self, settings: &AppSettings) -> Result<(), String> {
+        let data = serde_json::to_string(settings).map_err(|e| e.to_string())?;
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        conn.execute(
+            "INSERT INTO settings (id, data) VALUES (1, ?1) ON CONFLICT(id) DO UPDATE SET data = excluded.data",
+            params![data],
+        ).map_err(db_err)?;
+        Ok(())
+    }
+
+    fn upsert_with(conn: &Connection, item: &DownloadHistoryItem) -> Result<(), String> {
+        // ON CONFLICT instead of INSERT OR REPLACE so the FTS update trigger fires
+        conn.execute(
+            &format!(
+                "INSERT INTO downloads ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)
+                 ON CONFLICT(id) DO UPDATE SET
+                    url = excluded.url, path = excluded.path, filename = excluded.filename,
+                    total = excluded.total, downloaded = excluded.downloaded, status = excluded.status,
+                    etag = excluded.etag, last_modified = excluded.last_modified,
+                    updated_at = excluded.updated_at, download_type = excluded.download_type,
+                    original_url = excluded.original_url, checksum = excluded.checksum, error = excluded.error,
+                    selected_files = excluded.selected_files, parent_id = excluded.parent_id,
+                    proxy = excluded.proxy, request = excluded.request",
+                COLUMNS
+            ),
+            params![
+                item.id,
+                item.url,
+                item.path,
+                item.filename,
+                item.total.map(|v| v as i64),
+                item.downloaded as i64,
+                item.status,
+                item.etag,
+                item.last_modified,
+                item.created_at,
+                item.updated_at,
+                item.download_type.as_str(),
+                item.original_url,
+                item.checksum,
+                item.error,
+                item.selected_files.as_ref().and_then(|files| serde_json::to_string(files).ok()),
+                item.parent_id,
+                item.proxy.as_ref().and_then(|proxy| serde_json::to_string(proxy).ok()),
+                item.request.as_ref().and_then(|request| serde_json::to_string(request).ok()),
+            ],
+        ).map_err(db_err)?;
+        Ok(())
+    }
+
+    /// Upserts `changed` and deletes `removed` in one transaction.
+    pub fn write_items(&self, changed: &[DownloadHistoryItem], removed: &[String]) -> Result<(), String> {
+        let mut conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let tx = conn.transaction().map_err(db_err)?;
+        for item in changed {
+            Self::upsert_with(&tx, item)?;
+        }
+        for id in removed {
+            tx.execute("DELETE FROM downloads WHERE id = ?1", params![id]).map_err(db_err)?;
+        }
+        tx.commit().map_err(db_err)
+    }
+
+    pub fn replace_items(&self, history: &DownloadHistory) -> Result<(), String> {
+        let mut conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let tx = conn.transaction().map_err(db_err)?;
+        tx.execute("DELETE FROM downloads", []).map_err(db_err)?;
+        for item in &history.items {
+            Self::upsert_with(&tx, item)?;
+        }
+        tx.commit().map_err(db_err)
+    }
+
+    /// Rows matching `filter` (a `WHERE` clause or empty), oldest first.
+    fn items_where(&self, filter: &str, values: &[&str]) -> Result<Vec<DownloadHistoryItem>, String> {
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM downloads {} ORDER BY created_at ASC", COLUMNS, filter))
+            .map_err(db_err)?;
+        let items = stmt.query_map(params_from_iter(values.iter()), row_to_item)
+            .map_err(db_err)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(db_err)?;
+        Ok(items)
+    }
+
+    /// Oldest first, matching the order of the old `history.json`.
+    pub fn all_items(&self) -> Result<Vec<DownloadHistoryItem>, String> {
+        self.items_where("", &[])
+    }
+
+    pub fn get_item(&self, id: &str) -> Result<Option<DownloadHistoryItem>, String> {
+        Ok(self.items_where("WHERE id = ?", &[id])?.pop())
+    }
+
+    /// Files of the folder download `parent_id`, oldest first.
+    pub fn children(&self, parent_id: &str) -> Result<Vec<DownloadHistoryItem>, String> {
+        self.items_where("WHERE parent_id = ?", &[parent_id])
+    }
+
+    /// Entries with custom headers or cookies.
+    pub fn items_with_request(&self) -> Result<Vec<DownloadHistoryItem>, String> {
+        self.items_where("WHERE request IS NOT NULL", &[])
+    }
+
+    /// Newest first.
+    pub fn search(&self, query: &SearchQuery) -> Result<HistoryPage, String> {
+        let mut clauses: Vec<&str> = Vec::new();
+        let mut values: Vec<String> = Vec::new();
+
+        if let Some(fts) = query.text.as_deref().and_then(fts_query) {
+            clauses.push("rowid IN (SELECT rowid FROM downloads_fts WHERE downloads_fts MATCH ?)");
+            values.push(fts);
+        }
+        if let Some(status) = &query.status {
+            clauses.push("status = ?");
+            values.push(status.clone());
+        }
+        if let Some(from) = &query.from {
+            clauses.push("created_at >= ?");
+            values.push(from.clone());
+        }
+        if let Some(to) = &query.to {
+            clauses.push("created_at <= ?");
+            values.push(to.clone());
+        }
+
+        let filter = if clauses.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", clauses.join(" AND "))
+        };
+
+        let conn = self.conn.lock().map_err(|e| e.to_string())?;
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM downloads {}", filter),
+            params_from_iter(values.iter()),
+            |row| row.get(0),
+        ).map_err(db_err)?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM downloads {} ORDER BY created_at DESC LIMIT {} OFFSET {}",
+            COLUMNS, filter, query.limit, query.offset
+        )).map_err(db_err)?;
+        let items = stmt.query_map(params_from_iter(values.iter()), row_to_item)
+            .map_err(db_err)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(db_err)?;
+
+        Ok(HistoryPage {
+            items,
+            total: total as usize,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_old_timestamps() {
+        let conn = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..5] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", 5).unwrap();
+        conn.execute(
+            "INSERT INTO downloads (id, url, path, filename, status, created_at, updated_at)
+             VALUES ('a', 'https://example.com/a', '/tmp', 'a', 'completed', ?1, ?1)",
+            params!["2026-01-02T03:04:05.123456789+00:00"],
+        ).unwrap();
+
+        let db = Database { conn: Mutex::new(conn) };
+        assert!(!db.migrate().unwrap());
+        let item = db.get_item("a").unwrap().unwrap();
+        assert_eq!(item.created_at, "2026-01-02T03:04:05.123Z");
+        assert_eq!(item.updated_at, "2026-01-02T03:04:05.123Z");
+    }
+
+    #[test]
+    fn search_escapes_fts_syntax() {
+        assert_eq!(fts_query("linux iso").as_deref(), Some("\"linux\"* \"iso\"*"));
+        assert_eq!(fts_query("a\"b OR").as_deref(), Some("\"a\"\"b\"* \"OR\"*"));
+        assert_eq!(fts_query("  "), None);
+    }
+}
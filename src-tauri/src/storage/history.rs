@@ -0,0 +1,540 @@
+use super::db::{Database, SearchQuery};
+use super::{DownloadHistory, DownloadHistoryItem};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tauri::AppHandle;
+use tokio::sync::mpsc;
+
+/// Minimum time between two byte checkpoints written to disk
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+pub struct HistoryPage {
+    pub items: Vec<DownloadHistoryItem>,
+    /// Number of items matching the filter, before pagination
+    pub total: usize,
+}
+
+/// Delay before `history.json` is rewritten, so a burst of status changes
+/// costs one write of the whole file instead of one each
+const JSON_WRITE_DELAY: Duration = Duration::from_millis(500);
+
+/// Download history, owned by the `DownloadManager`. Backed by the SQLite
+/// database when it opened, otherwise by `history.json`, which is kept in
+/// memory whole. With SQLite memory only holds the entries changed since
+/// startup, and pages are queried from the database.
+///
+/// Changes only touch memory and queue a write for a background task, so the
+/// download loops never wait on the disk. Status transitions are queued right
+/// away; byte progress only every `CHECKPOINT_INTERVAL`, so a crash loses at
+/// most a few seconds of counters while the `.fdm` file still holds the
+/// actual data.
+#[derive(Clone, Default)]
+pub struct HistoryStore {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Clone)]
+enum Backend {
+    Db(Arc<Database>),
+    Json(AppHandle),
+}
+
+/// Pending work for the writer task.
+enum Write {
+    /// The item was added, changed or removed
+    Item(String),
+    /// The whole history was replaced
+    All,
+}
+
+#[derive(Default)]
+struct Inner {
+    backend: Option<Backend>,
+    writer: Option<mpsc::UnboundedSender<Write>>,
+    /// Everything with `history.json`; with SQLite the entries changed this
+    /// session, which are newer than their rows until the writer catches up
+    history: DownloadHistory,
+    /// Last checkpoint write per download
+    last_flush: HashMap<String, Instant>,
+}
+
+impl Inner {
+    /// Queues the item `id` for writing.
+    fn flush(&mut self, id: &str) {
+        if let Some(writer) = &self.writer {
+            let _ = writer.send(Write::Item(id.to_string()));
+        }
+        self.last_flush.insert(id.to_string(), Instant::now());
+    }
+
+    fn flush_all(&mut self) {
+        if let Some(writer) = &self.writer {
+            let _ = writer.send(Write::All);
+        }
+    }
+
+    fn checkpoint_due(&self, id: &str) -> bool {
+        self.last_flush.get(id)
+            .map(|t| t.elapsed() >= CHECKPOINT_INTERVAL)
+            .unwrap_or(true)
+    }
+
+    fn find(&mut self, id: &str) -> Option<&mut DownloadHistoryItem> {
+        self.history.items.iter_mut().find(|item| item.id == id)
+    }
+
+    /// Entries written before cookies and auth headers moved to the keychain
+    /// still hold them in plain text; move them over once.
+    fn stash_legacy_secrets(&mut self) {
+        let ids: Vec<String> = self.history.items.iter_mut()
+            .filter_map(|item| {
+                let request = item.request.as_mut().filter(|request| request.has_secrets())?;
+                *request = request.stash_secrets(&item.id);
+                Some(item.id.clone())
+            })
+            .collect();
+        if ids.is_empty() {
+            return;
+        }
+        eprintln!("[History] Moved cookies and headers of {} downloads to the keychain", ids.len());
+        if matches!(self.backend, Some(Backend::Json(_))) {
+            // Twice, so the `.bak` of history.json doesn't keep the old copy
+            self.flush_all();
+            self.flush_all();
+        } else {
+            for id in &ids {
+                self.flush(id);
+            }
+        }
+    }
+}
+
+/// What one round of the writer task puts on disk.
+enum Batch {
+    Db {
+        changed: Vec<DownloadHistoryItem>,
+        removed: Vec<String>,
+    },
+    DbAll(DownloadHistory),
+    Json(DownloadHistory),
+}
+
+impl Batch {
+    fn write(&self, backend: &Backend) -> Result<(), String> {
+        match (self, backend) {
+            (Batch::Db { changed, removed }, Backend::Db(db)) => db.write_items(changed, removed),
+            (Batch::DbAll(history), Backend::Db(db)) => db.replace_items(history),
+            (Batch::Json(history), Backend::Json(app)) => super::save_history_json(app, history),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Drains queued writes and persists them off the async threads. Everything
+/// queued while a batch is being written goes into the next one.
+async fn write_loop(inner: Arc<Mutex<Inner>>, backend: Backend, mut queue: mpsc::UnboundedReceiver<Write>) {
+    while let Some(first) = queue.recv().await {
+        if matches!(backend, Backend::Json(_)) {
+            tokio::time::sleep(JSON_WRITE_DELAY).await;
+        }
+        let mut ids = HashSet::new();
+        let mut all = false;
+        for write in std::iter::once(first).chain(std::iter::from_fn(|| queue.try_recv().ok())) {
+            match write {
+                Write::Item(id) => {
+                    ids.insert(id);
+                }
+                Write::All => all = true,
+            }
+        }
+
+        let batch = {
+            let Ok(inner) = inner.lock() else {
+                return;
+            };
+            match &backend {
+                Backend::Json(_) => Batch::Json(inner.history.clone()),
+                Backend::Db(_) if all => Batch::DbAll(inner.history.clone()),
+                Backend::Db(_) => {
+                    let changed: Vec<DownloadHistoryItem> = inner.history.items.iter()
+                        .filter(|item| ids.contains(&item.id))
+                        .cloned()
+                        .collect();
+                    let removed = ids.into_iter()
+                        .filter(|id| !changed.iter().any(|item| &item.id == id))
+                        .collect();
+                    Batch::Db { changed, removed }
+                }
+            }
+        };
+
+        let backend = backend.clone();
+        let result = tokio::task::spawn_blocking(move || batch.write(&backend)).await;
+        match result {
+            Ok(Err(e)) => eprintln!("[History] Failed to save history: {}", e),
+            Err(e) => eprintln!("[History] History writer failed: {}", e),
+            Ok(Ok(())) => {}
+        }
+    }
+}
+
+fn now() -> String {
+    super::timestamp()
+}
+
+/// A date filter bound in the stored form, so it compares as a string.
+fn bound(value: Option<&str>) -> Result<Option<String>, String> {
+    value.map(|v| super::normalize_timestamp(v).ok_or_else(|| format!("Invalid date: {}", v)))
+        .transpose()
+}
+
+impl HistoryStore {
+    /// With SQLite nothing is read up front: entries are fetched as they are
+    /// resumed or paged through. Only `history.json` is loaded whole.
+    pub fn load(&self, app: &AppHandle) {
+        let (backend, loaded) = match super::database(app) {
+            Some(db) => {
+                let legacy = db.items_with_request().map(|items| DownloadHistory {
+                    items: items.into_iter()
+                        .filter(|item| item.request.as_ref().is_some_and(|r| r.has_secrets()))
+                        .collect(),
+                });
+                (Backend::Db(db), legacy)
+            }
+            None => (Backend::Json(app.clone()), super::load_history_json(app)),
+        };
+        let history = loaded.unwrap_or_else(|e| {
+            eprintln!("[History] Failed to load history: {}", e);
+            DownloadHistory::default()
+        });
+        self.attach(backend, history);
+    }
+
+    fn attach(&self, backend: Backend, history: DownloadHistory) {
+        let (writer, queue) = mpsc::unbounded_channel();
+        tauri::async_runtime::spawn(write_loop(self.inner.clone(), backend.clone(), queue));
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.backend = Some(backend);
+            inner.writer = Some(writer);
+            inner.history = history;
+            inner.stash_legacy_secrets();
+        }
+    }
+
+    fn db(&self) -> Option<Arc<Database>> {
+        match &self.inner.lock().ok()?.backend {
+            Some(Backend::Db(db)) => Some(db.clone()),
+            _ => None,
+        }
+    }
+
+    /// Puts the copies changed in memory in place of `items` read from the
+    /// database, which may not have caught up with them yet.
+    fn overlay(&self, mut items: Vec<DownloadHistoryItem>) -> Vec<DownloadHistoryItem> {
+        if let Ok(inner) = self.inner.lock() {
+            for item in &mut items {
+                if let Some(newer) = inner.history.items.iter().find(|newer| newer.id == item.id) {
+                    *item = newer.clone();
+                }
+            }
+        }
+        items
+    }
+
+    /// Brings the row `id` into memory before it is changed. The read
+    /// happens outside the lock.
+    fn cache(&self, id: &str) {
+        let cached = self.inner.lock()
+            .map(|inner| inner.history.items.iter().any(|item| item.id == id))
+            .unwrap_or(true);
+        let Some(db) = self.db().filter(|_| !cached) else {
+            return;
+        };
+        match db.get_item(id) {
+            Ok(Some(item)) => {
+                if let Ok(mut inner) = self.inner.lock() {
+                    if !inner.history.items.iter().any(|existing| existing.id == id) {
+                        inner.history.items.push(item);
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("[History] Failed to read {}: {}", id, e),
+        }
+    }
+
+    /// Writes `history.json` right away, for shutdown: queued writes to it
+    /// wait `JSON_WRITE_DELAY`. SQLite writes are queued without delay.
+    pub fn persist_now(&self) {
+        let Ok(inner) = self.inner.lock() else {
+            return;
+        };
+        if let Some(Backend::Json(app)) = &inner.backend {
+            if let Err(e) = super::save_history_json(app, &inner.history) {
+                eprintln!("[History] Failed to save history: {}", e);
+            }
+        }
+    }
+
+    pub fn insert(&self, item: DownloadHistoryItem) {
+        if let Ok(mut inner) = self.inner.lock() {
+            let id = item.id.clone();
+            inner.history.items.retain(|existing| existing.id != item.id);
+            inner.history.items.push(item);
+            inner.flush(&id);
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<DownloadHistoryItem> {
+        let cached = self.inner.lock().ok()?.find(id).cloned();
+        if cached.is_some() {
+            return cached;
+        }
+        self.db()?.get_item(id).unwrap_or_else(|e| {
+            eprintln!("[History] Failed to read {}: {}", id, e);
+            None
+        })
+    }
+
+    /// Applies `f` to the item and persists right away.
+    pub fn update<F: FnOnce(&mut DownloadHistoryItem)>(&self, id: &str, f: F) {
+        self.cache(id);
+        if let Ok(mut inner) = self.inner.lock() {
+            if let Some(item) = inner.find(id) {
+                f(item);
+                item.updated_at = now();
+                inner.flush(id);
+            }
+        }
+    }
+
+    /// Files queued for the folder download `parent_id`, oldest first.
+    pub fn children(&self, parent_id: &str) -> Vec<DownloadHistoryItem> {
+        let is_child = |item: &DownloadHistoryItem| item.parent_id.as_deref() == Some(parent_id);
+        let mut children = match self.db() {
+            Some(db) => self.overlay(db.children(parent_id).unwrap_or_else(|e| {
+                eprintln!("[History] Failed to read files of {}: {}", parent_id, e);
+                Vec::new()
+            })),
+            None => Vec::new(),
+        };
+        // Files only memory knows about yet, or all of them with history.json
+        if let Ok(inner) = self.inner.lock() {
+            let known: HashSet<String> = children.iter().map(|item| item.id.clone()).collect();
+            children.extend(inner.history.items.iter()
+                .filter(|item| is_child(item) && !known.contains(&item.id))
+                .cloned());
+        }
+        children.retain(is_child);
+        children
+    }
+
+    pub fn set_status(&self, id: &str, status: &str) {
+        self.update(id, |item| item.status = status.to_string());
+    }
+
+    /// Records byte progress; only hits the disk once per `CHECKPOINT_INTERVAL`.
+    pub fn checkpoint(&self, id: &str, downloaded: u64, total: Option<u64>) {
+        self.cache(id);
+        if let Ok(mut inner) = self.inner.lock() {
+            let Some(item) = inner.find(id) else {
+                return;
+            };
+            item.downloaded = downloaded;
+            if total.is_some() {
+                item.total = total;
+            }
+            item.updated_at = now();
+
+            if inner.checkpoint_due(id) {
+                inner.flush(id);
+            }
+        }
+    }
+
+    pub fn remove(&self, id: &str) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.history.items.retain(|item| item.id != id);
+            inner.flush(id);
+            inner.last_flush.remove(id);
+        }
+    }
+
+    pub fn replace(&self, history: DownloadHistory) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.history = history;
+            inner.flush_all();
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.history = DownloadHistory::default();
+            inner.last_flush.clear();
+            inner.flush_all();
+        }
+    }
+
+    /// Every entry, oldest first. Reads the whole table; the UI pages
+    /// through `list` instead.
+    pub fn snapshot(&self) -> DownloadHistory {
+        if let Some(db) = self.db() {
+            match db.all_items() {
+                Ok(items) => return DownloadHistory { items: self.overlay(items) },
+                Err(e) => eprintln!("[History] Failed to read history: {}", e),
+            }
+        }
+        let items = self.inner.lock()
+            .map(|inner| inner.history.items.clone())
+            .unwrap_or_default();
+        DownloadHistory { items }
+    }
+
+    /// Newest first, optionally filtered by status.
+    pub fn list(&self, status: Option<&str>, offset: usize, limit: usize) -> Result<HistoryPage, String> {
+        self.search(&SearchQuery {
+            status: status.map(str::to_string),
+            offset,
+            limit,
+            ..Default::default()
+        })
+    }
+
+    /// Full-text search over filename and URL plus status and date filters,
+    /// newest first. Without the database this degrades to substring
+    /// matching in memory.
+    pub fn search(&self, query: &SearchQuery) -> Result<HistoryPage, String> {
+        let from = bound(query.from.as_deref())?;
+        let to = bound(query.to.as_deref())?;
+
+        if let Some(db) = self.db() {
+            let page = db.search(&SearchQuery {
+                text: query.text.clone(),
+                status: query.status.clone(),
+                from,
+                to,
+                offset: query.offset,
+                limit: query.limit,
+            })?;
+            return Ok(HistoryPage {
+                items: self.overlay(page.items),
+                total: page.total,
+            });
+        }
+
+        let inner = self.inner.lock().map_err(|e| e.to_string())?;
+        let text = query.text.as_deref().map(str::to_lowercase);
+        let created = |item: &DownloadHistoryItem| {
+            super::normalize_timestamp(&item.created_at).unwrap_or_else(|| item.created_at.clone())
+        };
+        let matching: Vec<&DownloadHistoryItem> = inner.history.items.iter()
+            .rev()
+            .filter(|item| text.as_deref().map(|t| {
+                t.split_whitespace().all(|word| {
+                    item.filename.to_lowercase().contains(word) || item.url.to_lowercase().contains(word)
+                })
+            }).unwrap_or(true))
+            .filter(|item| query.status.as_deref().map(|s| item.status == s).unwrap_or(true))
+            .filter(|item| from.as_deref().map(|f| created(item).as_str() >= f).unwrap_or(true))
+            .filter(|item| to.as_deref().map(|t| created(item).as_str() <= t).unwrap_or(true))
+            .collect();
+
+        Ok(HistoryPage {
+            total: matching.len(),
+            items: matching.into_iter()
+                .skip(query.offset)
+                .take(query.limit)
+                .cloned()
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(id: &str) -> DownloadHistoryItem {
+        serde_json::from_value(serde_json::json!({
+            "id": id, "url": "https://example.com/a", "path": "/tmp", "filename": "a",
+            "total": null, "downloaded": 0, "status": "queued", "etag": null,
+            "created_at": now(), "updated_at": now(),
+        })).unwrap()
+    }
+
+    /// Rows once `done` holds for them, or after a second.
+    async fn settled(db: &Database, done: impl Fn(&[DownloadHistoryItem]) -> bool) -> Vec<DownloadHistoryItem> {
+        for _ in 0..100 {
+            let items = db.all_items().unwrap();
+            if done(&items) {
+                return items;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        db.all_items().unwrap()
+    }
+
+    #[tokio::test]
+    async fn writes_in_the_background() {
+        let db = Arc::new(Database::open_in_memory().unwrap());
+        let store = HistoryStore::default();
+        store.attach(Backend::Db(db.clone()), DownloadHistory::default());
+
+        store.insert(item("a"));
+        store.insert(item("b"));
+        store.set_status("a", "completed");
+        store.checkpoint("b", 10, Some(100));
+        let items = settled(&db, |items| items.len() == 2 && items.iter().any(|i| i.status == "completed")).await;
+        assert_eq!(items.iter().find(|i| i.id == "a").unwrap().status, "completed");
+
+        // The next checkpoint isn't due yet, so it only reaches memory
+        store.checkpoint("b", 20, None);
+        assert_eq!(store.get("b").unwrap().downloaded, 20);
+
+        store.remove("a");
+        let items = settled(&db, |items| items.len() == 1).await;
+        assert_eq!(items[0].id, "b");
+        assert_eq!(items[0].total, Some(100));
+        assert!(!store.inner.lock().unwrap().last_flush.contains_key("a"));
+    }
+
+    #[tokio::test]
+    async fn pages_come_from_the_database() {
+        let db = Arc::new(Database::open_in_memory().unwrap());
+        let items: Vec<DownloadHistoryItem> = (1..=5).map(|day| DownloadHistoryItem {
+            created_at: format!("2026-01-0{}T10:00:00.000Z", day),
+            parent_id: (day > 3).then(|| "d1".to_string()),
+            ..item(&format!("d{}", day))
+        }).collect();
+        db.write_items(&items, &[]).unwrap();
+
+        let store = HistoryStore::default();
+        store.attach(Backend::Db(db.clone()), DownloadHistory::default());
+        assert!(store.inner.lock().unwrap().history.items.is_empty());
+
+        let page = store.list(None, 1, 2).unwrap();
+        assert_eq!(page.total, 5);
+        assert_eq!(page.items.iter().map(|i| i.id.as_str()).collect::<Vec<_>>(), ["d4", "d3"]);
+
+        // A change shows up before the writer has stored it
+        store.set_status("d4", "completed");
+        assert_eq!(store.list(None, 1, 1).unwrap().items[0].status, "completed");
+        assert_eq!(store.children("d1").len(), 2);
+
+        // Bounds in other offsets or without milliseconds compare correctly
+        let query = SearchQuery {
+            from: Some("2026-01-02T12:00:00+02:00".to_string()),
+            to: Some("2026-01-04T10:00:00Z".to_string()),
+            limit: 10,
+            ..Default::default()
+        };
+        let page = store.search(&query).unwrap();
+        assert_eq!(page.items.iter().map(|i| i.id.as_str()).collect::<Vec<_>>(), ["d4", "d3", "d2"]);
+
+        let query = SearchQuery { from: Some("yesterday".to_string()), ..Default::default() };
+        assert_eq!(store.search(&query).err(), Some("Invalid date: yesterday".to_string()));
+    }
+}
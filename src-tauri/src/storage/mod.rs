@@ -1,7 +1,19 @@
+pub mod credentials;
+pub mod db;
+pub mod history;
+
 use sysinfo::Disks;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::io::Write;
+use std::sync::Mutex;
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+use crate::download::http::{ProxySettings, RequestOptions};
+use crate::download::retry::RetryPolicy;
+use crate::download::media::MediaSettings;
+use crate::download::sftp::SshSettings;
+use crate::download::torrent::TorrentSettings;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
 #[serde(rename_all = "lowercase")]
@@ -12,6 +24,11 @@ pub enum DownloadType {
     GoogleDrive,
     Torrent,
     Magnet,
+    Ftp,
+    Sftp,
+    Hls,
+    Dash,
+    Metalink,
 }
 
 impl DownloadType {
@@ -21,6 +38,26 @@ impl DownloadType {
             DownloadType::GoogleDrive => "gdrive",
             DownloadType::Torrent => "torrent",
             DownloadType::Magnet => "magnet",
+            DownloadType::Ftp => "ftp",
+            DownloadType::Sftp => "sftp",
+            DownloadType::Hls => "hls",
+            DownloadType::Dash => "dash",
+            DownloadType::Metalink => "metalink",
+        }
+    }
+
+    /// Inverse of `as_str`; unknown values fall back to `Http`.
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "gdrive" => DownloadType::GoogleDrive,
+            "torrent" => DownloadType::Torrent,
+            "magnet" => DownloadType::Magnet,
+            "ftp" => DownloadType::Ftp,
+            "sftp" => DownloadType::Sftp,
+            "hls" => DownloadType::Hls,
+            "dash" => DownloadType::Dash,
+            "metalink" => DownloadType::Metalink,
+            _ => DownloadType::Http,
         }
     }
 }
@@ -45,6 +82,25 @@ pub struct AppSettings {
     pub use_new_ui: bool,
     pub auto_update_enabled: bool,
     pub silent_updates: bool,
+    #[serde(default = "default_max_concurrent_downloads")]
+    pub max_concurrent_downloads: usize,
+    /// Global bandwidth cap in bytes per second; `None` is unlimited
+    #[serde(default)]
+    pub speed_limit: Option<u64>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub torrent: TorrentSettings,
+    #[serde(default)]
+    pub ssh: SshSettings,
+    #[serde(default)]
+    pub media: MediaSettings,
+    #[serde(default)]
+    pub proxy: ProxySettings,
+}
+
+fn default_max_concurrent_downloads() -> usize {
+    crate::download::queue::DEFAULT_MAX_CONCURRENT
 }
 
 impl Default for AppSettings {
@@ -59,6 +115,13 @@ impl Default for AppSettings {
             use_new_ui: true,
             auto_update_enabled: true,
             silent_updates: false,
+            max_concurrent_downloads: default_max_concurrent_downloads(),
+            speed_limit: None,
+            retry: RetryPolicy::default(),
+            torrent: TorrentSettings::default(),
+            ssh: SshSettings::default(),
+            media: MediaSettings::default(),
+            proxy: ProxySettings::default(),
         }
     }
 }
@@ -73,15 +136,47 @@ pub struct DownloadHistoryItem {
     pub downloaded: u64,
     pub status: String,
     pub etag: Option<String>,
+    #[serde(default)]
+    pub last_modified: Option<String>,
     pub created_at: String,
     pub updated_at: String,
     #[serde(default)]
     pub download_type: DownloadType,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub original_url: Option<String>,
+    /// Expected digest (`sha256:<hex>`, bare hex, or a checksum file URL)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Files chosen from a multi-file torrent or metalink, or the chosen stream variant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selected_files: Option<Vec<usize>>,
+    /// Folder download this file was queued for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    /// Proxy chosen for this download instead of the one in settings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<ProxySettings>,
+    /// Headers, cookies, referer and User-Agent sent with this download
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<RequestOptions>,
+}
+
+/// Current time as stored in history: UTC with milliseconds and a `Z`, the
+/// form JavaScript's `toISOString` produces, so timestamps compare as strings.
+pub fn timestamp() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
 }
 
-#[derive(Serialize, Deserialize, Default)]
+/// Any RFC 3339 time in the form `timestamp` writes; `None` if it doesn't parse.
+pub fn normalize_timestamp(value: &str) -> Option<String> {
+    chrono::DateTime::parse_from_rfc3339(value.trim())
+        .ok()
+        .map(|time| time.with_timezone(&chrono::Utc).to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct DownloadHistory {
     pub items: Vec<DownloadHistoryItem>,
 }
@@ -122,52 +217,153 @@ fn ensure_data_dir(app: &tauri::AppHandle) -> Result<std::path::PathBuf, String>
     Ok(dir)
 }
 
-pub fn load_settings(app: &tauri::AppHandle) -> Result<AppSettings, String> {
-    let dir = get_data_dir(app)?;
-    let path = dir.join("settings.json");
-    
-    if !path.exists() {
-        return Ok(AppSettings::default());
+/// A JSON file that failed to parse at load time and what was done about it.
+#[derive(Serialize, Clone, Debug)]
+pub struct RecoveryNotice {
+    pub file: String,
+    pub error: String,
+    /// `false` when the backup was unusable too and defaults were used; the
+    /// broken file is kept next to it as `<name>.corrupt`
+    pub restored_from_backup: bool,
+}
+
+/// Recoveries since the frontend last asked, handed out once through
+/// `take_storage_recoveries`. Files are read at startup, before the frontend
+/// listens for events, so this is the only way they are reported.
+static RECOVERIES: Mutex<Vec<RecoveryNotice>> = Mutex::new(Vec::new());
+
+pub fn take_recoveries() -> Vec<RecoveryNotice> {
+    RECOVERIES.lock()
+        .map(|mut notices| std::mem::take(&mut *notices))
+        .unwrap_or_default()
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Writes to `<name>.tmp`, fsyncs and renames over the live file, so a crash
+/// leaves either the old or the new content. The previous version is kept as
+/// `<name>.bak`.
+fn write_atomic(path: &Path, content: &str) -> Result<(), String> {
+    let tmp = sibling(path, ".tmp");
+    {
+        let mut file = fs::File::create(&tmp).map_err(|e| e.to_string())?;
+        file.write_all(content.as_bytes()).map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())?;
     }
-    
-    let content = fs::read_to_string(&path).map_err(|e| e.to_string())?;
+
+    if path.exists() {
+        if let Err(e) = fs::copy(path, sibling(path, ".bak")) {
+            eprintln!("[Storage] Failed to back up {}: {}", path.display(), e);
+        }
+    }
+    fs::rename(&tmp, path).map_err(|e| e.to_string())?;
+
+    // Persist the rename itself; directories can't be opened this way on Windows
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = fs::File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    Ok(())
+}
+
+fn parse_file<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
     serde_json::from_str(&content).map_err(|e| e.to_string())
 }
 
+/// Loads a JSON file, falling back to its `.bak` when it is truncated or
+/// otherwise unreadable. `None` when the file was never written or neither
+/// copy could be used.
+fn read_json<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    if !path.exists() {
+        return None;
+    }
+    let error = match parse_file(path) {
+        Ok(value) => return Some(value),
+        Err(e) => e,
+    };
+
+    let file = path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    eprintln!("[Storage] {} is unreadable: {}", file, error);
+
+    let backup = sibling(path, ".bak");
+    let restored = match parse_file::<T>(&backup) {
+        Ok(value) => {
+            // Put the good copy back in place so the next load doesn't recover again
+            if let Err(e) = fs::copy(&backup, path) {
+                eprintln!("[Storage] Failed to restore {} from backup: {}", file, e);
+            }
+            eprintln!("[Storage] Restored {} from backup", file);
+            Some(value)
+        }
+        Err(e) => {
+            eprintln!("[Storage] Backup of {} is unusable too: {}", file, e);
+            let _ = fs::rename(path, sibling(path, ".corrupt"));
+            None
+        }
+    };
+
+    let notice = RecoveryNotice {
+        file,
+        error,
+        restored_from_backup: restored.is_some(),
+    };
+    if let Ok(mut notices) = RECOVERIES.lock() {
+        notices.push(notice);
+    }
+    restored
+}
+
+/// The SQLite store, if it opened successfully at startup.
+fn database(app: &tauri::AppHandle) -> Option<std::sync::Arc<db::Database>> {
+    app.try_state::<std::sync::Arc<db::Database>>().map(|db| db.inner().clone())
+}
+
+pub fn load_settings(app: &tauri::AppHandle) -> Result<AppSettings, String> {
+    if let Some(db) = database(app) {
+        return Ok(db.load_settings()?.unwrap_or_default());
+    }
+    Ok(load_settings_json(app)?.unwrap_or_default())
+}
+
 pub fn save_settings(app: &tauri::AppHandle, settings: &AppSettings) -> Result<(), String> {
+    if let Some(db) = database(app) {
+        return db.save_settings(settings);
+    }
+    save_settings_json(app, settings)
+}
+
+/// `None` when no usable `settings.json` exists.
+fn load_settings_json(app: &tauri::AppHandle) -> Result<Option<AppSettings>, String> {
+    let dir = get_data_dir(app)?;
+    Ok(read_json(&dir.join("settings.json")))
+}
+
+fn save_settings_json(app: &tauri::AppHandle, settings: &AppSettings) -> Result<(), String> {
     let dir = ensure_data_dir(app)?;
     let path = dir.join("settings.json");
     
     let content = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
-    fs::write(&path, content).map_err(|e| e.to_string())
+    write_atomic(&path, &content)
 }
 
-pub fn load_download_history(app: &tauri::AppHandle) -> Result<DownloadHistory, String> {
+fn load_history_json(app: &tauri::AppHandle) -> Result<DownloadHistory, String> {
     let dir = get_data_dir(app)?;
-    let path = dir.join("history.json");
-    
-    if !path.exists() {
-        return Ok(DownloadHistory::default());
-    }
-    
-    let content = fs::read_to_string(&path).map_err(|e| e.to_string())?;
-    serde_json::from_str(&content).map_err(|e| e.to_string())
+    Ok(read_json(&dir.join("history.json")).unwrap_or_default())
 }
 
-pub fn save_download_history(app: &tauri::AppHandle, history: &DownloadHistory) -> Result<(), String> {
+fn save_history_json(app: &tauri::AppHandle, history: &DownloadHistory) -> Result<(), String> {
     let dir = ensure_data_dir(app)?;
     let path = dir.join("history.json");
     
     let content = serde_json::to_string_pretty(history).map_err(|e| e.to_string())?;
-    fs::write(&path, content).map_err(|e| e.to_string())
-}
-
-pub fn clear_download_history(app: &tauri::AppHandle) -> Result<(), String> {
-    let dir = get_data_dir(app)?;
-    let path = dir.join("history.json");
-    
-    if path.exists() {
-        fs::remove_file(&path).map_err(|e| e.to_string())?;
-    }
-    Ok(())
+    write_atomic(&path, &content)
 }
@@ -4,9 +4,9 @@ import { listen } from "@tauri-apps/api/event";
 import { open } from "@tauri-apps/plugin-dialog";
 import { remove } from "@tauri-apps/plugin-fs";
 import { check, Update } from "@tauri-apps/plugin-updater";
-import { ref, computed, watch } from "vue";
+import { ref, computed } from "vue";
 
-export type DownloadType = 'http' | 'gdrive' | 'torrent' | 'magnet';
+export type DownloadType = 'http' | 'gdrive' | 'torrent' | 'magnet' | 'ftp' | 'sftp' | 'hls' | 'dash' | 'metalink';
 
 export interface DownloadItem {
   id: string;
@@ -19,10 +19,59 @@ export interface DownloadItem {
   status: "pending" | "downloading" | "paused" | "error" | "completed";
   error?: string;
   etag?: string;
+  lastModified?: string;
   createdAt?: string;
   updatedAt?: string;
   downloadType: DownloadType;
   originalUrl?: string;
+  checksum?: string;
+  selectedFiles?: number[];
+  // Folder download this file belongs to
+  parentId?: string;
+  // Set when the server or proxy turned down the saved login (or there was none)
+  authRequired?: { proxy: boolean; realm: string | null };
+  // Bytes uploaded while seeding a finished torrent
+  uploaded?: number;
+  // Segment counts for HLS streams, whose byte total is only an estimate
+  segmentsDone?: number;
+  segmentsTotal?: number;
+}
+
+export interface TorrentFile {
+  index: number;
+  path: string;
+  length: number;
+}
+
+export interface TorrentInfo {
+  name: string;
+  info_hash: string;
+  total: number;
+  files: TorrentFile[];
+}
+
+export interface StreamVariant {
+  index: number;
+  kind: 'video' | 'audio' | null;
+  bandwidth: number;
+  resolution: string | null;
+  codecs: string | null;
+}
+
+export interface StreamInfo {
+  variants: StreamVariant[];
+  default_variants: number[];
+  duration: number | null;
+}
+
+export interface UrlAnalysis {
+  download_type: DownloadType;
+  filename: string | null;
+  size: number | null;
+  content_type: string | null;
+  final_url: string | null;
+  resumable: boolean;
+  children: number;
 }
 
 export interface StorageInfo {
@@ -42,6 +91,26 @@ export interface AppSettings {
   use_new_ui: boolean;
   auto_update_enabled: boolean;
   silent_updates: boolean;
+  max_concurrent_downloads: number;
+  speed_limit: number | null;
+  proxy?: ProxySettings;
+}
+
+// HTTP(S) proxy; its login comes from the URL or from saveCredentials(proxy url)
+export interface ProxySettings {
+  url: string | null; // http://, https://, socks5:// or socks5h://
+  bypass: string[];
+  use_system: boolean;
+}
+
+// Extra request data for one download, e.g. a portal's session cookie
+export interface RequestOptions {
+  headers?: Record<string, string>;
+  cookies?: string; // "name=value; name2=value2"
+  cookies_file?: string; // Netscape cookies.txt
+  referer?: string;
+  user_agent?: string;
+  keychain_ref?: string; // history only: cookies and auth headers are in the keychain
 }
 
 interface DownloadHistoryItem {
@@ -53,14 +122,60 @@ interface DownloadHistoryItem {
   downloaded: number;
   status: string;
   etag: string | null;
+  last_modified?: string | null;
   created_at: string;
   updated_at: string;
   download_type?: string;
   original_url?: string | null;
+  checksum?: string | null;
+  error?: string | null;
+  selected_files?: number[] | null;
+  parent_id?: string | null;
 }
 
-interface DownloadHistory {
+// One page of history, newest first
+interface HistoryPage {
   items: DownloadHistoryItem[];
+  total: number; // entries in history, not just this page
+}
+
+const HISTORY_PAGE_SIZE = 200;
+
+function fromHistory(item: DownloadHistoryItem): DownloadItem {
+  return {
+    id: item.id,
+    url: item.url,
+    path: item.path,
+    filename: item.filename,
+    total: item.total,
+    downloaded: item.downloaded,
+    speed: 0,
+    status: item.status as DownloadItem["status"],
+    etag: item.etag || undefined,
+    lastModified: item.last_modified || undefined,
+    createdAt: item.created_at,
+    updatedAt: item.updated_at,
+    downloadType: (item.download_type as DownloadType) || 'http',
+    originalUrl: item.original_url || undefined,
+    checksum: item.checksum || undefined,
+    error: item.error || undefined,
+    selectedFiles: item.selected_files || undefined,
+    parentId: item.parent_id || undefined,
+  };
+}
+
+// A settings/history file that was corrupted and restored from its backup
+export interface StorageRecovery {
+  file: string;
+  error: string;
+  restored_from_backup: boolean;
+}
+
+// An SFTP server whose key was seen for the first time and trusted
+export interface TrustedHostKey {
+  id: string;
+  host: string;
+  fingerprint: string; // "SHA256:..."
 }
 
 export const useDownloadStore = defineStore("download", () => {
@@ -70,6 +185,8 @@ export const useDownloadStore = defineStore("download", () => {
   const settings = ref<AppSettings | null>(null);
   const initialized = ref(false);
   const filterStatus = ref<'all' | 'active' | 'completed'>('all');
+  const storageRecoveries = ref<StorageRecovery[]>([]);
+  const trustedHostKeys = ref<TrustedHostKey[]>([]);
 
   // Update state
   const updateAvailable = ref(false);
@@ -78,6 +195,11 @@ export const useDownloadStore = defineStore("download", () => {
   const updateDownloadProgress = ref(0);
   const showUpdateModal = ref(false);
 
+  // History paging
+  const historyLoaded = ref(0);
+  const historyTotal = ref(0);
+  const hasMoreHistory = computed(() => historyLoaded.value < historyTotal.value);
+
   // Getters
   const activeDownloads = computed(() => downloads.value.filter(d => d.status === "downloading"));
   const completedDownloads = computed(() => downloads.value.filter(d => d.status === "completed"));
@@ -102,25 +224,12 @@ export const useDownloadStore = defineStore("download", () => {
       // Load settings
       settings.value = await invoke<AppSettings>("load_settings");
       
-      // Load download history
-      const history = await invoke<DownloadHistory>("load_download_history");
-      if (history.items && history.items.length > 0) {
-        downloads.value = history.items.map(item => ({
-          id: item.id,
-          url: item.url,
-          path: item.path,
-          filename: item.filename,
-          total: item.total,
-          downloaded: item.downloaded,
-          speed: 0,
-          status: item.status as DownloadItem["status"],
-          etag: item.etag || undefined,
-          createdAt: item.created_at,
-          updatedAt: item.updated_at,
-          downloadType: (item.download_type as DownloadType) || 'http',
-          originalUrl: item.original_url || undefined,
-        }));
-      }
+      // Load the newest downloads; older ones come with loadMoreHistory()
+      await loadMoreHistory();
+      
+      // Storage files repaired while loading; the backend reports them only here
+      const recoveries = await invoke<StorageRecovery[]>("take_storage_recoveries");
+      storageRecoveries.value.push(...recoveries);
       
       // Restore default download path if set
       if (settings.value?.default_download_path) {
@@ -134,34 +243,21 @@ export const useDownloadStore = defineStore("download", () => {
     }
   }
 
-  // Watch downloads and save history on changes
-  watch(downloads, async () => {
-    if (!initialized.value) return;
-    await saveHistory();
-  }, { deep: true });
-
-  // Save download history
-  async function saveHistory() {
+  // Appends the next older page of history to the list
+  async function loadMoreHistory() {
     try {
-      const history: DownloadHistory = {
-        items: downloads.value.map(d => ({
-          id: d.id,
-          url: d.url,
-          path: d.path,
-          filename: d.filename,
-          total: d.total,
-          downloaded: d.downloaded,
-          status: d.status,
-          etag: d.etag || null,
-          created_at: d.createdAt || new Date().toISOString(),
-          updated_at: new Date().toISOString(),
-          download_type: d.downloadType,
-          original_url: d.originalUrl || null,
-        }))
-      };
-      await invoke("save_download_history", { history });
+      const page = await invoke<HistoryPage>("list_downloads", {
+        offset: historyLoaded.value,
+        limit: HISTORY_PAGE_SIZE,
+      });
+      historyLoaded.value += page.items.length;
+      historyTotal.value = page.total;
+      // Offsets shift as downloads are added, so skip ones already listed
+      const known = new Set(downloads.value.map(d => d.id));
+      const older = page.items.filter(item => !known.has(item.id)).map(fromHistory).reverse();
+      downloads.value.unshift(...older);
     } catch (e) {
-      console.error("Failed to save history:", e);
+      console.error("Failed to load history:", e);
     }
   }
 
@@ -196,6 +292,16 @@ export const useDownloadStore = defineStore("download", () => {
     }
   });
 
+  // Validators used by the backend to detect a changed file on resume
+  listen<any>("download://metadata", (event) => {
+    const { id, etag, last_modified } = event.payload;
+    const item = downloads.value.find((d) => d.id === id);
+    if (item) {
+      item.etag = etag || undefined;
+      item.lastModified = last_modified || undefined;
+    }
+  });
+
   listen<any>("download://complete", (event) => {
     const payload = event.payload;
     const id = typeof payload === 'string' ? payload : payload.id;
@@ -226,6 +332,11 @@ export const useDownloadStore = defineStore("download", () => {
     }
   });
 
+  // Shown so the user can compare it with the server's real key
+  listen<TrustedHostKey>("download://host-key", (event) => {
+    trustedHostKeys.value.push(event.payload);
+  });
+
   listen<any>("download://error", (event) => {
     const [id, error] = event.payload;
     const item = downloads.value.find((d) => d.id === id);
@@ -235,7 +346,43 @@ export const useDownloadStore = defineStore("download", () => {
       item.speed = 0;
     }
   });
+
+  // Comes right before the error event; prompt, saveCredentials, then resume
+  listen<{ id: string; proxy: boolean; realm: string | null }>("download://auth-required", (event) => {
+    const { id, proxy, realm } = event.payload;
+    const item = downloads.value.find(d => d.id === id);
+    if (item) {
+      item.authRequired = { proxy, realm };
+    }
+  });
   
+  // Queue transitions from the backend; completion and errors have their own events
+  listen<{ id: string; state: string }>("download://state", (event) => {
+    const { id, state } = event.payload;
+    const item = downloads.value.find(d => d.id === id);
+    if (!item) return;
+    if (state === "queued") {
+      item.status = "pending";
+    } else if (state === "active") {
+      item.status = "downloading";
+    }
+  });
+
+  listen<{ id: string; uploaded: number; peers: number }>("download://seeding", (event) => {
+    const item = downloads.value.find(d => d.id === event.payload.id);
+    if (item) {
+      item.uploaded = event.payload.uploaded;
+    }
+  });
+
+  listen<{ id: string; done: number; total: number }>("download://segments", (event) => {
+    const item = downloads.value.find(d => d.id === event.payload.id);
+    if (item) {
+      item.segmentsDone = event.payload.done;
+      item.segmentsTotal = event.payload.total;
+    }
+  });
+
   listen<string>("download://paused", (event) => {
      const id = event.payload;
      const item = downloads.value.find(d => d.id === id);
@@ -246,7 +393,28 @@ export const useDownloadStore = defineStore("download", () => {
   });
 
   // Actions
-  async function startDownload(url: string, filename: string) {
+  // Fetches a .torrent's file list so the user can pick files before starting
+  // Also accepts magnet links; their file list has to be fetched from peers,
+  // which can take a while
+  async function inspectTorrent(url: string): Promise<TorrentInfo> {
+    return await invoke<TorrentInfo>("inspect_torrent", { url });
+  }
+
+  // Lists an HLS playlist's variants or a DASH manifest's tracks; start with
+  // `files: [index]` for HLS, or a video and an audio index for DASH
+  async function inspectStream(url: string): Promise<StreamInfo> {
+    return await invoke<StreamInfo>("inspect_stream", { url });
+  }
+
+  // Suggested file name and size for the save dialog, resolved the same way
+  // the download itself will be
+  async function analyzeUrl(url: string, proxy?: ProxySettings, request?: RequestOptions): Promise<UrlAnalysis> {
+    return await invoke<UrlAnalysis>("analyze_url", { url, proxy, request });
+  }
+
+  // `proxy` replaces the proxy from settings for this download only; both it
+  // and `request` are kept so a resume connects the same way
+  async function startDownload(url: string, filename: string, checksum?: string, files?: number[], proxy?: ProxySettings, request?: RequestOptions) {
     if (!selectedPath.value) throw new Error("No folder selected");
     
     const sep = navigator.userAgent.includes("Windows") ? "\\" : "/";
@@ -257,21 +425,42 @@ export const useDownloadStore = defineStore("download", () => {
           id: string;
           download_type: string;
           original_url: string | null;
-        }>("download_file", { url, savePath: fullPath });
+          // Folder downloads: the folder's own name and its queued files
+          filename?: string;
+          children?: { id: string; url: string; path: string; filename: string; download_type: string }[];
+        }>("download_file", { url, savePath: fullPath, checksum: checksum || null, files: files || null, proxy: proxy || null, request: request || null });
         
         downloads.value.push({
             id: response.id,
             url,
             path: selectedPath.value,
-            filename,
+            filename: response.filename || filename,
             total: null,
             downloaded: 0,
             speed: 0,
-            status: "downloading",
+            // Backend queues it first; progress/state events flip it to downloading
+            status: "pending",
             createdAt: new Date().toISOString(),
             downloadType: response.download_type as DownloadType,
             originalUrl: response.original_url || undefined,
+            checksum,
+            selectedFiles: files,
         });
+        for (const child of response.children || []) {
+            downloads.value.push({
+                id: child.id,
+                url: child.url,
+                path: child.path,
+                filename: child.filename,
+                total: null,
+                downloaded: 0,
+                speed: 0,
+                status: "pending",
+                createdAt: new Date().toISOString(),
+                downloadType: child.download_type as DownloadType,
+                parentId: response.id,
+            });
+        }
     } catch (e: unknown) {
         console.error("Failed to start", e);
         throw e;
@@ -289,25 +478,36 @@ export const useDownloadStore = defineStore("download", () => {
   }
 
   async function resumeDownload(item: DownloadItem) {
-      const sep = navigator.userAgent.includes("Windows") ? "\\" : "/";
-      const fullPath = `${item.path}${sep}${item.filename}`;
-
       try {
-          const response = await invoke<{
-            id: string;
-            download_type: string;
-            original_url: string | null;
-          }>("download_file", { url: item.url, savePath: fullPath });
+          await invoke("resume_download", { id: item.id });
           
-          item.id = response.id;
           item.status = "downloading";
           item.error = undefined;
+          item.authRequired = undefined;
       } catch (e: unknown) {
           item.error = String(e);
           item.status = "error";
       }
   }
 
+  // Login used for any download from the same server (FTP and others)
+  async function saveCredentials(url: string, username: string, password: string) {
+    await invoke("save_credentials", { url, username, password });
+  }
+
+  async function deleteCredentials(url: string) {
+    await invoke("delete_credentials", { url });
+  }
+
+  // Sent as "Authorization: Bearer <token>" to every download from the server
+  async function saveBearerToken(url: string, token: string) {
+    await invoke("save_bearer_token", { url, token });
+  }
+
+  async function deleteBearerToken(url: string) {
+    await invoke("delete_bearer_token", { url });
+  }
+
   async function removeDownload(item: DownloadItem, deleteFile: boolean = false) {
     // If downloading, pause first
     if (item.status === "downloading") {
@@ -322,18 +522,23 @@ export const useDownloadStore = defineStore("download", () => {
     if (deleteFile) {
       const sep = navigator.userAgent.includes("Windows") ? "\\" : "/";
       const fullPath = `${item.path}${sep}${item.filename}`;
+      // A folder download removes the folder with everything in it
+      const isFolder = downloads.value.some(d => d.parentId === item.id);
       try {
-        await remove(fullPath);
+        await remove(fullPath, { recursive: isFolder });
       } catch (e) {
         console.error("Failed to delete file:", e);
       }
     }
     
-    // Remove from list
-    const index = downloads.value.findIndex(d => d.id === item.id);
-    if (index !== -1) {
-      downloads.value.splice(index, 1);
+    try {
+      await invoke("remove_download", { id: item.id });
+    } catch (e) {
+      console.error("Failed to remove from history:", e);
     }
+    
+    // Remove from list, along with the files of a folder download
+    downloads.value = downloads.value.filter(d => d.id !== item.id && d.parentId !== item.id);
   }
 
   async function clearAllHistory() {
@@ -349,6 +554,8 @@ export const useDownloadStore = defineStore("download", () => {
     }
     
     downloads.value = [];
+    historyLoaded.value = 0;
+    historyTotal.value = 0;
     
     try {
       await invoke("clear_download_history");
@@ -474,17 +681,28 @@ export const useDownloadStore = defineStore("download", () => {
     storageInfo,
     settings,
     filterStatus,
+    storageRecoveries,
+    trustedHostKeys,
     updateAvailable,
     updateInfo,
     isCheckingUpdate,
     updateDownloadProgress,
     showUpdateModal,
     init,
+    inspectTorrent,
+    inspectStream,
+    analyzeUrl,
     startDownload,
     pauseDownload,
     resumeDownload,
     removeDownload,
+    saveCredentials,
+    deleteCredentials,
+    saveBearerToken,
+    deleteBearerToken,
     clearAllHistory,
+    hasMoreHistory,
+    loadMoreHistory,
     selectFolder,
     refreshStorage,
     updateSettings,